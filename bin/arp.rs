@@ -11,7 +11,10 @@ use libc::{
 use m6coll::Array;
 use netlib::{
     aux::HostOrIPv4,
-    data::{InAddrN, SockAddrLL, getifaddrs, getifmac, getifnth},
+    data::{
+        getifaddrs, getifmac, getifnth, neighbours, proc_neighbours, InAddrN,
+        NudState, SockAddrLL,
+    },
     datalink::{Eth, EthTypeE, Mac, PacType},
     rs_error::{NetErr, Result},
     network::arp::{ARPOpE, ARP, ARPHTE},
@@ -153,12 +156,52 @@ unsafe fn ewait_arp(epfd: i32, events: &mut [epoll_event]) -> Result<i32> {
 
 
 
+/// Print kernel neighbour cache in the way of `arp -a`
+fn show_cache(ifname: Option<&str>) -> Result<()> {
+    // netlink or else /proc/net/arp
+    let neighs = unsafe { neighbours() }.or_else(|_| proc_neighbours())?;
+
+    for neigh in neighs.iter() {
+        if !neigh.addr.is_ipv4()
+            || neigh.state.contains(NudState::NOARP)
+            || ifname.is_some_and(|name| neigh.dev.as_deref() != Some(name))
+        {
+            continue;
+        }
+
+        let mac = match neigh.mac {
+            Some(mac) => format!("{mac} [ether]"),
+            None => "<incomplete>".to_owned(),
+        };
+        let perm = if neigh.state.contains(NudState::PERMANENT) {
+            " PERM"
+        }
+        else {
+            ""
+        };
+
+        println!(
+            "? ({}) at {mac}{perm} on {}",
+            neigh.addr,
+            neigh.dev.as_deref().unwrap_or("?")
+        );
+    }
+
+    Ok(())
+}
+
+
+
 #[derive(Parser)]
 #[clap()]
 struct Cli {
     /// Hostname or IP
-    #[clap()]
-    dst: String,
+    #[clap(required_unless_present = "cache")]
+    dst: Option<String>,
+
+    /// Show the kernel ARP cache instead (of interface by -i)
+    #[clap(short = 'a')]
+    cache: bool,
 
     /// Set interface by name or else use first nonloop interface
     #[clap(short = 'i')]
@@ -167,7 +210,12 @@ struct Cli {
 fn main() -> Result<!> {
     let cli = Cli::parse();
 
-    let hostorip = or2anyway!(HostOrIPv4::from_str(&cli.dst))?;
+    if cli.cache {
+        show_cache(cli.ifname.as_deref())?;
+        std::process::exit(0);
+    }
+
+    let hostorip = or2anyway!(HostOrIPv4::from_str(&cli.dst.unwrap()))?;
     let dst: Ipv4Addr = or2anyway!(hostorip.try_into())?;
     let dst_ip = InAddrN::from_ipv4addr(dst);
 
@@ -0,0 +1,160 @@
+#![feature(never_type)]
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use clap::Parser;
+use netlib::{
+    application::dhcp::{
+        server::{DhcpServer, ServerConf},
+        DhcpMsg, DHCP_SERVER_PORT,
+    },
+    data::{getifaddrs, Ipv4Cidr},
+    datalink::Mac,
+    rs_error::{NetErr, Result},
+    transport::udp::UdpSock,
+};
+
+
+
+/// Minimal DHCPv4 server for lab networks
+#[derive(Parser)]
+#[clap(name = "DHCPD")]
+struct Cli {
+    /// Serving interface name
+    #[clap()]
+    r#if: String,
+
+    /// Address pool in CIDR, e.g. 10.0.0.0/24
+    #[clap(long)]
+    pool: Ipv4Cidr,
+
+    /// Server identifier, default to the IPv4 address of the interface
+    #[clap(long)]
+    server_id: Option<Ipv4Addr>,
+
+    #[clap(long)]
+    router: Vec<Ipv4Addr>,
+
+    #[clap(long)]
+    dns: Vec<Ipv4Addr>,
+
+    /// Seconds
+    #[clap(long, default_value = "3600")]
+    lease_time: u64,
+
+    /// Static reservation `<mac>=<ip>`, could be repeated
+    #[clap(long)]
+    reserve: Vec<String>,
+
+    /// Persist leases to the file
+    #[clap(long)]
+    lease_file: Option<PathBuf>,
+}
+
+
+fn parse_reservations(items: &[String]) -> Result<HashMap<Mac, Ipv4Addr>> {
+    let mut reservations = HashMap::new();
+
+    for item in items {
+        let bad = || NetErr::AnyWay(format!("Invalid reservation: {item}"));
+
+        let (mac, ip) = item.split_once('=').ok_or_else(bad)?;
+        let mac: Mac = mac.parse().map_err(|_| bad())?;
+        let ip: Ipv4Addr = ip.parse().map_err(|_| bad())?;
+
+        reservations.insert(mac, ip);
+    }
+
+    Ok(reservations)
+}
+
+
+fn main() -> Result<!> {
+    let cli = Cli::parse();
+
+    let server_id = match cli.server_id {
+        Some(server_id) => server_id,
+        None => unsafe {
+            let ifaddrs = getifaddrs()?;
+            let found = ifaddrs
+                .get_inet_items()
+                .find(|(name, _, _)| *name == cli.r#if)
+                .map(|(_, ip, _)| *ip);
+
+            found.ok_or_else(|| {
+                NetErr::GetIf(format!("No IPv4 address on {}", cli.r#if))
+            })?
+        },
+    };
+
+    let conf = ServerConf {
+        server_id,
+        pool: cli.pool,
+        routers: cli.router,
+        dns: cli.dns,
+        lease_time: Duration::from_secs(cli.lease_time),
+        reservations: parse_reservations(&cli.reserve)?,
+        lease_file: cli.lease_file,
+    };
+    println!("{conf:#?}");
+
+    let mut server = DhcpServer::new(conf)?;
+
+    unsafe {
+        let sock = UdpSock::bind(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED,
+            DHCP_SERVER_PORT,
+        ))?;
+        sock.set_broadcast(true)?;
+        sock.bind_to_device(&cli.r#if)?;
+
+        println!("Serving on {} ({server_id})", cli.r#if);
+
+        let mut buf = [0u8; 1500];
+
+        loop {
+            let (n, from) = match sock.recv_from(&mut buf)? {
+                Some(res) => res,
+                None => continue,
+            };
+
+            let msg = match DhcpMsg::decode(&buf[..n]) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    eprintln!("Malformed message from {from}: {err:?}");
+                    continue;
+                }
+            };
+
+            println!(
+                "{:?} from {} ({from})",
+                msg.msg_type().ok(),
+                msg.hdr.get_chaddr_mac()
+            );
+
+            match server.handle(&msg, SystemTime::now()) {
+                Ok(Some(reply)) => {
+                    println!(
+                        "{:?} {} to {}",
+                        reply.msg.msg_type().ok(),
+                        reply.msg.hdr.yiaddr.ipv4(),
+                        reply.dst
+                    );
+                    // e.g. ENETUNREACH, the client will retransmit
+                    if let Err(err) =
+                        sock.send_to(&reply.msg.to_bytes(), reply.dst)
+                    {
+                        eprintln!("Send to {}: {err:?}", reply.dst);
+                    }
+                }
+                Ok(None) => (),
+                Err(err) => eprintln!("{err:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use netlib::application::dns::{DnsMsg, Question, RData, Rcode};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Upper bound of cached TTL
+const MAX_TTL: u32 = 86400;
+
+/// OPT pseudo record (rfc6891), its TTL field carries flags
+const RTYPE_OPT: u16 = 41;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Cache of forwarded responses, positive and negative (rfc2308)
+pub struct Cache {
+    entries: HashMap<(String, u16, u16), Entry>,
+    cap: usize,
+}
+
+struct Entry {
+    resp: DnsMsg,
+    stored: Instant,
+    ttl: u32,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Cache {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            cap,
+        }
+    }
+
+    /// Cached response with TTLs decreased by the time it has been stored
+    pub fn get(&mut self, q: &Question, now: Instant) -> Option<DnsMsg> {
+        let key = key_of(q);
+        let entry = self.entries.get(&key)?;
+        let elapsed = now.saturating_duration_since(entry.stored);
+
+        if elapsed >= Duration::from_secs(entry.ttl as u64) {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = elapsed.as_secs() as u32;
+        let mut resp = entry.resp.clone();
+
+        for rr in resp
+            .answers
+            .iter_mut()
+            .chain(resp.authorities.iter_mut())
+            .chain(resp.additionals.iter_mut())
+            .filter(|rr| rr.rtype() != RTYPE_OPT)
+        {
+            rr.ttl = rr.ttl.saturating_sub(elapsed);
+        }
+
+        Some(resp)
+    }
+
+    /// Only complete NOERROR / NXDOMAIN responses with a non-zero TTL are
+    /// kept.
+    pub fn put(&mut self, resp: &DnsMsg, now: Instant) {
+        if resp.hdr.tc || resp.questions.len() != 1 {
+            return;
+        }
+
+        if !matches!(resp.rcode(), Ok(Rcode::NoError | Rcode::NXDomain)) {
+            return;
+        }
+
+        let ttl = match ttl_of(resp) {
+            Some(ttl) if ttl > 0 => ttl.min(MAX_TTL),
+            _ => return,
+        };
+
+        if self.entries.len() >= self.cap {
+            self.entries.retain(|_, entry| {
+                now.saturating_duration_since(entry.stored)
+                    < Duration::from_secs(entry.ttl as u64)
+            });
+
+            if self.entries.len() >= self.cap {
+                self.entries.clear();
+            }
+        }
+
+        self.entries.insert(
+            key_of(&resp.questions[0]),
+            Entry {
+                resp: resp.clone(),
+                stored: now,
+                ttl,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+fn key_of(q: &Question) -> (String, u16, u16) {
+    (q.name.to_ascii_lowercase(), q.qtype, q.qclass)
+}
+
+
+/// Minimum TTL of answers, or negative TTL from SOA of authority section
+fn ttl_of(resp: &DnsMsg) -> Option<u32> {
+    if !resp.answers.is_empty() {
+        return resp.answers.iter().map(|rr| rr.ttl).min();
+    }
+
+    resp.authorities.iter().find_map(|rr| match rr.data {
+        RData::SOA { minimum, .. } => Some(rr.ttl.min(minimum)),
+        _ => None,
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use netlib::application::dns::{DnsMsg, RData, RType, Rcode, RR};
+
+    use super::{Cache, MAX_TTL, RTYPE_OPT};
+
+    fn soa(ttl: u32, minimum: u32) -> RR {
+        RR::new(
+            "lab.test",
+            ttl,
+            RData::SOA {
+                mname: "ns.lab.test".to_owned(),
+                rname: "hostmaster.lab.test".to_owned(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum,
+            },
+        )
+    }
+
+    #[test]
+    fn test_dnsd_cache_ttl() {
+        let mut cache = Cache::new(8);
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        let query = DnsMsg::query(1, "web.lab.test", RType::A);
+        let q = &query.questions[0];
+        let mut resp = DnsMsg::reply_to(&query);
+        resp.answers.extend([
+            RR::new("web.lab.test", 300, RData::A(Ipv4Addr::new(10, 0, 0, 1))),
+            RR::new("web.lab.test", 60, RData::A(Ipv4Addr::new(10, 0, 0, 2))),
+        ]);
+        resp.additionals.push(RR::new(
+            "",
+            0x8000,
+            RData::Other(RTYPE_OPT, vec![]),
+        ));
+
+        assert!(cache.get(q, now).is_none());
+        cache.put(&resp, now);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(q, now).unwrap(), resp);
+
+        // case insensitive, TTLs decreased but not of OPT
+        let upper = DnsMsg::query(2, "WEB.lab.test", RType::A);
+        let cached = cache.get(&upper.questions[0], secs(10)).unwrap();
+        assert_eq!(cached.answers[0].ttl, 290);
+        assert_eq!(cached.answers[1].ttl, 50);
+        assert_eq!(cached.additionals[0].ttl, 0x8000);
+
+        // expires with the minimum TTL of the answers
+        assert!(cache.get(q, secs(59)).is_some());
+        assert!(cache.get(q, secs(60)).is_none());
+        assert_eq!(cache.len(), 0);
+
+        // other type is other entry
+        cache.put(&resp, now);
+        let aaaa = DnsMsg::query(3, "web.lab.test", RType::AAAA);
+        assert!(cache.get(&aaaa.questions[0], now).is_none());
+
+        // capped
+        resp.answers.truncate(1);
+        resp.answers[0].ttl = u32::MAX;
+        cache.put(&resp, now);
+        assert!(cache.get(q, secs(MAX_TTL as u64 - 1)).is_some());
+        assert!(cache.get(q, secs(MAX_TTL as u64)).is_none());
+    }
+
+    #[test]
+    fn test_dnsd_cache_negative() {
+        let mut cache = Cache::new(8);
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        // NXDOMAIN for the minimum of SOA TTL and MINIMUM
+        let query = DnsMsg::query(1, "nope.lab.test", RType::A);
+        let q = &query.questions[0];
+        let mut resp = DnsMsg::reply_to(&query);
+        resp.hdr.rcode = Rcode::NXDomain as u8;
+        resp.authorities.push(soa(300, 30));
+
+        cache.put(&resp, now);
+        let cached = cache.get(q, secs(10)).unwrap();
+        assert_eq!(cached.rcode(), Ok(Rcode::NXDomain));
+        assert_eq!(cached.authorities[0].ttl, 290);
+        assert!(cache.get(q, secs(30)).is_none());
+
+        // NODATA
+        resp.hdr.rcode = Rcode::NoError as u8;
+        resp.authorities[0] = soa(20, 60);
+        cache.put(&resp, now);
+        assert!(cache.get(q, secs(19)).is_some());
+        assert!(cache.get(q, secs(20)).is_none());
+
+        // no SOA, no TTL to cache by
+        resp.authorities.clear();
+        cache.put(&resp, now);
+        assert_eq!(cache.len(), 0);
+
+        // never cached
+        resp.authorities.push(soa(300, 300));
+        for rcode in [Rcode::ServFail, Rcode::Refused] {
+            resp.hdr.rcode = rcode as u8;
+            cache.put(&resp, now);
+        }
+        resp.hdr.rcode = Rcode::NXDomain as u8;
+        resp.hdr.tc = true;
+        cache.put(&resp, now);
+        resp.hdr.tc = false;
+        resp.authorities[0] = soa(0, 300);
+        cache.put(&resp, now);
+        assert_eq!(cache.len(), 0);
+    }
+}
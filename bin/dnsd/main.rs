@@ -0,0 +1,392 @@
+mod cache;
+mod zone;
+
+
+use std::{
+    env,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use cache::Cache;
+use clap::Parser;
+use futures::executor::ThreadPool;
+use log::{debug, info, warn};
+use netlib::{
+    application::dns::{
+        resolver::{ResolvConf, Resolver},
+        DnsMsg, Question, RData, RType, Rcode, CLASS_IN, DNS_UDP_MAX,
+    },
+    aux::random_u16,
+    rs_error::{LoggerKind, NetErr, Result},
+};
+use zone::{load_dnsd_conf, DnsdConf, Zone};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+const CACHE_CAP: usize = 10000;
+
+/// Longest CNAME chain followed inside local zones
+const MAX_CNAME_CHAIN: usize = 8;
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OPT pseudo record (rfc6891), its CLASS field is the requestor's UDP
+/// payload size
+const RTYPE_OPT: u16 = 41;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Cli
+
+/// Tiny authoritative / forwarding DNS server
+#[derive(Parser)]
+#[clap(name = "DNSD")]
+struct Cli {
+    /// Zone file
+    #[clap(short = 'c', default_value = "res/dnsd/dnsd.default.yaml")]
+    config_file: PathBuf,
+
+    /// Override `listen` of the zone file
+    #[clap(long)]
+    listen: Option<SocketAddr>,
+}
+
+
+pub struct Dnsd {
+    zones: Vec<Zone>,
+    upstream: Option<Resolver>,
+    cache: Mutex<Cache>,
+}
+
+
+impl Dnsd {
+    fn new(conf: DnsdConf) -> Self {
+        let upstream = if conf.forward.is_empty() {
+            None
+        }
+        else {
+            Some(Resolver::new(ResolvConf {
+                nameservers: conf.forward,
+                timeout: UPSTREAM_TIMEOUT,
+                ..Default::default()
+            }))
+        };
+
+        Self {
+            zones: conf.zones,
+            upstream,
+            cache: Mutex::new(Cache::new(CACHE_CAP)),
+        }
+    }
+
+    fn handle(&self, query: &DnsMsg) -> DnsMsg {
+        let mut resp = DnsMsg::reply_to(query);
+
+        if query.hdr.qr || query.hdr.opcode != 0 {
+            resp.hdr.rcode = Rcode::NotImp as u8;
+            return resp;
+        }
+
+        if query.questions.len() != 1 {
+            resp.hdr.rcode = Rcode::FormErr as u8;
+            return resp;
+        }
+
+        let q = &query.questions[0];
+        resp.hdr.ra = self.upstream.is_some();
+
+        if let Some(zone) =
+            self.zones.iter().find(|zone| zone.contains(&q.name))
+        {
+            if q.qclass != CLASS_IN && q.qclass != RType::ANY as u16 {
+                resp.hdr.rcode = Rcode::Refused as u8;
+            }
+            else {
+                self.answer_authoritative(zone, q, &mut resp);
+            }
+
+            return resp;
+        }
+
+        match &self.upstream {
+            Some(upstream) if query.hdr.rd => {
+                let mut resp = self.forward(upstream, query);
+                resp.hdr.ra = true;
+                resp
+            }
+            _ => {
+                resp.hdr.rcode = Rcode::Refused as u8;
+                resp
+            }
+        }
+    }
+
+    fn answer_authoritative(
+        &self,
+        zone: &Zone,
+        q: &Question,
+        resp: &mut DnsMsg,
+    ) {
+        resp.hdr.aa = true;
+
+        let mut name = q.name.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let rrset = zone.rrset(&name, q.qtype);
+
+            if !rrset.is_empty() {
+                resp.answers.extend(rrset);
+                return;
+            }
+
+            let cname = zone.rrset(&name, RType::CNAME as u16);
+
+            match cname.first().map(|rr| &rr.data) {
+                Some(RData::CNAME(target)) => {
+                    let target = target.clone();
+                    resp.answers.extend(cname);
+
+                    // leave the out of zone target to the client
+                    if !zone.contains(&target) {
+                        return;
+                    }
+                    name = target;
+                }
+                _ => break,
+            }
+        }
+
+        // only the owner of the first name decides NXDOMAIN
+        if resp.answers.is_empty() && !zone.exists(&name) {
+            resp.hdr.rcode = Rcode::NXDomain as u8;
+        }
+        resp.authorities.push(zone.negative_soa());
+    }
+
+    fn forward(&self, upstream: &Resolver, query: &DnsMsg) -> DnsMsg {
+        let q = &query.questions[0];
+        let now = Instant::now();
+
+        if let Some(mut resp) = self.cache.lock().unwrap().get(q, now) {
+            debug!("cache hit {} {}", q.name, q.qtype);
+
+            resp.hdr.id = query.hdr.id;
+            resp.questions = query.questions.clone();
+            return resp;
+        }
+
+        let mut fwd = query.clone();
+        fwd.hdr.id = random_u16();
+
+        match upstream.exchange(&fwd) {
+            Ok(mut resp) => {
+                let mut cache = self.cache.lock().unwrap();
+                cache.put(&resp, now);
+                debug!("cached {} {}, total {}", q.name, q.qtype, cache.len());
+
+                resp.hdr.id = query.hdr.id;
+                resp.questions = query.questions.clone();
+                resp
+            }
+            Err(err) => {
+                warn!("forward {} {}: {err:?}", q.name, q.qtype);
+
+                let mut resp = DnsMsg::reply_to(query);
+                resp.hdr.rcode = Rcode::ServFail as u8;
+                resp
+            }
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// UDP payload size the client accepts (EDNS0), at least 512
+fn udp_max_of(query: &DnsMsg) -> usize {
+    query
+        .additionals
+        .iter()
+        .find(|rr| rr.rtype() == RTYPE_OPT)
+        .map(|rr| (rr.class as usize).clamp(DNS_UDP_MAX, 4096))
+        .unwrap_or(DNS_UDP_MAX)
+}
+
+
+fn serve_udp(
+    dnsd: Arc<Dnsd>,
+    sock: UdpSocket,
+    pool: ThreadPool,
+) -> Result<()> {
+    let sock = Arc::new(sock);
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let (n, from) = sock.recv_from(&mut buf).map_err(NetErr::Read)?;
+
+        let query = match DnsMsg::decode(&buf[..n]) {
+            Ok(query) => query,
+            Err(err) => {
+                debug!("malformed query from {from}: {err:?}");
+                continue;
+            }
+        };
+
+        let dnsd = dnsd.clone();
+        let sock = sock.clone();
+
+        pool.spawn_ok(async move {
+            let resp = dnsd.handle(&query);
+            log_exchange(from, "udp", &query, &resp);
+
+            match resp.to_udp_bytes(udp_max_of(&query)) {
+                Ok(bytes) => {
+                    if let Err(err) = sock.send_to(&bytes, from) {
+                        warn!("send to {from}: {err}");
+                    }
+                }
+                Err(err) => warn!("encode: {err:?}"),
+            }
+        });
+    }
+}
+
+
+fn serve_tcp_conn(dnsd: &Dnsd, mut stream: TcpStream) -> Result<()> {
+    let from = stream.peer_addr().map_err(NetErr::Read)?;
+
+    stream
+        .set_read_timeout(Some(TCP_IDLE_TIMEOUT))
+        .map_err(NetErr::SetStreamOpt)?;
+
+    // multiple queries could be sent on one connection (rfc7766)
+    loop {
+        let mut len = [0u8; 2];
+
+        if stream.read_exact(&mut len).is_err() {
+            return Ok(());
+        }
+
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).map_err(NetErr::Read)?;
+
+        let query = DnsMsg::decode(&buf)?;
+        let resp = dnsd.handle(&query);
+        log_exchange(from, "tcp", &query, &resp);
+
+        let bytes = resp.to_bytes()?;
+        let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&bytes);
+
+        stream.write_all(&framed).map_err(NetErr::Write)?;
+    }
+}
+
+
+fn serve_tcp(dnsd: Arc<Dnsd>, listener: TcpListener, pool: ThreadPool) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let dnsd = dnsd.clone();
+
+                pool.spawn_ok(async move {
+                    if let Err(err) = serve_tcp_conn(&dnsd, stream) {
+                        debug!("tcp: {err:?}");
+                    }
+                });
+            }
+            Err(err) => warn!("accept: {err}"),
+        }
+    }
+}
+
+
+fn log_exchange(from: SocketAddr, proto: &str, query: &DnsMsg, resp: &DnsMsg) {
+    if let Some(q) = query.questions.first() {
+        let rcode = match resp.rcode() {
+            Ok(rcode) => format!("{rcode:?}"),
+            Err(rcode) => format!("RCODE{rcode}"),
+        };
+
+        info!(
+            "{from} {proto} {} {} => {rcode} {} answers",
+            q.name,
+            q.qtype,
+            resp.answers.len()
+        );
+    }
+}
+
+
+fn setup_logger() -> Result<()> {
+    /* Logger should be configured first! */
+    let mut logconf = log4rs::config::load_config_file(
+        "res/dnsd/log4rs.default.yaml",
+        Default::default(),
+    )
+    .map_err(|err| {
+        NetErr::Log4RS(LoggerKind::LoadConfigFailed(format!("{}", err)))
+    })?;
+
+    if let Ok(levels) = env::var("RUST_LOG") {
+        match levels.parse() {
+            Ok(level) => {
+                logconf.root_mut().set_level(level);
+            }
+            Err(err) => {
+                return Err(NetErr::Log4RS(LoggerKind::LoadConfigFailed(
+                    format!("{}", err),
+                )));
+            }
+        }
+    }
+
+    log4rs::init_config(logconf).map_err(|err| {
+        NetErr::Log4RS(LoggerKind::InvalidEnv(format!("{}", err)))
+    })?;
+
+    Ok(())
+}
+
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    setup_logger()?;
+
+    let mut conf = load_dnsd_conf(&cli.config_file)?;
+
+    if let Some(listen) = cli.listen {
+        conf.listen = listen;
+    }
+    info!("dnsd conf: {:#?}", conf);
+
+    let listen = conf.listen;
+    let dnsd = Arc::new(Dnsd::new(conf));
+
+    let pool = ThreadPool::new().map_err(NetErr::CreateThreadPool)?;
+
+    let sock = UdpSocket::bind(listen).map_err(|_err| NetErr::Bind)?;
+    let listener = TcpListener::bind(listen).map_err(|_err| NetErr::Bind)?;
+
+    println!("Serving on {listen} (udp, tcp)");
+
+    {
+        let dnsd = dnsd.clone();
+        let pool = pool.clone();
+
+        thread::spawn(move || serve_tcp(dnsd, listener, pool));
+    }
+
+    serve_udp(dnsd, sock, pool)
+}
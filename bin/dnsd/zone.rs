@@ -0,0 +1,537 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use netlib::{
+    application::dns::{RData, RType, DNS_PORT, RR},
+    rs_error::*,
+};
+use serde_yaml::{self, Mapping, Value};
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const CONF_NAME_LISTEN: &str = "listen";
+pub const CONF_NAME_TTL: &str = "ttl";
+pub const CONF_NAME_FORWARD: &str = "forward";
+pub const CONF_NAME_ZONES: &str = "zones";
+
+pub const CONF_NAME_SOA: &str = "soa";
+pub const CONF_NAME_SOA_MNAME: &str = "mname";
+pub const CONF_NAME_SOA_RNAME: &str = "rname";
+pub const CONF_NAME_SOA_SERIAL: &str = "serial";
+pub const CONF_NAME_SOA_REFRESH: &str = "refresh";
+pub const CONF_NAME_SOA_RETRY: &str = "retry";
+pub const CONF_NAME_SOA_EXPIRE: &str = "expire";
+pub const CONF_NAME_SOA_MINIMUM: &str = "minimum";
+
+pub const CONF_NAME_RECORDS: &str = "records";
+pub const CONF_NAME_REC_NAME: &str = "name";
+pub const CONF_NAME_REC_TYPE: &str = "type";
+pub const CONF_NAME_REC_VALUE: &str = "value";
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:5353";
+const DEFAULT_TTL: u32 = 300;
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone)]
+pub struct DnsdConf {
+    pub listen: SocketAddr,
+    /// Upstream nameservers, empty means authoritative only
+    pub forward: Vec<SocketAddr>,
+    pub zones: Vec<Zone>,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: String,
+    pub soa: RR,
+    pub records: Vec<RR>,
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Zone {
+    /// Whether `name` is at or below the origin
+    pub fn contains(&self, name: &str) -> bool {
+        is_subdomain(name, &self.origin)
+    }
+
+    /// Whether the name owns records, is an empty non-terminal or is
+    /// covered by a wildcard
+    pub fn exists(&self, name: &str) -> bool {
+        self.exists_exact(name) || self.wildcard_of(name).is_some()
+    }
+
+    /// Records owned by `name` of `qtype` (`ANY` for all), synthesized
+    /// from the wildcard if the name doesn't exist
+    pub fn rrset(&self, name: &str, qtype: u16) -> Vec<RR> {
+        let owner = self.wildcard_of(name);
+        let owner = owner.as_deref().unwrap_or(name);
+
+        std::iter::once(&self.soa)
+            .chain(self.records.iter())
+            .filter(|rr| {
+                rr.name.eq_ignore_ascii_case(owner)
+                    && (qtype == RType::ANY as u16 || rr.rtype() == qtype)
+            })
+            .map(|rr| RR {
+                name: name.to_owned(),
+                ..rr.clone()
+            })
+            .collect()
+    }
+
+    fn exists_exact(&self, name: &str) -> bool {
+        name.eq_ignore_ascii_case(&self.origin)
+            || self.records.iter().any(|rr| is_subdomain(&rr.name, name))
+    }
+
+    /// `*.<closest encloser>` if it owns records and `name` doesn't exist
+    /// (rfc4592)
+    fn wildcard_of(&self, name: &str) -> Option<String> {
+        if !self.contains(name) || self.exists_exact(name) {
+            return None;
+        }
+
+        let mut encloser = name;
+
+        loop {
+            encloser = encloser.split_once('.').map(|(_, up)| up)?;
+
+            if self.exists_exact(encloser) {
+                let wildcard = format!("*.{encloser}");
+
+                return self
+                    .records
+                    .iter()
+                    .any(|rr| rr.name.eq_ignore_ascii_case(&wildcard))
+                    .then_some(wildcard);
+            }
+        }
+    }
+
+    /// SOA for the authority section of negative answers (rfc2308)
+    pub fn negative_soa(&self) -> RR {
+        let mut soa = self.soa.clone();
+
+        if let RData::SOA { minimum, .. } = soa.data {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+
+        soa
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Functions
+
+pub fn is_subdomain(name: &str, domain: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+
+    domain.is_empty()
+        || name == domain
+        || name.ends_with(&format!(".{domain}"))
+}
+
+
+/// `@` is the origin, names with tailing dot are absolute
+fn qualify(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_owned()
+    }
+    else if let Some(abs) = name.strip_suffix('.') {
+        abs.to_owned()
+    }
+    else if origin.is_empty() {
+        name.to_owned()
+    }
+    else {
+        format!("{name}.{origin}")
+    }
+}
+
+
+fn parse_socket_addr(v: &Value, field: &'static str) -> Result<SocketAddr> {
+    let s = match v {
+        Value::String(s) => s,
+        _ => return Err(NetErr::YAMLInvalidField(field)),
+    };
+
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        Ok(addr)
+    }
+    else if let Ok(ip) = s.parse::<IpAddr>() {
+        Ok(SocketAddr::new(ip, DNS_PORT))
+    }
+    else {
+        Err(NetErr::YAMLInvalidField(field))
+    }
+}
+
+
+fn get_u32(map: &Mapping, key: &'static str) -> Result<Option<u32>> {
+    match map.get(key) {
+        Some(v) => match v.as_u64() {
+            Some(n) if n <= u32::MAX as u64 => Ok(Some(n as u32)),
+            _ => Err(NetErr::YAMLInvalidField(key)),
+        },
+        None => Ok(None),
+    }
+}
+
+
+fn get_str<'a>(
+    map: &'a Mapping,
+    key: &'static str,
+) -> Result<Option<&'a str>> {
+    match map.get(key) {
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(NetErr::YAMLInvalidField(key)),
+        None => Ok(None),
+    }
+}
+
+
+fn load_soa(origin: &str, soav: Option<&Value>, ttl: u32) -> Result<RR> {
+    let empty = Mapping::new();
+    let map = match soav {
+        Some(Value::Mapping(map)) => map,
+        Some(_) => return Err(NetErr::YAMLInvalidField(CONF_NAME_SOA)),
+        None => &empty,
+    };
+
+    let mname = get_str(map, CONF_NAME_SOA_MNAME)?.unwrap_or("ns");
+    let rname = get_str(map, CONF_NAME_SOA_RNAME)?.unwrap_or("hostmaster");
+
+    let data = RData::SOA {
+        mname: qualify(mname, origin),
+        rname: qualify(rname, origin),
+        serial: get_u32(map, CONF_NAME_SOA_SERIAL)?.unwrap_or(1),
+        refresh: get_u32(map, CONF_NAME_SOA_REFRESH)?.unwrap_or(3600),
+        retry: get_u32(map, CONF_NAME_SOA_RETRY)?.unwrap_or(600),
+        expire: get_u32(map, CONF_NAME_SOA_EXPIRE)?.unwrap_or(86400),
+        minimum: get_u32(map, CONF_NAME_SOA_MINIMUM)?.unwrap_or(ttl),
+    };
+
+    Ok(RR::new(origin, ttl, data))
+}
+
+
+/// Value formats:
+///
+/// - A / AAAA: address
+/// - NS / CNAME / PTR: name
+/// - MX: `<preference> <exchange>`
+/// - SRV: `<priority> <weight> <port> <target>`
+/// - TXT: a string or list of strings
+fn load_rdata(ty: &str, value: &Value, origin: &str) -> Result<RData> {
+    let bad = || NetErr::YAMLInvalidField(CONF_NAME_REC_VALUE);
+
+    if ty.eq_ignore_ascii_case("TXT") {
+        return match value {
+            Value::String(s) => Ok(RData::TXT(vec![s.clone()])),
+            Value::Sequence(seq) => seq
+                .iter()
+                .map(|v| v.as_str().map(str::to_owned).ok_or_else(bad))
+                .collect::<Result<Vec<String>>>()
+                .map(RData::TXT),
+            _ => Err(bad()),
+        };
+    }
+
+    let s = match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        _ => return Err(bad()),
+    };
+    let words: Vec<&str> = s.split_whitespace().collect();
+
+    Ok(match (ty.to_ascii_uppercase().as_str(), &words[..]) {
+        ("A", [ip]) => RData::A(ip.parse().map_err(|_| bad())?),
+        ("AAAA", [ip]) => RData::AAAA(ip.parse().map_err(|_| bad())?),
+        ("NS", [name]) => RData::NS(qualify(name, origin)),
+        ("CNAME", [name]) => RData::CNAME(qualify(name, origin)),
+        ("PTR", [name]) => RData::PTR(qualify(name, origin)),
+        ("MX", [preference, exchange]) => RData::MX {
+            preference: preference.parse().map_err(|_| bad())?,
+            exchange: qualify(exchange, origin),
+        },
+        ("SRV", [priority, weight, port, target]) => RData::SRV {
+            priority: priority.parse().map_err(|_| bad())?,
+            weight: weight.parse().map_err(|_| bad())?,
+            port: port.parse().map_err(|_| bad())?,
+            target: qualify(target, origin),
+        },
+        _ => return Err(NetErr::YAMLInvalidField(CONF_NAME_REC_TYPE)),
+    })
+}
+
+
+fn load_records(seq: &[Value], origin: &str, ttl: u32) -> Result<Vec<RR>> {
+    let mut records = vec![];
+
+    for recv in seq.iter() {
+        let map = match recv {
+            Value::Mapping(map) => map,
+            _ => return Err(NetErr::YAMLInvalidField(CONF_NAME_RECORDS)),
+        };
+
+        let name = get_str(map, CONF_NAME_REC_NAME)?.unwrap_or("@");
+        let ty = get_str(map, CONF_NAME_REC_TYPE)?
+            .ok_or(NetErr::YAMLNonExistField(CONF_NAME_REC_TYPE))?;
+        let value = map
+            .get(CONF_NAME_REC_VALUE)
+            .ok_or(NetErr::YAMLNonExistField(CONF_NAME_REC_VALUE))?;
+        let rec_ttl = get_u32(map, CONF_NAME_TTL)?.unwrap_or(ttl);
+
+        records.push(RR::new(
+            &qualify(name, origin),
+            rec_ttl,
+            load_rdata(ty, value, origin)?,
+        ));
+    }
+
+    Ok(records)
+}
+
+
+fn load_zone(origin: &str, zonev: Value, ttl: u32) -> Result<Zone> {
+    let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+
+    let mut map = match zonev {
+        Value::Mapping(map) => map,
+        _ => return Err(NetErr::YAMLInvalidField(CONF_NAME_ZONES)),
+    };
+
+    let ttl = get_u32(&map, CONF_NAME_TTL)?.unwrap_or(ttl);
+    let soa = load_soa(&origin, map.get(CONF_NAME_SOA), ttl)?;
+
+    let records = match map.remove(CONF_NAME_RECORDS) {
+        Some(Value::Sequence(seq)) => load_records(&seq, &origin, ttl)?,
+        Some(_) => return Err(NetErr::YAMLInvalidField(CONF_NAME_RECORDS)),
+        None => vec![],
+    };
+
+    Ok(Zone {
+        origin,
+        soa,
+        records,
+    })
+}
+
+
+pub fn load_dnsd_conf<P: AsRef<Path>>(p: P) -> Result<DnsdConf> {
+    let file = File::open(p).map_err(NetErr::Open)?;
+    let buf_file = BufReader::new(file);
+
+    let mut map: BTreeMap<String, Value> =
+        serde_yaml::from_reader(buf_file)
+            .map_err(|_err| NetErr::MalformedYAML)?;
+
+    let listen = match map.remove(CONF_NAME_LISTEN) {
+        Some(v) => parse_socket_addr(&v, CONF_NAME_LISTEN)?,
+        None => DEFAULT_LISTEN.parse().unwrap(),
+    };
+
+    let ttl = match map.remove(CONF_NAME_TTL) {
+        Some(v) => match v.as_u64() {
+            Some(n) if n <= u32::MAX as u64 => n as u32,
+            _ => return Err(NetErr::YAMLInvalidField(CONF_NAME_TTL)),
+        },
+        None => DEFAULT_TTL,
+    };
+
+    let forward = match map.remove(CONF_NAME_FORWARD) {
+        Some(Value::Sequence(seq)) => seq
+            .iter()
+            .map(|v| parse_socket_addr(v, CONF_NAME_FORWARD))
+            .collect::<Result<Vec<SocketAddr>>>()?,
+        Some(v @ Value::String(_)) => {
+            vec![parse_socket_addr(&v, CONF_NAME_FORWARD)?]
+        }
+        Some(_) => return Err(NetErr::YAMLInvalidField(CONF_NAME_FORWARD)),
+        None => vec![],
+    };
+
+    let mut zones = vec![];
+
+    match map.remove(CONF_NAME_ZONES) {
+        Some(Value::Mapping(zonesv)) => {
+            for (origin, zonev) in zonesv.into_iter() {
+                let origin = match origin {
+                    Value::String(s) => s,
+                    _ => {
+                        return Err(NetErr::YAMLInvalidField(CONF_NAME_ZONES))
+                    }
+                };
+
+                zones.push(load_zone(&origin, zonev, ttl)?);
+            }
+        }
+        Some(_) => return Err(NetErr::YAMLInvalidField(CONF_NAME_ZONES)),
+        None => (),
+    }
+
+    // the most specific zone first
+    zones.sort_by_key(|zone| std::cmp::Reverse(zone.origin.len()));
+
+    Ok(DnsdConf {
+        listen,
+        forward,
+        zones,
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use netlib::application::dns::{RData, RType};
+    use serde_yaml::Value;
+
+    use super::{is_subdomain, load_zone, qualify};
+
+    fn zone() -> super::Zone {
+        let zonev: Value = serde_yaml::from_str(
+            r#"
+            soa: { serial: 7, minimum: 60 }
+            records:
+              - { name: "@", type: A, value: 10.0.0.1 }
+              - { name: "@", type: MX, value: "10 mail" }
+              - { name: "@", type: TXT, value: [a, b] }
+              - { name: mail, type: A, value: 10.0.0.25, ttl: 30 }
+              - { name: www, type: CNAME, value: web.other.test. }
+              - { name: a.b, type: A, value: 10.0.0.2 }
+              - { name: "*.dev", type: A, value: 10.0.0.80 }
+              - { name: x.dev, type: TXT, value: x }
+            "#,
+        )
+        .unwrap();
+
+        load_zone("Lab.Test.", zonev, 300).unwrap()
+    }
+
+    #[test]
+    fn test_dnsd_zone_parse() {
+        assert_eq!(qualify("@", "lab.test"), "lab.test");
+        assert_eq!(qualify("web", "lab.test"), "web.lab.test");
+        assert_eq!(qualify("web.other.", "lab.test"), "web.other");
+        assert!(is_subdomain("WWW.lab.test", "lab.test"));
+        assert!(!is_subdomain("xlab.test", "lab.test"));
+
+        let zone = zone();
+
+        assert_eq!(zone.origin, "lab.test");
+        assert_eq!(zone.records.len(), 8);
+
+        match &zone.soa.data {
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                minimum,
+                ..
+            } => {
+                assert_eq!(mname, "ns.lab.test");
+                assert_eq!(rname, "hostmaster.lab.test");
+                assert_eq!(*serial, 7);
+                assert_eq!(*minimum, 60);
+            }
+            data => panic!("{data:?}"),
+        }
+        assert_eq!(zone.soa.ttl, 300);
+        assert_eq!(zone.negative_soa().ttl, 60);
+
+        let mail = zone.rrset("MAIL.lab.test", RType::A as u16);
+        assert_eq!(mail.len(), 1);
+        assert_eq!(mail[0].ttl, 30);
+        assert_eq!(mail[0].data, RData::A(Ipv4Addr::new(10, 0, 0, 25)));
+
+        assert_eq!(
+            zone.rrset("lab.test", RType::MX as u16)[0].data,
+            RData::MX {
+                preference: 10,
+                exchange: "mail.lab.test".to_owned()
+            }
+        );
+        assert_eq!(
+            zone.rrset("lab.test", RType::TXT as u16)[0].data,
+            RData::TXT(vec!["a".to_owned(), "b".to_owned()])
+        );
+        assert_eq!(
+            zone.rrset("www.lab.test", RType::CNAME as u16)[0].data,
+            RData::CNAME("web.other.test".to_owned())
+        );
+        // SOA, A, MX, TXT
+        assert_eq!(zone.rrset("lab.test", RType::ANY as u16).len(), 4);
+
+        for bad in [
+            "records: [{ type: A, value: 10.0.0.256 }]",
+            "records: [{ type: MX, value: mail }]",
+            "records: [{ type: HINFO, value: x }]",
+            "records: [{ value: 10.0.0.1 }]",
+            "soa: { serial: -1 }",
+        ] {
+            let zonev: Value = serde_yaml::from_str(bad).unwrap();
+            assert!(load_zone("lab.test", zonev, 300).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_dnsd_zone_lookup() {
+        let zone = zone();
+
+        assert!(zone.contains("lab.test"));
+        assert!(zone.contains("x.y.lab.test"));
+        assert!(!zone.contains("other.test"));
+
+        // NODATA: the name exists without records of the type
+        assert!(zone.exists("mail.lab.test"));
+        assert!(zone.rrset("mail.lab.test", RType::AAAA as u16).is_empty());
+
+        // empty non-terminal is NODATA too
+        assert!(zone.exists("b.lab.test"));
+        assert!(zone.rrset("b.lab.test", RType::A as u16).is_empty());
+
+        // NXDOMAIN
+        assert!(!zone.exists("nope.lab.test"));
+        assert!(!zone.exists("c.b.lab.test"));
+        assert!(zone.rrset("nope.lab.test", RType::A as u16).is_empty());
+
+        // wildcard answers for the name asked
+        assert!(zone.exists("web.dev.lab.test"));
+        let rrset = zone.rrset("web.dev.lab.test", RType::A as u16);
+        assert_eq!(rrset.len(), 1);
+        assert_eq!(rrset[0].name, "web.dev.lab.test");
+        assert_eq!(rrset[0].data, RData::A(Ipv4Addr::new(10, 0, 0, 80)));
+        assert!(zone.exists("a.b.dev.lab.test"));
+        assert!(zone.rrset("web.dev.lab.test", RType::MX as u16).is_empty());
+
+        // an existing name is never covered by the wildcard
+        assert!(zone.rrset("x.dev.lab.test", RType::A as u16).is_empty());
+        assert_eq!(zone.rrset("x.dev.lab.test", RType::TXT as u16).len(), 1);
+
+        // nor is the wildcard's parent or a name under another encloser
+        assert!(zone.rrset("dev.lab.test", RType::A as u16).is_empty());
+        assert!(!zone.exists("web.lab.test"));
+    }
+}
@@ -0,0 +1,81 @@
+#![feature(never_type)]
+
+use std::{
+    fmt::Write, net::TcpListener, os::unix::net::UnixListener, thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use netlib::{
+    application::fastcgi::{
+        client::FcgiAddr,
+        responder::{serve_conn, FcgiRequest},
+    },
+    rs_error::{NetErr, Result},
+};
+
+
+
+/// FastCGI responder echoing params and stdin back, for testing
+#[derive(Parser)]
+#[clap(name = "FCGI Responder")]
+struct Cli {
+    /// `unix:/path/to/sock` or `host:port`
+    #[clap()]
+    addr: FcgiAddr,
+}
+
+
+/// Query `sleep=<ms>` delays the response
+fn echo(req: &FcgiRequest) -> Vec<u8> {
+    let query = req.param("QUERY_STRING").unwrap_or_default();
+    let sleep = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("sleep="))
+        .and_then(|ms| ms.parse().ok());
+
+    if let Some(ms) = sleep {
+        thread::sleep(Duration::from_millis(ms));
+    }
+
+    let mut out = String::from("Content-Type: text/plain\r\n\r\n");
+
+    for (name, val) in req.params.iter() {
+        writeln!(out, "{name}={val}").unwrap();
+    }
+    writeln!(out).unwrap();
+
+    let mut out = out.into_bytes();
+    out.extend_from_slice(&req.stdin);
+    out
+}
+
+
+fn main() -> Result<!> {
+    let cli = Cli::parse();
+    println!("listen on {}", cli.addr);
+
+    match cli.addr {
+        FcgiAddr::Unix(path) => {
+            let _ = std::fs::remove_file(&path);
+            let listener =
+                UnixListener::bind(&path).map_err(|_err| NetErr::Bind)?;
+
+            loop {
+                let (stream, _) =
+                    listener.accept().map_err(|_err| NetErr::Accept)?;
+                thread::spawn(move || serve_conn(stream, echo));
+            }
+        }
+        FcgiAddr::Tcp(addr) => {
+            let listener =
+                TcpListener::bind(addr).map_err(|_err| NetErr::Bind)?;
+
+            loop {
+                let (stream, _) =
+                    listener.accept().map_err(|_err| NetErr::Accept)?;
+                thread::spawn(move || serve_conn(stream, echo));
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    io::{stdout, Write},
+    time::Duration,
+};
+
+use clap::Parser;
+use http::{HeaderName, Method};
+use netlib::{
+    application::http::{
+        client::{Client, Request},
+        ws::Message,
+    },
+    rs_error::{HttpKind, NetErr, Result},
+};
+
+
+
+/// Minimal curl-like HTTP/1.1 client
+#[derive(Parser)]
+#[clap(name = "HTTP Client")]
+struct Cli {
+    /// Request method
+    #[clap(short = 'X', default_value = "GET")]
+    method: Method,
+
+    /// Extra header `Name: value`, repeatable
+    #[clap(short = 'H')]
+    headers: Vec<String>,
+
+    /// Request body
+    #[clap(short = 'd')]
+    data: Option<String>,
+
+    /// Print the status line and the headers
+    #[clap(short = 'i')]
+    include: bool,
+
+    /// Follow redirects
+    #[clap(short = 'L')]
+    location: bool,
+
+    /// Proxy `host:port`
+    #[clap(short = 'x')]
+    proxy: Option<String>,
+
+    /// Seconds of connect, read and write timeout
+    #[clap(short = 'm')]
+    timeout: Option<f64>,
+
+    /// Ask for a compressed response and decode it
+    #[clap(long)]
+    compressed: bool,
+
+    /// Open a WebSocket, send the data as a text message and print the
+    /// messages until it's closed
+    #[clap(long)]
+    ws: bool,
+
+    #[clap()]
+    url: String,
+}
+
+
+fn websocket(client: &Client, url: &str, data: Option<String>) -> Result<()> {
+    let mut ws = client.websocket(url, &[])?;
+
+    if let Some(data) = data {
+        ws.send(&Message::Text(data))?;
+    }
+
+    loop {
+        match ws.read()? {
+            Message::Text(text) => println!("{text}"),
+            Message::Binary(data) => println!("<{} bytes>", data.len()),
+            Message::Close(close) => {
+                eprintln!("closed: {close:?}");
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+}
+
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let mut client =
+        Client::new().with_decompress(cli.compressed).with_cookies();
+
+    if !cli.location {
+        client = client.with_max_redirects(0);
+    }
+    if let Some(proxy) = cli.proxy {
+        client = client.with_proxy(&proxy)?;
+    }
+    if let Some(secs) = cli.timeout {
+        client = client.with_timeout(Duration::from_secs_f64(secs));
+    }
+
+    if cli.ws {
+        return websocket(&client, &cli.url, cli.data);
+    }
+
+    let mut req = Request::get(&cli.url)?;
+    req.method = cli.method;
+
+    for header in cli.headers.iter() {
+        let (name, val) = header.split_once(':').ok_or_else(|| {
+            NetErr::Http(HttpKind::InvalidHeader(header.to_owned()))
+        })?;
+        let name = HeaderName::try_from(name.trim()).map_err(|_| {
+            NetErr::Http(HttpKind::InvalidHeader(header.to_owned()))
+        })?;
+
+        req = req.header(name, val.trim())?;
+    }
+    if let Some(data) = cli.data {
+        req = req.body(data.into_bytes());
+    }
+
+    let resp = client.send(req)?;
+    let mut out = stdout().lock();
+
+    if cli.include {
+        writeln!(
+            out,
+            "{:?} {} {}",
+            resp.version,
+            resp.status.as_u16(),
+            resp.reason
+        )
+        .map_err(NetErr::Write)?;
+
+        for (name, val) in resp.headers.iter() {
+            writeln!(
+                out,
+                "{name}: {}",
+                String::from_utf8_lossy(val.as_bytes())
+            )
+            .map_err(NetErr::Write)?;
+        }
+        writeln!(out).map_err(NetErr::Write)?;
+    }
+
+    out.write_all(&resp.body).map_err(NetErr::Write)
+}
@@ -0,0 +1,121 @@
+use std::{
+    error::Error,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use netlib::data::{IfRate, IfStatSampler};
+use serde::Serialize;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Cli
+
+/// Report per-second traffic of network interfaces
+#[derive(Parser)]
+#[clap()]
+struct Cli {
+    /// Only these interfaces (repeatable)
+    #[clap(short = 'i', long = "interface")]
+    interfaces: Vec<String>,
+
+    /// Print one JSON object per sample
+    #[clap(short = 'j', long)]
+    json: bool,
+
+    /// Stop after count samples
+    #[clap(short = 'c', long)]
+    count: Option<usize>,
+
+    /// Seconds between samples
+    #[clap(default_value = "1")]
+    interval: f64,
+}
+
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    /// Unix time in seconds
+    time: f64,
+    interfaces: &'a [IfRate],
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+fn print_header() {
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10} {:>7} {:>7} {:>7} {:>7}",
+        "interface",
+        "rx pkt/s",
+        "tx pkt/s",
+        "rx KB/s",
+        "tx KB/s",
+        "rx err",
+        "tx err",
+        "rx drop",
+        "tx drop"
+    );
+}
+
+
+fn print_rate(rate: &IfRate) {
+    println!(
+        "{:<12} {:>10.1} {:>10.1} {:>10.2} {:>10.2} {:>7.1} {:>7.1} {:>7.1} {:>7.1}",
+        rate.name,
+        rate.rx_packets,
+        rate.tx_packets,
+        rate.rx_bytes / 1024.0,
+        rate.tx_bytes / 1024.0,
+        rate.rx_errors,
+        rate.tx_errors,
+        rate.rx_dropped,
+        rate.tx_dropped
+    );
+}
+
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let interval = Duration::from_secs_f64(cli.interval.max(0.1));
+
+    let mut sampler = IfStatSampler::new();
+    unsafe { sampler.sample() }?;
+
+    let mut n = 0;
+
+    while cli.count.is_none_or(|count| n < count) {
+        thread::sleep(interval);
+        n += 1;
+
+        let mut rates = unsafe { sampler.sample() }?;
+
+        if !cli.interfaces.is_empty() {
+            rates.retain(|rate| cli.interfaces.contains(&rate.name));
+        }
+
+        if cli.json {
+            let time =
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+            let sample = Sample {
+                time,
+                interfaces: &rates,
+            };
+
+            println!("{}", serde_json::to_string(&sample)?);
+        }
+        else {
+            print_header();
+
+            for rate in rates.iter() {
+                print_rate(rate);
+            }
+
+            println!();
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,615 @@
+//! CGI/1.1 ([rfc3875](https://www.rfc-editor.org/rfc/rfc3875))
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use http::{
+    header::{
+        AUTHORIZATION, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, DATE,
+        LOCATION, PROXY_AUTHORIZATION, SERVER, TRANSFER_ENCODING,
+    },
+    uri::PathAndQuery,
+    HeaderMap, HeaderName, HeaderValue, StatusCode,
+};
+use libc::{kill, SIGKILL};
+use log::warn;
+use mime::Mime;
+use netlib::{application::fastcgi::client::FcgiClient, rs_error::NetErr, s};
+
+use crate::{
+    req::Req,
+    resp::{Body, Resp, SERVER_NAME},
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+const GATEWAY_INTERFACE: &str = "CGI/1.1";
+/// Header block of the output larger than that is malformed
+const MAX_HEAD_BYTES: u64 = 64 * 1024;
+/// Exited CGI is checked at this interval after it closes stdout
+const REAP_INTERVAL: Duration = Duration::from_millis(10);
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Header block of the output
+#[derive(Debug, Default)]
+pub struct CgiHead {
+    pub status: Option<StatusCode>,
+    pub content_type: Option<Mime>,
+    pub location: Option<String>,
+    /// Others that are passed to the client
+    pub headers: HeaderMap,
+}
+
+
+#[derive(Debug)]
+pub enum CgiReply {
+    Resp(Box<Resp>),
+    /// Server should serve that path instead
+    LocalRedirect(PathAndQuery),
+}
+
+
+/// Stdout of the running CGI. It's killed if this is dropped early,
+/// or else reaped in background.
+pub struct CgiOutput {
+    stdout: BufReader<ChildStdout>,
+    eof: bool,
+    timed_out: Arc<AtomicBool>,
+    /// Tells the watchdog to kill it now
+    stop: Option<Sender<()>>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Read for CgiOutput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+
+        if n == 0 && !buf.is_empty() {
+            if self.timed_out.load(Ordering::SeqCst) {
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            self.eof = true;
+        }
+
+        Ok(n)
+    }
+}
+
+
+impl Drop for CgiOutput {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            if !self.eof {
+                let _ = stop.send(());
+            }
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Standard meta-variables of the request, `path_info` is the part of
+/// the path after `script_name`.
+pub fn meta_vars(
+    req: &Req,
+    script_name: &str,
+    path_info: &str,
+    docroot: &Path,
+    port: u16,
+) -> Vec<(String, String)> {
+    let paq = req.uri.path_and_query();
+
+    let mut vars = vec![
+        (s!("GATEWAY_INTERFACE"), s!(GATEWAY_INTERFACE)),
+        (s!("SERVER_SOFTWARE"), s!(SERVER_NAME)),
+        (s!("SERVER_PROTOCOL"), format!("{:?}", req.version)),
+        (s!("SERVER_NAME"), s!(req.uri.host().unwrap_or("localhost"))),
+        (s!("SERVER_PORT"), port.to_string()),
+        (s!("REQUEST_METHOD"), req.method.to_string()),
+        (s!("SCRIPT_NAME"), s!(script_name)),
+        (
+            s!("QUERY_STRING"),
+            s!(paq.and_then(|paq| paq.query()).unwrap_or_default()),
+        ),
+        (s!("REMOTE_ADDR"), req.peer.ip().to_string()),
+        (s!("REMOTE_HOST"), req.peer.ip().to_string()),
+        (s!("REMOTE_PORT"), req.peer.port().to_string()),
+    ];
+
+    if !path_info.is_empty() {
+        let translated = docroot.join(path_info.trim_start_matches('/'));
+
+        vars.push((s!("PATH_INFO"), s!(path_info)));
+        vars.push((s!("PATH_TRANSLATED"), s!(translated.to_string_lossy())));
+    }
+
+    if !req.body.is_empty() {
+        vars.push((s!("CONTENT_LENGTH"), req.body.len().to_string()));
+    }
+
+    if let Some(content_type) = req.header_str(CONTENT_TYPE) {
+        vars.push((s!("CONTENT_TYPE"), s!(content_type)));
+    }
+
+    // credentials aren't exposed, body fields have their own, and
+    // HTTP_PROXY would be taken as the proxy of the CGI (httpoxy)
+    let skipped = [
+        AUTHORIZATION,
+        PROXY_AUTHORIZATION,
+        CONTENT_LENGTH,
+        HeaderName::from_static("proxy"),
+    ];
+
+    for name in req.headers.keys() {
+        if skipped.contains(name) || name == CONTENT_TYPE {
+            continue;
+        }
+
+        let sep = if name == COOKIE { "; " } else { ", " };
+        let val = req
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|val| String::from_utf8_lossy(val.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(sep);
+
+        let var =
+            format!("HTTP_{}", name.as_str().to_uppercase()).replace('-', "_");
+
+        vars.push((var, val));
+    }
+
+    vars
+}
+
+
+/// Start the CGI and read its header block. It's killed with its children
+/// after `timeout`, before the header block is done that is a 504.
+pub fn run(
+    mut cmd: Command,
+    body: Vec<u8>,
+    timeout: Duration,
+) -> Result<CgiReply, String> {
+    let name = format!("{:?}", cmd.get_program());
+
+    // children holding stdout are killed together
+    let mut child = cmd
+        .process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("spawn {name}: {err}"))?;
+
+    /* Feed body in another thread in case that the pipe is full */
+    let mut stdin = child.stdin.take().unwrap();
+    // CGI may exit without reading it
+    thread::spawn(move || stdin.write_all(&body));
+
+    /* It's just logged */
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let stderr_name = name.clone();
+    thread::spawn(move || {
+        for line in stderr.lines().map_while(|line| line.ok()) {
+            warn!("cgi {stderr_name}: {line}");
+        }
+    });
+
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (stop, stopped) = channel();
+
+    let watchdog_timed_out = timed_out.clone();
+    thread::spawn(move || {
+        let deadline = Instant::now() + timeout;
+        let mut exited = None;
+
+        match stopped.recv_timeout(timeout) {
+            Ok(()) => (),
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("cgi {name} timed out");
+                watchdog_timed_out.store(true, Ordering::SeqCst);
+            }
+            // output is done, it should exit soon
+            Err(RecvTimeoutError::Disconnected) => loop {
+                match child.try_wait() {
+                    Ok(None) if Instant::now() < deadline => {
+                        thread::sleep(REAP_INTERVAL)
+                    }
+                    Ok(None) => {
+                        warn!("cgi {name} timed out after output");
+                        break;
+                    }
+                    Ok(Some(status)) => {
+                        exited = Some(Ok(status));
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            },
+        }
+
+        // the group id is still ours as it's not reaped
+        let status = exited.unwrap_or_else(|| {
+            unsafe { kill(-(child.id() as i32), SIGKILL) };
+            child.wait()
+        });
+
+        match status {
+            Ok(status) if !status.success() => {
+                warn!("cgi {name} exits with {status}")
+            }
+            Ok(_) => (),
+            Err(err) => warn!("cgi {name} wait: {err}"),
+        }
+    });
+
+    let mut output = CgiOutput {
+        stdout,
+        eof: false,
+        timed_out,
+        stop: Some(stop),
+    };
+
+    let head = match parse_head(&mut output.stdout) {
+        Ok(head) => head,
+        Err(_) if output.timed_out.load(Ordering::SeqCst) => {
+            return Ok(CgiReply::Resp(Box::new(Resp::_504(String::new()))));
+        }
+        Err(errmsg) => return Err(errmsg),
+    };
+
+    into_reply(head, output)
+}
+
+
+/// Send the request to the FastCGI application and read the header block
+/// of its STDOUT. It's a 502 if the application isn't reachable and a 504
+/// on timeout.
+pub fn run_fastcgi(
+    client: &FcgiClient,
+    params: &[(String, String)],
+    body: &[u8],
+) -> Result<CgiReply, String> {
+    let addr = client.addr().to_string();
+
+    let output = match client.request(params, body) {
+        Ok(output) => output,
+        Err(NetErr::Read(err)) if is_timeout(&err) => {
+            warn!("fastcgi {addr} timed out");
+            return Ok(CgiReply::Resp(Box::new(Resp::_504(String::new()))));
+        }
+        Err(err) => {
+            warn!("fastcgi {addr}: {err:?}");
+            return Ok(CgiReply::Resp(Box::new(Resp::_502(String::new()))));
+        }
+    };
+
+    let stderr_addr = addr.clone();
+    let mut output = BufReader::new(output.on_stderr(move |bytes| {
+        for line in String::from_utf8_lossy(bytes).lines() {
+            warn!("fastcgi {stderr_addr}: {line}");
+        }
+    }));
+
+    match output.fill_buf() {
+        Ok(_) => (),
+        Err(err) if is_timeout(&err) => {
+            warn!("fastcgi {addr} timed out");
+            return Ok(CgiReply::Resp(Box::new(Resp::_504(String::new()))));
+        }
+        Err(err) => return Err(format!("fastcgi {addr}: {err}")),
+    }
+
+    reply_from(output)
+}
+
+
+/// Document, local redirect or client redirect (with or without document)
+/// ([rfc3875 6.2](https://www.rfc-editor.org/rfc/rfc3875#section-6.2))
+fn into_reply<R: Read + Send + 'static>(
+    head: CgiHead,
+    output: R,
+) -> Result<CgiReply, String> {
+    let CgiHead {
+        status,
+        content_type,
+        location,
+        headers,
+    } = head;
+
+    let body = match (content_type, &location) {
+        (None, Some(location)) if location.starts_with('/') => {
+            let paq = location
+                .parse()
+                .map_err(|_| format!("Invalid CGI Location: {location}"))?;
+
+            return Ok(CgiReply::LocalRedirect(paq));
+        }
+        (None, Some(_)) => Body::empty(),
+        (Some(content_type), _) => Body::stream(content_type, output),
+        (None, None) => return Err(s!("CGI response has no Content-Type")),
+    };
+
+    let status = status.unwrap_or(if location.is_some() {
+        StatusCode::FOUND
+    }
+    else {
+        StatusCode::OK
+    });
+
+    let mut resp = Resp::new(status, false, body);
+
+    if let Some(location) = location {
+        let val = HeaderValue::try_from(location)
+            .map_err(|_| s!("Invalid CGI Location"))?;
+
+        resp = resp.with_header(LOCATION, val);
+    }
+
+    for (name, val) in headers.iter() {
+        resp = resp.with_header(name.clone(), val.clone());
+    }
+
+    Ok(CgiReply::Resp(Box::new(resp)))
+}
+
+
+/// Output of a CGI that runs elsewhere, e.g. a FastCGI application
+pub fn reply_from<R: BufRead + Send + 'static>(
+    mut output: R,
+) -> Result<CgiReply, String> {
+    let head = parse_head(&mut output)?;
+
+    into_reply(head, output)
+}
+
+
+/// Header lines end with an empty line, either LF or CRLF
+pub fn parse_head<R: BufRead>(r: &mut R) -> Result<CgiHead, String> {
+    let mut head = CgiHead::default();
+    let mut total = 0;
+
+    loop {
+        let mut line = vec![];
+        let n = r
+            .take(MAX_HEAD_BYTES - total + 1)
+            .read_until(b'\n', &mut line)
+            .map_err(|err| err.to_string())?;
+
+        total += n as u64;
+
+        if total > MAX_HEAD_BYTES {
+            return Err(s!("CGI header block is too large"));
+        }
+
+        if !line.ends_with(b"\n") {
+            return Err(s!("CGI output ends in header block"));
+        }
+
+        line.pop();
+
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+
+        if line.is_empty() {
+            break;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let (name, val) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid CGI header: {line}"))?;
+        let val = val.trim();
+
+        match name.to_ascii_lowercase().as_str() {
+            "content-type" => {
+                head.content_type = Some(val.parse().map_err(|_| {
+                    format!("CGI Invalid Content-Type: {val}")
+                })?);
+            }
+            "status" => {
+                let code = val.split_whitespace().next().unwrap_or_default();
+
+                head.status = Some(
+                    StatusCode::from_bytes(code.as_bytes())
+                        .map_err(|_| format!("CGI Invalid Status: {val}"))?,
+                );
+            }
+            "location" => head.location = Some(s!(val)),
+            _ => {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("Invalid CGI header: {name}"))?;
+                let val = HeaderValue::from_str(val)
+                    .map_err(|_| format!("Invalid CGI header: {name}"))?;
+
+                // framing and those of ours are left to the server
+                let ours = [
+                    CONNECTION,
+                    CONTENT_LENGTH,
+                    TRANSFER_ENCODING,
+                    DATE,
+                    SERVER,
+                ];
+
+                if !ours.contains(&name) {
+                    head.headers.append(name, val);
+                }
+            }
+        }
+    }
+
+    Ok(head)
+}
+
+
+/// Read timeout of a socket is reported as either of them
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read},
+        net::TcpListener,
+        path::Path,
+        process::Command,
+        sync::mpsc::channel,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use http::{HeaderMap, HeaderName, Method, StatusCode, Version};
+    use netlib::application::{
+        fastcgi::{
+            client::{FcgiAddr, FcgiClient},
+            responder::serve_conn,
+        },
+        http::{body::ReqBody, parser::ReqHead},
+    };
+
+    use super::{meta_vars, parse_head, run, run_fastcgi, CgiReply};
+    use crate::req::Req;
+
+    fn req(uri: &str, headers: &[(&str, &str)]) -> Req {
+        let mut map = HeaderMap::new();
+        for (name, val) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                val.parse().unwrap(),
+            );
+        }
+
+        let head = ReqHead {
+            method: Method::GET,
+            uri: uri.parse().unwrap(),
+            version: Version::HTTP_11,
+            headers: map,
+        };
+
+        Req::from_head(
+            head,
+            ReqBody::default(),
+            "127.0.0.1:1".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_cgi() {
+        let mut out = Cursor::new(
+            "Status: 201 Made\r\nContent-Type: text/csv\nX-Foo: 1\n\
+            Content-Length: 5\n\nbody",
+        );
+        let head = parse_head(&mut out).unwrap();
+
+        assert_eq!(head.status, Some(StatusCode::CREATED));
+        assert_eq!(head.content_type, Some(mime::TEXT_CSV));
+        assert_eq!(head.headers.len(), 1);
+        assert_eq!(head.headers["x-foo"], "1");
+
+        let mut rest = String::new();
+        out.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "body");
+
+        assert!(parse_head(&mut Cursor::new("Content-Type: a/b\n")).is_err());
+        assert!(parse_head(&mut Cursor::new("hello\n\n")).is_err());
+
+        /* Local redirect and timeout */
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'Location: /a?b=1'; echo"]);
+
+        match run(cmd, vec![], Duration::from_secs(5)).unwrap() {
+            CgiReply::LocalRedirect(paq) => assert_eq!(paq, "/a?b=1"),
+            reply => panic!("{reply:?}"),
+        }
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 5"]);
+
+        let start = Instant::now();
+        match run(cmd, vec![], Duration::from_millis(100)).unwrap() {
+            CgiReply::Resp(resp) => {
+                assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT)
+            }
+            reply => panic!("{reply:?}"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        /* Meta-variables */
+
+        let req = req(
+            "/cgi/a/b?x=1",
+            &[
+                ("x-foo", "1"),
+                ("x-foo", "2"),
+                ("authorization", "Basic eDp5"),
+                ("proxy", "http://evil:8080"),
+            ],
+        );
+        let vars = meta_vars(&req, "/cgi", "/a/b", Path::new("/doc"), 80);
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        assert_eq!(var("QUERY_STRING"), Some("x=1"));
+        assert_eq!(var("PATH_INFO"), Some("/a/b"));
+        assert_eq!(var("PATH_TRANSLATED"), Some("/doc/a/b"));
+        assert_eq!(var("HTTP_X_FOO"), Some("1, 2"));
+        assert_eq!(var("HTTP_AUTHORIZATION"), None);
+        assert_eq!(var("HTTP_PROXY"), None);
+
+        // same params for FastCGI
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+
+            serve_conn(stream, |req| {
+                tx.send(req.params.clone()).unwrap();
+                b"Content-Type: text/plain\r\n\r\nok".to_vec()
+            })
+        });
+
+        let client = FcgiClient::new(FcgiAddr::Tcp(addr));
+        match run_fastcgi(&client, &vars, b"").unwrap() {
+            CgiReply::Resp(resp) => assert_eq!(resp.status(), StatusCode::OK),
+            reply => panic!("{reply:?}"),
+        }
+
+        let params = rx.recv().unwrap();
+        assert!(params.iter().any(|(k, v)| k == "HTTP_X_FOO" && v == "1, 2"));
+        assert!(!params.iter().any(|(k, _)| k == "HTTP_PROXY"));
+    }
+}
@@ -6,9 +6,11 @@ use std::{
 };
 
 use getset::{Getters, Setters};
-use netlib::rs_error::*;
+use netlib::{application::fastcgi::client::FcgiAddr, rs_error::*};
 use serde_yaml::{self, Mapping, Value};
 
+use crate::ws::WsService;
+
 
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -19,6 +21,7 @@ pub const CONF_NAME_DOCROOT: &str = "docroot";
 pub const CONF_NAME_CGIMAP: &str = "cgimapping";
 pub const CONF_NAME_CGIMAP_CGI: &str = "cgi";
 pub const CONF_NAME_CGIMAP_ROUTE: &str = "route";
+pub const CONF_NAME_CGIMAP_FASTCGI: &str = "fastcgi";
 
 pub const CONF_NAME_DEFAULT_FILE: &str = "default-file";
 pub const CONF_NAME_PERSISROOT: &str = "persisroot";
@@ -28,6 +31,13 @@ pub const CONF_NAME_LISTEN_PORT: &str = "listen-port";
 // pub const CONF_NAME_CLIENT_MAX: &str = "max";
 // pub const CONF_NAME_CLIENT_INIT: &str = "init";
 pub const CONF_NAME_TIMEOUT: &str = "timeout";
+pub const CONF_NAME_MAX_BODY_SIZE: &str = "max-body-size";
+pub const CONF_NAME_MAX_KEEPALIVE_REQS: &str = "max-keepalive-requests";
+pub const CONF_NAME_CGI_TIMEOUT: &str = "cgi-timeout";
+pub const CONF_NAME_COMPRESS_MIN_SIZE: &str = "compress-min-size";
+pub const CONF_NAME_WEBSOCKET: &str = "websocket";
+pub const CONF_NAME_WEBSOCKET_ROUTE: &str = "route";
+pub const CONF_NAME_WEBSOCKET_SERVICE: &str = "service";
 
 
 
@@ -45,6 +55,15 @@ pub struct ServConf {
     // max_client: u32,
     // init_client: u32,
     timeout: u64,
+    /// Bytes of decoded request body
+    max_body_size: u64,
+    /// Requests served over one connection
+    max_keepalive_requests: u64,
+    /// Milliseconds a CGI may run, or the I/O timeout of FastCGI
+    cgi_timeout: u64,
+    /// Bytes of body below which it's sent without content coding
+    compress_min_size: u64,
+    ws_routes: Vec<WsRoute>,
 }
 
 #[derive(Debug)]
@@ -58,6 +77,11 @@ pub struct ServConfOpt {
     // max_client: u32,
     // init_client: u32,
     timeout: Option<u64>,
+    max_body_size: Option<u64>,
+    max_keepalive_requests: Option<u64>,
+    cgi_timeout: Option<u64>,
+    compress_min_size: Option<u64>,
+    ws_routes: Option<Vec<WsRoute>>,
 }
 
 #[derive(Debug, Clone)]
@@ -75,6 +99,16 @@ pub struct CGIMapOpt {
 pub struct CGIMapItem {
     pub route: PathBuf,
     pub cgi: PathBuf,
+    /// Served by the FastCGI application instead of spawning `cgi`, which
+    /// is passed as SCRIPT_FILENAME
+    pub fastcgi: Option<FcgiAddr>,
+}
+
+/// Route upgraded to WebSocket
+#[derive(Debug, Clone)]
+pub struct WsRoute {
+    pub route: PathBuf,
+    pub service: WsService,
 }
 
 
@@ -91,6 +125,28 @@ impl CGIMap {
         self.get(p)
             .map(|cgiitem| cgiitem.joined_by(&self.root))
     }
+
+    /// The longest route that `/route` or `/route/...` matches, the
+    /// remains is PATH_INFO.
+    pub fn match_path<'a>(
+        &self,
+        path: &'a str,
+    ) -> Option<(&CGIMapItem, &'a str)> {
+        self.items
+            .iter()
+            .filter_map(|item| {
+                let route = item.route.to_str()?.trim_end_matches('/');
+                let info = path.strip_prefix(route)?;
+
+                if info.is_empty() || info.starts_with('/') {
+                    Some((item, info))
+                }
+                else {
+                    None
+                }
+            })
+            .max_by_key(|(item, _)| item.route.as_os_str().len())
+    }
 }
 
 
@@ -112,6 +168,11 @@ impl ServConf {
             persisroot,
             listen_port,
             timeout,
+            max_body_size,
+            max_keepalive_requests,
+            cgi_timeout,
+            compress_min_size,
+            ws_routes,
         } = other;
 
         if let Some(cgiroot) = cgiroot {
@@ -142,6 +203,26 @@ impl ServConf {
             self.timeout = timeout;
         }
 
+        if let Some(max_body_size) = max_body_size {
+            self.max_body_size = max_body_size;
+        }
+
+        if let Some(max_keepalive_requests) = max_keepalive_requests {
+            self.max_keepalive_requests = max_keepalive_requests;
+        }
+
+        if let Some(cgi_timeout) = cgi_timeout {
+            self.cgi_timeout = cgi_timeout;
+        }
+
+        if let Some(compress_min_size) = compress_min_size {
+            self.compress_min_size = compress_min_size;
+        }
+
+        if let Some(ws_routes) = ws_routes {
+            self.ws_routes = ws_routes;
+        }
+
     }
 }
 
@@ -174,6 +255,20 @@ impl TryFrom<ServConfOpt> for ServConf {
         let timeout = value.timeout
         .ok_or(NetErr::YAMLNonExistField(CONF_NAME_TIMEOUT))?;
 
+        let max_body_size = value.max_body_size
+        .ok_or(NetErr::YAMLNonExistField(CONF_NAME_MAX_BODY_SIZE))?;
+
+        let max_keepalive_requests = value.max_keepalive_requests
+        .ok_or(NetErr::YAMLNonExistField(CONF_NAME_MAX_KEEPALIVE_REQS))?;
+
+        let cgi_timeout = value.cgi_timeout
+        .ok_or(NetErr::YAMLNonExistField(CONF_NAME_CGI_TIMEOUT))?;
+
+        let compress_min_size = value.compress_min_size
+        .ok_or(NetErr::YAMLNonExistField(CONF_NAME_COMPRESS_MIN_SIZE))?;
+
+        let ws_routes = value.ws_routes
+        .ok_or(NetErr::YAMLNonExistField(CONF_NAME_WEBSOCKET))?;
 
         Ok(Self {
             cgimap,
@@ -182,6 +277,11 @@ impl TryFrom<ServConfOpt> for ServConf {
             persisroot,
             listen_port,
             timeout,
+            max_body_size,
+            max_keepalive_requests,
+            cgi_timeout,
+            compress_min_size,
+            ws_routes,
         })
 
     }
@@ -246,7 +346,46 @@ fn load_cgimapping_item(mut map: Mapping) -> Result<CGIMapItem> {
         return Err(NetErr::YAMLInvalidField(CONF_NAME_CGIMAP));
     };
 
-    Ok(CGIMapItem { route, cgi })
+    let fastcgi = match map.remove(CONF_NAME_CGIMAP_FASTCGI) {
+        Some(Value::String(s)) => Some(
+            s.parse()
+                .map_err(|_| NetErr::YAMLInvalidField(CONF_NAME_CGIMAP))?,
+        ),
+        Some(_) => return Err(NetErr::YAMLInvalidField(CONF_NAME_CGIMAP)),
+        None => None,
+    };
+
+    Ok(CGIMapItem {
+        route,
+        cgi,
+        fastcgi,
+    })
+}
+
+
+fn load_ws_routes(seq: Vec<Value>) -> Result<Vec<WsRoute>> {
+    let invalid = || NetErr::YAMLInvalidField(CONF_NAME_WEBSOCKET);
+
+    seq.into_iter()
+        .map(|itemv| {
+            let Value::Mapping(mut map) = itemv
+            else {
+                return Err(invalid());
+            };
+
+            let route = match map.remove(CONF_NAME_WEBSOCKET_ROUTE) {
+                Some(Value::String(s)) => PathBuf::from(s),
+                _ => return Err(invalid()),
+            };
+
+            let service = match map.remove(CONF_NAME_WEBSOCKET_SERVICE) {
+                Some(Value::String(s)) => s.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            };
+
+            Ok(WsRoute { route, service })
+        })
+        .collect()
 }
 
 
@@ -381,6 +520,74 @@ pub fn load_serv_conf<P: AsRef<Path>>(p: P) -> Result<ServConfOpt> {
         None
     };
 
+    let max_body_size =
+    if let Some(v) = map.remove(CONF_NAME_MAX_BODY_SIZE) {
+        Some(if let Some(n) = v.as_u64() {
+            n
+        }
+        else {
+            return Err(NetErr::YAMLInvalidField(CONF_NAME_MAX_BODY_SIZE));
+        })
+    }
+    else {
+        None
+    };
+
+    let max_keepalive_requests =
+    if let Some(v) = map.remove(CONF_NAME_MAX_KEEPALIVE_REQS) {
+        Some(if let Some(n) = v.as_u64() {
+            n
+        }
+        else {
+            return Err(NetErr::YAMLInvalidField(
+                CONF_NAME_MAX_KEEPALIVE_REQS,
+            ));
+        })
+    }
+    else {
+        None
+    };
+
+    let cgi_timeout =
+    if let Some(v) = map.remove(CONF_NAME_CGI_TIMEOUT) {
+        Some(if let Some(n) = v.as_u64() {
+            n
+        }
+        else {
+            return Err(NetErr::YAMLInvalidField(CONF_NAME_CGI_TIMEOUT));
+        })
+    }
+    else {
+        None
+    };
+
+    let compress_min_size =
+    if let Some(v) = map.remove(CONF_NAME_COMPRESS_MIN_SIZE) {
+        Some(if let Some(n) = v.as_u64() {
+            n
+        }
+        else {
+            return Err(NetErr::YAMLInvalidField(
+                CONF_NAME_COMPRESS_MIN_SIZE,
+            ));
+        })
+    }
+    else {
+        None
+    };
+
+
+    let ws_routes =
+    if let Some(v) = map.remove(CONF_NAME_WEBSOCKET) {
+        Some(match v {
+            Value::Sequence(vec) => load_ws_routes(vec)?,
+            _ => return Err(NetErr::YAMLInvalidField(CONF_NAME_WEBSOCKET)),
+        })
+    }
+    else {
+        None
+    };
+
 
     Ok(ServConfOpt {
         cgiroot,
@@ -392,5 +599,10 @@ pub fn load_serv_conf<P: AsRef<Path>>(p: P) -> Result<ServConfOpt> {
         // max_client,
         // init_client,
         timeout,
+        max_body_size,
+        max_keepalive_requests,
+        cgi_timeout,
+        compress_min_size,
+        ws_routes,
     })
 }
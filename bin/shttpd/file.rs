@@ -0,0 +1,528 @@
+//! Static file with validators and ranges
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Component, Path},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use http::{
+    header::{
+        ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+    HeaderName, HeaderValue,
+};
+use mime::Mime;
+use netlib::application::http::{
+    cond::{etag_list_matches, fmt_http_date, parse_http_date, ETag},
+    range::{parse_range, unsatisfied_range, ByteRange, RangeStatus},
+};
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{
+    req::Req,
+    resp::{Body, Chunk, Resp},
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Extension (lowercase) to MIME, text ones are in utf-8
+const MIME_TABLE: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "text/xml; charset=utf-8"),
+    ("yaml", "text/plain; charset=utf-8"),
+    ("yml", "text/plain; charset=utf-8"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+];
+
+const BOUNDARY_LEN: usize = 24;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Validators of the file
+struct Meta {
+    len: u64,
+    mtime: SystemTime,
+    etag: ETag,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Meta {
+    /// HTTP-date has only seconds
+    fn mtime_secs(&self) -> SystemTime {
+        let secs = self
+            .mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// ETag and Last-Modified, also sent with 304
+    fn headers(&self) -> [(HeaderName, HeaderValue); 2] {
+        [
+            (ETAG, header_val(self.etag.to_string())),
+            (LAST_MODIFIED, header_val(fmt_http_date(self.mtime))),
+        ]
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// MIME by extension, `application/octet-stream` for unknown ones
+pub fn guess_mime<P: AsRef<Path>>(path: P) -> Mime {
+    let ext = path
+        .as_ref()
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    MIME_TABLE
+        .iter()
+        .find(|(name, _)| *name == ext)
+        .and_then(|(_, mime)| mime.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+
+/// Request path is relative and without `..`, so it stays under the root
+pub fn is_safe_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .components()
+        .all(|comp| matches!(comp, Component::Normal(_) | Component::CurDir))
+}
+
+
+/// GET on a regular file: 304 if the client has it, 206/416 for ranges,
+/// or else the whole file. Bytes are written by `sendfile` later.
+pub fn serve_file<P: AsRef<Path>>(req: &Req, path: P) -> Resp {
+    let path = path.as_ref();
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Resp::_404(String::new())
+        }
+        Err(err) => return Resp::_500(err.to_string()),
+    };
+
+    let meta = match file.metadata().and_then(|meta| {
+        let mtime = meta.modified()?;
+
+        Ok(Meta {
+            len: meta.len(),
+            mtime,
+            etag: ETag::from_meta(meta.len(), mtime),
+        })
+    }) {
+        Ok(meta) => meta,
+        Err(err) => return Resp::_500(err.to_string()),
+    };
+
+    if is_not_modified(req, &meta) {
+        return with_headers(Resp::_304(), meta.headers());
+    }
+
+    let content_type = guess_mime(path);
+    let file = Arc::new(file);
+
+    let status = match req.header_str(RANGE) {
+        Some(range) if is_range_fresh(req, &meta) => {
+            parse_range(range, meta.len)
+        }
+        _ => RangeStatus::Full,
+    };
+
+    let resp = match status {
+        RangeStatus::Full => {
+            Resp::_200(Body::file(content_type, file, 0, meta.len))
+        }
+        RangeStatus::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+
+            Resp::_206(Body::file(
+                content_type,
+                file,
+                range.start,
+                range.len(),
+            ))
+            .with_header(
+                CONTENT_RANGE,
+                header_val(range.content_range(meta.len)),
+            )
+        }
+        RangeStatus::Partial(ranges) => {
+            Resp::_206(multipart_body(content_type, file, &ranges, meta.len))
+        }
+        RangeStatus::Unsatisfiable => {
+            return Resp::_416(String::new()).with_header(
+                CONTENT_RANGE,
+                header_val(unsatisfied_range(meta.len)),
+            );
+        }
+    };
+
+    with_headers(resp, meta.headers())
+        .with_header(ACCEPT_RANGES, HeaderValue::from_static("bytes"))
+}
+
+
+/// If-None-Match takes precedence over If-Modified-Since
+/// ([rfc9110 13.2.2](https://www.rfc-editor.org/rfc/rfc9110#section-13.2.2))
+fn is_not_modified(req: &Req, meta: &Meta) -> bool {
+    if let Some(val) = req.header_str(IF_NONE_MATCH) {
+        return etag_list_matches(val, &meta.etag, true);
+    }
+
+    req.header_str(IF_MODIFIED_SINCE)
+        .and_then(parse_http_date)
+        .map(|since| meta.mtime_secs() <= since)
+        .unwrap_or_default()
+}
+
+
+/// Range applies only if If-Range (if any) still matches, a strong ETag
+/// or the exact Last-Modified
+fn is_range_fresh(req: &Req, meta: &Meta) -> bool {
+    let Some(val) = req.header_str(IF_RANGE)
+    else {
+        return true;
+    };
+
+    if let Ok(etag) = val.parse::<ETag>() {
+        return etag.strong_eq(&meta.etag);
+    }
+
+    parse_http_date(val) == Some(meta.mtime_secs())
+}
+
+
+/// `multipart/byteranges`, each part has its own Content-Type and
+/// Content-Range
+fn multipart_body(
+    content_type: Mime,
+    file: Arc<File>,
+    ranges: &[ByteRange],
+    total: u64,
+) -> Body {
+    let boundary: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(BOUNDARY_LEN)
+        .map(char::from)
+        .collect();
+
+    let mut chunks = vec![];
+
+    for range in ranges {
+        let mut part = vec![];
+
+        write!(part, "\r\n--{boundary}\r\n").unwrap();
+        write!(part, "Content-Type: {content_type}\r\n").unwrap();
+        write!(
+            part,
+            "Content-Range: {}\r\n\r\n",
+            range.content_range(total)
+        )
+        .unwrap();
+
+        chunks.push(Chunk::Bytes(part));
+        chunks.push(Chunk::File {
+            file: file.clone(),
+            offset: range.start,
+            len: range.len(),
+        });
+    }
+
+    chunks.push(Chunk::Bytes(format!("\r\n--{boundary}--\r\n").into_bytes()));
+
+    Body {
+        content_type: format!("multipart/byteranges; boundary={boundary}")
+            .parse()
+            .unwrap(),
+        chunks,
+        stream: None,
+    }
+}
+
+
+fn with_headers<I>(resp: Resp, headers: I) -> Resp
+where
+    I: IntoIterator<Item = (HeaderName, HeaderValue)>,
+{
+    headers
+        .into_iter()
+        .fold(resp, |resp, (name, val)| resp.with_header(name, val))
+}
+
+
+/// Values we make are always visible ASCII
+fn header_val(s: String) -> HeaderValue {
+    HeaderValue::try_from(s).unwrap()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::FileExt, path::PathBuf};
+
+    use http::{HeaderMap, HeaderName, Method, Version};
+    use mime::{APPLICATION_OCTET_STREAM, IMAGE_PNG, TEXT_HTML_UTF_8};
+    use netlib::application::http::{body::ReqBody, parser::ReqHead};
+
+    use super::{guess_mime, is_safe_path, serve_file};
+    use crate::{
+        req::Req,
+        resp::{Chunk, Resp},
+    };
+
+    const CONTENT: &str = "0123456789abcdef";
+
+    /// Removed on drop
+    struct TempFile(PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn req(headers: &[(&str, &str)]) -> Req {
+        let mut map = HeaderMap::new();
+        for (name, val) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                val.parse().unwrap(),
+            );
+        }
+
+        let head = ReqHead {
+            method: Method::GET,
+            uri: "/a.txt".parse().unwrap(),
+            version: Version::HTTP_11,
+            headers: map,
+        };
+
+        Req::from_head(
+            head,
+            ReqBody::default(),
+            "127.0.0.1:1".parse().unwrap(),
+        )
+    }
+
+    /// Head and body as they are sent, file parts read in
+    fn render(resp: Resp) -> (String, String) {
+        let (chunks, _) = resp.into_chunks(None, u64::MAX);
+        let mut out = vec![];
+
+        for chunk in chunks {
+            match chunk {
+                Chunk::Bytes(bytes) => out.extend(bytes),
+                Chunk::File { file, offset, len } => {
+                    let mut buf = vec![0; len as usize];
+                    file.read_exact_at(&mut buf, offset).unwrap();
+                    out.extend(buf);
+                }
+            }
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+
+        (head.to_owned(), body.to_owned())
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines().find_map(|line| {
+            let (key, val) = line.split_once(": ")?;
+            key.eq_ignore_ascii_case(name).then_some(val)
+        })
+    }
+
+    #[test]
+    fn test_guess_mime() {
+        assert_eq!(guess_mime("a/index.HTML"), TEXT_HTML_UTF_8);
+        assert_eq!(guess_mime("logo.png"), IMAGE_PNG);
+        assert_eq!(guess_mime("print-hi"), APPLICATION_OCTET_STREAM);
+
+        assert!(is_safe_path("a/./b.txt"));
+        assert!(!is_safe_path("a/../../etc/passwd"));
+        assert!(!is_safe_path("/etc/passwd"));
+    }
+
+    #[test]
+    fn test_serve_file_cond() {
+        let path = std::env::temp_dir()
+            .join(format!("shttpd-cond-{}.txt", std::process::id()));
+        fs::write(&path, CONTENT).unwrap();
+        let _tmp = TempFile(path.clone());
+
+        let (head, body) = render(serve_file(&req(&[]), &path));
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert_eq!(header(&head, "accept-ranges"), Some("bytes"));
+        assert_eq!(header(&head, "content-length"), Some("16"));
+        assert_eq!(body, CONTENT);
+
+        let etag = header(&head, "etag").unwrap().to_owned();
+        let mtime = header(&head, "last-modified").unwrap().to_owned();
+        let weak = format!("W/{etag}");
+        let list = format!("\"other\", {etag}");
+        let old_date = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        for headers in [
+            vec![("if-none-match", etag.as_str())],
+            vec![("if-none-match", weak.as_str())],
+            vec![("if-none-match", list.as_str())],
+            vec![("if-none-match", "*")],
+            vec![("if-modified-since", mtime.as_str())],
+        ] {
+            let (head, body) = render(serve_file(&req(&headers), &path));
+
+            assert!(head.starts_with("HTTP/1.1 304 "), "{headers:?}");
+            assert_eq!(header(&head, "etag"), Some(etag.as_str()));
+            assert_eq!(header(&head, "content-length"), None);
+            assert!(body.is_empty());
+        }
+
+        for headers in [
+            vec![("if-none-match", "\"other\"")],
+            vec![("if-modified-since", old_date)],
+            // If-None-Match takes precedence
+            vec![
+                ("if-none-match", "\"other\""),
+                ("if-modified-since", mtime.as_str()),
+            ],
+        ] {
+            let (head, body) = render(serve_file(&req(&headers), &path));
+
+            assert!(head.starts_with("HTTP/1.1 200 "), "{headers:?}");
+            assert_eq!(body, CONTENT);
+        }
+
+        let (head, _) = render(serve_file(&req(&[]), path.with_extension("")));
+        assert!(head.starts_with("HTTP/1.1 404 "), "{head}");
+    }
+
+    #[test]
+    fn test_serve_file_range() {
+        let path = std::env::temp_dir()
+            .join(format!("shttpd-range-{}.txt", std::process::id()));
+        fs::write(&path, CONTENT).unwrap();
+        let _tmp = TempFile(path.clone());
+
+        let serve = |headers: &[(&str, &str)]| {
+            render(serve_file(&req(headers), &path))
+        };
+
+        let (head, body) = serve(&[("range", "bytes=2-5")]);
+        assert!(head.starts_with("HTTP/1.1 206 "), "{head}");
+        assert_eq!(header(&head, "content-range"), Some("bytes 2-5/16"));
+        assert_eq!(header(&head, "content-length"), Some("4"));
+        assert_eq!(body, "2345");
+
+        let (head, body) = serve(&[("range", "bytes=-3")]);
+        assert_eq!(header(&head, "content-range"), Some("bytes 13-15/16"));
+        assert_eq!(body, "def");
+
+        let (head, _) = serve(&[("range", "bytes=16-")]);
+        assert!(head.starts_with("HTTP/1.1 416 "), "{head}");
+        assert_eq!(header(&head, "content-range"), Some("bytes */16"));
+
+        // not a byte range, it's ignored
+        let (head, body) = serve(&[("range", "lines=1-2")]);
+        assert!(head.starts_with("HTTP/1.1 200 "), "{head}");
+        assert_eq!(body, CONTENT);
+
+        let (head, body) = serve(&[("range", "bytes=0-1, 10-11")]);
+        assert!(head.starts_with("HTTP/1.1 206 "), "{head}");
+        assert_eq!(header(&head, "content-range"), None);
+        assert_eq!(
+            header(&head, "content-length").unwrap(),
+            body.len().to_string()
+        );
+
+        let boundary = header(&head, "content-type")
+            .unwrap()
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let parts: Vec<&str> = body.split(&format!("--{boundary}")).collect();
+
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "\r\n");
+        assert_eq!(
+            parts[1],
+            "\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 0-1/16\r\n\r\n01\r\n"
+        );
+        assert!(
+            parts[2].ends_with("Content-Range: bytes 10-11/16\r\n\r\nab\r\n")
+        );
+        assert_eq!(parts[3], "--\r\n");
+
+        // If-Range
+        let (head, _) = serve(&[]);
+        let etag = header(&head, "etag").unwrap().to_owned();
+        let mtime = header(&head, "last-modified").unwrap().to_owned();
+
+        for (if_range, status) in [
+            (etag.as_str(), "206"),
+            (mtime.as_str(), "206"),
+            // stale, or weak that never matches for ranges
+            ("\"stale\"", "200"),
+            ("Sun, 06 Nov 1994 08:49:37 GMT", "200"),
+            (&format!("W/{etag}"), "200"),
+        ] {
+            let (head, body) =
+                serve(&[("range", "bytes=2-5"), ("if-range", if_range)]);
+
+            assert!(
+                head.starts_with(&format!("HTTP/1.1 {status} ")),
+                "{if_range}: {head}"
+            );
+            assert_eq!(body.len(), if status == "206" { 4 } else { 16 });
+        }
+    }
+}
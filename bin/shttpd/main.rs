@@ -1,11 +1,14 @@
 #![feature(read_buf)]
 
 
+mod cgi;
 mod conf;
+mod file;
 mod req;
 mod resp;
 mod route;
 mod worker;
+mod ws;
 
 
 use std::{
@@ -13,39 +16,36 @@ use std::{
     net::{Ipv4Addr, SocketAddrV4, TcpListener},
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 use clap::Parser;
 use conf::*;
-use futures::executor::{self, ThreadPool};
 use log::info;
 use log4rs;
-use netlib::rs_error::*;
+use netlib::{
+    reactor::{Reactor, Waker},
+    rs_error::*,
+};
 use route::RouteResolver;
 use worker::*;
 
 
-async fn do_listen(ctx: Arc<GloablContext>) -> Result<()> {
+fn do_listen(ctx: Arc<GloablContext>) -> Result<()> {
     let servaddr =
         SocketAddrV4::new(Ipv4Addr::LOCALHOST, *ctx.servconf.listen_port());
 
     let listener =
         TcpListener::bind(servaddr).or_else(|_err| Err(NetErr::Bind))?;
 
+    // idle keep-alive connections only cost a buffer in the reactor
+    let timeout = Duration::from_millis(*ctx.servconf.timeout());
+    let waker = Waker::new()?;
+    let handler = HttpHandler::new(ctx, waker.clone())?;
 
-    let pool =
-        ThreadPool::new().or_else(|err| Err(NetErr::CreateThreadPool(err)))?;
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => pool.spawn_ok(do_work(ctx.clone(), stream)),
-            Err(err) => {
-                eprintln!("{:?}", err);
-            }
-        }
-    }
-
-    Ok(())
+    Reactor::with_waker(listener, handler, waker)?
+        .with_idle_timeout(timeout)
+        .run()
 }
 
 
@@ -116,7 +116,7 @@ fn main() -> Result<()> {
 
     let global = Arc::new(GloablContext { resolver, servconf });
 
-    executor::block_on(do_listen(global))?;
+    do_listen(global)?;
 
     Ok(())
 }
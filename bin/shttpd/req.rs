@@ -1,18 +1,19 @@
-use std::str::FromStr;
+use std::net::SocketAddr;
 
 use http::{
-    header::{HeaderName, ACCEPT, COOKIE, ACCEPT_ENCODING},
-    uri::{PathAndQuery, Scheme},
-    HeaderMap, HeaderValue, Method, Uri, Version,
+    header::{
+        AsHeaderName, ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CONTENT_LENGTH,
+        CONTENT_TYPE, COOKIE, TRANSFER_ENCODING,
+    },
+    uri::PathAndQuery,
+    HeaderMap, Method, Uri, Version,
 };
-use itertools::Itertools;
-use netlib::{
-    application::http::{HeaderAccept, HeaderAcceptEncoding, HeaderCookie},
-    rs_error::*,
-    s,
+use mime::{Mime, APPLICATION_WWW_FORM_URLENCODED};
+use netlib::application::http::{
+    body::ReqBody, parser::ReqHead, HeaderAccept, HeaderAcceptEncoding,
+    HeaderAcceptLanguage, HeaderCookie,
 };
-
-use crate::resp::Body;
+use qstring::QString;
 
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -24,10 +25,16 @@ pub struct Req {
     pub version: Version,
     pub method: Method,
     pub uri: Uri,
+    pub headers: HeaderMap,
     pub accept: HeaderAccept,
     pub accept_encoding: HeaderAcceptEncoding,
+    pub accept_language: HeaderAcceptLanguage,
     pub cookie: HeaderCookie,
-    pub body: Body,
+    /// Decoded body (no transfer coding)
+    pub body: Vec<u8>,
+    pub trailers: HeaderMap,
+    /// Client address
+    pub peer: SocketAddr,
 }
 
 
@@ -35,168 +42,84 @@ pub struct Req {
 //// Implementation
 
 impl Req {
-    pub fn parse_from_bytes(
-        bytes: &[u8],
-        filln: usize,
-    ) -> std::result::Result<Req, HttpKind> {
-        // let plain = String::from_utf8_lossy(&bytes[..filledn]);
-        if filln < 3 * 2 {
-            return Err(HttpKind::TooShort(
-                String::from_utf8_lossy(&bytes[..filln]).to_string(),
-            ));
+    /// Split the list-based headers we care about
+    pub fn from_head(head: ReqHead, body: ReqBody, peer: SocketAddr) -> Self {
+        let accept = head
+            .joined(ACCEPT, ",")
+            .and_then(|val| val.parse().ok())
+            .unwrap_or_default();
+
+        let accept_encoding = head
+            .joined(ACCEPT_ENCODING, ",")
+            .and_then(|val| val.parse().ok())
+            .unwrap_or_default();
+
+        let accept_language = head
+            .joined(ACCEPT_LANGUAGE, ",")
+            .and_then(|val| val.parse().ok())
+            .unwrap_or_default();
+
+        let cookie = head
+            .joined(COOKIE, "; ")
+            .and_then(|val| val.parse().ok())
+            .unwrap_or_default();
+
+        Req {
+            version: head.version,
+            method: head.method,
+            uri: head.uri,
+            headers: head.headers,
+            accept,
+            accept_encoding,
+            accept_language,
+            cookie,
+            body: body.data,
+            trailers: body.trailers,
+            peer,
         }
+    }
+
+    /// GET of another path on behalf of the client, for the local redirect
+    /// of CGI
+    pub fn redirected(&self, paq: PathAndQuery) -> Self {
+        let mut parts = self.uri.clone().into_parts();
+        parts.path_and_query = Some(paq);
 
-        // bytes[filledn] = 0, on guard
-        let mut i = 0;
-        loop {
-            if bytes[i] == '\r' as u8 && bytes[i + 1] == '\n' as u8 {
-                break Ok(());
-            }
-            if i >= filln - 2 {
-                break Err(HttpKind::InvalidReqLn);
-            }
-
-            i += 1;
-        }?;
-
-        // A request line containing only the path name is accepted by servers
-        // to maintain compatibility with HTTP clients before the HTTP/1.0
-        // specification in RFC 1945.
-        let reqln = &bytes[..i];
-        let (mb, urlb, verb) = reqln
-            .split(|c| *c == ' ' as u8)
-            .next_tuple()
-            .ok_or(HttpKind::InvalidReqLn)?;
-
-        let method = Method::from_bytes(mb)
-            .or_else(|_err| Err(HttpKind::InvalidReqLn))?;
-
-        let pqs = String::from_utf8_lossy(urlb);
-        let pq = PathAndQuery::from_str(&pqs)
-            .or_else(|_err| Err(HttpKind::InvalidReqLn))?;
-        if !verb.starts_with(b"HTTP/") {
-            return Err(HttpKind::InvalidReqLn);
+        let mut headers = self.headers.clone();
+        for name in [CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING] {
+            headers.remove(name);
         }
-        let uri = Uri::builder()
-            .scheme(Scheme::HTTP)
-            .authority("localhost")
-            .path_and_query(pq)
-            .build()
-            .or_else(|_err| Err(HttpKind::InvalidReqLn))?;
-        let version = match &verb[5..8] {
-            b"0.9" => Version::HTTP_09,
-            b"1.0" => Version::HTTP_10,
-            b"1.1" => Version::HTTP_11,
-            _ => {
-                return Err(HttpKind::InvalidReqLn);
-            }
-        };
 
+        let head = ReqHead {
+            method: Method::GET,
+            uri: Uri::from_parts(parts).unwrap(),
+            version: self.version,
+            headers,
+        };
 
-        /* Let's parsing headers now */
-        i += 2;
+        Self::from_head(head, ReqBody::default(), self.peer)
+    }
 
-        let mut hdrs = HeaderMap::new();
-        /* DETECT AN EMPTYLINE */
-        if bytes[i] == '\r' as u8 && bytes[i + 1] == '\n' as u8 {
-            if version != Version::HTTP_09 {
-                return Err(HttpKind::HeaderNotFoundForVersion(format!(
-                    "{:#?}",
-                    version
-                )));
-            }
-        }
-        else {
-            // zero or more request header fields (at least 1
-            // or more headers in case of HTTP/1.1)
-            // let mut headers = HeaderMap::new();
-            let mut hdrlns = vec![];
-            let mut start = i;
-
-            loop {
-                if bytes[i] == '\r' as u8 && bytes[i + 1] == '\n' as u8 {
-                    if start == i {
-                        // EMPTY LINE found!
-                        break Ok(());
-                    }
-                    // else push and start next header scan
-                    hdrlns.push(&bytes[start..i]);
-                    start = i + 2;
-                    if start == filln {
-                        break Ok(());
-                    }
-                }
-
-                if i >= filln - 2 {
-                    break Err(HttpKind::InvalidHeader(s!(
-                        "Unexpected header item END"
-                    )));
-                }
-
-                i += 1;
-            }?;
-
-            for ln in hdrlns {
-                let (pos_colon, _) = ln
-                    .into_iter()
-                    .find_position(|c| **c == ':' as u8)
-                    .ok_or(HttpKind::InvalidHeader(format!(
-                        "There is no colon sign in header line:\n{ln:#?}"
-                    )))?;
-
-                let name = HeaderName::from_bytes(&ln[..pos_colon]).or_else(
-                    |err| Err(HttpKind::InvalidHeader(format!("{}", err))),
-                )?;
-
-                let value = HeaderValue::from_bytes(&ln[pos_colon + 1..])
-                    .or_else(|err| {
-                        Err(HttpKind::InvalidHeader(format!("{}", err)))
-                    })?;
-
-                hdrs.insert(name, value);
-            }
-        }
+    /// First value of the header if it's visible ASCII
+    pub fn header_str<K: AsHeaderName>(&self, name: K) -> Option<&str> {
+        self.headers.get(name).and_then(|val| val.to_str().ok())
+    }
 
-        /* Handle split header */
-        /* Accept */
-        let accept = if let Some(val) = hdrs.get(ACCEPT) {
-            match val.to_str().unwrap().parse() {
-                Ok(res) => res,
-            }
-        }
-        else {
-            Default::default()
-        };
+    pub fn content_type(&self) -> Option<Mime> {
+        self.header_str(CONTENT_TYPE)
+            .and_then(|val| val.parse().ok())
+    }
 
-        let accept_encoding = if let Some(val) = hdrs.get(ACCEPT_ENCODING) {
-            match val.to_str().unwrap().parse() {
-                Ok(res) => res,
-            }
-        }
-        else {
-            Default::default()
-        };
+    /// Pairs of `application/x-www-form-urlencoded` body
+    pub fn form(&self) -> Option<QString> {
+        let content_type = self.content_type()?;
 
-        /* Cookie */
-        let cookie = if let Some(val) = hdrs.get(COOKIE) {
-            match val.to_str().unwrap().parse() {
-                Ok(res) => res,
-                Err(_) => Default::default(),
-            }
+        if content_type.essence_str()
+            != APPLICATION_WWW_FORM_URLENCODED.essence_str()
+        {
+            return None;
         }
-        else {
-            Default::default()
-        };
-
 
-        Ok(Req {
-            version,
-            method,
-            uri,
-            accept,
-            accept_encoding,
-            cookie,
-            body: Body::empty(),
-        })
+        std::str::from_utf8(&self.body).ok().map(QString::from)
     }
 }
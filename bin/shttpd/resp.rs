@@ -1,15 +1,45 @@
-use std::io::Write;
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{Read, Write},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use http::{
-    header::{CONTENT_TYPE, DATE, SERVER, CONNECTION, CONTENT_LENGTH, CONTENT_ENCODING},
-    StatusCode, Version,
+    header::{
+        ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH,
+        CONTENT_TYPE, DATE, SERVER, TRANSFER_ENCODING, VARY,
+    },
+    HeaderMap, HeaderName, HeaderValue, StatusCode, Version,
 };
 use mime::{Mime, TEXT_PLAIN_UTF_8, TEXT_HTML_UTF_8};
-use flate2::{write::GzEncoder, Compression};
-use netlib::{s, application::http::AcceptEncoding};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use netlib::{
+    s,
+    application::http::{cond::fmt_http_date, AcceptEncoding},
+};
+
+use crate::ws::WsUpgrade;
 
 
 pub const SERVER_NAME: &str = "Shttpd-minghu6 (Linux)";
+/// Interim response to `Expect: 100-continue`
+pub const CONTINUE_RESP: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+/// Content codings we produce, in the order of preference
+pub const ENCODINGS: [AcceptEncoding; 5] = [
+    AcceptEncoding::Br,
+    AcceptEncoding::Zstd,
+    AcceptEncoding::Gzip,
+    AcceptEncoding::Deflate,
+    AcceptEncoding::Identity,
+];
+/// Quality of brotli, the max (11) is too slow for dynamic content
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_LGWIN: u32 = 22;
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Structure
@@ -23,14 +53,38 @@ pub struct Resp {
     // content_length: u64,, replacing with body.len()
     server: String,
     is_close: bool,
+    /// Extra headers, e.g. ETag
+    headers: HeaderMap,
     body: Body,
+    /// The connection is switched to WebSocket after it
+    upgrade: Option<WsUpgrade>,
 }
 
 
 #[derive(Debug)]
 pub struct Body {
     pub content_type: Mime,
-    pub body: String
+    pub chunks: Vec<Chunk>,
+    /// Rest of the body of unknown length, sent in chunked coding or
+    /// until the connection is closed
+    pub stream: Option<BodyStream>,
+}
+
+
+/// Body read until EOF, e.g. output of CGI
+pub struct BodyStream(pub Box<dyn Read + Send>);
+
+
+/// Part of the body (or the whole response), file part is written
+/// by `sendfile`
+#[derive(Debug)]
+pub enum Chunk {
+    Bytes(Vec<u8>),
+    File {
+        file: Arc<File>,
+        offset: u64,
+        len: u64,
+    },
 }
 
 
@@ -38,136 +92,404 @@ pub struct Body {
 ////////////////////////////////////////////////////////////////////////////////
 //// Implementation
 
+impl Chunk {
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File { len, .. } => *len,
+        }
+    }
+}
+
+
+impl Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BodyStream")
+    }
+}
+
+
 impl Body {
-    pub fn len(&self) -> usize {
-        self.body.len()
+    /// Known part only
+    pub fn len(&self) -> u64 {
+        self.chunks.iter().map(Chunk::len).sum()
     }
 
     pub fn plain(raw: String) -> Self {
+        Self::bytes(TEXT_PLAIN_UTF_8, raw.into_bytes())
+    }
+
+    pub fn html(raw: String) -> Self {
+        Self::bytes(TEXT_HTML_UTF_8, raw.into_bytes())
+    }
+
+    pub fn empty() -> Self {
         Self {
             content_type: TEXT_PLAIN_UTF_8,
-            body: raw,
+            chunks: vec![],
+            stream: None,
         }
     }
 
-    pub fn html(raw: String) -> Self {
+    pub fn bytes(content_type: Mime, bytes: Vec<u8>) -> Self {
         Self {
-            content_type: TEXT_HTML_UTF_8,
-            body: raw,
+            content_type,
+            chunks: vec![Chunk::Bytes(bytes)],
+            stream: None,
         }
     }
 
-    pub fn empty() -> Self {
+    /// `len` bytes of the file from `offset`
+    pub fn file(
+        content_type: Mime,
+        file: Arc<File>,
+        offset: u64,
+        len: u64,
+    ) -> Self {
         Self {
-            content_type: TEXT_PLAIN_UTF_8,
-            body: String::new(),
+            content_type,
+            chunks: vec![Chunk::File { file, offset, len }],
+            stream: None,
+        }
+    }
+
+    pub fn stream<R: Read + Send + 'static>(
+        content_type: Mime,
+        reader: R,
+    ) -> Self {
+        Self {
+            content_type,
+            chunks: vec![],
+            stream: Some(BodyStream(Box::new(reader))),
         }
     }
 
+    /// Whole body is in memory
+    fn is_bytes(&self) -> bool {
+        self.stream.is_none()
+            && self
+                .chunks
+                .iter()
+                .all(|chunk| matches!(chunk, Chunk::Bytes(_)))
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
 }
 
 
 
 impl Resp {
-    pub fn _404(msg: String) -> Self {
+    pub fn new(status: StatusCode, is_close: bool, body: Body) -> Self {
         Self {
             version: Version::HTTP_11,
-            status: StatusCode::NOT_FOUND,
-            date: chrono::offset::Local::now().to_rfc2822(),
+            status,
+            date: fmt_http_date(SystemTime::now()),
             server: s!(SERVER_NAME),
-            is_close: true,
-            body: Body::plain(msg),
+            is_close,
+            headers: HeaderMap::new(),
+            body,
+            upgrade: None,
         }
     }
 
+    /// Without body, `headers` are those of the handshake
+    pub fn _101(headers: HeaderMap, upgrade: WsUpgrade) -> Self {
+        let mut resp =
+            Self::new(StatusCode::SWITCHING_PROTOCOLS, false, Body::empty());
+        resp.headers = headers;
+        resp.upgrade = Some(upgrade);
+        resp
+    }
+
+    pub fn _404(msg: String) -> Self {
+        Self::new(StatusCode::NOT_FOUND, false, Body::plain(msg))
+    }
+
     pub fn _405(msg: String) -> Self {
-        Self {
-            version: Version::HTTP_11,
-            status: StatusCode::METHOD_NOT_ALLOWED,
-            date: chrono::offset::Local::now().to_rfc2822(),
-            server: s!(SERVER_NAME),
-            is_close: true,
-            body: Body::plain(msg),
-        }
+        Self::new(StatusCode::METHOD_NOT_ALLOWED, false, Body::plain(msg))
+    }
+
+    pub fn _400(msg: String) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, true, Body::plain(msg))
+    }
+
+    pub fn _413(msg: String) -> Self {
+        Self::new(StatusCode::PAYLOAD_TOO_LARGE, true, Body::plain(msg))
+    }
+
+    pub fn _416(msg: String) -> Self {
+        Self::new(StatusCode::RANGE_NOT_SATISFIABLE, false, Body::plain(msg))
+    }
+
+    pub fn _417(msg: String) -> Self {
+        Self::new(StatusCode::EXPECTATION_FAILED, true, Body::plain(msg))
+    }
+
+    pub fn _426(msg: String) -> Self {
+        Self::new(StatusCode::UPGRADE_REQUIRED, false, Body::plain(msg))
     }
 
     pub fn _500(msg: String) -> Self {
-        Self {
-            version: Version::HTTP_11,
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            date: chrono::offset::Local::now().to_rfc2822(),
-            server: s!(SERVER_NAME),
-            is_close: true,
-            body: Body::plain(msg),
-        }
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, true, Body::plain(msg))
+    }
+
+    pub fn _502(msg: String) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, true, Body::plain(msg))
+    }
+
+    pub fn _504(msg: String) -> Self {
+        Self::new(StatusCode::GATEWAY_TIMEOUT, true, Body::plain(msg))
+    }
+
+    pub fn _503(msg: String) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, true, Body::plain(msg))
     }
 
     pub fn _200(body: Body) -> Self {
-        Self {
-            version: Version::HTTP_11,
-            status: StatusCode::OK,
-            date: chrono::offset::Local::now().to_rfc2822(),
-            server: s!(SERVER_NAME),
-            is_close: false,
-            body,
-        }
+        Self::new(StatusCode::OK, false, body)
+    }
+
+    pub fn _206(body: Body) -> Self {
+        Self::new(StatusCode::PARTIAL_CONTENT, false, body)
+    }
+
+    /// Without body and its headers
+    pub fn _304() -> Self {
+        Self::new(StatusCode::NOT_MODIFIED, false, Body::empty())
+    }
+
+    pub fn with_header(mut self, name: HeaderName, val: HeaderValue) -> Self {
+        self.headers.append(name, val);
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
     }
 
-    pub fn into_bytes(self, bytes: &mut Vec<u8>, encoding: Option<AcceptEncoding>) {
+    pub fn is_stream(&self) -> bool {
+        self.body.is_stream()
+    }
+
+    pub fn is_close(&self) -> bool {
+        self.is_close
+    }
+
+    pub fn set_close(&mut self, is_close: bool) {
+        self.is_close = is_close;
+    }
+
+    pub fn take_upgrade(&mut self) -> Option<WsUpgrade> {
+        self.upgrade.take()
+    }
+
+    /// Head and in-memory body are merged into bytes chunks. Only that
+    /// kind of body of at least `min_size` bytes is compressed (and varies
+    /// by Accept-Encoding), file and partial ones are sent as is.
+    /// The stream is left to the caller, in chunked coding if the
+    /// connection is kept alive.
+    pub fn into_chunks(
+        self,
+        encoding: Option<AcceptEncoding>,
+        min_size: u64,
+    ) -> (Vec<Chunk>, Option<BodyStream>) {
         let Self {
                 version,
                 status,
                 date,
                 server,
                 is_close,
+                headers,
                 body,
+                upgrade: _,
             } = self;
 
-        let Body { content_type, body } = body;
-
-        let mut body: Vec<u8> = body.into();
+        let mut bytes = vec![];
 
         /* Write status line */
         write!(bytes, "{version:?} {status}\r\n").unwrap();
         write!(bytes, "{DATE}: {date}\r\n").unwrap();
         write!(bytes, "{SERVER}: {server}\r\n").unwrap();
-        if let Some(ref encoding) = encoding {
-            write!(bytes, "{CONTENT_ENCODING}: {encoding}\r\n").unwrap();
-            match encoding {
-                AcceptEncoding::Gzip => {
-                    let mut tmpbuf = vec![];
-                    let mut e = GzEncoder::new(&mut tmpbuf, Compression::default());
-                    e.write(&mut body).unwrap();
-                    drop(e);
-                    body = tmpbuf;
-                },
-                _=> todo!(),
+
+        for (name, val) in headers.iter() {
+            write!(bytes, "{name}: ").unwrap();
+            bytes.extend_from_slice(val.as_bytes());
+            write!(bytes, "\r\n").unwrap();
+        }
+
+        // 101 has Connection: Upgrade already
+        let upgrading = status == StatusCode::SWITCHING_PROTOCOLS;
+        let has_body = status != StatusCode::NOT_MODIFIED && !upgrading;
+        let compressible = has_body
+            && status != StatusCode::PARTIAL_CONTENT
+            && body.is_bytes()
+            && body.len() >= min_size
+            && !headers.contains_key(CONTENT_ENCODING);
+
+        if compressible {
+            write!(bytes, "{VARY}: {ACCEPT_ENCODING}\r\n").unwrap();
+        }
+
+        let encoding = encoding.filter(|encoding| {
+            compressible && *encoding != AcceptEncoding::Identity
+        });
+
+        let Body {
+            content_type,
+            chunks,
+            mut stream,
+        } = body;
+
+        let compressed = encoding.and_then(|encoding| {
+            Some((encoding, compress(encoding, &chunks)?))
+        });
+
+        let mut chunks = match compressed {
+            Some((encoding, compressed)) => {
+                write!(bytes, "{CONTENT_ENCODING}: {encoding}\r\n").unwrap();
+                vec![Chunk::Bytes(compressed)]
+            }
+            None => chunks,
+        };
+
+        if !has_body {
+            chunks.clear();
+            stream = None;
+        }
+        else if stream.is_some() {
+            write!(bytes, "{CONTENT_TYPE}: {content_type}\r\n").unwrap();
+
+            if !is_close {
+                write!(bytes, "{TRANSFER_ENCODING}: chunked\r\n").unwrap();
             }
         }
-        write!(bytes, "{CONTENT_TYPE}: {content_type}\r\n").unwrap();
-        write!(bytes, "{CONTENT_LENGTH}: {}\r\n", body.len()).unwrap();
+        else {
+            let len: u64 = chunks.iter().map(Chunk::len).sum();
+
+            write!(bytes, "{CONTENT_TYPE}: {content_type}\r\n").unwrap();
+            write!(bytes, "{CONTENT_LENGTH}: {len}\r\n").unwrap();
+        }
 
-        if is_close {
-            write!(bytes, "{CONNECTION}: close\r\n").unwrap();
+        if !upgrading {
+            // keep-alive is needed by HTTP/1.0 clients
+            let connection = if is_close { "close" } else { "keep-alive" };
+            write!(bytes, "{CONNECTION}: {connection}\r\n").unwrap();
         }
 
         write!(bytes, "\r\n").unwrap();
 
-        bytes.write(&body).unwrap();
+        // small body goes with the head in one write
+        if let Some(Chunk::Bytes(first)) = chunks.first_mut() {
+            bytes.append(first);
+            chunks.remove(0);
+        }
 
+        chunks.insert(0, Chunk::Bytes(bytes));
+        (chunks, stream)
     }
 }
 
 
 
+/// In-memory chunks in the content coding, `None` if it isn't supported
+fn compress(encoding: AcceptEncoding, chunks: &[Chunk]) -> Option<Vec<u8>> {
+    let raws = chunks.iter().filter_map(|chunk| match chunk {
+        Chunk::Bytes(raw) => Some(raw),
+        Chunk::File { .. } => None,
+    });
+    let mut buf = vec![];
+
+    match encoding {
+        AcceptEncoding::Gzip => {
+            let mut e = GzEncoder::new(&mut buf, Compression::default());
+            raws.for_each(|raw| e.write_all(raw).unwrap());
+            e.finish().unwrap();
+        }
+        AcceptEncoding::Deflate => {
+            let mut e = ZlibEncoder::new(&mut buf, Compression::default());
+            raws.for_each(|raw| e.write_all(raw).unwrap());
+            e.finish().unwrap();
+        }
+        AcceptEncoding::Br => {
+            let mut e = brotli::CompressorWriter::new(
+                &mut buf,
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_LGWIN,
+            );
+            raws.for_each(|raw| e.write_all(raw).unwrap());
+            drop(e);
+        }
+        AcceptEncoding::Zstd => {
+            let mut e = zstd::Encoder::new(&mut buf, 0).unwrap();
+            raws.for_each(|raw| e.write_all(raw).unwrap());
+            e.finish().unwrap();
+        }
+        AcceptEncoding::Compress | AcceptEncoding::Identity => return None,
+    }
+
+    Some(buf)
+}
+
+
+
 
 #[cfg(test)]
 mod tests {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
     use http::Version;
+    use netlib::application::http::AcceptEncoding;
+
+    use super::{compress, Chunk};
 
 
     #[test]
     fn test_write_response() {
         println!("{:?}", Version::HTTP_11);
     }
+
+    #[test]
+    fn test_compress() {
+        let raw = b"hello shttpd ".repeat(100);
+        let chunks = vec![
+            Chunk::Bytes(raw[..500].to_vec()),
+            Chunk::Bytes(raw[500..].to_vec()),
+        ];
+
+        for encoding in [
+            AcceptEncoding::Gzip,
+            AcceptEncoding::Deflate,
+            AcceptEncoding::Br,
+            AcceptEncoding::Zstd,
+        ] {
+            let compressed = compress(encoding, &chunks).unwrap();
+            assert!(compressed.len() < raw.len());
+
+            let mut decoded = vec![];
+            match encoding {
+                AcceptEncoding::Gzip => GzDecoder::new(&compressed[..])
+                    .read_to_end(&mut decoded)
+                    .map(|_| ()),
+                AcceptEncoding::Deflate => ZlibDecoder::new(&compressed[..])
+                    .read_to_end(&mut decoded)
+                    .map(|_| ()),
+                AcceptEncoding::Br => {
+                    brotli::Decompressor::new(&compressed[..], 4096)
+                        .read_to_end(&mut decoded)
+                        .map(|_| ())
+                }
+                _ => zstd::stream::copy_decode(&compressed[..], &mut decoded),
+            }
+            .unwrap();
+
+            assert_eq!(decoded, raw, "{encoding}");
+        }
+
+        assert!(compress(AcceptEncoding::Identity, &chunks).is_none());
+        assert!(compress(AcceptEncoding::Compress, &chunks).is_none());
+    }
 }
@@ -1,35 +1,50 @@
 use std::{
+    collections::HashMap,
     fmt::Write,
-    fs::{create_dir_all, read_dir, read_to_string},
-    iter::once_with,
+    fs::{create_dir_all, read_dir},
+    iter::{once, once_with},
     path::{Path, PathBuf},
     process::Command,
+    time::Duration,
 };
 
 use chrono::{DateTime, Local};
 use http::Method;
 use log::info;
-use mime::{TEXT_HTML, TEXT_HTML_UTF_8, TEXT_PLAIN, TEXT_PLAIN_UTF_8};
 use qstring::QString;
 use netlib::{
-    application::http::parse_content_type,
+    application::fastcgi::client::FcgiClient,
     rs_error::{NetErr, Result},
     or2s, s,
 };
 
 use crate::{
-    conf::{CGIMap, CGIMapItem, ServConf},
+    cgi::{self, meta_vars, CgiReply},
+    conf::{CGIMap, CGIMapItem, ServConf, WsRoute},
+    file::{is_safe_path, serve_file},
     req::Req,
     resp::{Body, Resp},
+    ws,
 };
 
 
+/// CGI may redirect to another one
+const MAX_LOCAL_REDIRECTS: usize = 4;
+
+
 
 pub struct RouteResolver {
     docroot: PathBuf,
     default_file: PathBuf,
     persisroot: PathBuf,
     cgimap: CGIMap,
+    listen_port: u16,
+    cgi_timeout: Duration,
+    /// Connection pools of FastCGI routes
+    fcgi_clients: HashMap<PathBuf, FcgiClient>,
+    ws_routes: Vec<WsRoute>,
+    /// Bytes of a WebSocket message
+    max_message: usize,
 }
 
 
@@ -42,52 +57,97 @@ impl RouteResolver {
                 .or_else(|err| Err(NetErr::CreateDirAll(err)))?;
         }
 
+        let cgi_timeout = Duration::from_millis(*servconf.cgi_timeout());
+        let fcgi_clients = servconf
+            .cgimap()
+            .items
+            .iter()
+            .filter_map(|cgi| {
+                let client = FcgiClient::new(cgi.fastcgi.clone()?)
+                    .with_timeout(cgi_timeout);
+
+                Some((cgi.route.clone(), client))
+            })
+            .collect();
+
         Ok(Self {
             docroot: servconf.docroot().clone(),
             default_file: servconf.default_file().clone(),
             persisroot: servconf.persisroot().clone(),
             cgimap: servconf.cgimap().clone(),
+            listen_port: *servconf.listen_port(),
+            cgi_timeout,
+            fcgi_clients,
+            ws_routes: servconf.ws_routes().clone(),
+            max_message: *servconf.max_body_size() as usize,
         })
     }
 
     pub fn resolve(&self, req: &Req) -> Resp {
-        if req.method != Method::GET {
-            return Resp::_405(String::new());
-        }
+        self.resolve_redirected(req, 0)
+    }
 
+    /// `depth` is the number of local redirects so far
+    fn resolve_redirected(&self, req: &Req, depth: usize) -> Resp {
         let paq = req.uri.path_and_query().unwrap();
         info!("url: {}", paq.path());
 
+        let ws_route = self
+            .ws_routes
+            .iter()
+            .find(|ws_route| ws_route.route == Path::new(paq.path()));
+
+        if let Some(ws_route) = ws_route {
+            if depth > 0 {
+                return Resp::_500(s!("Local redirect to WebSocket"));
+            }
+
+            return ws::upgrade(req, ws_route.service, self.max_message);
+        }
+
+        // only CGI takes a body
+        let cgi = self.cgimap.match_path(paq.path());
+        let allowed = match req.method {
+            Method::GET => true,
+            Method::POST | Method::PUT => cgi.is_some(),
+            _ => false,
+        };
+
+        if !allowed {
+            return Resp::_405(String::new());
+        }
+
         if paq.path() == "/" {
-            return loop {
-                let index_path = self.docroot.join(&self.default_file);
-                let body = match read_to_string(index_path) {
-                    Ok(s) => Body::html(s),
-                    Err(err) => break Resp::_500(err.to_string()),
-                };
-
-                break Resp::_200(body);
-            };
+            return serve_file(req, self.docroot.join(&self.default_file));
         }
 
-        if let Some(cgi) = self.cgimap.get(paq.path()) {
+        if let Some((cgi, path_info)) = cgi {
             info!("cgi map item: {cgi:?}");
 
-            let q = QString::from(paq.query().unwrap_or_default());
-            return match self.do_ipc_cgi(&cgi, q) {
-                Ok(resp) => resp,
+            return match self.do_ipc_cgi(cgi, path_info, req) {
+                Ok(CgiReply::Resp(resp)) => *resp,
+                Ok(CgiReply::LocalRedirect(paq)) => {
+                    if depth >= MAX_LOCAL_REDIRECTS {
+                        return Resp::_500(s!("Too many local redirects"));
+                    }
+
+                    self.resolve_redirected(&req.redirected(paq), depth + 1)
+                }
                 Err(errmsg) => Resp::_500(errmsg),
             };
         }
 
-        let docp = self.docroot.join(paq.path().strip_prefix("/").unwrap());
+        let relp = paq.path().strip_prefix("/").unwrap();
+
+        if !is_safe_path(relp) {
+            return Resp::_404(String::new());
+        }
+
+        let docp = self.docroot.join(relp);
         info!("try access docp: {docp:?}");
 
-        if docp.is_file() || docp.is_symlink() {
-            return match read_to_string(docp) {
-                Ok(s) => Resp::_200(Body::plain(s)),
-                Err(err) => Resp::_500(err.to_string()),
-            };
+        if docp.is_file() {
+            return serve_file(req, docp);
         }
 
         if docp.is_dir() {
@@ -105,13 +165,26 @@ impl RouteResolver {
     pub fn do_ipc_cgi(
         &self,
         cgi: &CGIMapItem,
-        q: QString,
-    ) -> std::result::Result<Resp, String> {
-        /* Generate env list from query */
+        path_info: &str,
+        req: &Req,
+    ) -> std::result::Result<CgiReply, String> {
+        let paq = req.uri.path_and_query().unwrap();
+        let q = QString::from(paq.query().unwrap_or_default());
+        let script_name = paq.path().strip_suffix(path_info).unwrap();
+
+        /* Generate env list from query and then urlencoded form */
         let envs = q
             .into_pairs()
             .into_iter()
+            .chain(req.form().map(QString::into_pairs).unwrap_or_default())
             .map(|(k, v)| (format!("SHTTPD_Q_{}", k.to_uppercase()), v))
+            .chain(meta_vars(
+                req,
+                script_name,
+                path_info,
+                &self.docroot,
+                self.listen_port,
+            ))
             .chain(once_with(|| {
                 (
                     s!("SHTTPD_PERSIS_ROOT"),
@@ -125,44 +198,23 @@ impl RouteResolver {
                 )
             }));
 
-        let output =
-            or2s!(Command::new(cgi.joined_by(&self.cgimap.root).as_os_str())
-                .current_dir(or2s!(std::env::current_dir())?)
-                .envs(envs)
-                .output())?;
-
-        if !output.stderr.is_empty() {
-            return Err(String::from_utf8_lossy(&output.stderr).to_string());
-        }
-
-        let out_decoded = String::from_utf8_lossy(&output.stdout);
-        let lf_end = if let Some(lf_end) = out_decoded.find("\n") {
-            lf_end
-        }
-        else {
-            return Err(s!("There are no first line"));
-        };
+        let script = cgi.joined_by(&self.cgimap.root);
 
-        info!("cgi-content-type: {}", &out_decoded[0..lf_end]);
-        let content_type = parse_content_type(&out_decoded[0..lf_end])
-            .or_else(|_| Err("CGI Invalid Content-Type"))?;
-        let remains = &out_decoded[lf_end + 1..];
+        if let Some(client) = self.fcgi_clients.get(&cgi.route) {
+            let params: Vec<_> = envs
+                .chain(once((
+                    s!("SCRIPT_FILENAME"),
+                    s!(script.to_string_lossy()),
+                )))
+                .collect();
 
-        let body = if content_type == TEXT_PLAIN
-            || content_type == TEXT_PLAIN_UTF_8
-        {
-            Body::plain(remains.to_owned())
+            return cgi::run_fastcgi(client, &params, &req.body);
         }
-        else if content_type == TEXT_HTML || content_type == TEXT_HTML_UTF_8
-        {
-            Body::html(remains.to_owned())
-        }
-        else {
-            return Err(format!("Unsupported cgi mime type: {content_type}"));
-        };
 
+        let mut cmd = Command::new(script);
+        cmd.current_dir(or2s!(std::env::current_dir())?).envs(envs);
 
-        Ok(Resp::_200(body))
+        cgi::run(cmd, req.body.clone(), self.cgi_timeout)
     }
 }
 
@@ -1,90 +1,595 @@
 #![allow(unused_imports)]
 
 use std::{
-    fs::read_to_string,
-    io::{Read, Write},
-    net::TcpStream,
-    str::FromStr,
-    time::Duration, sync::Arc,
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
 };
 
-use http::{
-    header::{HeaderName, ACCEPT, ACCEPT_RANGES, COOKIE},
-    request,
-    uri::{PathAndQuery, Scheme},
-    HeaderValue, Method, Request, Uri, Version,
+use futures::executor::ThreadPool;
+use http::{header::CONNECTION, Version};
+use log::{info, warn};
+use netlib::{
+    application::http::{
+        body::BodyDecoder,
+        parser::{ParseStatus, ReqHead, ReqParser},
+        ws::Message,
+    },
+    reactor::{Conn, ConnState, Conns, Handler, Token, Waker},
+    rs_error::*,
 };
-use itertools::Itertools;
-use lazy_static::lazy_static;
-use log::info;
-use netlib::{rs_error::*, s, application::http::AcceptEncoding};
 
 use crate::req::*;
 use crate::{
-    conf::ServConf,
-    resp::{Body, Resp},
-    route::RouteResolver,
+    resp::{BodyStream, Chunk, Resp, CONTINUE_RESP, ENCODINGS},
+    ws::{WsConn, WsUpgrade},
     GloablContext,
 };
 
 
-const REQ_BUF_LEN: usize = 1024 * 2;
+/// Bytes read from a body stream at a time
+const STREAM_BUF: usize = 16 * 1024;
 
 
 
-pub async fn do_work(ctx:Arc<GloablContext>, stream: TcpStream) {
-    match _do_work(ctx, stream) {
-        Ok(_) => (),
-        Err(err) => eprintln!("#{err}"),
+/// Keep-alive connection, bytes of pipelined requests stay in `rest`
+#[derive(Debug, Default)]
+pub struct HttpConn {
+    parser: ReqParser,
+    /// Head whose body is being read
+    pending: Option<(ReqHead, BodyDecoder)>,
+    rest: Vec<u8>,
+    /// A request is being handled by the pool
+    busy: bool,
+    /// Requests have been served
+    served: u64,
+    /// Upgraded to WebSocket, no more HTTP
+    ws: Option<WsConn>,
+}
+
+
+/// Requests are parsed in the reactor thread and handled in the pool,
+/// responses come back through the channel.
+pub struct HttpHandler {
+    ctx: Arc<GloablContext>,
+    pool: ThreadPool,
+    waker: Waker,
+    tx: Sender<Reply>,
+    rx: Receiver<Reply>,
+}
+
+
+/// Response of the request on the connection, a streamed body comes
+/// in many parts. After a 101 the connection is upgraded and the
+/// WebSocket service pushes messages.
+enum Reply {
+    Part { token: Token, chunks: Vec<Chunk> },
+    End { token: Token, keep_alive: bool },
+    Upgrade { token: Token, upgrade: Box<WsUpgrade> },
+    Message { token: Token, msg: Message },
+}
+
+
+
+impl HttpHandler {
+    pub fn new(ctx: Arc<GloablContext>, waker: Waker) -> Result<Self> {
+        let pool = ThreadPool::new().map_err(NetErr::CreateThreadPool)?;
+        let (tx, rx) = channel();
+
+        Ok(Self {
+            ctx,
+            pool,
+            waker,
+            tx,
+            rx,
+        })
+    }
+
+    /// Dispatch next request if it's complete and none is in flight.
+    /// Pipelined requests are handled one by one so that the responses
+    /// are in order.
+    fn advance(&mut self, conn: &mut Conn<HttpConn>) {
+        if conn.data.busy || conn.state() != ConnState::Open {
+            return;
+        }
+
+        let max_body = *self.ctx.servconf.max_body_size() as usize;
+
+        let req = match read_req(conn, max_body) {
+            Ok(Some(req)) => req,
+            Ok(None) => {
+                if conn.is_eof() {
+                    // the peer closes between requests or in the middle
+                    let http = &conn.data;
+
+                    if !http.parser.is_idle() || http.pending.is_some() {
+                        let rest = String::from_utf8_lossy(http.parser.rest());
+                        let kind = HttpKind::TooShort(rest.to_string());
+
+                        reply_bad_req(conn, kind);
+                    }
+
+                    conn.close();
+                }
+
+                return;
+            }
+            Err(kind) => {
+                reply_bad_req(conn, kind);
+                return;
+            }
+        };
+
+        info!("Incomming request: \n{:#?}\n", req);
+
+        conn.data.busy = true;
+        conn.data.served += 1;
+        conn.pause_read();
+
+        let keep_alive = is_keep_alive(&req)
+            && conn.data.served < *self.ctx.servconf.max_keepalive_requests();
+
+        let token = conn.token();
+        let ctx = self.ctx.clone();
+        let tx = self.tx.clone();
+        let waker = self.waker.clone();
+
+        self.pool.spawn_ok(async move {
+            let mut resp = ctx.resolver.resolve(&req);
+
+            // the reactor is gone only when the server quits
+            let send = |reply| {
+                let _ = tx.send(reply);
+                waker.wake();
+            };
+
+            if let Some(upgrade) = resp.take_upgrade() {
+                let (chunks, _) = resp.into_chunks(None, u64::MAX);
+
+                send(Reply::Part { token, chunks });
+                send(Reply::Upgrade {
+                    token,
+                    upgrade: Box::new(upgrade),
+                });
+                return;
+            }
+
+            // HTTP/1.0 has no chunked coding, so a stream ends by closing
+            let keep_alive = keep_alive
+                && !resp.is_close()
+                && !(resp.is_stream() && req.version != Version::HTTP_11);
+            resp.set_close(!keep_alive);
+
+            let encoding = req.accept_encoding.select(&ENCODINGS);
+            let min_size = *ctx.servconf.compress_min_size();
+            let (chunks, stream) = resp.into_chunks(encoding, min_size);
+
+            send(Reply::Part { token, chunks });
+
+            let complete = stream.is_none_or(|stream| {
+                forward_stream(stream, keep_alive, |part| {
+                    let chunks = vec![Chunk::Bytes(part)];
+                    send(Reply::Part { token, chunks })
+                })
+            });
+
+            send(Reply::End {
+                token,
+                keep_alive: keep_alive && complete,
+            });
+        });
+    }
+
+    /// Hand the connection over to WebSocket after the 101 is queued,
+    /// bytes after the upgrade request are its first frames.
+    fn upgrade(&mut self, conn: &mut Conn<HttpConn>, upgrade: WsUpgrade) {
+        let token = conn.token();
+        let tx = self.tx.clone();
+        let waker = self.waker.clone();
+
+        conn.data.ws = Some(WsConn::start(upgrade, move |msg| {
+            let _ = tx.send(Reply::Message { token, msg });
+            waker.wake();
+        }));
+        conn.data.busy = false;
+        conn.resume_read();
+
+        ws_read(conn);
+    }
+}
+
+
+impl Handler for HttpHandler {
+    type State = HttpConn;
+
+    fn on_accept(&mut self, peer: SocketAddr) -> HttpConn {
+        info!("accept {peer}");
+
+        HttpConn::default()
+    }
+
+    fn on_read(&mut self, conn: &mut Conn<HttpConn>) {
+        if conn.data.ws.is_some() {
+            ws_read(conn);
+        }
+        else {
+            self.advance(conn);
+        }
+    }
+
+    fn on_wake(&mut self, conns: &mut Conns<HttpConn>) {
+        while let Ok(reply) = self.rx.try_recv() {
+            let (token, keep_alive) = match reply {
+                Reply::Part { token, chunks } => {
+                    if let Some(conn) = conns.get_mut(token) {
+                        write_chunks(conn, chunks);
+                    }
+
+                    continue;
+                }
+                Reply::Upgrade { token, upgrade } => {
+                    if let Some(conn) = conns.get_mut(token) {
+                        self.upgrade(conn, *upgrade);
+                    }
+
+                    continue;
+                }
+                Reply::Message { token, msg } => {
+                    if let Some(conn) = conns.get_mut(token) {
+                        if let Some(ws) = conn.data.ws.as_mut() {
+                            let out = ws.on_message(msg);
+                            conn.write(&out);
+                        }
+                    }
+
+                    continue;
+                }
+                Reply::End { token, keep_alive } => (token, keep_alive),
+            };
+
+            // it may have been reset by the peer
+            let Some(conn) = conns.get_mut(token)
+            else {
+                continue;
+            };
+
+            conn.data.busy = false;
+
+            if keep_alive {
+                conn.resume_read();
+                self.advance(conn);
+            }
+            else {
+                // In HTTP/1.0, as stated in RFC 1945, the TCP/IP connection
+                // should always be closed by server after a response has
+                // been sent, unless the client asks for keep-alive.
+                conn.close();
+            }
+        }
+    }
+
+    /// The idle timeout doesn't apply to one in the pool, WebSocket is
+    /// pinged before it's closed.
+    fn on_idle(&mut self, conn: &mut Conn<HttpConn>) -> bool {
+        let Some(ws) = conn.data.ws.as_mut()
+        else {
+            return !conn.data.busy;
+        };
+
+        match ws.on_idle() {
+            Some(ping) => {
+                conn.write(&ping);
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn on_close(&mut self, conn: &mut Conn<HttpConn>) {
+        if let Some(ws) = &conn.data.ws {
+            ws.stop();
+        }
+    }
+}
+
+
+/// Messages already there are handled at once
+fn ws_read(conn: &mut Conn<HttpConn>) {
+    let mut input = std::mem::take(&mut conn.data.rest);
+    input.extend(conn.take_input());
+
+    let Some(ws) = conn.data.ws.as_mut()
+    else {
+        return;
+    };
+
+    let (out, done) = ws.on_input(&input);
+    conn.write(&out);
+
+    if done || conn.is_eof() {
+        conn.close();
     }
 }
 
 
-fn _do_work(ctx: Arc<GloablContext>, mut stream: TcpStream) -> Result<()> {
-    stream
-        .set_read_timeout(Some(Duration::from_millis(*ctx.servconf.timeout())))
-        .or_else(|err| Err(NetErr::SetStreamOpt(err)))?;
+/// Head and then body, `100 Continue` is queued before reading the body
+/// if the client asks for it. `None` if it's not complete yet.
+fn read_req(
+    conn: &mut Conn<HttpConn>,
+    max_body: usize,
+) -> std::result::Result<Option<Req>, HttpKind> {
+    let peer = conn.peer();
+
+    // the pipelined one may be there already
+    let mut input = std::mem::take(&mut conn.data.rest);
+    input.extend(conn.take_input());
+
+    let http = &mut conn.data;
 
-    stream
-        .set_write_timeout(Some(Duration::from_millis(
-            *ctx.servconf.timeout(),
-        )))
-        .or_else(|err| Err(NetErr::SetStreamOpt(err)))?;
+    if http.pending.is_none() {
+        let ParseStatus::Complete(head) = http.parser.feed(&input)?
+        else {
+            return Ok(None);
+        };
 
-    let mut bytes_raw = [0u8; REQ_BUF_LEN];
+        let kind = head.body_kind()?;
+        let expects_continue = head.expects_continue()?;
 
-    let filln = stream
-        .read(&mut bytes_raw)
-        .or_else(|err| Err(NetErr::Read(err)))?;
+        let mut decoder = BodyDecoder::with_max(kind, max_body);
 
-    // on guard
-    let req = Req::parse_from_bytes(&bytes_raw, filln)
-        .or_else(|kind| Err(NetErr::HttpBadReq(kind)))?;
+        // a too large Content-Length is rejected here without reading
+        let status = decoder.feed(&http.parser.take_rest())?;
 
-    info!("Incomming request: \n{:#?}\n", req);
+        if let ParseStatus::Complete(body) = status {
+            http.rest = decoder.take_rest();
+            return Ok(Some(Req::from_head(head, body, peer)));
+        }
 
-    let mut bytes_resp = Vec::new();
-    let resp = ctx.resolver.resolve(&req);
+        http.pending = Some((head, decoder));
 
-    let encoding =
-    if req.accept_encoding.contains(&AcceptEncoding::Gzip) {
-        Some(AcceptEncoding::Gzip)
+        if expects_continue {
+            conn.write(CONTINUE_RESP);
+        }
+
+        return Ok(None);
     }
+
+    let (_, decoder) = http.pending.as_mut().unwrap();
+
+    let ParseStatus::Complete(body) = decoder.feed(&input)?
     else {
-        None
+        return Ok(None);
     };
 
-    resp.into_bytes(&mut bytes_resp, encoding);
+    http.rest = decoder.take_rest();
+    let (head, _) = http.pending.take().unwrap();
+
+    Ok(Some(Req::from_head(head, body, peer)))
+}
+
+
+fn reply_bad_req(conn: &mut Conn<HttpConn>, kind: HttpKind) {
+    // not compressed, it's tiny anyway
+    write_chunks(conn, bad_req_resp(&kind).into_chunks(None, u64::MAX).0);
+    conn.close();
+
+    eprintln!("#{}", NetErr::HttpBadReq(kind));
+}
+
+
+fn write_chunks(conn: &mut Conn<HttpConn>, chunks: Vec<Chunk>) {
+    for chunk in chunks {
+        match chunk {
+            Chunk::Bytes(bytes) => conn.write(&bytes),
+            Chunk::File { file, offset, len } => {
+                conn.write_file(file, offset, len)
+            }
+        }
+    }
+}
+
 
-    stream
-        .write(bytes_resp.as_ref())
-        .or_else(|err| Err(NetErr::Write(err)))?;
+/// Read the stream to the end and send it in parts, in chunked coding
+/// if `chunked`. False if it fails in the middle.
+fn forward_stream<F: FnMut(Vec<u8>)>(
+    mut stream: BodyStream,
+    chunked: bool,
+    mut send: F,
+) -> bool {
+    let mut buf = vec![0; STREAM_BUF];
 
-    // In HTTP/1.0, as stated in RFC 1945, the TCP/IP connection should always
-    // be closed by server after a response has been sent.
-    stream
-        .shutdown(std::net::Shutdown::Write)
-        .or_else(|err| Err(NetErr::StreamShutDown(err)))?;
+    loop {
+        let n = match stream.0.read(&mut buf) {
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                warn!("body stream: {err}");
+                return false;
+            }
+        };
+
+        let mut part = vec![];
+
+        if chunked {
+            // the last chunk is of zero size without trailer
+            write!(part, "{n:x}\r\n").unwrap();
+            part.extend_from_slice(&buf[..n]);
+            write!(part, "\r\n").unwrap();
+        }
+        else {
+            part.extend_from_slice(&buf[..n]);
+        }
+
+        if !part.is_empty() {
+            send(part);
+        }
+
+        if n == 0 {
+            return true;
+        }
+    }
+}
+
+
+/// HTTP/1.1 persists by default, HTTP/1.0 needs `Connection: keep-alive`
+fn is_keep_alive(req: &Req) -> bool {
+    let has_token = |token: &str| {
+        req.headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','))
+            .any(|opt| opt.trim().eq_ignore_ascii_case(token))
+    };
+
+    match req.version {
+        Version::HTTP_11 => !has_token("close"),
+        Version::HTTP_10 => has_token("keep-alive"),
+        _ => false,
+    }
+}
+
+
+fn bad_req_resp(kind: &HttpKind) -> Resp {
+    match kind {
+        HttpKind::BodyTooLarge(_) => Resp::_413(format!("{kind:?}")),
+        HttpKind::ExpectationFailed(_) => Resp::_417(format!("{kind:?}")),
+        _ => Resp::_400(format!("{kind:?}")),
+    }
+}
 
-    Ok(())
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        path::PathBuf,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use netlib::reactor::{Reactor, Waker};
+
+    use super::HttpHandler;
+    use crate::{
+        conf::{load_default_serv_conf, CGIMap},
+        route::RouteResolver,
+        GloablContext,
+    };
+
+    /// Removed on drop
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Serve `docroot` until the client returns
+    fn serve<F>(docroot: &PathBuf, client: F)
+    where
+        F: FnOnce(&str) + Send + 'static,
+    {
+        let mut servconf =
+            load_default_serv_conf("res/shttpd/shttpd.default.yaml").unwrap();
+        servconf.set_docroot(docroot.clone());
+        servconf.set_persisroot(docroot.join("persis"));
+        servconf.set_cgimap(CGIMap {
+            root: docroot.join("cgi"),
+            items: vec![],
+        });
+
+        let resolver = RouteResolver::init(&servconf).unwrap();
+        let ctx = Arc::new(GloablContext { resolver, servconf });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let waker = Waker::new().unwrap();
+        let handler = HttpHandler::new(ctx, waker.clone()).unwrap();
+        let mut reactor =
+            Reactor::with_waker(listener, handler, waker).unwrap();
+
+        let client = thread::spawn(move || client(&addr));
+        let start = Instant::now();
+        let mut events = vec![];
+
+        while !client.is_finished() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timeout");
+
+            reactor
+                .run_once(Some(Duration::from_millis(10)), &mut events)
+                .unwrap();
+        }
+
+        client.join().unwrap();
+    }
+
+    /// All of the responses, the server closes the connection at last
+    fn request(addr: &str, reqs: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(reqs.as_bytes()).unwrap();
+
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+
+        out
+    }
+
+    #[test]
+    fn test_worker_pipelined_and_close() {
+        let docroot = std::env::temp_dir()
+            .join(format!("shttpd-worker-{}", std::process::id()));
+        fs::create_dir_all(&docroot).unwrap();
+        let _tmp = TempDir(docroot.clone());
+
+        fs::write(docroot.join("a.txt"), "first body").unwrap();
+        fs::write(docroot.join("b.txt"), "second body").unwrap();
+
+        serve(&docroot, |addr| {
+            // in one write, the second one closes
+            let out = request(
+                addr,
+                "GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n\
+                 GET /b.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            );
+
+            let first = out.find("first body").expect(&out);
+            let second = out.find("second body").expect(&out);
+
+            assert!(out.starts_with("HTTP/1.1 200 "), "{out}");
+            assert_eq!(out.matches(" 200 OK\r\n").count(), 2, "{out}");
+            assert!(first < second, "{out}");
+            assert!(out.ends_with("second body"), "{out}");
+
+            // HTTP/1.0 closes without keep-alive, the rest is never read
+            let out = request(
+                addr,
+                "GET /a.txt HTTP/1.0\r\n\r\nGET /b.txt HTTP/1.0\r\n\r\n",
+            );
+
+            assert_eq!(out.matches(" 200 OK\r\n").count(), 1, "{out}");
+            assert!(out.ends_with("first body"), "{out}");
+
+            let out = request(
+                addr,
+                "GET /a.txt HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+                 GET /b.txt HTTP/1.0\r\n\r\n",
+            );
+
+            assert_eq!(out.matches(" 200 OK\r\n").count(), 2, "{out}");
+            assert!(out.ends_with("second body"), "{out}");
+        });
+    }
 }
@@ -0,0 +1,565 @@
+//! WebSocket routes, the connection is handed over from HTTP to the
+//! service after the 101 response and stays in the reactor.
+//!
+//! Messages are decoded and encoded in the reactor thread, a streaming
+//! service runs in its own thread and sends messages back through the
+//! handler.
+
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use http::{
+    header::{SEC_WEBSOCKET_VERSION, UPGRADE},
+    HeaderValue,
+};
+use log::{info, warn};
+use netlib::{
+    application::http::{
+        parser::ParseStatus,
+        ws::{
+            close_code, is_upgrade, CloseFrame, Handshake, Message, WsCodec,
+            WsRole, CLOSE_INTERNAL_ERROR, CLOSE_NORMAL, WS_VERSION,
+        },
+    },
+    aux::HostOrIP,
+    network::ping::{PingConf, PingSession},
+    rs_error::WsKind,
+};
+use qstring::QString;
+
+use crate::{req::Req, resp::Resp};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Shortest interval of the ping service
+const MIN_PING_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Most echo requests of one ping stream, also the default `count`
+const MAX_PING_COUNT: usize = 100;
+
+/// Ping streams running at the same time, more get 503
+const MAX_PING_STREAMS: usize = 16;
+
+/// Ping streams running now
+static PING_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Service behind a WebSocket route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsService {
+    /// Sends data messages back
+    Echo,
+    /// Pings `host` of the query and streams the events
+    Ping,
+}
+
+
+/// What the service does for this connection, from the upgrade request
+#[derive(Debug)]
+pub enum WsTask {
+    Echo,
+    Ping {
+        dst: IpAddr,
+        conf: PingConf,
+        slot: PingSlot,
+    },
+}
+
+
+/// One of the `MAX_PING_STREAMS`, released on drop
+#[derive(Debug)]
+pub struct PingSlot(());
+
+
+/// Negotiated codec and the task, carried by the 101 response
+#[derive(Debug)]
+pub struct WsUpgrade {
+    pub codec: WsCodec,
+    pub task: WsTask,
+}
+
+
+/// Upgraded connection
+#[derive(Debug)]
+pub struct WsConn {
+    codec: WsCodec,
+    /// Data messages are sent back
+    echo: bool,
+    /// Asks the streaming thread to quit
+    stop: Arc<AtomicBool>,
+    close_sent: bool,
+    /// A ping has been sent for idle and no message comes since then
+    awaiting_pong: bool,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl FromStr for WsService {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "echo" => Self::Echo,
+            "ping" => Self::Ping,
+            _ => return Err(format!("unknown websocket service {s}")),
+        })
+    }
+}
+
+
+impl WsService {
+    /// `count` (1 to `MAX_PING_COUNT`) and `interval` (seconds) of the
+    /// query are optional for ping. 400 if the query is invalid, 503 if
+    /// there are too many ping streams.
+    fn task(&self, req: &Req) -> Result<WsTask, Box<Resp>> {
+        let q = QString::from(req.uri.query().unwrap_or_default());
+
+        Ok(match self {
+            Self::Echo => WsTask::Echo,
+            Self::Ping => {
+                let host = q.get("host").ok_or_else(|| {
+                    Box::new(Resp::_400("no host in the query".into()))
+                })?;
+
+                let count = match q.get("count") {
+                    Some(count) => count
+                        .parse()
+                        .ok()
+                        .filter(|n| (1..=MAX_PING_COUNT).contains(n))
+                        .ok_or_else(|| {
+                            Box::new(Resp::_400(format!(
+                                "count {count} (1 to {MAX_PING_COUNT})"
+                            )))
+                        })?,
+                    None => MAX_PING_COUNT,
+                };
+                let interval = match q.get("interval") {
+                    Some(secs) => secs
+                        .parse()
+                        .ok()
+                        .and_then(|secs| {
+                            Duration::try_from_secs_f64(secs).ok()
+                        })
+                        .ok_or_else(|| {
+                            Box::new(Resp::_400(format!("interval {secs}")))
+                        })?,
+                    None => Duration::from_secs(1),
+                };
+
+                let dst = HostOrIP::from_str(host)
+                    .and_then(|host| host.resolve(None))
+                    .map_err(|err| {
+                        Box::new(Resp::_400(format!("{host}: {err}")))
+                    })?;
+
+                let slot = PingSlot::acquire().ok_or_else(|| {
+                    let msg = format!("{MAX_PING_STREAMS} ping streams");
+                    Box::new(Resp::_503(msg))
+                })?;
+
+                let conf = PingConf {
+                    count: Some(count),
+                    interval: interval.max(MIN_PING_INTERVAL),
+                    ..Default::default()
+                };
+
+                WsTask::Ping { dst, conf, slot }
+            }
+        })
+    }
+}
+
+
+impl PingSlot {
+    fn acquire() -> Option<Self> {
+        PING_STREAMS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_PING_STREAMS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(()))
+    }
+}
+
+
+impl Drop for PingSlot {
+    fn drop(&mut self) {
+        PING_STREAMS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+
+impl WsConn {
+    /// Streaming task is started with `send` to push its messages
+    pub fn start<F>(upgrade: WsUpgrade, send: F) -> Self
+    where
+        F: Fn(Message) + Send + 'static,
+    {
+        let WsUpgrade { codec, task } = upgrade;
+        let stop = Arc::new(AtomicBool::new(false));
+        let echo = matches!(task, WsTask::Echo);
+
+        if let WsTask::Ping { dst, conf, slot } = task {
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                stream_ping(dst, conf, &stop, send);
+                drop(slot);
+            });
+        }
+
+        Self {
+            codec,
+            echo,
+            stop,
+            close_sent: false,
+            awaiting_pong: false,
+        }
+    }
+
+    /// Frames to send back for the input, and whether to close the
+    /// connection after them
+    pub fn on_input(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = vec![];
+        let mut input = input;
+
+        loop {
+            let msg = match self.codec.feed(input) {
+                Ok(ParseStatus::Complete(msg)) => msg,
+                Ok(ParseStatus::Partial) => return (out, false),
+                Err(kind) => {
+                    warn!("websocket: {kind:?}");
+
+                    if !self.close_sent {
+                        let close = CloseFrame::new(close_code(&kind), "");
+                        out.extend(self.encode(Message::Close(Some(close))));
+                    }
+                    return (out, true);
+                }
+            };
+            input = &[];
+            self.awaiting_pong = false;
+
+            match msg {
+                Message::Ping(payload) => {
+                    out.extend(self.encode(Message::Pong(payload)))
+                }
+                Message::Pong(_) => (),
+                // the server closes the TCP connection first
+                Message::Close(close) => {
+                    info!("websocket closed by peer: {close:?}");
+
+                    let code = close.map(|close| close.code);
+                    let echo = code.map(|code| CloseFrame::new(code, ""));
+                    out.extend(self.encode(Message::Close(echo)));
+
+                    return (out, true);
+                }
+                data => {
+                    if self.echo {
+                        out.extend(self.encode(data));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Frames of the message from the streaming task
+    pub fn on_message(&mut self, msg: Message) -> Vec<u8> {
+        self.encode(msg)
+    }
+
+    /// Ping on the first idle timeout, close on the next one without any
+    /// message. `None` to close.
+    pub fn on_idle(&mut self) -> Option<Vec<u8>> {
+        if self.awaiting_pong || self.close_sent {
+            return None;
+        }
+        self.awaiting_pong = true;
+
+        Some(self.encode(Message::Ping(vec![])))
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Nothing is sent after the close frame
+    fn encode(&mut self, msg: Message) -> Vec<u8> {
+        if self.close_sent {
+            return vec![];
+        }
+        if let Message::Close(_) = msg {
+            self.close_sent = true;
+            self.stop();
+        }
+
+        self.codec.encode(&msg)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// 101 response carrying the upgrade, 426 if it isn't a WebSocket
+/// request of version 13, 400 if the handshake or the query is invalid,
+/// 503 if the service is busy
+pub fn upgrade(req: &Req, service: WsService, max_message: usize) -> Resp {
+    if !is_upgrade(&req.headers) {
+        return Resp::_426(String::new())
+            .with_header(UPGRADE, HeaderValue::from_static("websocket"));
+    }
+
+    let handshake =
+        match Handshake::from_req(&req.method, req.version, &req.headers) {
+            Ok(handshake) => handshake,
+            Err(WsKind::UnsupportedVersion(version)) => {
+                return Resp::_426(format!("version {version}")).with_header(
+                    SEC_WEBSOCKET_VERSION,
+                    HeaderValue::from_static(WS_VERSION),
+                );
+            }
+            Err(kind) => return Resp::_400(format!("{kind:?}")),
+        };
+
+    let task = match service.task(req) {
+        Ok(task) => task,
+        Err(resp) => return *resp,
+    };
+
+    let mut codec = WsCodec::new(WsRole::Server).with_max_message(max_message);
+    if let Some(deflate) = handshake.deflate {
+        codec = codec.with_deflate(deflate);
+    }
+
+    info!(
+        "websocket upgrade: {task:?}, deflate: {:?}",
+        handshake.deflate
+    );
+
+    Resp::_101(handshake.resp_headers(None), WsUpgrade { codec, task })
+}
+
+
+/// One text message for each event and the statistics at the end
+fn stream_ping<F: Fn(Message)>(
+    dst: IpAddr,
+    conf: PingConf,
+    stop: &AtomicBool,
+    send: F,
+) {
+    let res = unsafe {
+        PingSession::new(dst, conf).and_then(|mut session| {
+            send(Message::Text(format!("PING {dst}")));
+
+            session
+                .run(stop, |event| send(Message::Text(event.to_string())))
+                .map(|stats| stats.to_string())
+        })
+    };
+
+    let close = match res {
+        Ok(stats) => {
+            send(Message::Text(stats));
+            CloseFrame::new(CLOSE_NORMAL, "done")
+        }
+        Err(err) => {
+            warn!("ping {dst}: {err}");
+            CloseFrame::new(CLOSE_INTERNAL_ERROR, &err.to_string())
+        }
+    };
+
+    if !stop.load(Ordering::Relaxed) {
+        send(Message::Close(Some(close)));
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderName, Method, StatusCode, Version};
+    use netlib::application::http::{
+        body::ReqBody,
+        parser::{ParseStatus, ReqHead},
+        ws::{CloseFrame, Message, WsCodec, WsRole, CLOSE_NORMAL},
+    };
+
+    use super::{upgrade, WsConn, WsService, MAX_PING_COUNT};
+    use crate::{
+        req::Req,
+        resp::{Chunk, Resp},
+    };
+
+    fn req(uri: &str, headers: &[(&str, &str)]) -> Req {
+        let mut map = HeaderMap::new();
+        for (name, val) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                val.parse().unwrap(),
+            );
+        }
+
+        let head = ReqHead {
+            method: Method::GET,
+            uri: uri.parse().unwrap(),
+            version: Version::HTTP_11,
+            headers: map,
+        };
+
+        Req::from_head(
+            head,
+            ReqBody::default(),
+            "127.0.0.1:1".parse().unwrap(),
+        )
+    }
+
+    /// Status line and headers
+    fn head(resp: Resp) -> String {
+        let (chunks, _) = resp.into_chunks(None, u64::MAX);
+
+        chunks
+            .into_iter()
+            .map(|chunk| match chunk {
+                Chunk::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+                chunk => panic!("{chunk:?}"),
+            })
+            .collect()
+    }
+
+    /// Messages of the frames from the server
+    fn decode(client: &mut WsCodec, mut out: &[u8]) -> Vec<Message> {
+        let mut msgs = vec![];
+
+        while let ParseStatus::Complete(msg) = client.feed(out).unwrap() {
+            msgs.push(msg);
+            out = &[];
+        }
+
+        msgs
+    }
+
+    #[test]
+    fn test_ws_upgrade() {
+        let handshake = [
+            ("upgrade", "websocket"),
+            ("connection", "keep-alive, Upgrade"),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ];
+
+        let resp = upgrade(&req("/ws", &[]), WsService::Echo, 1024);
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert!(head(resp).contains("upgrade: websocket\r\n"));
+
+        let mut headers = handshake;
+        headers[2].1 = "8";
+        let resp = upgrade(&req("/ws", &headers), WsService::Echo, 1024);
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert!(head(resp).contains("sec-websocket-version: 13\r\n"));
+
+        let mut headers = handshake;
+        headers[3].1 = "short";
+        let resp = upgrade(&req("/ws", &headers), WsService::Echo, 1024);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        for uri in [
+            "/ws".to_owned(),
+            format!("/ws?host=127.0.0.1&count={}", MAX_PING_COUNT + 1),
+            "/ws?host=127.0.0.1&count=0".to_owned(),
+            "/ws?host=127.0.0.1&interval=-1".to_owned(),
+        ] {
+            let resp = upgrade(&req(&uri, &handshake), WsService::Ping, 1024);
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+
+        let mut resp = upgrade(&req("/ws", &handshake), WsService::Echo, 1024);
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert!(resp.take_upgrade().is_some());
+        assert!(head(resp).contains(
+            "sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_ws_conn() {
+        let handshake = [
+            ("upgrade", "websocket"),
+            ("connection", "Upgrade"),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ];
+        let mut resp = upgrade(&req("/ws", &handshake), WsService::Echo, 1024);
+        let mut conn = WsConn::start(resp.take_upgrade().unwrap(), |_| ());
+        let mut client = WsCodec::new(WsRole::Client);
+
+        /* Echo and pong */
+
+        let mut input = client.encode(&Message::Text("hi".to_owned()));
+        input.extend(client.encode(&Message::Ping(b"p".to_vec())));
+        input.extend(client.encode(&Message::Binary(vec![1, 2])));
+
+        let (out, done) = conn.on_input(&input);
+        assert!(!done);
+        assert_eq!(
+            decode(&mut client, &out),
+            [
+                Message::Text("hi".to_owned()),
+                Message::Pong(b"p".to_vec()),
+                Message::Binary(vec![1, 2])
+            ]
+        );
+
+        // partial frame
+        let input = client.encode(&Message::Text("hello".to_owned()));
+        let (out, done) = conn.on_input(&input[..3]);
+        assert!(out.is_empty() && !done);
+        let (out, _) = conn.on_input(&input[3..]);
+        assert_eq!(
+            decode(&mut client, &out),
+            [Message::Text("hello".to_owned())]
+        );
+
+        /* Ping on idle, then close */
+
+        let out = conn.on_idle().unwrap();
+        assert_eq!(decode(&mut client, &out), [Message::Ping(vec![])]);
+        assert!(conn.on_idle().is_none());
+
+        // any message resets it
+        let (out, _) = conn.on_input(&client.encode(&Message::Pong(vec![])));
+        assert!(out.is_empty());
+        assert!(conn.on_idle().is_some());
+
+        /* Close is echoed */
+
+        let close = CloseFrame::new(CLOSE_NORMAL, "bye");
+        let input = client.encode(&Message::Close(Some(close)));
+        let (out, done) = conn.on_input(&input);
+        assert!(done);
+        assert_eq!(
+            decode(&mut client, &out),
+            [Message::Close(Some(CloseFrame::new(CLOSE_NORMAL, "")))]
+        );
+
+        // nothing after the close
+        assert!(conn.on_message(Message::Text("late".to_owned())).is_empty());
+        assert!(conn.on_idle().is_none());
+    }
+}
@@ -2,14 +2,18 @@ use std::{
     fmt::Debug,
     mem::{size_of, zeroed},
     net::IpAddr,
+    time::Duration,
 };
 
 use libc::{
-    bind, memcpy, read, sendto, sleep, socket, ETH_FRAME_LEN, IFNAMSIZ, AF_PACKET, SOCK_RAW,
+    bind, memcpy, read, sendto, sleep, socket, ETH_DATA_LEN, ETH_FRAME_LEN, IFNAMSIZ, AF_PACKET, SOCK_RAW,
+    packet_mreq, poll, pollfd, setsockopt, PACKET_ADD_MEMBERSHIP,
+    PACKET_DROP_MEMBERSHIP, PACKET_MR_MULTICAST, POLLIN, SOL_PACKET,
 };
 use log::{debug, info};
 use netlib::{
-    data::{getgateway, getifaddrs, FixStr, InAddrN, Subnet, SockAddrLL, getifnth, getifmac},
+    application::dhcp::client::Lease,
+    data::{getgateway, getifaddrs, FixStr, InAddrN, Subnet, SockAddrLL, getifnth, getifmac, getifmtu},
     datalink::{Eth, EthTypeE, EthTypeN, Mac, PacType},
     defraw1,
     rs_error::{NetErr, Result},
@@ -19,6 +23,7 @@ use netlib::{
 
 use crate::{
     arp::{arp_input, arp_req, ARPLIVE, ARPTAB},
+    igmp::igmp_accepts,
     ip::{ip_input, IPHLEN},
     skbuff::SKBuff,
 };
@@ -44,6 +49,7 @@ defraw1! {
         hwa_len: u8,
         hwa: Mac,
         hwa_broadcast: Mac,
+        /// IP MTU of the interface
         mtu: u16,
         /// Sock descriptor
         sd: i32,
@@ -71,7 +77,7 @@ impl NetDevice {
 
         let sockaddr = SockAddrLL {
             family: AF_PACKET as u16,
-            proto: EthTypeE::ARP.net(),
+            proto: EthTypeE::PAll.net(),
             ifindex: ifnth,
             hatype: ARPHTE::Ethernet10Mb.net(),
             pkttype: PacType::Host,
@@ -108,7 +114,7 @@ impl NetDevice {
 
         /* bind gateway */
         let gateway = getgateway()?;
-        let ip_gateway = match gateway.ip_addr {
+        let ip_gateway = match gateway {
             IpAddr::V4(ipv4) => ipv4,
             IpAddr::V6(ipv6) => {
                 return Err(NetErr::AnyWay(format!("{ipv6:?}")))
@@ -116,16 +122,70 @@ impl NetDevice {
         };
         dev.ip_gateway = InAddrN::from_ipv4addr(ip_gateway);
 
-        dev.mtu = ETH_FRAME_LEN as u16;
+        dev.mtu = getifmtu(ifname)
+            .map(|mtu| mtu.min(u16::MAX as u32) as u16)
+            .unwrap_or(ETH_DATA_LEN as u16);
         dev.type_ = EthTypeE::P8023.net();
 
         Ok(dev)
     }
 
+    /// Take address, netmask, broadcast and gateway from DHCP lease
+    pub fn apply_lease(&mut self, lease: &Lease) {
+        self.ip_host = InAddrN::from_ipv4addr(lease.addr);
+        self.ip_netmask = InAddrN::from_ipv4addr(lease.netmask);
+        self.ip_broadcast = InAddrN::from_ipv4addr(lease.broadcast());
+
+        if let Some(gateway) = lease.gateway() {
+            self.ip_gateway = InAddrN::from_ipv4addr(gateway);
+        }
+    }
+
     pub unsafe fn input(&self) -> Result<()> {
         input(self)
     }
 
+    /// Wait for incoming frame, false if timeout
+    pub unsafe fn poll(&self, timeout: Duration) -> Result<bool> {
+        let mut pfd = pollfd {
+            fd: self.sd,
+            events: POLLIN,
+            revents: 0,
+        };
+
+        let n = throw_errno!(
+            poll(&mut pfd, 1, timeout.as_millis() as i32) throws Poll
+        );
+
+        Ok(n > 0)
+    }
+
+    /// Receive the frames to multicast `mac` (or stop it)
+    pub unsafe fn set_multicast(&self, mac: Mac, on: bool) -> Result<()> {
+        let mut mreq: packet_mreq = zeroed();
+        mreq.mr_ifindex = self.to.ifindex;
+        mreq.mr_type = PACKET_MR_MULTICAST as u16;
+        mreq.mr_alen = size_of::<Mac>() as u16;
+        mreq.mr_address[..8].copy_from_slice(&mac.into_arr8());
+
+        let opt = if on {
+            PACKET_ADD_MEMBERSHIP
+        }
+        else {
+            PACKET_DROP_MEMBERSHIP
+        };
+
+        throw_errno!(setsockopt(
+            self.sd,
+            SOL_PACKET,
+            opt,
+            &mreq as *const packet_mreq as *const _,
+            size_of::<packet_mreq>() as u32
+        ) throws SetSockOpt);
+
+        Ok(())
+    }
+
     pub unsafe fn output(&self, skbuff: &SKBuff) -> Result<()> {
         output(self, skbuff)
     }
@@ -184,18 +244,18 @@ pub unsafe fn input(dev: &NetDevice) -> Result<()> {
     if [dev.hwa, dev.hwa_broadcast]
         .into_iter()
         .any(|x| x == ethh.dst)
+        || ethh.dst.is_multicast() && igmp_accepts(ethh.dst)
     {
         match ethh.proto.native()? {
             EthTypeE::IPv4 => {
                 skb.nh.iph = skb.forward(IPHLEN) as *mut _;
+                // behind 14 bytes Ethernet header
+                let iph = skb.nh.iph.read_unaligned();
 
                 ARPTAB.with_borrow_mut(|tab| {
-                    tab.insert((*skb.nh.iph).ip_src, ethh.src, ARPLIVE as i64)
+                    tab.insert(iph.ip_src, ethh.src, ARPLIVE as i64)
                 });
-                debug!(
-                    "Incomming Network IPv4 handled {:?}",
-                    (*skb.nh.iph).ip_dst
-                );
+                debug!("Incomming Network IPv4 handled {:?}", iph.ip_dst);
                 ip_input(dev, skb)?;
             }
             EthTypeE::ARP => {
@@ -253,7 +313,18 @@ pub unsafe fn linkoutput(dev: &NetDevice, skbuff: &SKBuff) -> Result<()> {
 
 /// 从网卡输出数据
 pub unsafe fn output(dev: &NetDevice, skbuff: &SKBuff) -> Result<()> {
-    let mut dst_ip = (*skbuff.nh.iph).ip_dst;
+    let mut dst_ip = skbuff.nh.iph.read_unaligned().ip_dst;
+
+    // no ARP for multicast
+    if let Some(dst_mac) = Mac::from_ipv4_multicast(dst_ip.ipv4()) {
+        let ethh = &mut (*skbuff.phy.ethh);
+
+        ethh.dst = dst_mac;
+        ethh.src = dev.hwa;
+        ethh.proto = EthTypeE::IPv4.net();
+
+        return dev.linkoutput(skbuff);
+    }
 
     // is same subnet
     if dst_ip.subnet(&dev.ip_netmask) == dev.ip_host.subnet(&dev.ip_netmask) {
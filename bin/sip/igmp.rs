@@ -0,0 +1,168 @@
+use std::{cell::RefCell, net::Ipv4Addr, slice, time::Instant};
+
+use libc::memcpy;
+use log::{debug, info};
+use netlib::{
+    data::InAddrN,
+    datalink::{EthTypeE, Mac},
+    network::{
+        igmp::{IgmpHost, IgmpMsg, IGMP_ALL_HOSTS, IP_ROUTER_ALERT},
+        inet_cksum,
+        ip::{FragFlag, FragOff, Protocol, ToS, HLV, IP, PL},
+    },
+    view::U16N,
+    Result,
+};
+
+use crate::{
+    eth::{NetDevice, ETH_HLEN},
+    ip::IPHLEN,
+    skbuff::SKBuff,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// ThreadLocal
+
+thread_local! {
+    pub static IGMPHOST: RefCell<IgmpHost> = RefCell::new(IgmpHost::new());
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Whether the frame to `mac` belongs to a joined group
+pub fn igmp_accepts(mac: Mac) -> bool {
+    if Mac::from_ipv4_multicast(IGMP_ALL_HOSTS) == Some(mac) {
+        return true;
+    }
+
+    IGMPHOST.with_borrow(|host| {
+        host.groups()
+            .any(|group| Mac::from_ipv4_multicast(*group) == Some(mac))
+    })
+}
+
+
+pub fn igmp_is_member(group: Ipv4Addr) -> bool {
+    group == IGMP_ALL_HOSTS
+        || IGMPHOST.with_borrow(|host| host.is_member(group))
+}
+
+
+pub unsafe fn igmp_join(dev: &NetDevice, group: Ipv4Addr) -> Result<()> {
+    info!("join {group}");
+
+    if let Some(mac) = Mac::from_ipv4_multicast(group) {
+        dev.set_multicast(mac, true)?;
+    }
+
+    let msgs =
+        IGMPHOST.with_borrow_mut(|host| host.join(group, Instant::now()));
+
+    for msg in msgs.iter() {
+        igmp_output(dev, msg)?;
+    }
+
+    Ok(())
+}
+
+
+pub unsafe fn igmp_leave(dev: &NetDevice, group: Ipv4Addr) -> Result<()> {
+    info!("leave {group}");
+
+    let msgs =
+        IGMPHOST.with_borrow_mut(|host| host.leave(group, Instant::now()));
+
+    for msg in msgs.iter() {
+        igmp_output(dev, msg)?;
+    }
+
+    if let Some(mac) = Mac::from_ipv4_multicast(group) {
+        dev.set_multicast(mac, false)?;
+    }
+
+    Ok(())
+}
+
+
+/// Send the due reports
+pub unsafe fn igmp_timer(dev: &NetDevice) -> Result<()> {
+    let msgs = IGMPHOST.with_borrow_mut(|host| host.poll(Instant::now()));
+
+    for msg in msgs.iter() {
+        igmp_output(dev, msg)?;
+    }
+
+    Ok(())
+}
+
+
+pub unsafe fn igmp_input(_dev: &NetDevice, skb: SKBuff) -> Result<()> {
+    let iph = skb.nh.iph.read_unaligned();
+    let iphlen = iph.ihl_v.get_hdrsize();
+    let plen = iph.len.native() as usize;
+
+    let payload = slice::from_raw_parts(
+        skb.nh.raw.add(iphlen) as *const u8,
+        plen.saturating_sub(iphlen),
+    );
+    let msg = IgmpMsg::parse(payload)?;
+
+    debug!("Incomming IGMP {msg:?} from {:?}", iph.ip_src);
+
+    IGMPHOST.with_borrow_mut(|host| host.handle(&msg, Instant::now()));
+
+    Ok(())
+}
+
+
+/// IGMP goes straight to the link, with TTL 1 and Router Alert option
+pub unsafe fn igmp_output(dev: &NetDevice, msg: &IgmpMsg) -> Result<()> {
+    let payload = msg.to_bytes();
+    let dst = msg.dst();
+    let iphlen = IPHLEN + IP_ROUTER_ALERT.len();
+    let iplen = iphlen + payload.len();
+
+    let mut skb = SKBuff::with_capcity(ETH_HLEN + iplen);
+    skb.phy.raw = skb.forward(ETH_HLEN);
+    skb.nh.raw = skb.forward(iphlen);
+    skb.th.raw = skb.forward(payload.len());
+    skb.dev = dev;
+
+    let ethh = &mut *skb.phy.ethh;
+    ethh.dst = Mac::from_ipv4_multicast(dst).unwrap();
+    ethh.src = dev.hwa;
+    ethh.proto = EthTypeE::IPv4.net();
+
+    let mut iph = IP {
+        ihl_v: HLV::new((iphlen / 4) as u8, 4),
+        tos: ToS::default(),
+        len: PL::from_native(iplen as u16),
+        id: U16N::default(),
+        frag_off: FragOff::new(FragFlag::DF, 0),
+        ttl: 1,
+        protocol: Protocol::IGMP,
+        checksum: 0,
+        ip_src: dev.ip_host,
+        ip_dst: InAddrN::from_ipv4addr(dst),
+    };
+    // behind 14 bytes Ethernet header
+    skb.nh.iph.write_unaligned(iph);
+    memcpy(
+        skb.nh.raw.add(IPHLEN) as _,
+        IP_ROUTER_ALERT.as_ptr() as _,
+        IP_ROUTER_ALERT.len(),
+    );
+    iph.checksum = inet_cksum(skb.nh.raw, iphlen);
+    skb.nh.iph.write_unaligned(iph);
+
+    memcpy(skb.th.raw as _, payload.as_ptr() as _, payload.len());
+
+    skb.curproto_len = skb.total_len;
+
+    debug!("Outgoing IGMP {msg:?} to {dst}");
+
+    dev.linkoutput(&skb)
+}
@@ -1,6 +1,8 @@
 use std::{
+    cell::RefCell,
     mem::size_of,
     ptr::null_mut,
+    time::Instant,
 };
 
 use libc::memcpy;
@@ -8,12 +10,16 @@ use netlib::{
     aux::htons,
     data::InAddrN,
     rs_error::NetErr,
-    network::ip::{FragFlag, FragOff, Protocol, IP},
+    network::{
+        ip::{FragFlag, FragOff, Protocol, IP},
+        pmtu::PmtuCache,
+    },
     Result,
 };
 
 use crate::{
     eth::{NetDevice, ETH_HLEN},
+    igmp::{igmp_input, igmp_is_member},
     skbuff::SKBuff,
     push_skbuff
 };
@@ -25,6 +31,15 @@ use crate::{
 
 pub const IPHLEN: usize = size_of::<IP>();
 
+////////////////////////////////////////////////////////////////////////////////
+//// ThreadLocal
+
+thread_local! {
+    /// Learned from ICMP, bounded by the MTU of device
+    pub static PMTU: RefCell<PmtuCache> =
+        RefCell::new(PmtuCache::new(u16::MAX));
+}
+
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Structure
@@ -94,7 +109,7 @@ pub unsafe fn cksum(datap: *mut u8, len: u16) -> u16 {
 
 
 unsafe fn validate_ip(dev: &NetDevice, skb: &mut SKBuff) -> Result<()> {
-    let iph = &*skb.nh.iph;
+    let iph = skb.nh.iph.read_unaligned();
 
     if iph.ihl_v.get_version() != 4 {
         return Err(NetErr::AnyWay(format!(
@@ -113,7 +128,7 @@ unsafe fn validate_ip(dev: &NetDevice, skb: &mut SKBuff) -> Result<()> {
         return Err(NetErr::AnyWay(format!("Invalid IP package total len",)));
     }
 
-    if iphlen < iph.len.native() as usize {
+    if iphlen > iph.len.native() as usize {
         return Err(NetErr::AnyWay(format!(
             "IP header smaller than package len"
         )));
@@ -130,6 +145,14 @@ unsafe fn validate_ip(dev: &NetDevice, skb: &mut SKBuff) -> Result<()> {
         return Err(NetErr::AnyWay(format!("No local and no broadcast")));
     }
 
+    if iph.ip_dst.ipv4().is_multicast() && !igmp_is_member(iph.ip_dst.ipv4())
+    {
+        return Err(NetErr::AnyWay(format!(
+            "Not a member of {:?}",
+            iph.ip_dst.ipv4()
+        )));
+    }
+
     Ok(())
 }
 
@@ -137,7 +160,7 @@ unsafe fn validate_ip(dev: &NetDevice, skb: &mut SKBuff) -> Result<()> {
 pub unsafe fn ip_input(dev: &NetDevice, mut skb: SKBuff) -> Result<()> {
     validate_ip(dev, &mut skb)?;
 
-    let iph = &*skb.nh.iph;
+    let iph = skb.nh.iph.read_unaligned();
 
     if iph.frag_off.get_frag_off_size() > 0 {
         // Reassemable
@@ -145,10 +168,20 @@ pub unsafe fn ip_input(dev: &NetDevice, mut skb: SKBuff) -> Result<()> {
 
     match iph.protocol {
         Protocol::ICMP => {
-            todo!()
+            let pkt = std::slice::from_raw_parts(
+                skb.nh.raw as *const u8,
+                iph.len.native() as usize,
+            );
+
+            PMTU.with_borrow_mut(|pmtu| {
+                pmtu.handle_icmp(pkt, Instant::now())
+            });
+        }
+        Protocol::IGMP => {
+            igmp_input(dev, skb)?;
         }
         Protocol::UDP => {}
-        _ => todo!(),
+        _ => (),
     }
 
     Ok(())
@@ -169,18 +202,28 @@ pub unsafe fn ip_output(
     cksum(skb.nh.raw, size_of::<IP>() as u16);
     skb.curproto_len = skb.total_len;
 
-    if skb.curproto_len > dev.mtu as u32 {
+    let mtu = PMTU.with_borrow_mut(|pmtu| {
+        pmtu.get(dst.ipv4().into(), Instant::now()).min(dev.mtu)
+    });
+
+    if skb.curproto_len - ETH_HLEN as u32 > mtu as u32 {
+        if iph.frag_off.get_frag_flag() == FragFlag::DF {
+            return Err(NetErr::AnyWay(format!(
+                "Fragmentation needed for {:?}, pmtu {mtu}",
+                dst.ipv4()
+            )));
+        }
+
         /* fragmentation */
-        skb = ip_frag(dev, skb);
+        skb = ip_frag(skb, ETH_HLEN + mtu as usize);
     }
 
     dev.output(&skb)
 }
 
 
-/// Do IP package fragmentation
-unsafe fn ip_frag(dev: &NetDevice, mut skb: SKBuff) -> SKBuff {
-    let mtu = dev.mtu as usize;
+/// Do IP package fragmentation, `mtu` is frame length
+unsafe fn ip_frag(mut skb: SKBuff, mtu: usize) -> SKBuff {
     // let half_mtu = ((dev.mtu + 1) / 2) as usize;
     let plen = (*skb.nh.iph).len.native() as usize;
 
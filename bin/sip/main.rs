@@ -4,15 +4,29 @@ mod skbuff;
 mod eth;
 mod arp;
 mod ip;
+mod igmp;
 mod udp;
 
 
-use std::env;
+use std::{
+    env,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use clap::Parser;
 use log::info;
 use eth::NetDevice;
-use netlib::rs_error::{LoggerKind, NetErr, Result};
+use igmp::{igmp_join, igmp_leave, igmp_timer};
+use signal_hook::{consts::SIGINT, flag::register};
+use netlib::{
+    application::dhcp::client::obtain_lease,
+    rs_error::{LoggerKind, NetErr, Result},
+};
 
 
 /// Simple UDP/IP Network Protocol Stack
@@ -22,6 +36,14 @@ struct Cli {
     /// If name
     #[clap()]
     r#if: String,
+
+    /// Configure the device by DHCP instead of the host if address
+    #[clap(long)]
+    dhcp: bool,
+
+    /// Join the multicast group (repeatable)
+    #[clap(long)]
+    join: Vec<Ipv4Addr>,
 }
 
 fn setup_logger() -> Result<()> {
@@ -64,14 +86,49 @@ fn main() {
     setup_logger().unwrap();
 
     unsafe {
-        let dev = NetDevice::init(cli.r#if.as_str()).unwrap();
+        let mut dev = NetDevice::init(cli.r#if.as_str()).unwrap();
         info!("dev init: {:#?}", dev);
 
-        loop {
-            match dev.input() {
-                Ok(_) => (),
+        if cli.dhcp {
+            let lease = obtain_lease(
+                Some(cli.r#if.as_str()),
+                dev.hwa,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            info!("dhcp lease: {:#?}", lease);
+
+            dev.apply_lease(&lease);
+            info!("dev configured: {:#?}", dev);
+        }
+
+        for group in cli.join.iter() {
+            igmp_join(&dev, *group).unwrap();
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        register(SIGINT, stop.clone()).unwrap();
+
+        while !stop.load(Ordering::Relaxed) {
+            // wake up for IGMP timers
+            match dev.poll(Duration::from_millis(100)) {
+                Ok(true) => match dev.input() {
+                    Ok(_) => (),
+                    Err(err) => println!("{err:#?}"),
+                },
+                Ok(false) => (),
                 Err(err) => println!("{err:#?}"),
             }
+
+            if let Err(err) = igmp_timer(&dev) {
+                println!("{err:#?}");
+            }
+        }
+
+        for group in cli.join.iter() {
+            if let Err(err) = igmp_leave(&dev, *group) {
+                println!("{err:#?}");
+            }
         }
     }
 
@@ -0,0 +1,124 @@
+use std::error::Error;
+
+use clap::Parser;
+use netlib::{
+    aux::IpFamily,
+    data::{proc_sockets, sockets, SockInfo, SockProto},
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Cli
+
+/// Dump socket statistics
+#[derive(Parser)]
+#[clap()]
+struct Cli {
+    /// Display TCP sockets
+    #[clap(short = 't', long)]
+    tcp: bool,
+
+    /// Display UDP sockets
+    #[clap(short = 'u', long)]
+    udp: bool,
+
+    /// Display only listening sockets
+    #[clap(short = 'l', long, conflicts_with = "all")]
+    listening: bool,
+
+    /// Display both listening and non-listening sockets
+    #[clap(short = 'a', long)]
+    all: bool,
+
+    /// Only IPv4 sockets
+    #[clap(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only IPv6 sockets
+    #[clap(short = '6')]
+    ipv6: bool,
+
+    /// Show uid and inode
+    #[clap(short = 'e', long)]
+    extended: bool,
+
+    /// Read /proc/net instead of sock_diag netlink
+    #[clap(long)]
+    proc: bool,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+fn collect(cli: &Cli) -> Result<Vec<SockInfo>, Box<dyn Error>> {
+    let protos = match (cli.tcp, cli.udp) {
+        (true, false) => vec![SockProto::Tcp],
+        (false, true) => vec![SockProto::Udp],
+        _ => vec![SockProto::Tcp, SockProto::Udp],
+    };
+    let families = match (cli.ipv4, cli.ipv6) {
+        (true, _) => vec![IpFamily::V4],
+        (_, true) => vec![IpFamily::V6],
+        _ => vec![IpFamily::V4, IpFamily::V6],
+    };
+
+    let mut socks = vec![];
+
+    for proto in protos.iter() {
+        for family in families.iter() {
+            let res = if cli.proc {
+                proc_sockets(*proto, *family)
+            }
+            else {
+                // fall back if sock_diag isn't there
+                unsafe { sockets(*proto, *family) }
+                    .or_else(|_| proc_sockets(*proto, *family))
+            };
+
+            socks.extend(res?);
+        }
+    }
+
+    socks.retain(|sock| cli.all || sock.is_listening() == cli.listening);
+
+    Ok(socks)
+}
+
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let socks = collect(&cli)?;
+
+    print!(
+        "{:<6} {:<12} {:>6} {:>6} {:<45} {:<45}",
+        "Netid",
+        "State",
+        "Recv-Q",
+        "Send-Q",
+        "Local Address:Port",
+        "Peer Address:Port"
+    );
+    if cli.extended {
+        print!(" {:>6} {:>10}", "uid", "inode");
+    }
+    println!();
+
+    for sock in socks.iter() {
+        print!(
+            "{:<6} {:<12} {:>6} {:>6} {:<45} {:<45}",
+            sock.proto.to_string(),
+            sock.state.name(sock.proto),
+            sock.recv_q,
+            sock.send_q,
+            sock.local.to_string(),
+            sock.peer.to_string()
+        );
+        if cli.extended {
+            print!(" {:>6} {:>10}", sock.uid, sock.inode);
+        }
+        println!();
+    }
+
+    Ok(())
+}
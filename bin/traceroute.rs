@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::{atomic::AtomicBool, mpsc::channel, Arc},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use netlib::{
+    application::dns::resolver::Resolver,
+    aux::{HostOrIP, IpFamily},
+    network::traceroute::{Hop, ProbeMethod, TraceConf, Tracer},
+    rs_error::Result,
+};
+use signal_hook::{consts::SIGINT, flag::register};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Cli
+
+/// Print the route packets trace to network host
+#[derive(Parser)]
+#[clap()]
+struct Cli {
+    /// Use ICMP echo for probes
+    #[clap(short = 'I', conflicts_with = "tcp")]
+    icmp: bool,
+
+    /// Use TCP SYN for probes
+    #[clap(short = 'T')]
+    tcp: bool,
+
+    /// Paris traceroute, keep the flow identifier of probes constant
+    #[clap(short = 'P', long)]
+    paris: bool,
+
+    /// Start from the first_ttl hop
+    #[clap(short = 'f', default_value = "1")]
+    first_ttl: u8,
+
+    /// Max number of hops
+    #[clap(short = 'm', default_value = "30")]
+    max_ttl: u8,
+
+    /// Number of probes per hop
+    #[clap(short = 'q', default_value = "3")]
+    nqueries: usize,
+
+    /// Number of probes on the way simultaneously
+    #[clap(short = 'N', default_value = "16")]
+    sim_queries: usize,
+
+    /// Seconds to wait for a response
+    #[clap(short = 'w', default_value = "5")]
+    wait: f64,
+
+    /// Destination port (base port for UDP)
+    #[clap(short = 'p')]
+    port: Option<u16>,
+
+    /// Don't resolve hop addresses to names
+    #[clap(short = 'n')]
+    numeric: bool,
+
+    #[clap()]
+    host: String,
+
+    /// Total size of the probing packet
+    #[clap()]
+    packetlen: Option<usize>,
+}
+
+
+/// Reverse DNS with cache, falls back to the address
+struct Names {
+    resolver: Result<Resolver>,
+    cache: HashMap<Ipv4Addr, String>,
+}
+
+
+impl Names {
+    fn new() -> Self {
+        Self {
+            resolver: Resolver::system(),
+            cache: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, ip: Ipv4Addr) -> String {
+        let resolver = match self.resolver {
+            Ok(ref resolver) => resolver,
+            Err(_) => return ip.to_string(),
+        };
+
+        self.cache
+            .entry(ip)
+            .or_insert_with(|| {
+                resolver
+                    .reverse(IpAddr::V4(ip))
+                    .ok()
+                    .and_then(|names| names.into_iter().next())
+                    .map(|name| name.trim_end_matches('.').to_owned())
+                    .unwrap_or_else(|| ip.to_string())
+            })
+            .clone()
+    }
+}
+
+
+fn print_hop(hop: &Hop, names: &mut Names) {
+    let mut line = format!("{:2} ", hop.ttl);
+    let mut last = None;
+
+    for probe in hop.probes.iter() {
+        match probe {
+            Some(reply) => {
+                if last != Some(reply.from) {
+                    line += &format!(
+                        " {} ({})",
+                        names.get(reply.from),
+                        reply.from
+                    );
+                    last = Some(reply.from);
+                }
+                line +=
+                    &format!("  {:.3} ms", reply.rtt.as_secs_f64() * 1000.0);
+
+                if let Some(anno) = reply.kind.annotation() {
+                    line += &format!(" {anno}");
+                }
+            }
+            None => line += " *",
+        }
+    }
+
+    println!("{line}");
+}
+
+
+fn main() -> std::result::Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    let method = if cli.icmp {
+        ProbeMethod::Icmp
+    }
+    else if cli.tcp {
+        ProbeMethod::TcpSyn
+    }
+    else {
+        ProbeMethod::Udp
+    };
+
+    let mut conf = TraceConf {
+        method,
+        first_ttl: cli.first_ttl,
+        max_ttl: cli.max_ttl,
+        probes_per_hop: cli.nqueries,
+        port: cli.port,
+        wait: Duration::try_from_secs_f64(cli.wait)?,
+        parallel: cli.sim_queries,
+        paris: cli.paris,
+        ..Default::default()
+    };
+    if let Some(packetlen) = cli.packetlen {
+        conf.packet_len = packetlen;
+    }
+
+    let dst =
+        match HostOrIP::from_str(&cli.host)?.resolve(Some(IpFamily::V4))? {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => unreachable!(),
+        };
+
+    println!(
+        "traceroute to {} ({}), {} hops max, {} byte packets",
+        cli.host, dst, conf.max_ttl, conf.packet_len
+    );
+
+    let mut tracer = unsafe { Tracer::new(dst, conf)? };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    register(SIGINT, stop.clone())?;
+
+    // reverse DNS is slow, keep it away from the probing loop
+    let (tx, rx) = channel::<Hop>();
+    let numeric = cli.numeric;
+    let printer = thread::spawn(move || {
+        let mut names = Names::new();
+
+        for hop in rx {
+            if numeric {
+                println!("{hop}");
+            }
+            else {
+                print_hop(&hop, &mut names);
+            }
+        }
+    });
+
+    unsafe {
+        tracer.run(&stop, |hop| {
+            let _ = tx.send(hop.clone());
+        })?;
+    }
+
+    drop(tx);
+    let _ = printer.join();
+
+    Ok(())
+}
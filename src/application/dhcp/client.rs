@@ -0,0 +1,881 @@
+//! DHCP client state machine (rfc2131 4.4)
+//!
+//! The state machine itself does no IO, it's driven by `poll` (timer) and
+//! `handle` (incoming message), the caller carries the messages on any wire
+//! (UDP socket, raw packet socket, or in-process for test).
+//!
+//! ```none
+//!  INIT -> SELECTING -> REQUESTING -> BOUND -> RENEWING -> REBINDING
+//!   ^                       |          ^         |           |
+//!   +---------- NAK --------+          +-- ACK --+-----------+
+//!   +------------------ NAK / lease expired -----------------+
+//! ```
+
+use std::{
+    cmp::{max, min},
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use super::{
+    BootOp, DhcpMsg, DhcpMsgType, DhcpOpt, DHCP_CLIENT_PORT,
+    DHCP_FLAG_BROADCAST, DHCP_SERVER_PORT, HTYPE_ETHERNET,
+};
+use crate::{
+    aux::random_u32,
+    data::{setifaddr, InAddrN, Subnet},
+    datalink::Mac,
+    rs_error::{DhcpKind, NetErr},
+    s,
+    transport::udp::UdpSock,
+    view::U16N,
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// First retransmission timeout, doubled each time up to `RETRANS_MAX`
+pub const RETRANS_INIT: Duration = Duration::from_secs(4);
+pub const RETRANS_MAX: Duration = Duration::from_secs(64);
+
+/// Give up REQUESTING and restart from INIT after that many tries
+pub const REQUEST_MAX_TRIES: u32 = 4;
+
+/// Lower bound of retransmission interval in RENEWING and REBINDING
+pub const RENEW_RETRANS_MIN: Duration = Duration::from_secs(60);
+
+/// Subnet mask, router, DNS, lease time, T1, T2
+pub const PARAM_REQ_LIST: [u8; 6] = [1, 3, 6, 51, 58, 59];
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub server_id: Ipv4Addr,
+    pub lease_time: Duration,
+    /// Renewal time
+    pub t1: Duration,
+    /// Rebinding time
+    pub t2: Duration,
+    /// Time that the acknowledged REQUEST was sent, lease timers start from it
+    pub acquired: Instant,
+}
+
+
+/// Message to be sent to `dst` (server port)
+#[derive(Debug, Clone)]
+pub struct Transmit {
+    pub msg: DhcpMsg,
+    pub dst: Ipv4Addr,
+}
+
+
+#[derive(Debug, Clone)]
+pub enum ClientAction {
+    Send(Transmit),
+    /// A lease is obtained or extended
+    Bound(Lease),
+    /// Got NAK or lease expired, the address should be deconfigured
+    Lost,
+}
+
+
+#[derive(Debug)]
+pub struct DhcpClient {
+    mac: Mac,
+    client_id: Vec<u8>,
+    xid: u32,
+    state: ClientState,
+    /// Start of current acquisition, for `secs` field
+    start: Instant,
+    /// OFFER choosed in SELECTING
+    offer: Option<(Ipv4Addr, Ipv4Addr)>,
+    lease: Option<Lease>,
+    /// Send time of last REQUEST
+    last_req: Instant,
+    next_send: Instant,
+    retrans: Duration,
+    tries: u32,
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Lease {
+    fn from_ack(
+        ack: &DhcpMsg,
+        server_id: Ipv4Addr,
+        acquired: Instant,
+    ) -> Self {
+        let addr = ack.hdr.yiaddr.ipv4();
+        let lease_secs = ack.lease_time().unwrap_or(u32::MAX);
+
+        let lease_time = Duration::from_secs(lease_secs as u64);
+        let t1 = ack
+            .renewal_time()
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(lease_time / 2);
+        let t2 = ack
+            .rebinding_time()
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(lease_time * 7 / 8);
+
+        Self {
+            addr,
+            netmask: ack
+                .subnet_mask()
+                .unwrap_or_else(|| default_netmask(addr)),
+            routers: ack.routers().to_vec(),
+            dns: ack.dns().to_vec(),
+            server_id: ack.server_id().unwrap_or(server_id),
+            lease_time,
+            t1: min(t1, lease_time),
+            t2: min(max(t1, t2), lease_time),
+            acquired,
+        }
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        self.addr.broadcast(&self.netmask)
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.routers.first().copied()
+    }
+
+    /// Assign the leased address to a kernel interface (TAP, veth ...)
+    pub unsafe fn apply_to_if(&self, ifname: &str) -> Result<()> {
+        setifaddr(ifname, self.addr, self.netmask)
+    }
+}
+
+
+impl DhcpClient {
+    pub fn new(mac: Mac, now: Instant) -> Self {
+        let mut client_id = vec![HTYPE_ETHERNET];
+        client_id.extend(mac.0.iter().map(|octet| octet.0));
+
+        Self {
+            mac,
+            client_id,
+            xid: random_u32(),
+            state: ClientState::Init,
+            start: now,
+            offer: None,
+            lease: None,
+            last_req: now,
+            next_send: now,
+            retrans: RETRANS_INIT,
+            tries: 0,
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    pub fn xid(&self) -> u32 {
+        self.xid
+    }
+
+    /// Time until the next timer fired
+    pub fn next_timeout(&self, now: Instant) -> Duration {
+        let mut deadline = self.next_send;
+
+        if let Some(ref lease) = self.lease {
+            let timer = match self.state {
+                ClientState::Bound => lease.acquired + lease.t1,
+                ClientState::Renewing => lease.acquired + lease.t2,
+                _ => lease.acquired + lease.lease_time,
+            };
+            deadline = min(deadline, timer);
+        }
+
+        deadline.saturating_duration_since(now)
+    }
+
+    /// Fire timers, it should be called repeatedly until None
+    pub fn poll(&mut self, now: Instant) -> Option<ClientAction> {
+        match self.state {
+            ClientState::Init => {
+                self.xid = random_u32();
+                self.start = now;
+                self.offer = None;
+                self.retrans = RETRANS_INIT;
+                self.tries = 0;
+                self.state = ClientState::Selecting;
+
+                Some(ClientAction::Send(self.discover(now)))
+            }
+            ClientState::Selecting => {
+                if now < self.next_send {
+                    return None;
+                }
+
+                Some(ClientAction::Send(self.discover(now)))
+            }
+            ClientState::Requesting => {
+                if now < self.next_send {
+                    return None;
+                }
+
+                if self.tries >= REQUEST_MAX_TRIES {
+                    self.state = ClientState::Init;
+                    return self.poll(now);
+                }
+
+                let (server_id, addr) = self.offer.unwrap();
+                Some(ClientAction::Send(self.request(
+                    now,
+                    Ipv4Addr::BROADCAST,
+                    Some((server_id, addr)),
+                    Ipv4Addr::UNSPECIFIED,
+                )))
+            }
+            ClientState::Bound
+            | ClientState::Renewing
+            | ClientState::Rebinding => {
+                let lease = self.lease.clone().unwrap();
+                let elapsed = now.saturating_duration_since(lease.acquired);
+
+                if elapsed >= lease.lease_time {
+                    self.lease = None;
+                    self.state = ClientState::Init;
+                    return Some(ClientAction::Lost);
+                }
+
+                if self.state == ClientState::Bound && elapsed >= lease.t1 {
+                    self.state = ClientState::Renewing;
+                    self.next_send = now;
+                }
+
+                if self.state == ClientState::Renewing && elapsed >= lease.t2 {
+                    self.state = ClientState::Rebinding;
+                    self.next_send = now;
+                }
+
+                if self.state == ClientState::Bound || now < self.next_send {
+                    return None;
+                }
+
+                // wait one-half of the remaining time until T2 (RENEWING)
+                // or lease expiration (REBINDING), down to a minimum of 60s
+                let (dst, until) = if self.state == ClientState::Renewing {
+                    (lease.server_id, lease.t2)
+                }
+                else {
+                    (Ipv4Addr::BROADCAST, lease.lease_time)
+                };
+
+                let transmit = self.request(now, dst, None, lease.addr);
+                self.next_send =
+                    now + max((until - elapsed) / 2, RENEW_RETRANS_MIN);
+
+                Some(ClientAction::Send(transmit))
+            }
+        }
+    }
+
+    /// Handle a message from server
+    pub fn handle(
+        &mut self,
+        msg: &DhcpMsg,
+        now: Instant,
+    ) -> Result<Option<ClientAction>> {
+        if msg.hdr.op != BootOp::Reply as u8
+            || msg.hdr.get_xid() != self.xid
+            || msg.hdr.get_chaddr_mac() != self.mac
+        {
+            return Err(NetErr::Dhcp(DhcpKind::UnexpectedMsg(format!(
+                "xid: {:#x}, chaddr: {}",
+                msg.hdr.get_xid(),
+                msg.hdr.get_chaddr_mac()
+            ))));
+        }
+
+        let ty = msg.msg_type()?;
+
+        Ok(match (self.state, ty) {
+            (ClientState::Selecting, DhcpMsgType::Offer) => {
+                let server_id = msg.server_id().ok_or_else(|| {
+                    NetErr::Dhcp(DhcpKind::UnexpectedMsg(s!(
+                        "OFFER without server id"
+                    )))
+                })?;
+                let addr = msg.hdr.yiaddr.ipv4();
+
+                self.offer = Some((server_id, addr));
+                self.state = ClientState::Requesting;
+                self.retrans = RETRANS_INIT;
+                self.tries = 0;
+
+                Some(ClientAction::Send(self.request(
+                    now,
+                    Ipv4Addr::BROADCAST,
+                    Some((server_id, addr)),
+                    Ipv4Addr::UNSPECIFIED,
+                )))
+            }
+            // other servers may answer the broadcast REQUEST too
+            (
+                ClientState::Requesting | ClientState::Renewing,
+                DhcpMsgType::Ack | DhcpMsgType::Nak,
+            ) if msg.server_id() != self.requested_server() => None,
+            (
+                ClientState::Requesting
+                | ClientState::Renewing
+                | ClientState::Rebinding,
+                DhcpMsgType::Ack,
+            ) => {
+                let server_id = match (self.offer, &self.lease) {
+                    (Some((server_id, _)), _) => server_id,
+                    (None, Some(lease)) => lease.server_id,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                let lease = Lease::from_ack(msg, server_id, self.last_req);
+
+                self.lease = Some(lease.clone());
+                self.offer = None;
+                self.state = ClientState::Bound;
+                self.next_send = lease.acquired + lease.t1;
+
+                Some(ClientAction::Bound(lease))
+            }
+            (
+                ClientState::Requesting
+                | ClientState::Renewing
+                | ClientState::Rebinding,
+                DhcpMsgType::Nak,
+            ) => {
+                self.lease = None;
+                self.offer = None;
+                self.state = ClientState::Init;
+
+                Some(ClientAction::Lost)
+            }
+            // e.g. late OFFERs from other servers
+            _ => None,
+        })
+    }
+
+    /// Give up the lease (DHCPRELEASE)
+    pub fn release(&mut self, now: Instant) -> Option<Transmit> {
+        let lease = self.lease.take()?;
+        self.state = ClientState::Init;
+
+        let mut msg = self.msg(DhcpMsgType::Release, now);
+        msg.hdr.ciaddr = InAddrN::from_ipv4addr(lease.addr);
+        msg.opts.push(DhcpOpt::ServerId(lease.server_id));
+
+        Some(Transmit {
+            msg,
+            dst: lease.server_id,
+        })
+    }
+
+    /// Refuse the lease since the address is already in use (DHCPDECLINE)
+    pub fn decline(&mut self, now: Instant) -> Option<Transmit> {
+        let lease = self.lease.take()?;
+        self.state = ClientState::Init;
+
+        let mut msg = self.msg(DhcpMsgType::Decline, now);
+        msg.opts.push(DhcpOpt::RequestedIP(lease.addr));
+        msg.opts.push(DhcpOpt::ServerId(lease.server_id));
+
+        Some(Transmit {
+            msg,
+            dst: Ipv4Addr::BROADCAST,
+        })
+    }
+
+    /// Run the state machine on `sock` until a lease is bound or lost,
+    /// or `deadline` is reached
+    pub unsafe fn drive(
+        &mut self,
+        sock: &UdpSock,
+        server_port: u16,
+        deadline: Option<Instant>,
+    ) -> Result<Option<ClientAction>> {
+        let mut buf = [0u8; 1500];
+
+        loop {
+            let now = Instant::now();
+
+            while let Some(action) = self.poll(now) {
+                match action {
+                    ClientAction::Send(transmit) => {
+                        transmit.send(sock, server_port)?;
+                    }
+                    action => return Ok(Some(action)),
+                }
+            }
+
+            let mut timeout = self.next_timeout(now);
+            if let Some(deadline) = deadline {
+                if now >= deadline {
+                    return Ok(None);
+                }
+                timeout = min(timeout, deadline - now);
+            }
+            sock.set_recv_timeout(Some(max(
+                timeout,
+                Duration::from_millis(1),
+            )))?;
+
+            let n = match sock.recv_from(&mut buf)? {
+                Some((n, _from)) => n,
+                None => continue,
+            };
+
+            let msg = match DhcpMsg::decode(&buf[..n]) {
+                Ok(msg) => msg,
+                Err(_err) => continue,
+            };
+
+            match self.handle(&msg, Instant::now()) {
+                Ok(Some(ClientAction::Send(transmit))) => {
+                    transmit.send(sock, server_port)?;
+                }
+                Ok(Some(action)) => return Ok(Some(action)),
+                // not for us
+                Ok(None) | Err(_) => (),
+            }
+        }
+    }
+
+
+    fn msg(&self, ty: DhcpMsgType, now: Instant) -> DhcpMsg {
+        let mut msg = DhcpMsg::new(BootOp::Request, ty, self.xid, self.mac);
+
+        let secs = now.saturating_duration_since(self.start).as_secs();
+        msg.hdr.secs = U16N::from_native(min(secs, u16::MAX as u64) as u16);
+        msg.opts.push(DhcpOpt::ClientId(self.client_id.clone()));
+
+        msg
+    }
+
+    fn discover(&mut self, now: Instant) -> Transmit {
+        let mut msg = self.msg(DhcpMsgType::Discover, now);
+        msg.hdr.flags = U16N::from_native(DHCP_FLAG_BROADCAST);
+        msg.opts
+            .push(DhcpOpt::ParamReqList(PARAM_REQ_LIST.to_vec()));
+
+        self.backoff(now);
+
+        Transmit {
+            msg,
+            dst: Ipv4Addr::BROADCAST,
+        }
+    }
+
+    /// `selected`: (server id, requested ip) in SELECTING
+    fn request(
+        &mut self,
+        now: Instant,
+        dst: Ipv4Addr,
+        selected: Option<(Ipv4Addr, Ipv4Addr)>,
+        ciaddr: Ipv4Addr,
+    ) -> Transmit {
+        let mut msg = self.msg(DhcpMsgType::Request, now);
+        msg.hdr.ciaddr = InAddrN::from_ipv4addr(ciaddr);
+
+        if let Some((server_id, addr)) = selected {
+            msg.hdr.flags = U16N::from_native(DHCP_FLAG_BROADCAST);
+            msg.opts.push(DhcpOpt::RequestedIP(addr));
+            msg.opts.push(DhcpOpt::ServerId(server_id));
+            self.backoff(now);
+        }
+        msg.opts
+            .push(DhcpOpt::ParamReqList(PARAM_REQ_LIST.to_vec()));
+
+        self.last_req = now;
+
+        Transmit { msg, dst }
+    }
+
+    fn backoff(&mut self, now: Instant) {
+        self.next_send = now + self.retrans;
+        self.retrans = min(self.retrans * 2, RETRANS_MAX);
+        self.tries += 1;
+    }
+
+    /// Server the REQUEST is for, any one answers in REBINDING
+    fn requested_server(&self) -> Option<Ipv4Addr> {
+        match self.state {
+            ClientState::Requesting => {
+                self.offer.map(|(server_id, _)| server_id)
+            }
+            ClientState::Renewing => {
+                self.lease.as_ref().map(|lease| lease.server_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+
+impl Transmit {
+    pub unsafe fn send(&self, sock: &UdpSock, port: u16) -> Result<usize> {
+        sock.send_to(&self.msg.to_bytes(), SocketAddrV4::new(self.dst, port))
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Classful netmask, used if server doesn't provide subnet mask option
+fn default_netmask(addr: Ipv4Addr) -> Ipv4Addr {
+    match addr.octets()[0] {
+        0..=127 => Ipv4Addr::new(255, 0, 0, 0),
+        128..=191 => Ipv4Addr::new(255, 255, 0, 0),
+        _ => Ipv4Addr::new(255, 255, 255, 0),
+    }
+}
+
+
+/// Open client socket (0.0.0.0:68) on `ifname`, requires CAP_NET_RAW
+/// for binding device and CAP_NET_BIND_SERVICE for the port
+pub unsafe fn open_client_sock(ifname: Option<&str>) -> Result<UdpSock> {
+    let sock = UdpSock::bind(SocketAddrV4::new(
+        Ipv4Addr::UNSPECIFIED,
+        DHCP_CLIENT_PORT,
+    ))?;
+    sock.set_broadcast(true)?;
+
+    if let Some(ifname) = ifname {
+        sock.bind_to_device(ifname)?;
+    }
+
+    Ok(sock)
+}
+
+
+/// Obtain a lease for `mac` through `ifname`
+pub unsafe fn obtain_lease(
+    ifname: Option<&str>,
+    mac: Mac,
+    timeout: Duration,
+) -> Result<Lease> {
+    let sock = open_client_sock(ifname)?;
+    let mut client = DhcpClient::new(mac, Instant::now());
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match client.drive(&sock, DHCP_SERVER_PORT, Some(deadline))? {
+            Some(ClientAction::Bound(lease)) => return Ok(lease),
+            Some(_) => continue,
+            None => return Err(NetErr::Dhcp(DhcpKind::NoLease)),
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{ClientAction, ClientState, DhcpClient, Transmit};
+    use crate::{
+        application::dhcp::{BootOp, DhcpMsg, DhcpMsgType, DhcpOpt},
+        data::InAddrN,
+        datalink::Mac,
+        transport::udp::UdpSock,
+    };
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const OTHER_SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OFFERED: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+
+    /// Stand-in server: OFFER for DISCOVER, ACK (or NAK) for REQUEST
+    fn stand_in_server(req: &DhcpMsg, nak: bool) -> Option<DhcpMsg> {
+        let ty = match req.msg_type().unwrap() {
+            DhcpMsgType::Discover => DhcpMsgType::Offer,
+            DhcpMsgType::Request if nak => DhcpMsgType::Nak,
+            DhcpMsgType::Request => DhcpMsgType::Ack,
+            _ => return None,
+        };
+
+        let mut resp = DhcpMsg::new(
+            BootOp::Reply,
+            ty,
+            req.hdr.get_xid(),
+            req.hdr.get_chaddr_mac(),
+        );
+        resp.opts.push(DhcpOpt::ServerId(SERVER));
+
+        if ty != DhcpMsgType::Nak {
+            resp.hdr.yiaddr = InAddrN::from_ipv4addr(OFFERED);
+            resp.opts.extend([
+                DhcpOpt::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+                DhcpOpt::Router(vec![SERVER]),
+                DhcpOpt::DNS(vec![SERVER]),
+                DhcpOpt::LeaseTime(1000),
+            ]);
+        }
+
+        // go through the codec as on a real wire
+        Some(DhcpMsg::decode(&resp.to_bytes()).unwrap())
+    }
+
+    /// The same answer as if another server sent it
+    fn from_other_server(msg: &DhcpMsg) -> DhcpMsg {
+        let mut other = msg.clone();
+
+        for opt in other.opts.iter_mut() {
+            if let DhcpOpt::ServerId(server_id) = opt {
+                *server_id = OTHER_SERVER;
+            }
+        }
+
+        other
+    }
+
+    fn expect_send(action: Option<ClientAction>) -> Transmit {
+        match action {
+            Some(ClientAction::Send(transmit)) => transmit,
+            other => panic!("expect send, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_dhcp_client_states() {
+        let mac = Mac::new(0x00, 0x12, 0x34, 0x56, 0x78, 0x90);
+        let t0 = Instant::now();
+        let mut client = DhcpClient::new(mac, t0);
+
+        /* INIT -> SELECTING */
+        let discover = expect_send(client.poll(t0));
+        assert_eq!(client.state(), ClientState::Selecting);
+        assert_eq!(discover.dst, Ipv4Addr::BROADCAST);
+        assert!(discover.msg.hdr.is_broadcast());
+        assert!(client.poll(t0).is_none());
+
+        /* retransmit DISCOVER */
+        let discover = expect_send(client.poll(t0 + Duration::from_secs(4)));
+
+        /* SELECTING -> REQUESTING */
+        let offer = stand_in_server(&discover.msg, false).unwrap();
+        let request = expect_send(client.handle(&offer, t0).unwrap());
+        assert_eq!(client.state(), ClientState::Requesting);
+        assert_eq!(request.msg.requested_ip(), Some(OFFERED));
+        assert_eq!(request.msg.server_id(), Some(SERVER));
+
+        /* a stale OFFER is ignored */
+        assert!(client.handle(&offer, t0).unwrap().is_none());
+
+        /* ACK or NAK of other server is ignored */
+        for nak in [false, true] {
+            let resp = stand_in_server(&request.msg, nak).unwrap();
+            let other = from_other_server(&resp);
+
+            assert!(client.handle(&other, t0).unwrap().is_none());
+        }
+        assert_eq!(client.state(), ClientState::Requesting);
+
+        /* REQUESTING -> BOUND */
+        let ack = stand_in_server(&request.msg, false).unwrap();
+        let lease = match client.handle(&ack, t0).unwrap() {
+            Some(ClientAction::Bound(lease)) => lease,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(client.state(), ClientState::Bound);
+        assert_eq!(lease.addr, OFFERED);
+        assert_eq!(lease.gateway(), Some(SERVER));
+        assert_eq!(lease.broadcast(), Ipv4Addr::new(10, 0, 0, 255));
+        assert_eq!(lease.t1, Duration::from_secs(500));
+        assert_eq!(lease.t2, Duration::from_secs(875));
+        assert_eq!(client.next_timeout(t0), Duration::from_secs(500));
+        assert!(client.poll(t0 + Duration::from_secs(499)).is_none());
+
+        /* BOUND -> RENEWING, unicast to server */
+        let t1 = t0 + Duration::from_secs(500);
+        let renew = expect_send(client.poll(t1));
+        assert_eq!(client.state(), ClientState::Renewing);
+        assert_eq!(renew.dst, SERVER);
+        assert_eq!(renew.msg.hdr.ciaddr.ipv4(), OFFERED);
+        assert!(renew.msg.requested_ip().is_none());
+
+        /* RENEWING -> REBINDING, broadcast */
+        let t2 = t0 + Duration::from_secs(875);
+        let rebind = expect_send(client.poll(t2));
+        assert_eq!(client.state(), ClientState::Rebinding);
+        assert_eq!(rebind.dst, Ipv4Addr::BROADCAST);
+
+        /* REBINDING -> BOUND */
+        let ack = stand_in_server(&rebind.msg, false).unwrap();
+        match client.handle(&ack, t2).unwrap() {
+            Some(ClientAction::Bound(lease)) => {
+                assert_eq!(lease.acquired, t2)
+            }
+            other => panic!("{other:?}"),
+        }
+        assert_eq!(client.state(), ClientState::Bound);
+
+        /* lease expired */
+        assert!(matches!(
+            client.poll(t2 + Duration::from_secs(1000)),
+            Some(ClientAction::Lost)
+        ));
+        assert_eq!(client.state(), ClientState::Init);
+        assert!(client.lease().is_none());
+    }
+
+    #[test]
+    fn test_dhcp_client_nak() {
+        let mac = Mac::new(0x00, 0x12, 0x34, 0x56, 0x78, 0x91);
+        let t0 = Instant::now();
+        let mut client = DhcpClient::new(mac, t0);
+
+        let discover = expect_send(client.poll(t0));
+        let xid = client.xid();
+
+        /* other's transaction */
+        let mut other = stand_in_server(&discover.msg, false).unwrap();
+        other.hdr.set_xid(xid.wrapping_add(1));
+        assert!(client.handle(&other, t0).is_err());
+
+        let offer = stand_in_server(&discover.msg, false).unwrap();
+        let request = expect_send(client.handle(&offer, t0).unwrap());
+
+        let nak = stand_in_server(&request.msg, true).unwrap();
+        assert!(matches!(
+            client.handle(&nak, t0).unwrap(),
+            Some(ClientAction::Lost)
+        ));
+        assert_eq!(client.state(), ClientState::Init);
+
+        /* restart with a new transaction */
+        expect_send(client.poll(t0));
+        assert_eq!(client.state(), ClientState::Selecting);
+    }
+
+    #[test]
+    fn test_dhcp_client_request_timeout() {
+        let mac = Mac::new(0x00, 0x12, 0x34, 0x56, 0x78, 0x92);
+        let mut now = Instant::now();
+        let mut client = DhcpClient::new(mac, now);
+
+        let discover = expect_send(client.poll(now));
+        let offer = stand_in_server(&discover.msg, false).unwrap();
+        expect_send(client.handle(&offer, now).unwrap());
+
+        for _ in 1..4 {
+            now += client.next_timeout(now);
+            expect_send(client.poll(now));
+            assert_eq!(client.state(), ClientState::Requesting);
+        }
+
+        now += client.next_timeout(now);
+        let discover = expect_send(client.poll(now));
+        assert_eq!(client.state(), ClientState::Selecting);
+        assert_eq!(discover.msg.msg_type().unwrap(), DhcpMsgType::Discover);
+    }
+
+    #[test]
+    fn test_dhcp_client_drive_loopback() {
+        let mac = Mac::new(0x00, 0x12, 0x34, 0x56, 0x78, 0x93);
+
+        unsafe {
+            let server =
+                UdpSock::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                    .unwrap();
+            let port = server.local_addr().unwrap().port();
+
+            let sock =
+                UdpSock::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                    .unwrap();
+            sock.set_broadcast(true).unwrap();
+
+            // the broadcast goes out by the default route, looped back to
+            // the local sockets
+            if let Err(err) = sock.send_to(
+                b"probe",
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, port),
+            ) {
+                eprintln!("skip, {err:?}");
+                return;
+            }
+
+            /* OFFER for DISCOVER, ACK of other server and then ours for
+             * REQUEST */
+            let server = thread::spawn(move || {
+                let mut buf = [0u8; 1500];
+                let mut seen = vec![];
+
+                server
+                    .set_recv_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+
+                while let Some((n, from)) = server.recv_from(&mut buf).unwrap()
+                {
+                    let Ok(req) = DhcpMsg::decode(&buf[..n])
+                    else {
+                        continue;
+                    };
+                    let ty = req.msg_type().unwrap();
+                    let resp = stand_in_server(&req, false).unwrap();
+
+                    if ty == DhcpMsgType::Request {
+                        let other = from_other_server(&resp);
+                        server.send_to(&other.to_bytes(), from).unwrap();
+                    }
+                    server.send_to(&resp.to_bytes(), from).unwrap();
+                    seen.push(ty);
+
+                    if ty == DhcpMsgType::Request {
+                        break;
+                    }
+                }
+
+                seen
+            });
+
+            let mut client = DhcpClient::new(mac, Instant::now());
+            let deadline = Instant::now() + Duration::from_secs(5);
+
+            let lease = match client.drive(&sock, port, Some(deadline)) {
+                Ok(Some(ClientAction::Bound(lease))) => lease,
+                other => panic!("{other:?}"),
+            };
+
+            assert_eq!(lease.addr, OFFERED);
+            assert_eq!(lease.server_id, SERVER);
+            assert_eq!(client.state(), ClientState::Bound);
+            assert_eq!(
+                server.join().unwrap(),
+                [DhcpMsgType::Discover, DhcpMsgType::Request]
+            );
+        }
+    }
+}
@@ -0,0 +1,555 @@
+//! DHCPv4 ([rfc2131](https://www.rfc-editor.org/rfc/rfc2131),
+//! options from [rfc2132](https://www.rfc-editor.org/rfc/rfc2132))
+//!
+
+pub mod client;
+pub mod server;
+
+
+use std::{
+    mem::size_of,
+    net::Ipv4Addr,
+    ptr::{read_unaligned, write_unaligned},
+};
+
+use crate::{
+    aux::{htonl, ntohl},
+    data::{FixStr, InAddrN},
+    datalink::Mac,
+    defraw, enum_try_from_int,
+    rs_error::{DhcpKind, NetErr},
+    view::U16N,
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+pub const DHCP_MAGIC_COOKIE: u32 = 0x6382_5363;
+
+/// BOOTP header + magic cookie
+pub const DHCP_FIXED_LEN: usize = size_of::<BOOTP>() + 4;
+
+/// Minimum BOOTP message size that relay agents must accept (rfc1542)
+pub const DHCP_MIN_LEN: usize = 300;
+
+/// Broadcast flag of `BOOTP::flags`, ask server to broadcast its reply
+pub const DHCP_FLAG_BROADCAST: u16 = 0x8000;
+
+pub const HTYPE_ETHERNET: u8 = 1;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+defraw! {
+    /// BOOTP fixed header, 236 bytes
+    #[repr(packed)]
+    pub struct BOOTP {
+        /// `BootOp`
+        op: u8,
+        /// Hardware address type, 1 for 10Mb Ethernet
+        htype: u8,
+        hlen: u8,
+        /// Relay agent hops
+        hops: u8,
+        /// Transaction ID, network bytes order
+        xid: u32,
+        /// Seconds elapsed since client began address acquisition
+        secs: U16N,
+        flags: U16N,
+        /// Client IP address (filled only in BOUND, RENEW, REBINDING)
+        ciaddr: InAddrN,
+        /// "your" (client) IP address
+        yiaddr: InAddrN,
+        /// Next server IP address
+        siaddr: InAddrN,
+        /// Relay agent IP address
+        giaddr: InAddrN,
+        /// Client hardware address
+        chaddr: [u8; 16],
+        sname: FixStr<64>,
+        file: FixStr<128>,
+    }
+}
+
+
+enum_try_from_int! {
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum BootOp {
+        Request = 1,
+        Reply = 2,
+    }
+
+    /// DHCP Message Type (option 53)
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum DhcpMsgType {
+        Discover = 1,
+        Offer = 2,
+        Request = 3,
+        Decline = 4,
+        Ack = 5,
+        Nak = 6,
+        Release = 7,
+        Inform = 8,
+    }
+}
+
+
+/// Options TLVs, (unrecognized option would be kept as `Other`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DhcpOpt {
+    /// 1
+    SubnetMask(Ipv4Addr),
+    /// 3
+    Router(Vec<Ipv4Addr>),
+    /// 6
+    DNS(Vec<Ipv4Addr>),
+    /// 12
+    HostName(String),
+    /// 50
+    RequestedIP(Ipv4Addr),
+    /// 51 seconds
+    LeaseTime(u32),
+    /// 53
+    MsgType(DhcpMsgType),
+    /// 54
+    ServerId(Ipv4Addr),
+    /// 55
+    ParamReqList(Vec<u8>),
+    /// 56 error message
+    Message(String),
+    /// 58 seconds (T1)
+    RenewalTime(u32),
+    /// 59 seconds (T2)
+    RebindingTime(u32),
+    /// 61 type + identifier
+    ClientId(Vec<u8>),
+    Other(u8, Vec<u8>),
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct DhcpMsg {
+    pub hdr: BOOTP,
+    pub opts: Vec<DhcpOpt>,
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl DhcpOpt {
+    pub const PAD: u8 = 0;
+    pub const END: u8 = 255;
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::SubnetMask(_) => 1,
+            Self::Router(_) => 3,
+            Self::DNS(_) => 6,
+            Self::HostName(_) => 12,
+            Self::RequestedIP(_) => 50,
+            Self::LeaseTime(_) => 51,
+            Self::MsgType(_) => 53,
+            Self::ServerId(_) => 54,
+            Self::ParamReqList(_) => 55,
+            Self::Message(_) => 56,
+            Self::RenewalTime(_) => 58,
+            Self::RebindingTime(_) => 59,
+            Self::ClientId(_) => 61,
+            Self::Other(code, _) => *code,
+        }
+    }
+
+    fn decode(code: u8, val: &[u8]) -> Result<Self> {
+        let bad = || NetErr::Dhcp(DhcpKind::InvalidOpt(code));
+
+        let ipv4 = |val: &[u8]| -> Result<Ipv4Addr> {
+            if val.len() != 4 {
+                return Err(bad());
+            }
+            Ok(Ipv4Addr::new(val[0], val[1], val[2], val[3]))
+        };
+        let ipv4s = |val: &[u8]| -> Result<Vec<Ipv4Addr>> {
+            if val.is_empty() || !val.len().is_multiple_of(4) {
+                return Err(bad());
+            }
+            val.chunks(4).map(ipv4).collect()
+        };
+        let secs = |val: &[u8]| -> Result<u32> {
+            if val.len() != 4 {
+                return Err(bad());
+            }
+            Ok(u32::from_be_bytes([val[0], val[1], val[2], val[3]]))
+        };
+        let text = |val: &[u8]| String::from_utf8_lossy(val).into_owned();
+
+        Ok(match code {
+            1 => Self::SubnetMask(ipv4(val)?),
+            3 => Self::Router(ipv4s(val)?),
+            6 => Self::DNS(ipv4s(val)?),
+            12 => Self::HostName(text(val)),
+            50 => Self::RequestedIP(ipv4(val)?),
+            51 => Self::LeaseTime(secs(val)?),
+            53 => {
+                if val.len() != 1 {
+                    return Err(bad());
+                }
+                Self::MsgType(
+                    DhcpMsgType::try_from(val[0]).map_err(|_| bad())?,
+                )
+            }
+            54 => Self::ServerId(ipv4(val)?),
+            55 => Self::ParamReqList(val.to_vec()),
+            56 => Self::Message(text(val)),
+            58 => Self::RenewalTime(secs(val)?),
+            59 => Self::RebindingTime(secs(val)?),
+            61 => Self::ClientId(val.to_vec()),
+            _ => Self::Other(code, val.to_vec()),
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut val = vec![];
+
+        match self {
+            Self::SubnetMask(ip)
+            | Self::RequestedIP(ip)
+            | Self::ServerId(ip) => val.extend_from_slice(&ip.octets()),
+            Self::Router(ips) | Self::DNS(ips) => {
+                for ip in ips {
+                    val.extend_from_slice(&ip.octets())
+                }
+            }
+            Self::HostName(s) | Self::Message(s) => {
+                val.extend_from_slice(s.as_bytes())
+            }
+            Self::LeaseTime(n)
+            | Self::RenewalTime(n)
+            | Self::RebindingTime(n) => {
+                val.extend_from_slice(&n.to_be_bytes())
+            }
+            Self::MsgType(ty) => val.push(*ty as u8),
+            Self::ParamReqList(v) | Self::ClientId(v) | Self::Other(_, v) => {
+                val.extend_from_slice(v)
+            }
+        }
+
+        if val.is_empty() {
+            buf.push(self.code());
+            buf.push(0);
+            return;
+        }
+
+        // Options longer than 255 bytes should be split (rfc3396),
+        // which is the same as concatenation on decode.
+        for chunk in val.chunks(u8::MAX as usize) {
+            buf.push(self.code());
+            buf.push(chunk.len() as u8);
+            buf.extend_from_slice(chunk);
+        }
+    }
+}
+
+
+impl BOOTP {
+    pub fn get_xid(&self) -> u32 {
+        unsafe { ntohl(self.xid) }
+    }
+
+    pub fn set_xid(&mut self, xid: u32) {
+        self.xid = unsafe { htonl(xid) };
+    }
+
+    pub fn get_chaddr_mac(&self) -> Mac {
+        Mac::from_slice(&self.chaddr[..6])
+    }
+
+    pub fn set_chaddr_mac(&mut self, mac: Mac) {
+        self.htype = HTYPE_ETHERNET;
+        self.hlen = size_of::<Mac>() as u8;
+        self.chaddr = [0; 16];
+
+        for (i, octet) in mac.0.iter().enumerate() {
+            self.chaddr[i] = octet.0;
+        }
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        let flags = self.flags;
+
+        flags.native() & DHCP_FLAG_BROADCAST > 0
+    }
+}
+
+
+impl DhcpMsg {
+    /// Create a message with the message type option
+    pub fn new(op: BootOp, ty: DhcpMsgType, xid: u32, mac: Mac) -> Self {
+        let mut hdr = BOOTP {
+            op: op as u8,
+            ..Default::default()
+        };
+        hdr.set_xid(xid);
+        hdr.set_chaddr_mac(mac);
+
+        Self {
+            hdr,
+            opts: vec![DhcpOpt::MsgType(ty)],
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < DHCP_FIXED_LEN {
+            return Err(NetErr::Dhcp(DhcpKind::TooShort(bytes.len())));
+        }
+
+        let hdr: BOOTP = unsafe { read_unaligned(bytes.as_ptr() as *const _) };
+        let magic = u32::from_be_bytes(
+            bytes[size_of::<BOOTP>()..DHCP_FIXED_LEN]
+                .try_into()
+                .unwrap(),
+        );
+
+        if magic != DHCP_MAGIC_COOKIE {
+            return Err(NetErr::Dhcp(DhcpKind::BadMagic(magic)));
+        }
+
+        let opts = decode_opts(&bytes[DHCP_FIXED_LEN..])?;
+
+        Ok(Self { hdr, opts })
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.resize(start + size_of::<BOOTP>(), 0);
+
+        unsafe {
+            write_unaligned(buf[start..].as_mut_ptr() as *mut BOOTP, self.hdr);
+        }
+        buf.extend_from_slice(&DHCP_MAGIC_COOKIE.to_be_bytes());
+
+        for opt in self.opts.iter() {
+            opt.encode(buf);
+        }
+        buf.push(DhcpOpt::END);
+
+        // pad to the minimum BOOTP length
+        if buf.len() - start < DHCP_MIN_LEN {
+            buf.resize(start + DHCP_MIN_LEN, DhcpOpt::PAD);
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+
+        buf
+    }
+
+    pub fn get_opt(&self, code: u8) -> Option<&DhcpOpt> {
+        self.opts.iter().find(|opt| opt.code() == code)
+    }
+
+    /// Replace the option with same code or else push it
+    pub fn set_opt(&mut self, opt: DhcpOpt) {
+        if let Some(old) =
+            self.opts.iter_mut().find(|old| old.code() == opt.code())
+        {
+            *old = opt;
+        }
+        else {
+            self.opts.push(opt);
+        }
+    }
+
+    pub fn msg_type(&self) -> Result<DhcpMsgType> {
+        match self.get_opt(53) {
+            Some(DhcpOpt::MsgType(ty)) => Ok(*ty),
+            _ => Err(NetErr::Dhcp(DhcpKind::NoMsgType)),
+        }
+    }
+
+    pub fn server_id(&self) -> Option<Ipv4Addr> {
+        match self.get_opt(54) {
+            Some(DhcpOpt::ServerId(ip)) => Some(*ip),
+            _ => None,
+        }
+    }
+
+    pub fn requested_ip(&self) -> Option<Ipv4Addr> {
+        match self.get_opt(50) {
+            Some(DhcpOpt::RequestedIP(ip)) => Some(*ip),
+            _ => None,
+        }
+    }
+
+    pub fn subnet_mask(&self) -> Option<Ipv4Addr> {
+        match self.get_opt(1) {
+            Some(DhcpOpt::SubnetMask(ip)) => Some(*ip),
+            _ => None,
+        }
+    }
+
+    pub fn routers(&self) -> &[Ipv4Addr] {
+        match self.get_opt(3) {
+            Some(DhcpOpt::Router(ips)) => ips,
+            _ => &[],
+        }
+    }
+
+    pub fn dns(&self) -> &[Ipv4Addr] {
+        match self.get_opt(6) {
+            Some(DhcpOpt::DNS(ips)) => ips,
+            _ => &[],
+        }
+    }
+
+    pub fn lease_time(&self) -> Option<u32> {
+        match self.get_opt(51) {
+            Some(DhcpOpt::LeaseTime(secs)) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    pub fn renewal_time(&self) -> Option<u32> {
+        match self.get_opt(58) {
+            Some(DhcpOpt::RenewalTime(secs)) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    pub fn rebinding_time(&self) -> Option<u32> {
+        match self.get_opt(59) {
+            Some(DhcpOpt::RebindingTime(secs)) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    pub fn client_id(&self) -> Option<&[u8]> {
+        match self.get_opt(61) {
+            Some(DhcpOpt::ClientId(id)) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Decode options field, split options with same code are concatenated
+/// (rfc3396).
+fn decode_opts(mut bytes: &[u8]) -> Result<Vec<DhcpOpt>> {
+    let mut raw: Vec<(u8, Vec<u8>)> = vec![];
+
+    loop {
+        let code = match bytes.first() {
+            Some(&DhcpOpt::END) | None => break,
+            Some(&DhcpOpt::PAD) => {
+                bytes = &bytes[1..];
+                continue;
+            }
+            Some(code) => *code,
+        };
+
+        if bytes.len() < 2 || bytes.len() < 2 + bytes[1] as usize {
+            return Err(NetErr::Dhcp(DhcpKind::InvalidOpt(code)));
+        }
+
+        let len = bytes[1] as usize;
+        let val = &bytes[2..2 + len];
+
+        if let Some((_, old)) = raw.iter_mut().find(|(c, _)| *c == code) {
+            old.extend_from_slice(val);
+        }
+        else {
+            raw.push((code, val.to_vec()));
+        }
+
+        bytes = &bytes[2 + len..];
+    }
+
+    raw.into_iter()
+        .map(|(code, val)| DhcpOpt::decode(code, &val))
+        .collect()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{mem::size_of, net::Ipv4Addr};
+
+    use super::{
+        BootOp, DhcpMsg, DhcpMsgType, DhcpOpt, BOOTP, DHCP_FIXED_LEN,
+        DHCP_MIN_LEN,
+    };
+    use crate::{data::InAddrN, datalink::Mac};
+
+    #[test]
+    fn test_bootp_layout() {
+        assert_eq!(size_of::<BOOTP>(), 236);
+        assert_eq!(DHCP_FIXED_LEN, 240);
+    }
+
+    #[test]
+    fn test_dhcp_codec() {
+        let mac = Mac::new(0x00, 0x12, 0x34, 0x56, 0x78, 0x90);
+        let mut msg =
+            DhcpMsg::new(BootOp::Reply, DhcpMsgType::Offer, 0xdead_beef, mac);
+
+        msg.hdr.yiaddr = InAddrN::from_ipv4addr(Ipv4Addr::new(10, 0, 0, 2));
+        msg.opts.extend([
+            DhcpOpt::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+            DhcpOpt::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+            DhcpOpt::DNS(vec![
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(1, 1, 1, 1),
+            ]),
+            DhcpOpt::LeaseTime(3600),
+            DhcpOpt::ServerId(Ipv4Addr::new(10, 0, 0, 1)),
+            DhcpOpt::ClientId(vec![1, 0x00, 0x12, 0x34, 0x56, 0x78, 0x90]),
+            DhcpOpt::Other(252, vec![]),
+        ]);
+
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes.len(), DHCP_MIN_LEN);
+        assert_eq!(bytes[0], 2);
+
+        let msg2 = DhcpMsg::decode(&bytes).unwrap();
+
+        assert_eq!(msg2.hdr.get_xid(), 0xdead_beef);
+        assert_eq!(msg2.hdr.get_chaddr_mac(), mac);
+        assert_eq!(msg2.hdr.yiaddr.ipv4(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(msg2.msg_type().unwrap(), DhcpMsgType::Offer);
+        assert_eq!(msg2.opts, msg.opts);
+        assert_eq!(msg2.lease_time(), Some(3600));
+        assert_eq!(msg2.dns().len(), 2);
+
+        assert!(DhcpMsg::decode(&bytes[..100]).is_err());
+
+        let mut bad = bytes.clone();
+        bad[236] = 0;
+        assert!(DhcpMsg::decode(&bad).is_err());
+    }
+
+    #[test]
+    fn test_dhcp_long_opt() {
+        let mut msg = DhcpMsg::default();
+        let long = DhcpOpt::HostName("x".repeat(300));
+        msg.opts.push(long.clone());
+
+        let msg2 = DhcpMsg::decode(&msg.to_bytes()).unwrap();
+
+        assert_eq!(msg2.opts, vec![long]);
+    }
+}
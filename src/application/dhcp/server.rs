@@ -0,0 +1,657 @@
+//! Minimal DHCP server (rfc2131 4.3) for lab networks
+//!
+//! Like the client, `DhcpServer::handle` does no IO, the caller receives
+//! on port 67 and sends the `Reply` back.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs::{read_to_string, write},
+    net::{Ipv4Addr, SocketAddrV4},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use super::{
+    BootOp, DhcpMsg, DhcpMsgType, DhcpOpt, DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+};
+use crate::{
+    data::{InAddrN, Ipv4Cidr},
+    datalink::Mac,
+    rs_error::{DhcpKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// How long an offered address is kept for the client
+pub const OFFER_HOLD: Duration = Duration::from_secs(60);
+
+/// How long a declined address is kept out of the pool
+pub const DECLINE_HOLD: Duration = Duration::from_secs(10 * 60);
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone)]
+pub struct ServerConf {
+    /// Server identifier, the address of the serving interface
+    pub server_id: Ipv4Addr,
+    pub pool: Ipv4Cidr,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub lease_time: Duration,
+    /// Static MAC-to-IP reservations
+    pub reservations: HashMap<Mac, Ipv4Addr>,
+    /// Bound leases are persisted to it if provided
+    pub lease_file: Option<PathBuf>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseState {
+    Offered,
+    Bound,
+    /// Reported in use by DECLINE
+    Declined,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaseRec {
+    pub mac: Mac,
+    pub addr: Ipv4Addr,
+    pub state: LeaseState,
+    pub expire: SystemTime,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub msg: DhcpMsg,
+    pub dst: SocketAddrV4,
+}
+
+
+#[derive(Debug)]
+pub struct DhcpServer {
+    conf: ServerConf,
+    /// Indexed by address
+    leases: BTreeMap<Ipv4Addr, LeaseRec>,
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl DhcpServer {
+    /// Load persisted leases from `conf.lease_file` if it exists
+    pub fn new(conf: ServerConf) -> Result<Self> {
+        let mut it = Self {
+            conf,
+            leases: BTreeMap::new(),
+        };
+
+        if let Some(ref path) = it.conf.lease_file {
+            if path.exists() {
+                let text = read_to_string(path).map_err(NetErr::Open)?;
+                it.leases = parse_leases(&text)?;
+            }
+        }
+
+        Ok(it)
+    }
+
+    pub fn conf(&self) -> &ServerConf {
+        &self.conf
+    }
+
+    pub fn leases(&self) -> impl Iterator<Item = &LeaseRec> {
+        self.leases.values()
+    }
+
+    pub fn lease_of(&self, mac: Mac) -> Option<&LeaseRec> {
+        self.leases
+            .values()
+            .find(|rec| rec.mac == mac && rec.state != LeaseState::Declined)
+    }
+
+    /// Handle a client message, return the reply if any
+    pub fn handle(
+        &mut self,
+        msg: &DhcpMsg,
+        now: SystemTime,
+    ) -> Result<Option<Reply>> {
+        if msg.hdr.op != BootOp::Request as u8 {
+            return Ok(None);
+        }
+
+        self.expire(now);
+
+        let mac = msg.hdr.get_chaddr_mac();
+
+        match msg.msg_type()? {
+            DhcpMsgType::Discover => {
+                let addr = match self.choose(mac, msg.requested_ip()) {
+                    Some(addr) => addr,
+                    None => {
+                        warn!("DHCP pool exhausted for {mac}");
+                        return Ok(None);
+                    }
+                };
+
+                // don't downgrade a bound lease
+                if !self.owned_by(addr, mac)
+                    || self.leases[&addr].state != LeaseState::Bound
+                {
+                    self.bind(
+                        mac,
+                        addr,
+                        LeaseState::Offered,
+                        now + OFFER_HOLD,
+                    );
+                }
+
+                Ok(Some(self.reply(msg, DhcpMsgType::Offer, addr)))
+            }
+            DhcpMsgType::Request => self.handle_request(msg, mac, now),
+            DhcpMsgType::Decline => {
+                if msg.server_id() != Some(self.conf.server_id) {
+                    return Ok(None);
+                }
+
+                if let Some(addr) = msg.requested_ip() {
+                    if self.owned_by(addr, mac) {
+                        self.bind(
+                            mac,
+                            addr,
+                            LeaseState::Declined,
+                            now + DECLINE_HOLD,
+                        );
+                        self.save()?;
+                    }
+                }
+
+                Ok(None)
+            }
+            DhcpMsgType::Release => {
+                let addr = msg.hdr.ciaddr.ipv4();
+
+                if self.owned_by(addr, mac) {
+                    self.leases.remove(&addr);
+                    self.save()?;
+                }
+
+                Ok(None)
+            }
+            DhcpMsgType::Inform => {
+                let mut reply =
+                    self.reply(msg, DhcpMsgType::Ack, Ipv4Addr::UNSPECIFIED);
+                reply
+                    .msg
+                    .opts
+                    .retain(|opt| !matches!(opt, DhcpOpt::LeaseTime(_)));
+
+                Ok(Some(reply))
+            }
+            ty => Err(NetErr::Dhcp(DhcpKind::UnexpectedMsg(format!(
+                "{ty:?} from client {mac}"
+            )))),
+        }
+    }
+
+
+    fn handle_request(
+        &mut self,
+        msg: &DhcpMsg,
+        mac: Mac,
+        now: SystemTime,
+    ) -> Result<Option<Reply>> {
+        let ciaddr = msg.hdr.ciaddr.ipv4();
+
+        let addr = match msg.server_id() {
+            /* SELECTING */
+            Some(server_id) => {
+                if server_id != self.conf.server_id {
+                    // client choosed other server
+                    if let Some(rec) = self.lease_of(mac).copied() {
+                        if rec.state == LeaseState::Offered {
+                            self.leases.remove(&rec.addr);
+                        }
+                    }
+
+                    return Ok(None);
+                }
+
+                msg.requested_ip().unwrap_or(ciaddr)
+            }
+            /* INIT-REBOOT */
+            None if ciaddr.is_unspecified() => match msg.requested_ip() {
+                Some(addr) => addr,
+                None => return Ok(None),
+            },
+            /* RENEWING or REBINDING */
+            None => ciaddr,
+        };
+
+        if self.owned_by(addr, mac) {
+            self.bind(
+                mac,
+                addr,
+                LeaseState::Bound,
+                now + self.conf.lease_time,
+            );
+            self.save()?;
+
+            return Ok(Some(self.reply(msg, DhcpMsgType::Ack, addr)));
+        }
+
+        // unknown client in INIT-REBOOT on the right network: remain silent
+        if msg.server_id().is_none()
+            && self.conf.pool.contains(addr)
+            && !self.leases.contains_key(&addr)
+            && self.reserved_by_other(addr, mac).is_none()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(self.reply(
+            msg,
+            DhcpMsgType::Nak,
+            Ipv4Addr::UNSPECIFIED,
+        )))
+    }
+
+    /// Drop expired records
+    fn expire(&mut self, now: SystemTime) {
+        self.leases.retain(|_, rec| rec.expire > now);
+    }
+
+    fn bind(
+        &mut self,
+        mac: Mac,
+        addr: Ipv4Addr,
+        state: LeaseState,
+        expire: SystemTime,
+    ) {
+        self.leases.retain(|_, rec| {
+            rec.mac != mac || rec.state == LeaseState::Declined
+        });
+        self.leases.insert(
+            addr,
+            LeaseRec {
+                mac,
+                addr,
+                state,
+                expire,
+            },
+        );
+    }
+
+    /// If `addr` is leased (offered or bound) to `mac`
+    fn owned_by(&self, addr: Ipv4Addr, mac: Mac) -> bool {
+        match self.leases.get(&addr) {
+            Some(rec) => rec.mac == mac && rec.state != LeaseState::Declined,
+            None => false,
+        }
+    }
+
+    fn reserved_by_other(&self, addr: Ipv4Addr, mac: Mac) -> Option<Mac> {
+        self.conf
+            .reservations
+            .iter()
+            .find(|(other, reserved)| **reserved == addr && **other != mac)
+            .map(|(other, _)| *other)
+    }
+
+    fn is_free(&self, addr: Ipv4Addr, mac: Mac) -> bool {
+        self.conf.pool.contains(addr)
+            && addr != self.conf.server_id
+            && addr != self.conf.pool.network()
+            && addr != self.conf.pool.broadcast()
+            && !self.conf.routers.contains(&addr)
+            && self.reserved_by_other(addr, mac).is_none()
+            && match self.leases.get(&addr) {
+                Some(rec) => {
+                    rec.mac == mac && rec.state != LeaseState::Declined
+                }
+                None => true,
+            }
+    }
+
+    /// Reservation > current lease > requested > first free, the
+    /// reservation is skipped while another client holds its lease.
+    fn choose(
+        &self,
+        mac: Mac,
+        requested: Option<Ipv4Addr>,
+    ) -> Option<Ipv4Addr> {
+        if let Some(addr) = self.conf.reservations.get(&mac) {
+            match self.leases.get(addr) {
+                Some(rec) if rec.mac != mac => warn!(
+                    "DHCP reserved {addr} for {mac} is leased to {}",
+                    rec.mac
+                ),
+                _ => return Some(*addr),
+            }
+        }
+
+        if let Some(rec) = self.lease_of(mac) {
+            return Some(rec.addr);
+        }
+
+        if let Some(addr) = requested {
+            if self.is_free(addr, mac) {
+                return Some(addr);
+            }
+        }
+
+        self.conf.pool.hosts().find(|addr| self.is_free(*addr, mac))
+    }
+
+    fn reply(
+        &self,
+        req: &DhcpMsg,
+        ty: DhcpMsgType,
+        yiaddr: Ipv4Addr,
+    ) -> Reply {
+        let mut msg = DhcpMsg::new(
+            BootOp::Reply,
+            ty,
+            req.hdr.get_xid(),
+            req.hdr.get_chaddr_mac(),
+        );
+        msg.hdr.flags = req.hdr.flags;
+        msg.hdr.giaddr = req.hdr.giaddr;
+        msg.hdr.yiaddr = InAddrN::from_ipv4addr(yiaddr);
+        msg.opts.push(DhcpOpt::ServerId(self.conf.server_id));
+
+        if ty != DhcpMsgType::Nak {
+            msg.hdr.ciaddr = req.hdr.ciaddr;
+            msg.opts.push(DhcpOpt::SubnetMask(self.conf.pool.netmask()));
+
+            if !self.conf.routers.is_empty() {
+                msg.opts.push(DhcpOpt::Router(self.conf.routers.clone()));
+            }
+            if !self.conf.dns.is_empty() {
+                msg.opts.push(DhcpOpt::DNS(self.conf.dns.clone()));
+            }
+
+            msg.opts.push(DhcpOpt::LeaseTime(
+                self.conf.lease_time.as_secs().min(u32::MAX as u64) as u32,
+            ));
+        }
+
+        /* rfc2131 4.1 */
+        let giaddr = req.hdr.giaddr.ipv4();
+        let ciaddr = req.hdr.ciaddr.ipv4();
+
+        let dst = if !giaddr.is_unspecified() {
+            SocketAddrV4::new(giaddr, DHCP_SERVER_PORT)
+        }
+        else if ty != DhcpMsgType::Nak && !ciaddr.is_unspecified() {
+            SocketAddrV4::new(ciaddr, DHCP_CLIENT_PORT)
+        }
+        else {
+            // unicast to yiaddr requires injecting ARP entry for chaddr,
+            // just broadcast
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT)
+        };
+
+        Reply { msg, dst }
+    }
+
+    /// Persist bound and declined leases
+    fn save(&self) -> Result<()> {
+        let path = match self.conf.lease_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut text = String::new();
+
+        for rec in self.leases.values() {
+            let state = match rec.state {
+                LeaseState::Offered => continue,
+                LeaseState::Bound => "bound",
+                LeaseState::Declined => "declined",
+            };
+            let expire = rec
+                .expire
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            writeln!(text, "{} {} {} {}", rec.mac, rec.addr, state, expire)
+                .unwrap();
+        }
+
+        write(path, text).map_err(NetErr::Write)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Line format: `<mac> <ip> <bound|declined> <expire unix seconds>`
+fn parse_leases(text: &str) -> Result<BTreeMap<Ipv4Addr, LeaseRec>> {
+    let mut leases = BTreeMap::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let bad = || NetErr::AnyWay(format!("Invalid lease line: {line}"));
+        let mut cols = line.split_whitespace();
+
+        let mac: Mac =
+            cols.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+        let addr: Ipv4Addr =
+            cols.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+        let state = match cols.next() {
+            Some("bound") => LeaseState::Bound,
+            Some("declined") => LeaseState::Declined,
+            _ => return Err(bad()),
+        };
+        let expire: u64 =
+            cols.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+
+        leases.insert(
+            addr,
+            LeaseRec {
+                mac,
+                addr,
+                state,
+                expire: UNIX_EPOCH + Duration::from_secs(expire),
+            },
+        );
+    }
+
+    Ok(leases)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        env::temp_dir,
+        fs::remove_file,
+        net::Ipv4Addr,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use super::{DhcpServer, LeaseState, ServerConf};
+    use crate::{
+        application::dhcp::{
+            client::{ClientAction, ClientState, DhcpClient},
+            BootOp, DhcpMsg, DhcpMsgType, DhcpOpt,
+        },
+        datalink::Mac,
+    };
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn conf() -> ServerConf {
+        ServerConf {
+            server_id: SERVER,
+            pool: "10.0.0.0/29".parse().unwrap(),
+            routers: vec![SERVER],
+            dns: vec![SERVER],
+            lease_time: Duration::from_secs(3600),
+            reservations: HashMap::new(),
+            lease_file: None,
+        }
+    }
+
+    fn mac(n: u8) -> Mac {
+        Mac::new(0x00, 0x12, 0x34, 0x56, 0x78, n)
+    }
+
+    /// Run a client against the server on the in-process wire until bound
+    fn acquire(
+        server: &mut DhcpServer,
+        client: &mut DhcpClient,
+        now: Instant,
+    ) -> Option<Ipv4Addr> {
+        let mut outgoing = vec![];
+        while let Some(action) = client.poll(now) {
+            if let ClientAction::Send(transmit) = action {
+                outgoing.push(transmit.msg);
+            }
+        }
+
+        while let Some(req) = outgoing.pop() {
+            let req = DhcpMsg::decode(&req.to_bytes()).unwrap();
+            let reply = server.handle(&req, SystemTime::now()).unwrap()?;
+            let reply = DhcpMsg::decode(&reply.msg.to_bytes()).unwrap();
+
+            match client.handle(&reply, now).unwrap() {
+                Some(ClientAction::Send(transmit)) => {
+                    outgoing.push(transmit.msg)
+                }
+                Some(ClientAction::Bound(lease)) => return Some(lease.addr),
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    #[test]
+    fn test_dhcp_server_pool() {
+        let now = Instant::now();
+        let mut conf = conf();
+        conf.reservations.insert(mac(9), Ipv4Addr::new(10, 0, 0, 6));
+        let mut server = DhcpServer::new(conf).unwrap();
+
+        /* .1 is the server, .6 is reserved */
+        let expected = [2, 3, 4, 5];
+        let mut clients = vec![];
+
+        for (i, last) in expected.iter().enumerate() {
+            let mut client = DhcpClient::new(mac(i as u8), now);
+            let addr = acquire(&mut server, &mut client, now);
+
+            assert_eq!(addr, Some(Ipv4Addr::new(10, 0, 0, *last)));
+            assert_eq!(client.state(), ClientState::Bound);
+            clients.push(client);
+        }
+
+        /* exhausted */
+        let mut client = DhcpClient::new(mac(100), now);
+        assert_eq!(acquire(&mut server, &mut client, now), None);
+
+        /* reservation */
+        let mut client = DhcpClient::new(mac(9), now);
+        assert_eq!(
+            acquire(&mut server, &mut client, now),
+            Some(Ipv4Addr::new(10, 0, 0, 6))
+        );
+
+        /* release, then the address can be reused */
+        let release = clients[0].release(now).unwrap();
+        server.handle(&release.msg, SystemTime::now()).unwrap();
+        assert!(server.lease_of(mac(0)).is_none());
+
+        let mut client = DhcpClient::new(mac(100), now);
+        assert_eq!(
+            acquire(&mut server, &mut client, now),
+            Some(Ipv4Addr::new(10, 0, 0, 2))
+        );
+
+        /* reserved one leased to another client isn't taken over */
+        server
+            .conf
+            .reservations
+            .insert(mac(50), Ipv4Addr::new(10, 0, 0, 3));
+        let mut client = DhcpClient::new(mac(50), now);
+        assert_eq!(acquire(&mut server, &mut client, now), None);
+        assert_eq!(
+            server.lease_of(mac(1)).unwrap().addr,
+            Ipv4Addr::new(10, 0, 0, 3)
+        );
+    }
+
+    #[test]
+    fn test_dhcp_server_decline_and_nak() {
+        let now = Instant::now();
+        let mut server = DhcpServer::new(conf()).unwrap();
+
+        let mut client = DhcpClient::new(mac(1), now);
+        let addr = acquire(&mut server, &mut client, now).unwrap();
+
+        /* DECLINE keeps the address out of the pool */
+        let decline = client.decline(now).unwrap();
+        server.handle(&decline.msg, SystemTime::now()).unwrap();
+        assert_eq!(
+            server.leases().find(|rec| rec.addr == addr).unwrap().state,
+            LeaseState::Declined
+        );
+
+        let addr2 = acquire(&mut server, &mut client, now).unwrap();
+        assert_ne!(addr, addr2);
+
+        /* INIT-REBOOT REQUEST for an address owned by other is NAKed */
+        let mut req =
+            DhcpMsg::new(BootOp::Request, DhcpMsgType::Request, 1, mac(2));
+        req.opts.push(DhcpOpt::RequestedIP(addr2));
+
+        let reply = server.handle(&req, SystemTime::now()).unwrap().unwrap();
+        assert_eq!(reply.msg.msg_type().unwrap(), DhcpMsgType::Nak);
+        assert_eq!(reply.dst.ip(), &Ipv4Addr::BROADCAST);
+    }
+
+    #[test]
+    fn test_dhcp_server_persist() {
+        let path = temp_dir()
+            .join(format!("netlib-dhcp-leases-{}", std::process::id()));
+        let _ = remove_file(&path);
+
+        let mut conf = conf();
+        conf.lease_file = Some(path.clone());
+
+        let now = Instant::now();
+        let mut server = DhcpServer::new(conf.clone()).unwrap();
+        let mut client = DhcpClient::new(mac(1), now);
+        let addr = acquire(&mut server, &mut client, now).unwrap();
+        drop(server);
+
+        let server = DhcpServer::new(conf).unwrap();
+        let rec = server.lease_of(mac(1)).unwrap();
+
+        assert_eq!(rec.addr, addr);
+        assert_eq!(rec.state, LeaseState::Bound);
+
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,950 @@
+//! DNS message ([rfc1035](https://www.rfc-editor.org/rfc/rfc1035),
+//! AAAA from [rfc3596](https://www.rfc-editor.org/rfc/rfc3596),
+//! SRV from [rfc2782](https://www.rfc-editor.org/rfc/rfc2782))
+//!
+//! Names are kept as dotted strings without the trailing root dot,
+//! root itself is the empty string.
+
+pub mod resolver;
+
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{
+    enum_try_from_int,
+    rs_error::{DnsKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const DNS_PORT: u16 = 53;
+
+/// Maximum UDP payload without EDNS0
+pub const DNS_UDP_MAX: usize = 512;
+
+pub const DNS_HDR_LEN: usize = 12;
+
+pub const CLASS_IN: u16 = 1;
+
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+/// Upper bound of compression pointers followed in one name
+const MAX_POINTER_JUMPS: usize = 64;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+enum_try_from_int! {
+    #[repr(u16)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum RType {
+        A = 1,
+        NS = 2,
+        CNAME = 5,
+        SOA = 6,
+        PTR = 12,
+        MX = 15,
+        TXT = 16,
+        AAAA = 28,
+        SRV = 33,
+        /// QTYPE only
+        ANY = 255,
+    }
+
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Rcode {
+        NoError = 0,
+        FormErr = 1,
+        ServFail = 2,
+        NXDomain = 3,
+        NotImp = 4,
+        Refused = 5,
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsHdr {
+    pub id: u16,
+    /// Response
+    pub qr: bool,
+    pub opcode: u8,
+    /// Authoritative answer
+    pub aa: bool,
+    /// Truncated
+    pub tc: bool,
+    /// Recursion desired
+    pub rd: bool,
+    /// Recursion available
+    pub ra: bool,
+    pub rcode: u8,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    NS(String),
+    CNAME(String),
+    SOA {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR(String),
+    MX {
+        preference: u16,
+        exchange: String,
+    },
+    /// Character strings, decoded lossy as UTF-8
+    TXT(Vec<String>),
+    AAAA(Ipv6Addr),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// Unrecognized type with raw RDATA
+    Other(u16, Vec<u8>),
+}
+
+
+/// Resource record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RR {
+    pub name: String,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsMsg {
+    pub hdr: DnsHdr,
+    pub questions: Vec<Question>,
+    pub answers: Vec<RR>,
+    pub authorities: Vec<RR>,
+    pub additionals: Vec<RR>,
+}
+
+
+/// Cursor over the whole message, needed for following compression pointers
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+
+/// Encoder with name compression table
+struct Writer {
+    buf: Vec<u8>,
+    /// lowercase name suffix => offset
+    names: HashMap<String, usize>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Rcode {
+    pub fn of(hdr: &DnsHdr) -> std::result::Result<Self, u8> {
+        Self::try_from(hdr.rcode)
+    }
+}
+
+
+impl DnsHdr {
+    fn flags(&self) -> u16 {
+        (self.qr as u16) << 15
+            | (self.opcode as u16 & 0xf) << 11
+            | (self.aa as u16) << 10
+            | (self.tc as u16) << 9
+            | (self.rd as u16) << 8
+            | (self.ra as u16) << 7
+            | (self.rcode as u16 & 0xf)
+    }
+
+    fn set_flags(&mut self, flags: u16) {
+        self.qr = flags & 0x8000 != 0;
+        self.opcode = ((flags >> 11) & 0xf) as u8;
+        self.aa = flags & 0x0400 != 0;
+        self.tc = flags & 0x0200 != 0;
+        self.rd = flags & 0x0100 != 0;
+        self.ra = flags & 0x0080 != 0;
+        self.rcode = (flags & 0xf) as u8;
+    }
+}
+
+
+impl Question {
+    pub fn new(name: &str, qtype: RType) -> Self {
+        Self {
+            name: name.trim_end_matches('.').to_owned(),
+            qtype: qtype as u16,
+            qclass: CLASS_IN,
+        }
+    }
+
+    /// Whether `msg` is an answer to this question (name is case insensitive)
+    pub fn is_answered_by(&self, msg: &DnsMsg) -> bool {
+        msg.questions.len() == 1 && {
+            let q = &msg.questions[0];
+
+            q.qtype == self.qtype
+                && q.qclass == self.qclass
+                && q.name.eq_ignore_ascii_case(&self.name)
+        }
+    }
+}
+
+
+impl RData {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            Self::A(_) => RType::A as u16,
+            Self::NS(_) => RType::NS as u16,
+            Self::CNAME(_) => RType::CNAME as u16,
+            Self::SOA { .. } => RType::SOA as u16,
+            Self::PTR(_) => RType::PTR as u16,
+            Self::MX { .. } => RType::MX as u16,
+            Self::TXT(_) => RType::TXT as u16,
+            Self::AAAA(_) => RType::AAAA as u16,
+            Self::SRV { .. } => RType::SRV as u16,
+            Self::Other(rtype, _) => *rtype,
+        }
+    }
+
+    fn decode(rtype: u16, r: &mut Reader, len: usize) -> Result<Self> {
+        let start = r.pos;
+        let end = start + len;
+
+        if end > r.buf.len() {
+            return Err(NetErr::Dns(DnsKind::TooShort(r.buf.len())));
+        }
+
+        let it = match RType::try_from(rtype) {
+            Ok(RType::A) if len == 4 => Self::A(Ipv4Addr::from(r.u32()?)),
+            Ok(RType::AAAA) if len == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(r.bytes(16)?);
+                Self::AAAA(Ipv6Addr::from(octets))
+            }
+            Ok(RType::NS) => Self::NS(r.name()?),
+            Ok(RType::CNAME) => Self::CNAME(r.name()?),
+            Ok(RType::PTR) => Self::PTR(r.name()?),
+            Ok(RType::SOA) => Self::SOA {
+                mname: r.name()?,
+                rname: r.name()?,
+                serial: r.u32()?,
+                refresh: r.u32()?,
+                retry: r.u32()?,
+                expire: r.u32()?,
+                minimum: r.u32()?,
+            },
+            Ok(RType::MX) => Self::MX {
+                preference: r.u16()?,
+                exchange: r.name()?,
+            },
+            Ok(RType::SRV) => Self::SRV {
+                priority: r.u16()?,
+                weight: r.u16()?,
+                port: r.u16()?,
+                target: r.name()?,
+            },
+            Ok(RType::TXT) => {
+                let mut strings = vec![];
+
+                while r.pos < end {
+                    let n = r.u8()? as usize;
+                    strings.push(
+                        String::from_utf8_lossy(r.bytes(n)?).into_owned(),
+                    );
+                }
+
+                Self::TXT(strings)
+            }
+            Ok(RType::A | RType::AAAA) => {
+                return Err(NetErr::Dns(DnsKind::BadRData(rtype)))
+            }
+            _ => Self::Other(rtype, r.bytes(len)?.to_vec()),
+        };
+
+        if r.pos != end {
+            return Err(NetErr::Dns(DnsKind::BadRData(rtype)));
+        }
+
+        Ok(it)
+    }
+
+    fn encode(&self, w: &mut Writer) -> Result<()> {
+        match self {
+            Self::A(ip) => w.bytes(&ip.octets()),
+            Self::AAAA(ip) => w.bytes(&ip.octets()),
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => {
+                w.name(name)?
+            }
+            Self::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                w.name(mname)?;
+                w.name(rname)?;
+
+                for v in [serial, refresh, retry, expire, minimum] {
+                    w.u32(*v);
+                }
+            }
+            Self::MX {
+                preference,
+                exchange,
+            } => {
+                w.u16(*preference);
+                w.name(exchange)?;
+            }
+            Self::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                w.u16(*priority);
+                w.u16(*weight);
+                w.u16(*port);
+                // rfc2782: target name must not be compressed
+                w.name_uncompressed(target)?;
+            }
+            Self::TXT(strings) => {
+                // at least one <character-string> (rfc1035 3.3.14)
+                if strings.is_empty() {
+                    w.u8(0);
+                }
+
+                for s in strings {
+                    if s.is_empty() {
+                        w.u8(0);
+                    }
+
+                    for chunk in s.as_bytes().chunks(255) {
+                        w.u8(chunk.len() as u8);
+                        w.bytes(chunk);
+                    }
+                }
+            }
+            Self::Other(_, raw) => w.bytes(raw),
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Display for RData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::A(ip) => write!(f, "{ip}"),
+            Self::AAAA(ip) => write!(f, "{ip}"),
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => {
+                write!(f, "{name}.")
+            }
+            Self::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{mname}. {rname}. {serial} {refresh} {retry} {expire} {minimum}"
+            ),
+            Self::MX {
+                preference,
+                exchange,
+            } => write!(f, "{preference} {exchange}."),
+            Self::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{priority} {weight} {port} {target}."),
+            Self::TXT(strings) => {
+                let quoted: Vec<String> =
+                    strings.iter().map(|s| format!("{s:?}")).collect();
+                write!(f, "{}", quoted.join(" "))
+            }
+            Self::Other(rtype, raw) => {
+                write!(f, "TYPE{rtype} \\# {}", raw.len())
+            }
+        }
+    }
+}
+
+
+impl RR {
+    pub fn new(name: &str, ttl: u32, data: RData) -> Self {
+        Self {
+            name: name.trim_end_matches('.').to_owned(),
+            class: CLASS_IN,
+            ttl,
+            data,
+        }
+    }
+
+    pub fn rtype(&self) -> u16 {
+        self.data.rtype()
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self> {
+        let name = r.name()?;
+        let rtype = r.u16()?;
+        let class = r.u16()?;
+        let ttl = r.u32()?;
+        let len = r.u16()? as usize;
+        let data = RData::decode(rtype, r, len)?;
+
+        Ok(Self {
+            name,
+            class,
+            ttl,
+            data,
+        })
+    }
+
+    fn encode(&self, w: &mut Writer) -> Result<()> {
+        w.name(&self.name)?;
+        w.u16(self.rtype());
+        w.u16(self.class);
+        w.u32(self.ttl);
+
+        let len_pos = w.buf.len();
+        w.u16(0);
+        self.data.encode(w)?;
+
+        let len = w.buf.len() - len_pos - 2;
+
+        if len > u16::MAX as usize {
+            return Err(NetErr::Dns(DnsKind::BadRData(self.rtype())));
+        }
+        w.buf[len_pos..len_pos + 2]
+            .copy_from_slice(&(len as u16).to_be_bytes());
+
+        Ok(())
+    }
+}
+
+
+impl Display for RR {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let rtype = self.rtype();
+        let ty = match RType::try_from(rtype) {
+            Ok(ty) => format!("{ty:?}"),
+            Err(_) => format!("TYPE{rtype}"),
+        };
+
+        write!(f, "{}.\t{}\tIN\t{ty}\t{}", self.name, self.ttl, self.data)
+    }
+}
+
+
+impl DnsMsg {
+    /// Standard recursive query with one question
+    pub fn query(id: u16, name: &str, qtype: RType) -> Self {
+        Self {
+            hdr: DnsHdr {
+                id,
+                rd: true,
+                ..Default::default()
+            },
+            questions: vec![Question::new(name, qtype)],
+            ..Default::default()
+        }
+    }
+
+    /// Empty response echoing id, rd and questions of `query`
+    pub fn reply_to(query: &DnsMsg) -> Self {
+        Self {
+            hdr: DnsHdr {
+                id: query.hdr.id,
+                qr: true,
+                opcode: query.hdr.opcode,
+                rd: query.hdr.rd,
+                ..Default::default()
+            },
+            questions: query.questions.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < DNS_HDR_LEN {
+            return Err(NetErr::Dns(DnsKind::TooShort(bytes.len())));
+        }
+
+        let mut r = Reader { buf: bytes, pos: 0 };
+        let mut hdr = DnsHdr {
+            id: r.u16()?,
+            ..Default::default()
+        };
+        hdr.set_flags(r.u16()?);
+
+        let qdcount = r.u16()?;
+        let ancount = r.u16()?;
+        let nscount = r.u16()?;
+        let arcount = r.u16()?;
+
+        let mut msg = Self {
+            hdr,
+            ..Default::default()
+        };
+
+        for _ in 0..qdcount {
+            msg.questions.push(Question {
+                name: r.name()?,
+                qtype: r.u16()?,
+                qclass: r.u16()?,
+            });
+        }
+
+        for (count, sec) in [
+            (ancount, &mut msg.answers),
+            (nscount, &mut msg.authorities),
+            (arcount, &mut msg.additionals),
+        ] {
+            for _ in 0..count {
+                sec.push(RR::decode(&mut r)?);
+            }
+        }
+
+        Ok(msg)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut w = Writer {
+            buf: Vec::with_capacity(DNS_UDP_MAX),
+            names: HashMap::new(),
+        };
+
+        w.u16(self.hdr.id);
+        w.u16(self.hdr.flags());
+
+        for n in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            w.u16(n as u16);
+        }
+
+        for q in self.questions.iter() {
+            w.name(&q.name)?;
+            w.u16(q.qtype);
+            w.u16(q.qclass);
+        }
+
+        for rr in self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additionals.iter())
+        {
+            rr.encode(&mut w)?;
+        }
+
+        Ok(w.buf)
+    }
+
+    /// Encode for UDP, drop records from the tail and set TC if it exceeds
+    /// `max` bytes.
+    pub fn to_udp_bytes(&self, max: usize) -> Result<Vec<u8>> {
+        let bytes = self.to_bytes()?;
+
+        if bytes.len() <= max {
+            return Ok(bytes);
+        }
+
+        let mut truncated = Self {
+            hdr: self.hdr.clone(),
+            questions: self.questions.clone(),
+            answers: self.answers.clone(),
+            ..Default::default()
+        };
+        truncated.hdr.tc = true;
+
+        loop {
+            let bytes = truncated.to_bytes()?;
+
+            if bytes.len() <= max || truncated.answers.is_empty() {
+                return Ok(bytes);
+            }
+            truncated.answers.pop();
+        }
+    }
+
+    pub fn rcode(&self) -> std::result::Result<Rcode, u8> {
+        Rcode::of(&self.hdr)
+    }
+}
+
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.buf.len() {
+            return Err(NetErr::Dns(DnsKind::TooShort(self.buf.len())));
+        }
+
+        let it = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+
+        Ok(it)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Read a possibly compressed name, cursor stops after the first pointer
+    fn name(&mut self) -> Result<String> {
+        let mut labels: Vec<String> = vec![];
+        let mut wire_len = 1;
+        let mut pos = self.pos;
+        let mut resume = None;
+        let mut jumps = 0;
+
+        loop {
+            let len = *self
+                .buf
+                .get(pos)
+                .ok_or(NetErr::Dns(DnsKind::TooShort(self.buf.len())))?
+                as usize;
+
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self.buf.get(pos + 1..pos + 1 + len).ok_or(
+                        NetErr::Dns(DnsKind::TooShort(self.buf.len())),
+                    )?;
+
+                    wire_len += 1 + len;
+                    if wire_len > MAX_NAME_LEN {
+                        return Err(NetErr::Dns(DnsKind::BadName(
+                            labels.join("."),
+                        )));
+                    }
+
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                0xc0 => {
+                    let lo = *self.buf.get(pos + 1).ok_or(NetErr::Dns(
+                        DnsKind::TooShort(self.buf.len()),
+                    ))?;
+                    let target = (len & 0x3f) << 8 | lo as usize;
+
+                    jumps += 1;
+                    if jumps > MAX_POINTER_JUMPS || target >= self.buf.len() {
+                        return Err(NetErr::Dns(DnsKind::BadPointer(pos)));
+                    }
+
+                    if resume.is_none() {
+                        resume = Some(pos + 2);
+                    }
+                    pos = target;
+                }
+                // 0x40, 0x80 are extended / reserved label types
+                _ => {
+                    return Err(NetErr::Dns(DnsKind::BadName(
+                        labels.join("."),
+                    )))
+                }
+            }
+        }
+
+        self.pos = resume.unwrap_or(pos);
+
+        Ok(labels.join("."))
+    }
+}
+
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    fn name(&mut self, name: &str) -> Result<()> {
+        self.name_(name, true)
+    }
+
+    fn name_uncompressed(&mut self, name: &str) -> Result<()> {
+        self.name_(name, false)
+    }
+
+    fn name_(&mut self, name: &str, compress: bool) -> Result<()> {
+        let labels = split_name(name)?;
+
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_ascii_lowercase();
+
+            if compress {
+                if let Some(&off) = self.names.get(&suffix) {
+                    self.u16(0xc000 | off as u16);
+                    return Ok(());
+                }
+            }
+
+            // pointer has only 14 bits of offset
+            if self.buf.len() < 0x4000 {
+                self.names.entry(suffix).or_insert(self.buf.len());
+            }
+
+            self.u8(labels[i].len() as u8);
+            self.bytes(labels[i].as_bytes());
+        }
+
+        self.u8(0);
+
+        Ok(())
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Split and validate a dotted name, tailing root dot is optional
+fn split_name(name: &str) -> Result<Vec<&str>> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    if name.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let labels: Vec<&str> = name.split('.').collect();
+    let wire_len = labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1;
+
+    if wire_len > MAX_NAME_LEN
+        || labels
+            .iter()
+            .any(|l| l.is_empty() || l.len() > MAX_LABEL_LEN)
+    {
+        return Err(NetErr::Dns(DnsKind::BadName(name.to_owned())));
+    }
+
+    Ok(labels)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::{DnsMsg, RData, RType, Rcode, DNS_HDR_LEN, RR};
+
+    #[test]
+    fn test_dns_codec() {
+        let query = DnsMsg::query(0x1234, "www.example.com.", RType::A);
+        let bytes = query.to_bytes().unwrap();
+
+        assert_eq!(bytes.len(), DNS_HDR_LEN + 17 + 4);
+        assert_eq!(&bytes[2..4], &[0x01, 0x00]);
+        assert_eq!(DnsMsg::decode(&bytes).unwrap(), query);
+
+        let mut resp = DnsMsg::reply_to(&query);
+        resp.hdr.ra = true;
+        resp.answers.extend([
+            RR::new(
+                "www.example.com",
+                300,
+                RData::CNAME("web.example.com".to_owned()),
+            ),
+            RR::new(
+                "web.example.com",
+                300,
+                RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            ),
+            RR::new("web.example.com", 300, RData::AAAA(Ipv6Addr::LOCALHOST)),
+            RR::new(
+                "example.com",
+                60,
+                RData::MX {
+                    preference: 10,
+                    exchange: "mail.example.com".to_owned(),
+                },
+            ),
+            RR::new(
+                "example.com",
+                60,
+                RData::TXT(vec!["v=spf1 -all".to_owned(), "x".repeat(300)]),
+            ),
+            RR::new(
+                "_sip._udp.example.com",
+                60,
+                RData::SRV {
+                    priority: 1,
+                    weight: 2,
+                    port: 5060,
+                    target: "sip.example.com".to_owned(),
+                },
+            ),
+            RR::new(
+                "4.3.2.1.in-addr.arpa",
+                60,
+                RData::PTR("example.com".to_owned()),
+            ),
+            RR::new("example.com", 60, RData::Other(99, vec![1, 2, 3])),
+        ]);
+        resp.authorities.push(RR::new(
+            "example.com",
+            60,
+            RData::SOA {
+                mname: "ns.example.com".to_owned(),
+                rname: "root.example.com".to_owned(),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            },
+        ));
+
+        let bytes = resp.to_bytes().unwrap();
+        let resp2 = DnsMsg::decode(&bytes).unwrap();
+
+        assert!(resp2.hdr.qr && resp2.hdr.ra && resp2.hdr.rd);
+        assert_eq!(resp2.rcode(), Ok(Rcode::NoError));
+        assert_eq!(resp2.answers[..4], resp.answers[..4]);
+        // long TXT string is split into 255 bytes chunks
+        assert_eq!(
+            resp2.answers[4].data,
+            RData::TXT(vec![
+                "v=spf1 -all".to_owned(),
+                "x".repeat(255),
+                "x".repeat(45)
+            ])
+        );
+        assert_eq!(resp2.answers[5..], resp.answers[5..]);
+        assert_eq!(resp2.authorities, resp.authorities);
+
+        // empty TXT is still one empty <character-string>
+        for (txt, decoded) in [
+            (vec![], vec![""]),
+            (vec![""], vec![""]),
+            (vec!["a", "", "b"], vec!["a", "", "b"]),
+        ] {
+            let mut msg = DnsMsg::reply_to(&query);
+            let txt = txt.into_iter().map(str::to_owned).collect();
+            msg.answers
+                .push(RR::new("example.com", 60, RData::TXT(txt)));
+
+            let bytes = msg.to_bytes().unwrap();
+            let msg = DnsMsg::decode(&bytes).unwrap();
+            assert_eq!(
+                msg.answers[0].data,
+                RData::TXT(decoded.into_iter().map(str::to_owned).collect())
+            );
+        }
+
+        // compression: "example" is written literally only once, except the
+        // uncompressed SRV target
+        let occurs = bytes.windows(7).filter(|w| w == b"example").count();
+        assert_eq!(occurs, 2);
+
+        for n in 0..bytes.len() {
+            assert!(DnsMsg::decode(&bytes[..n]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_dns_bad_name() {
+        assert!(DnsMsg::query(1, &"a".repeat(64), RType::A)
+            .to_bytes()
+            .is_err());
+        assert!(DnsMsg::query(1, "a..b", RType::A).to_bytes().is_err());
+
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert!(DnsMsg::query(1, &long, RType::A).to_bytes().is_err());
+
+        // pointer to itself
+        let mut bytes = DnsMsg::query(1, "", RType::A).to_bytes().unwrap();
+        bytes[4..6].copy_from_slice(&[0, 1]);
+        bytes[DNS_HDR_LEN] = 0xc0;
+        bytes.insert(DNS_HDR_LEN + 1, DNS_HDR_LEN as u8);
+        assert!(DnsMsg::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_dns_truncate() {
+        let query = DnsMsg::query(1, "example.com", RType::A);
+        let mut resp = DnsMsg::reply_to(&query);
+
+        for i in 0..100 {
+            resp.answers.push(RR::new(
+                "example.com",
+                1,
+                RData::A(Ipv4Addr::new(10, 0, 0, i)),
+            ));
+        }
+
+        let bytes = resp.to_udp_bytes(512).unwrap();
+        let resp2 = DnsMsg::decode(&bytes).unwrap();
+
+        assert!(bytes.len() <= 512);
+        assert!(resp2.hdr.tc);
+        assert!(!resp2.answers.is_empty() && resp2.answers.len() < 100);
+    }
+}
@@ -0,0 +1,792 @@
+//! Stub resolver, UDP first and retry over TCP on truncation
+//!
+//! Configuration follows resolv.conf(5): `nameserver`, `search`/`domain`,
+//! `options ndots: timeout: attempts: use-vc`.
+
+use std::{
+    fs::read_to_string,
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use super::{DnsMsg, RData, RType, Rcode, DNS_PORT};
+use crate::{
+    aux::random_u16,
+    rs_error::{DnsKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+pub const HOSTS: &str = "/etc/hosts";
+
+/// MAXNS of glibc
+const MAX_NAMESERVERS: usize = 3;
+
+/// Longest CNAME chain followed inside one answer
+const MAX_CNAME_CHAIN: usize = 8;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<SocketAddr>,
+    pub search: Vec<String>,
+    /// Names with fewer dots are tried with search domains first
+    pub ndots: usize,
+    /// Per server per attempt
+    pub timeout: Duration,
+    /// Rounds over all nameservers
+    pub attempts: usize,
+    /// Always query over TCP
+    pub use_tcp: bool,
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    pub conf: ResolvConf,
+    /// Static entries consulted before DNS, like `/etc/hosts`
+    pub hosts: Vec<(IpAddr, Vec<String>)>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Default for ResolvConf {
+    /// Defaults of resolv.conf(5)
+    fn default() -> Self {
+        Self {
+            nameservers: vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                DNS_PORT,
+            )],
+            search: vec![],
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            use_tcp: false,
+        }
+    }
+}
+
+
+impl ResolvConf {
+    /// Load from file, missing file results in default configuration
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        match read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Ok(Self::default())
+            }
+            Err(err) => Err(NetErr::Open(err)),
+        }
+    }
+
+    /// Unrecognized lines are ignored like libc does
+    pub fn parse(text: &str) -> Self {
+        let mut conf = Self::default();
+        let mut nameservers = vec![];
+
+        for line in text.lines() {
+            let line = line.split(['#', ';']).next().unwrap();
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("nameserver") => {
+                    // drop IPv6 zone index, e.g. fe80::1%eth0
+                    let addr = words
+                        .next()
+                        .and_then(|w| w.split('%').next())
+                        .and_then(|w| w.parse::<IpAddr>().ok());
+
+                    if let Some(addr) = addr {
+                        if nameservers.len() < MAX_NAMESERVERS {
+                            nameservers.push(SocketAddr::new(addr, DNS_PORT));
+                        }
+                    }
+                }
+                // the last one of `domain` and `search` wins
+                Some("domain") => {
+                    conf.search = words.take(1).map(str::to_owned).collect();
+                }
+                Some("search") => {
+                    conf.search = words.map(str::to_owned).collect();
+                }
+                Some("options") => {
+                    for opt in words {
+                        let (key, val) =
+                            opt.split_once(':').unwrap_or((opt, ""));
+                        let val = val.parse::<u64>().ok();
+
+                        match (key, val) {
+                            ("ndots", Some(n)) => {
+                                conf.ndots = n.min(15) as usize
+                            }
+                            ("timeout", Some(n)) => {
+                                conf.timeout =
+                                    Duration::from_secs(n.clamp(1, 30))
+                            }
+                            ("attempts", Some(n)) => {
+                                conf.attempts = n.clamp(1, 5) as usize
+                            }
+                            ("use-vc" | "usevc", _) => conf.use_tcp = true,
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if !nameservers.is_empty() {
+            conf.nameservers = nameservers;
+        }
+
+        conf
+    }
+
+    /// Candidate names for a query, in order (see resolv.conf(5) `ndots`)
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(fqdn) = name.strip_suffix('.') {
+            return vec![fqdn.to_owned()];
+        }
+
+        let searched = self.search.iter().map(|d| format!("{name}.{d}"));
+
+        if name.matches('.').count() >= self.ndots {
+            std::iter::once(name.to_owned()).chain(searched).collect()
+        }
+        else {
+            searched.chain(std::iter::once(name.to_owned())).collect()
+        }
+    }
+}
+
+
+impl Resolver {
+    pub fn new(conf: ResolvConf) -> Self {
+        Self {
+            conf,
+            hosts: vec![],
+        }
+    }
+
+    /// Configured from `/etc/resolv.conf` and `/etc/hosts`
+    pub fn system() -> Result<Self> {
+        let hosts = match read_to_string(HOSTS) {
+            Ok(text) => parse_hosts(&text),
+            Err(_) => vec![],
+        };
+
+        Ok(Self {
+            conf: ResolvConf::load(RESOLV_CONF)?,
+            hosts,
+        })
+    }
+
+    /// Send one question to the nameservers in turn until one of them gives
+    /// an authoritative result (NOERROR or NXDOMAIN).
+    pub fn query(&self, name: &str, qtype: RType) -> Result<DnsMsg> {
+        self.exchange(&DnsMsg::query(random_u16(), name, qtype))
+    }
+
+    /// Like `query` but with a prepared message (e.g. forwarded from a client)
+    pub fn exchange(&self, query: &DnsMsg) -> Result<DnsMsg> {
+        // no reply could ever match it
+        if query.questions.len() != 1 {
+            return Err(NetErr::Dns(DnsKind::NoQuestion));
+        }
+
+        let bytes = query.to_bytes()?;
+
+        let mut last_err = NetErr::Dns(DnsKind::Timeout);
+
+        for _ in 0..self.conf.attempts {
+            for ns in self.conf.nameservers.iter() {
+                let res = if self.conf.use_tcp {
+                    self.exchange_tcp(*ns, query, &bytes)
+                }
+                else {
+                    match self.exchange_udp(*ns, query, &bytes) {
+                        Ok(resp) if resp.hdr.tc => {
+                            self.exchange_tcp(*ns, query, &bytes)
+                        }
+                        res => res,
+                    }
+                };
+
+                match res {
+                    Ok(resp) => match resp.rcode() {
+                        Ok(Rcode::NoError | Rcode::NXDomain) => {
+                            return Ok(resp)
+                        }
+                        _ => {
+                            last_err =
+                                NetErr::Dns(DnsKind::Rcode(resp.hdr.rcode))
+                        }
+                    },
+                    Err(err) => last_err = err,
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Records of `qtype` for `name`, applying search domains and following
+    /// CNAME inside answers.
+    pub fn lookup(&self, name: &str, qtype: RType) -> Result<Vec<RData>> {
+        let mut last_err = None;
+
+        for cand in self.conf.candidates(name) {
+            let resp = match self.query(&cand, qtype) {
+                Ok(resp) => resp,
+                Err(err) => {
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+
+            if resp.hdr.rcode == Rcode::NXDomain as u8 {
+                continue;
+            }
+
+            let found = collect_answers(&resp, &cand, qtype);
+
+            if !found.is_empty() {
+                return Ok(found);
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            NetErr::Dns(DnsKind::NoAnswer(name.to_owned()))
+        }))
+    }
+
+    pub fn lookup_ipv4(&self, host: &str) -> Result<Vec<Ipv4Addr>> {
+        let mut found: Vec<Ipv4Addr> = self
+            .lookup_hosts(host)
+            .into_iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+
+        if found.is_empty() {
+            for data in self.lookup(host, RType::A)? {
+                if let RData::A(ip) = data {
+                    found.push(ip);
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub fn lookup_ipv6(&self, host: &str) -> Result<Vec<Ipv6Addr>> {
+        let mut found: Vec<Ipv6Addr> = self
+            .lookup_hosts(host)
+            .into_iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V6(ip) => Some(ip),
+                IpAddr::V4(_) => None,
+            })
+            .collect();
+
+        if found.is_empty() {
+            for data in self.lookup(host, RType::AAAA)? {
+                if let RData::AAAA(ip) = data {
+                    found.push(ip);
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// IPv4 addresses first, fail only if both families fail
+    pub fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let v4 = self.lookup_ipv4(host);
+        let v6 = self.lookup_ipv6(host);
+
+        match (v4, v6) {
+            (Err(err), Err(_)) => Err(err),
+            (v4, v6) => Ok(v4
+                .unwrap_or_default()
+                .into_iter()
+                .map(IpAddr::V4)
+                .chain(v6.unwrap_or_default().into_iter().map(IpAddr::V6))
+                .collect()),
+        }
+    }
+
+    /// PTR lookup
+    pub fn reverse(&self, ip: IpAddr) -> Result<Vec<String>> {
+        let name = reverse_name(ip);
+
+        Ok(self
+            .lookup(&format!("{name}."), RType::PTR)?
+            .into_iter()
+            .filter_map(|data| match data {
+                RData::PTR(name) => Some(name),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn lookup_hosts(&self, host: &str) -> Vec<IpAddr> {
+        let host = host.trim_end_matches('.');
+
+        self.hosts
+            .iter()
+            .filter(|(_, names)| {
+                names.iter().any(|n| n.eq_ignore_ascii_case(host))
+            })
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+
+    fn exchange_udp(
+        &self,
+        ns: SocketAddr,
+        query: &DnsMsg,
+        bytes: &[u8],
+    ) -> Result<DnsMsg> {
+        let local = match ns {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        let sock = UdpSocket::bind((local, 0)).map_err(|_| NetErr::Bind)?;
+        sock.connect(ns).map_err(NetErr::Connect)?;
+        sock.send(bytes).map_err(NetErr::Write)?;
+
+        let deadline = Instant::now() + self.conf.timeout;
+        let mut buf = [0u8; 65535];
+
+        // drop stray or forged replies until deadline
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+
+            if left.is_zero() {
+                return Err(NetErr::Dns(DnsKind::Timeout));
+            }
+            sock.set_read_timeout(Some(left))
+                .map_err(NetErr::SetStreamOpt)?;
+
+            let n = match sock.recv(&mut buf) {
+                Ok(n) => n,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Err(NetErr::Dns(DnsKind::Timeout))
+                }
+                Err(err) => return Err(NetErr::Read(err)),
+            };
+
+            if let Ok(resp) = DnsMsg::decode(&buf[..n]) {
+                if is_reply_of(&resp, query) {
+                    return Ok(resp);
+                }
+            }
+        }
+    }
+
+    fn exchange_tcp(
+        &self,
+        ns: SocketAddr,
+        query: &DnsMsg,
+        bytes: &[u8],
+    ) -> Result<DnsMsg> {
+        let mut stream = TcpStream::connect_timeout(&ns, self.conf.timeout)
+            .map_err(NetErr::Connect)?;
+
+        stream
+            .set_read_timeout(Some(self.conf.timeout))
+            .map_err(NetErr::SetStreamOpt)?;
+        stream
+            .set_write_timeout(Some(self.conf.timeout))
+            .map_err(NetErr::SetStreamOpt)?;
+
+        // two bytes length prefix (rfc1035 4.2.2)
+        let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(bytes);
+        stream.write_all(&framed).map_err(NetErr::Write)?;
+
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).map_err(NetErr::Read)?;
+
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).map_err(NetErr::Read)?;
+
+        let resp = DnsMsg::decode(&buf)?;
+
+        if !is_reply_of(&resp, query) {
+            return Err(match query.questions.first() {
+                Some(q) => NetErr::Dns(DnsKind::NoAnswer(q.name.clone())),
+                None => NetErr::Dns(DnsKind::NoQuestion),
+            });
+        }
+
+        Ok(resp)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// `4.3.2.1.in-addr.arpa` or nibble format `...ip6.arpa`
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(ip) => {
+            let mut labels: Vec<String> = ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|o| [o & 0xf, o >> 4])
+                .map(|n| format!("{n:x}"))
+                .collect();
+            labels.push("ip6.arpa".to_owned());
+
+            labels.join(".")
+        }
+    }
+}
+
+
+/// Parse hosts(5) text
+pub fn parse_hosts(text: &str) -> Vec<(IpAddr, Vec<String>)> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap();
+            let mut words = line.split_whitespace();
+
+            let ip = words.next()?.split('%').next()?.parse().ok()?;
+            let names: Vec<String> = words.map(str::to_owned).collect();
+
+            if names.is_empty() {
+                None
+            }
+            else {
+                Some((ip, names))
+            }
+        })
+        .collect()
+}
+
+
+fn is_reply_of(resp: &DnsMsg, query: &DnsMsg) -> bool {
+    resp.hdr.qr
+        && resp.hdr.id == query.hdr.id
+        && query
+            .questions
+            .first()
+            .map(|q| q.is_answered_by(resp))
+            .unwrap_or(false)
+}
+
+
+/// Follow CNAME chain from `name` and collect data of `qtype`
+fn collect_answers(resp: &DnsMsg, name: &str, qtype: RType) -> Vec<RData> {
+    let mut target = name.trim_end_matches('.').to_owned();
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        let found: Vec<RData> = resp
+            .answers
+            .iter()
+            .filter(|rr| {
+                rr.rtype() == qtype as u16
+                    && rr.name.eq_ignore_ascii_case(&target)
+            })
+            .map(|rr| rr.data.clone())
+            .collect();
+
+        if !found.is_empty() {
+            return found;
+        }
+
+        let cname = resp.answers.iter().find_map(|rr| match &rr.data {
+            RData::CNAME(cname) if rr.name.eq_ignore_ascii_case(&target) => {
+                Some(cname.clone())
+            }
+            _ => None,
+        });
+
+        match cname {
+            Some(cname) => target = cname,
+            None => break,
+        }
+    }
+
+    vec![]
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{
+            IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use super::{parse_hosts, reverse_name, ResolvConf, Resolver};
+    use crate::{
+        application::dns::{DnsMsg, RData, RType, Rcode, RR},
+        rs_error::{DnsKind, NetErr},
+    };
+
+    /// Answer `n` queries on UDP with `handle`, queries over TCP are answered
+    /// the same but never truncated.
+    fn mock_server<F>(
+        udp_max: usize,
+        n: usize,
+        handle: F,
+    ) -> (SocketAddr, JoinHandle<()>)
+    where
+        F: Fn(&DnsMsg) -> DnsMsg + Send + 'static,
+    {
+        // the port may be taken on TCP by clients of tests running along
+        let (udp, tcp, addr) = loop {
+            let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let addr = udp.local_addr().unwrap();
+
+            if let Ok(tcp) = TcpListener::bind(addr) {
+                break (udp, tcp, addr);
+            }
+        };
+
+        let join = thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+
+            for _ in 0..n {
+                let (len, from) = udp.recv_from(&mut buf).unwrap();
+                let query = DnsMsg::decode(&buf[..len]).unwrap();
+                let resp = handle(&query);
+                let bytes = resp.to_udp_bytes(udp_max).unwrap();
+                udp.send_to(&bytes, from).unwrap();
+
+                if resp.to_bytes().unwrap().len() > udp_max {
+                    let (mut stream, _) = tcp.accept().unwrap();
+                    let mut len = [0u8; 2];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut buf).unwrap();
+
+                    let query = DnsMsg::decode(&buf).unwrap();
+                    let bytes = handle(&query).to_bytes().unwrap();
+                    stream
+                        .write_all(&(bytes.len() as u16).to_be_bytes())
+                        .unwrap();
+                    stream.write_all(&bytes).unwrap();
+                }
+            }
+        });
+
+        (addr, join)
+    }
+
+    fn zone(query: &DnsMsg) -> DnsMsg {
+        let mut resp = DnsMsg::reply_to(query);
+        let q = &query.questions[0];
+
+        match (q.name.as_str(), RType::try_from(q.qtype)) {
+            ("www.lab.test" | "web.lab.test", Ok(RType::A)) => {
+                resp.answers.extend([
+                    RR::new(
+                        "www.lab.test",
+                        60,
+                        RData::CNAME("web.lab.test".to_owned()),
+                    ),
+                    RR::new(
+                        "web.lab.test",
+                        60,
+                        RData::A(Ipv4Addr::new(10, 0, 0, 80)),
+                    ),
+                ]);
+            }
+            ("web.lab.test", Ok(RType::AAAA)) => {
+                resp.answers.push(RR::new(
+                    "web.lab.test",
+                    60,
+                    RData::AAAA(Ipv6Addr::LOCALHOST),
+                ));
+            }
+            ("many.lab.test", Ok(RType::A)) => {
+                for i in 0..64 {
+                    resp.answers.push(RR::new(
+                        "many.lab.test",
+                        60,
+                        RData::A(Ipv4Addr::new(10, 0, 1, i)),
+                    ));
+                }
+            }
+            ("80.0.0.10.in-addr.arpa", Ok(RType::PTR)) => {
+                resp.answers.push(RR::new(
+                    "80.0.0.10.in-addr.arpa",
+                    60,
+                    RData::PTR("web.lab.test".to_owned()),
+                ));
+            }
+            ("broken.lab.test", _) => resp.hdr.rcode = Rcode::ServFail as u8,
+            _ => resp.hdr.rcode = Rcode::NXDomain as u8,
+        }
+
+        resp
+    }
+
+    fn resolver_of(nameservers: Vec<SocketAddr>) -> Resolver {
+        Resolver::new(ResolvConf {
+            nameservers,
+            search: vec!["lab.test".to_owned()],
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_resolv_conf() {
+        let conf = ResolvConf::parse(
+            "# comment\n\
+             nameserver 10.0.0.1\n\
+             nameserver fe80::1%eth0 ; zoned\n\
+             nameserver bad\n\
+             domain a.test\n\
+             search b.test c.test\n\
+             options ndots:2 timeout:3 attempts:9 rotate\n",
+        );
+
+        assert_eq!(conf.nameservers.len(), 2);
+        assert_eq!(conf.nameservers[0], "10.0.0.1:53".parse().unwrap());
+        assert_eq!(conf.search, vec!["b.test", "c.test"]);
+        assert_eq!(conf.ndots, 2);
+        assert_eq!(conf.timeout, Duration::from_secs(3));
+        assert_eq!(conf.attempts, 5);
+
+        assert_eq!(
+            conf.candidates("www"),
+            vec!["www.b.test", "www.c.test", "www"]
+        );
+        assert_eq!(
+            conf.candidates("a.b.c"),
+            vec!["a.b.c", "a.b.c.b.test", "a.b.c.c.test"]
+        );
+        assert_eq!(conf.candidates("x.y."), vec!["x.y"]);
+
+        assert_eq!(ResolvConf::parse(""), ResolvConf::default());
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!(
+            reverse_name(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 80))),
+            "80.0.0.10.in-addr.arpa"
+        );
+        assert!(reverse_name(IpAddr::V6(Ipv6Addr::LOCALHOST))
+            .starts_with("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0."));
+
+        let hosts =
+            parse_hosts("127.0.0.1 localhost lo # x\n::1 localhost\n\n#\n");
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].1, vec!["localhost", "lo"]);
+    }
+
+    #[test]
+    fn test_resolver_mock() {
+        let (ns, join) = mock_server(512, 5, zone);
+        let resolver = resolver_of(vec![ns]);
+
+        // search domain is appended, CNAME is followed
+        assert_eq!(
+            resolver.lookup_ipv4("www").unwrap(),
+            vec![Ipv4Addr::new(10, 0, 0, 80)]
+        );
+        assert_eq!(
+            resolver.lookup_ipv6("web.lab.test.").unwrap(),
+            vec![Ipv6Addr::LOCALHOST]
+        );
+        assert_eq!(
+            resolver
+                .reverse(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 80)))
+                .unwrap(),
+            vec!["web.lab.test"]
+        );
+        // NXDOMAIN for both "nx.lab.test" and "nx"
+        assert!(resolver.lookup_ipv4("nx").is_err());
+
+        join.join().unwrap();
+    }
+
+    #[test]
+    fn test_resolver_tcp_fallback() {
+        let (ns, join) = mock_server(512, 1, zone);
+        let resolver = resolver_of(vec![ns]);
+
+        let found = resolver.lookup_ipv4("many.lab.test.").unwrap();
+        assert_eq!(found.len(), 64);
+
+        join.join().unwrap();
+    }
+
+    #[test]
+    fn test_resolver_retry() {
+        // a nameserver that never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (ns, join) = mock_server(512, 2, zone);
+        let resolver = resolver_of(vec![silent.local_addr().unwrap(), ns]);
+
+        assert_eq!(
+            resolver.lookup_ipv4("web.lab.test.").unwrap(),
+            vec![Ipv4Addr::new(10, 0, 0, 80)]
+        );
+
+        // SERVFAIL is not final
+        assert!(resolver.query("broken.lab.test", RType::A).is_err());
+
+        join.join().unwrap();
+
+        let mut resolver = resolver_of(vec![silent.local_addr().unwrap()]);
+        assert!(resolver.lookup_ipv4("web.lab.test.").is_err());
+
+        resolver.hosts = parse_hosts("10.9.9.9 pinned.lab.test");
+        assert_eq!(
+            resolver.lookup_ipv4("pinned.lab.test").unwrap(),
+            vec![Ipv4Addr::new(10, 9, 9, 9)]
+        );
+
+        // nothing to send without a question, on either transport
+        let mut query = DnsMsg::query(1, "web.lab.test", RType::A);
+        query.questions.clear();
+
+        for use_tcp in [false, true] {
+            resolver.conf.use_tcp = use_tcp;
+            assert!(matches!(
+                resolver.exchange(&query),
+                Err(NetErr::Dns(DnsKind::NoQuestion))
+            ));
+        }
+    }
+}
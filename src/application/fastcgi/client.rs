@@ -0,0 +1,459 @@
+//! Client of a FastCGI Responder over TCP or Unix socket, connections are
+//! kept (FCGI_KEEP_CONN) and reused for later requests.
+
+use std::{
+    fmt::Display,
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{
+    encode_params, encode_stream, BeginRequestBody, EndRequestBody,
+    ProtocolStatus, Record, RecordType, Role, FCGI_KEEP_CONN,
+};
+use crate::{
+    rs_error::{FcgiKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Idle connections kept by default
+const DEFAULT_MAX_IDLE: usize = 8;
+
+/// Requests aren't multiplexed, so it's always the same
+const REQUEST_ID: u16 = 1;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Called with the content of each STDERR record
+pub type StderrHandler = Box<dyn FnMut(&[u8]) + Send>;
+
+/// `unix:/path/to/sock` or `host:port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FcgiAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+
+#[derive(Debug)]
+pub struct FcgiClient {
+    addr: FcgiAddr,
+    idle: Arc<Mutex<Vec<Stream>>>,
+    max_idle: usize,
+    /// Read and write timeout of connections
+    timeout: Option<Duration>,
+}
+
+
+/// STDOUT of a request read on demand, STDERR goes to the callback or is
+/// collected. The connection returns to the pool after END_REQUEST.
+pub struct FcgiOutput {
+    stream: Option<Stream>,
+    /// Content of the last STDOUT record
+    buf: Vec<u8>,
+    pos: usize,
+    end: Option<EndRequestBody>,
+    stderr: Vec<u8>,
+    on_stderr: Option<StderrHandler>,
+    idle: Arc<Mutex<Vec<Stream>>>,
+    max_idle: usize,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl FromStr for FcgiAddr {
+    type Err = NetErr;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        s.to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .map(Self::Tcp)
+            .ok_or_else(|| NetErr::UnresolvedHost(s.to_owned()))
+    }
+}
+
+
+impl Display for FcgiAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+
+impl Stream {
+    fn connect(addr: &FcgiAddr, timeout: Option<Duration>) -> Result<Self> {
+        let stream = match addr {
+            FcgiAddr::Tcp(addr) => {
+                Self::Tcp(TcpStream::connect(addr).map_err(NetErr::Connect)?)
+            }
+            FcgiAddr::Unix(path) => {
+                Self::Unix(UnixStream::connect(path).map_err(NetErr::Connect)?)
+            }
+        };
+
+        let res = match &stream {
+            Self::Tcp(stream) => stream
+                .set_read_timeout(timeout)
+                .and_then(|_| stream.set_write_timeout(timeout)),
+            Self::Unix(stream) => stream
+                .set_read_timeout(timeout)
+                .and_then(|_| stream.set_write_timeout(timeout)),
+        };
+        res.map_err(NetErr::SetStreamOpt)?;
+
+        Ok(stream)
+    }
+}
+
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+
+impl FcgiClient {
+    pub fn new(addr: FcgiAddr) -> Self {
+        Self {
+            addr,
+            idle: Arc::new(Mutex::new(vec![])),
+            max_idle: DEFAULT_MAX_IDLE,
+            timeout: None,
+        }
+    }
+
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn addr(&self) -> &FcgiAddr {
+        &self.addr
+    }
+
+    /// Connections in the pool
+    pub fn idle_conns(&self) -> usize {
+        self.idle.lock().map(|idle| idle.len()).unwrap_or_default()
+    }
+
+    /// Send a Responder request and wait for the first record. A pooled
+    /// connection closed by the application is retried with a new one.
+    pub fn request<K, V>(
+        &self,
+        params: &[(K, V)],
+        stdin: &[u8],
+    ) -> Result<FcgiOutput>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let begin = BeginRequestBody {
+            role: Role::Responder,
+            flags: FCGI_KEEP_CONN,
+        };
+
+        let mut req = vec![];
+        Record::new(RecordType::BeginRequest, REQUEST_ID, begin.encode())
+            .encode(&mut req);
+        encode_stream(
+            &mut req,
+            RecordType::Params,
+            REQUEST_ID,
+            &encode_params(params),
+        );
+        encode_stream(&mut req, RecordType::Stdin, REQUEST_ID, stdin);
+
+        loop {
+            let pooled = self.idle.lock().map_err(|_| NetErr::MMPoison)?.pop();
+            let is_pooled = pooled.is_some();
+
+            let mut stream = match pooled {
+                Some(stream) => stream,
+                None => Stream::connect(&self.addr, self.timeout)?,
+            };
+
+            let first = stream
+                .write_all(&req)
+                .map_err(NetErr::Write)
+                .and_then(|_| Record::read_from(&mut stream));
+
+            match first {
+                Ok(Some(record)) => {
+                    let mut output = FcgiOutput {
+                        stream: Some(stream),
+                        buf: vec![],
+                        pos: 0,
+                        end: None,
+                        stderr: vec![],
+                        on_stderr: None,
+                        idle: self.idle.clone(),
+                        max_idle: self.max_idle,
+                    };
+                    output.handle(record)?;
+
+                    return Ok(output);
+                }
+                Ok(None) if is_pooled => (),
+                Err(err) if is_pooled && is_stale(&err) => (),
+                Ok(None) => return Err(NetErr::FastCgi(FcgiKind::ConnClosed)),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+
+impl FcgiOutput {
+    /// Collected STDERR so far is passed to it at once
+    pub fn on_stderr<F: FnMut(&[u8]) + Send + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        let mut f = Box::new(f);
+
+        if !self.stderr.is_empty() {
+            f(&std::mem::take(&mut self.stderr));
+        }
+
+        self.on_stderr = Some(f);
+        self
+    }
+
+    /// Collected STDERR if there is no callback
+    pub fn stderr(&self) -> &[u8] {
+        &self.stderr
+    }
+
+    /// END_REQUEST, it's there once STDOUT is read to the end
+    pub fn end(&self) -> Option<&EndRequestBody> {
+        self.end.as_ref()
+    }
+
+    fn handle(&mut self, record: Record) -> Result<()> {
+        if record.request_id != REQUEST_ID {
+            return Err(NetErr::FastCgi(FcgiKind::UnexpectedRecord(
+                record.rtype as u8,
+            )));
+        }
+
+        match record.rtype {
+            // the empty one ends the stream
+            RecordType::Stdout => {
+                self.buf = record.content;
+                self.pos = 0;
+            }
+            RecordType::Stderr => {
+                if let Some(f) = self.on_stderr.as_mut() {
+                    f(&record.content);
+                }
+                else {
+                    self.stderr.extend_from_slice(&record.content);
+                }
+            }
+            RecordType::EndRequest => {
+                let end = EndRequestBody::decode(&record.content)?;
+                self.end = Some(end);
+
+                let stream = self.stream.take().unwrap();
+                if let Ok(mut idle) = self.idle.lock() {
+                    if idle.len() < self.max_idle {
+                        idle.push(stream);
+                    }
+                }
+
+                if end.protocol_status != ProtocolStatus::RequestComplete {
+                    return Err(NetErr::FastCgi(FcgiKind::ProtocolStatus(
+                        end.protocol_status as u8,
+                    )));
+                }
+            }
+            rtype => {
+                return Err(NetErr::FastCgi(FcgiKind::UnexpectedRecord(
+                    rtype as u8,
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Read for FcgiOutput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = buf.len().min(self.buf.len() - self.pos);
+                buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+                self.pos += n;
+
+                return Ok(n);
+            }
+
+            let Some(stream) = self.stream.as_mut()
+            else {
+                return Ok(0);
+            };
+
+            let record = Record::read_from(stream)
+                .and_then(|record| {
+                    record.ok_or(NetErr::FastCgi(FcgiKind::ConnClosed))
+                })
+                .map_err(into_io_err)?;
+
+            self.handle(record).map_err(into_io_err)?;
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Errors of a pooled connection closed by the peer in the meantime
+fn is_stale(err: &NetErr) -> bool {
+    match err {
+        NetErr::Write(_) | NetErr::FastCgi(FcgiKind::ConnClosed) => true,
+        NetErr::Read(err) => err.kind() == ErrorKind::ConnectionReset,
+        _ => false,
+    }
+}
+
+
+/// I/O error itself is kept, e.g. a timeout
+fn into_io_err(err: NetErr) -> std::io::Error {
+    match err {
+        NetErr::Read(err) => err,
+        err => std::io::Error::other(err),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        os::unix::net::UnixListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::{FcgiAddr, FcgiClient};
+    use crate::application::fastcgi::responder::{serve_conn, FcgiRequest};
+
+    fn hello(req: &FcgiRequest) -> Vec<u8> {
+        let name = req.param("QUERY_STRING").unwrap_or_default();
+        let mut out = b"Content-Type: text/plain\r\n\r\n".to_vec();
+
+        out.extend_from_slice(format!("hello {name} ").as_bytes());
+        out.extend_from_slice(&req.stdin);
+        out
+    }
+
+    #[test]
+    fn test_fcgi_client() {
+        assert_eq!(
+            "unix:/tmp/a.sock".parse::<FcgiAddr>().unwrap(),
+            FcgiAddr::Unix("/tmp/a.sock".into())
+        );
+        assert_eq!(
+            "127.0.0.1:9000".parse::<FcgiAddr>().unwrap().to_string(),
+            "127.0.0.1:9000"
+        );
+
+        let path = std::env::temp_dir()
+            .join(format!("netlib-fcgi-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted2 = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepted2.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || serve_conn(stream.unwrap(), hello));
+            }
+        });
+
+        let client = FcgiClient::new(FcgiAddr::Unix(path.clone()));
+
+        for i in 0..3 {
+            let big = vec![b'x'; 70000 * i];
+            let params = [("QUERY_STRING", format!("n{i}"))];
+            let mut output = client.request(&params, &big).unwrap();
+
+            let mut out = vec![];
+            output.read_to_end(&mut out).unwrap();
+
+            let expected = format!("hello n{i} ");
+            assert!(out.ends_with(&big));
+            assert!(out.starts_with(b"Content-Type: text/plain\r\n\r\n"));
+            assert_eq!(&out[28..28 + expected.len()], expected.as_bytes());
+            assert_eq!(output.end().unwrap().app_status, 0);
+        }
+
+        // the connection is reused
+        assert_eq!(client.idle_conns(), 1);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+//! FastCGI record
+//! ([spec](https://fastcgi-archives.github.io/FastCGI_Specification.html))
+//!
+//! Only the Responder role is implemented, one request at a time on a
+//! connection (no multiplexing).
+
+pub mod client;
+pub mod responder;
+
+
+use std::io::{ErrorKind, Read, Write};
+
+use crate::{
+    enum_try_from_int,
+    rs_error::{FcgiKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const FCGI_VERSION_1: u8 = 1;
+
+pub const FCGI_HEADER_LEN: usize = 8;
+
+/// Request id of management records
+pub const FCGI_NULL_REQUEST_ID: u16 = 0;
+
+/// Flag of BEGIN_REQUEST: don't close the connection after the request
+pub const FCGI_KEEP_CONN: u8 = 1;
+
+/// Content length is an u16
+pub const FCGI_MAX_CONTENT_LEN: usize = 0xffff;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+enum_try_from_int! {
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RecordType {
+        BeginRequest = 1,
+        AbortRequest = 2,
+        EndRequest = 3,
+        Params = 4,
+        Stdin = 5,
+        Stdout = 6,
+        Stderr = 7,
+        Data = 8,
+        GetValues = 9,
+        GetValuesResult = 10,
+        UnknownType = 11,
+    }
+
+    #[repr(u16)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Role {
+        Responder = 1,
+        Authorizer = 2,
+        Filter = 3,
+    }
+
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ProtocolStatus {
+        RequestComplete = 0,
+        CantMpxConn = 1,
+        Overloaded = 2,
+        UnknownRole = 3,
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub rtype: RecordType,
+    pub request_id: u16,
+    /// At most `FCGI_MAX_CONTENT_LEN` bytes
+    pub content: Vec<u8>,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeginRequestBody {
+    pub role: Role,
+    pub flags: u8,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndRequestBody {
+    /// Exit status of the application
+    pub app_status: u32,
+    pub protocol_status: ProtocolStatus,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Record {
+    pub fn new(rtype: RecordType, request_id: u16, content: Vec<u8>) -> Self {
+        debug_assert!(content.len() <= FCGI_MAX_CONTENT_LEN);
+
+        Self {
+            rtype,
+            request_id,
+            content,
+        }
+    }
+
+    /// Content is padded to a multiple of 8 bytes
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let len = self.content.len();
+        let padding = (8 - len % 8) % 8;
+
+        buf.push(FCGI_VERSION_1);
+        buf.push(self.rtype as u8);
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+        buf.push(padding as u8);
+        buf.push(0);
+        buf.extend_from_slice(&self.content);
+        buf.resize(buf.len() + padding, 0);
+    }
+
+    /// Record at the start of `buf` and its length with padding, `None`
+    /// if it's not complete yet
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        if buf.len() < FCGI_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let (rtype, request_id, len, padding) =
+            decode_header(buf[..FCGI_HEADER_LEN].try_into().unwrap())?;
+        let total = FCGI_HEADER_LEN + len + padding;
+
+        if buf.len() < total {
+            return Ok(None);
+        }
+
+        let content = buf[FCGI_HEADER_LEN..FCGI_HEADER_LEN + len].to_vec();
+
+        Ok(Some((Self::new(rtype, request_id, content), total)))
+    }
+
+    /// `None` on EOF before the header, which is a clean close
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Option<Self>> {
+        let mut header = [0u8; FCGI_HEADER_LEN];
+        let mut filled = 0;
+
+        while filled < FCGI_HEADER_LEN {
+            match r.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(NetErr::FastCgi(FcgiKind::ConnClosed));
+                }
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(NetErr::Read(err)),
+            }
+        }
+
+        let (rtype, request_id, len, padding) = decode_header(&header)?;
+
+        let mut content = vec![0; len + padding];
+        r.read_exact(&mut content).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                NetErr::FastCgi(FcgiKind::ConnClosed)
+            }
+            else {
+                NetErr::Read(err)
+            }
+        })?;
+        content.truncate(len);
+
+        Ok(Some(Self::new(rtype, request_id, content)))
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = vec![];
+        self.encode(&mut buf);
+
+        w.write_all(&buf).map_err(NetErr::Write)
+    }
+}
+
+
+impl BeginRequestBody {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; 8];
+
+        buf[..2].copy_from_slice(&(self.role as u16).to_be_bytes());
+        buf[2] = self.flags;
+
+        buf
+    }
+
+    pub fn decode(content: &[u8]) -> Result<Self> {
+        if content.len() < 8 {
+            return Err(NetErr::FastCgi(FcgiKind::TooShort(content.len())));
+        }
+
+        let role = u16::from_be_bytes([content[0], content[1]]);
+
+        Ok(Self {
+            role: Role::try_from(role).map_err(|role| {
+                NetErr::FastCgi(FcgiKind::UnknownRole(role))
+            })?,
+            flags: content[2],
+        })
+    }
+
+    pub fn keep_conn(&self) -> bool {
+        self.flags & FCGI_KEEP_CONN != 0
+    }
+}
+
+
+impl EndRequestBody {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; 8];
+
+        buf[..4].copy_from_slice(&self.app_status.to_be_bytes());
+        buf[4] = self.protocol_status as u8;
+
+        buf
+    }
+
+    pub fn decode(content: &[u8]) -> Result<Self> {
+        if content.len() < 8 {
+            return Err(NetErr::FastCgi(FcgiKind::TooShort(content.len())));
+        }
+
+        Ok(Self {
+            app_status: u32::from_be_bytes(content[..4].try_into().unwrap()),
+            protocol_status: ProtocolStatus::try_from(content[4]).map_err(
+                |status| NetErr::FastCgi(FcgiKind::ProtocolStatus(status)),
+            )?,
+        })
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Type, request id, content length and padding length
+fn decode_header(
+    header: &[u8; FCGI_HEADER_LEN],
+) -> Result<(RecordType, u16, usize, usize)> {
+    if header[0] != FCGI_VERSION_1 {
+        return Err(NetErr::FastCgi(FcgiKind::UnsupportedVersion(header[0])));
+    }
+
+    let rtype = RecordType::try_from(header[1])
+        .map_err(|rtype| NetErr::FastCgi(FcgiKind::UnknownType(rtype)))?;
+
+    Ok((
+        rtype,
+        u16::from_be_bytes([header[2], header[3]]),
+        u16::from_be_bytes([header[4], header[5]]) as usize,
+        header[6] as usize,
+    ))
+}
+
+
+/// Records of a stream (PARAMS, STDIN, STDOUT ...) ended by an empty one
+pub fn encode_stream(
+    buf: &mut Vec<u8>,
+    rtype: RecordType,
+    request_id: u16,
+    data: &[u8],
+) {
+    for chunk in data.chunks(FCGI_MAX_CONTENT_LEN) {
+        Record::new(rtype, request_id, chunk.to_vec()).encode(buf);
+    }
+
+    Record::new(rtype, request_id, vec![]).encode(buf);
+}
+
+
+/// Lengths below 128 take one byte, others four bytes with the high bit
+pub fn encode_params<K, V>(pairs: &[(K, V)]) -> Vec<u8>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut buf = vec![];
+    let push_len = |buf: &mut Vec<u8>, len: usize| {
+        if len < 0x80 {
+            buf.push(len as u8);
+        }
+        else {
+            buf.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    };
+
+    for (name, val) in pairs {
+        let (name, val) = (name.as_ref(), val.as_ref());
+
+        push_len(&mut buf, name.len());
+        push_len(&mut buf, val.len());
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(val);
+    }
+
+    buf
+}
+
+
+/// Names and values are decoded lossy as UTF-8
+pub fn decode_params(mut buf: &[u8]) -> Result<Vec<(String, String)>> {
+    let bad = || NetErr::FastCgi(FcgiKind::BadParams);
+    let pop_len = |buf: &mut &[u8]| {
+        let first = *buf.first().ok_or_else(bad)?;
+
+        if first & 0x80 == 0 {
+            *buf = &buf[1..];
+            return Ok(first as usize);
+        }
+
+        if buf.len() < 4 {
+            return Err(bad());
+        }
+
+        let len = u32::from_be_bytes(buf[..4].try_into().unwrap());
+        *buf = &buf[4..];
+
+        Ok((len & 0x7fff_ffff) as usize)
+    };
+
+    let mut pairs = vec![];
+
+    while !buf.is_empty() {
+        let name_len = pop_len(&mut buf)?;
+        let val_len = pop_len(&mut buf)?;
+
+        if buf.len() < name_len + val_len {
+            return Err(bad());
+        }
+
+        let (name, rest) = buf.split_at(name_len);
+        let (val, rest) = rest.split_at(val_len);
+
+        pairs.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(val).into_owned(),
+        ));
+        buf = rest;
+    }
+
+    Ok(pairs)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_params, encode_params, encode_stream, BeginRequestBody,
+        EndRequestBody, ProtocolStatus, Record, RecordType, Role,
+        FCGI_KEEP_CONN, FCGI_MAX_CONTENT_LEN,
+    };
+
+    #[test]
+    fn test_fcgi_codec() {
+        let begin = BeginRequestBody {
+            role: Role::Responder,
+            flags: FCGI_KEEP_CONN,
+        };
+        let record = Record::new(RecordType::BeginRequest, 1, begin.encode());
+
+        let mut buf = vec![];
+        record.encode(&mut buf);
+        assert_eq!(buf, [1, 1, 0, 1, 0, 8, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0]);
+
+        let (decoded, len) = Record::decode(&buf).unwrap().unwrap();
+        assert_eq!((decoded, len), (record, 16));
+        assert!(Record::decode(&buf[..15]).unwrap().is_none());
+        assert_eq!(BeginRequestBody::decode(&buf[8..]).unwrap(), begin);
+
+        // padded to 8
+        let mut buf = vec![];
+        Record::new(RecordType::Stdout, 2, b"abc".to_vec()).encode(&mut buf);
+        assert_eq!(buf.len(), 16);
+        assert_eq!(buf[6], 5);
+        assert_eq!(
+            Record::read_from(&mut &buf[..]).unwrap().unwrap().content,
+            b"abc"
+        );
+        assert!(Record::read_from(&mut &buf[..10]).is_err());
+        assert!(Record::read_from(&mut &[][..]).unwrap().is_none());
+
+        let end = EndRequestBody {
+            app_status: 3,
+            protocol_status: ProtocolStatus::Overloaded,
+        };
+        assert_eq!(EndRequestBody::decode(&end.encode()).unwrap(), end);
+
+        // long stream is split, then ended by an empty record
+        let mut buf = vec![];
+        encode_stream(
+            &mut buf,
+            RecordType::Stdin,
+            1,
+            &vec![7; FCGI_MAX_CONTENT_LEN + 1],
+        );
+
+        let mut lens = vec![];
+        let mut rest = &buf[..];
+        while let Some((record, len)) = Record::decode(rest).unwrap() {
+            lens.push(record.content.len());
+            rest = &rest[len..];
+        }
+        assert_eq!(lens, [FCGI_MAX_CONTENT_LEN, 1, 0]);
+
+        // bad version
+        assert!(Record::decode(&[2, 1, 0, 1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_fcgi_params() {
+        let long = "v".repeat(200);
+        let pairs = vec![("SCRIPT_NAME", "/a"), ("LONG", long.as_str())];
+
+        let buf = encode_params(&pairs);
+        assert_eq!(&buf[..2], [11, 2]);
+        assert_eq!(&buf[15..21], [4, 0x80, 0, 0, 200, b'L']);
+
+        let decoded = decode_params(&buf).unwrap();
+        assert_eq!(decoded[0], ("SCRIPT_NAME".into(), "/a".into()));
+        assert_eq!(decoded[1].1, long);
+
+        assert!(decode_params(&buf[..buf.len() - 1]).is_err());
+        assert!(decode_params(&[0x80, 0]).is_err());
+    }
+}
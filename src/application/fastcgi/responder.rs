@@ -0,0 +1,194 @@
+//! Minimal FastCGI Responder, it serves requests on a connection one by
+//! one with a handler returning the whole STDOUT (CGI head and body).
+
+use std::io::{Read, Write};
+
+use super::{
+    decode_params, encode_params, encode_stream, BeginRequestBody,
+    EndRequestBody, ProtocolStatus, Record, RecordType, Role,
+    FCGI_NULL_REQUEST_ID,
+};
+use crate::{
+    rs_error::{FcgiKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, Default)]
+pub struct FcgiRequest {
+    pub id: u16,
+    pub params: Vec<(String, String)>,
+    pub stdin: Vec<u8>,
+    pub keep_conn: bool,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl FcgiRequest {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Serve until the peer closes the connection or a request is done
+/// without FCGI_KEEP_CONN
+pub fn serve_conn<S, F>(mut stream: S, mut handler: F) -> Result<()>
+where
+    S: Read + Write,
+    F: FnMut(&FcgiRequest) -> Vec<u8>,
+{
+    while let Some(req) = read_request(&mut stream)? {
+        let mut buf = vec![];
+
+        encode_stream(&mut buf, RecordType::Stdout, req.id, &handler(&req));
+
+        let end = EndRequestBody {
+            app_status: 0,
+            protocol_status: ProtocolStatus::RequestComplete,
+        };
+        Record::new(RecordType::EndRequest, req.id, end.encode())
+            .encode(&mut buf);
+
+        stream.write_all(&buf).map_err(NetErr::Write)?;
+
+        if !req.keep_conn {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+
+/// `None` if the connection is closed between requests
+fn read_request<S: Read + Write>(
+    stream: &mut S,
+) -> Result<Option<FcgiRequest>> {
+    let mut req: Option<FcgiRequest> = None;
+    let mut params = vec![];
+    let mut params_done = false;
+    // streams of a request with other role are skipped
+    let mut rejected = None;
+
+    loop {
+        let Some(record) = Record::read_from(stream)?
+        else {
+            if req.is_none() {
+                return Ok(None);
+            }
+            return Err(NetErr::FastCgi(FcgiKind::ConnClosed));
+        };
+
+        if record.request_id == FCGI_NULL_REQUEST_ID {
+            reply_management(stream, &record)?;
+            continue;
+        }
+
+        if rejected == Some(record.request_id)
+            && record.rtype != RecordType::BeginRequest
+        {
+            continue;
+        }
+
+        match (record.rtype, req.as_mut()) {
+            (RecordType::BeginRequest, None) => {
+                let begin = BeginRequestBody::decode(&record.content)?;
+
+                if begin.role != Role::Responder {
+                    let end = EndRequestBody {
+                        app_status: 0,
+                        protocol_status: ProtocolStatus::UnknownRole,
+                    };
+                    Record::new(
+                        RecordType::EndRequest,
+                        record.request_id,
+                        end.encode(),
+                    )
+                    .write_to(stream)?;
+                    rejected = Some(record.request_id);
+                    continue;
+                }
+
+                req = Some(FcgiRequest {
+                    id: record.request_id,
+                    keep_conn: begin.keep_conn(),
+                    ..Default::default()
+                });
+            }
+            (RecordType::Params, Some(req))
+                if req.id == record.request_id && !params_done =>
+            {
+                if record.content.is_empty() {
+                    req.params = decode_params(&params)?;
+                    params_done = true;
+                }
+                else {
+                    params.extend_from_slice(&record.content);
+                }
+            }
+            (RecordType::Stdin, Some(req))
+                if req.id == record.request_id && params_done =>
+            {
+                if record.content.is_empty() {
+                    break;
+                }
+                req.stdin.extend_from_slice(&record.content);
+            }
+            (rtype, _) => {
+                return Err(NetErr::FastCgi(FcgiKind::UnexpectedRecord(
+                    rtype as u8,
+                )));
+            }
+        }
+    }
+
+    Ok(req)
+}
+
+
+/// GET_VALUES is answered with the values known here, other types as
+/// UNKNOWN_TYPE
+fn reply_management<W: Write>(w: &mut W, record: &Record) -> Result<()> {
+    if record.rtype != RecordType::GetValues {
+        let mut content = vec![0; 8];
+        content[0] = record.rtype as u8;
+
+        return Record::new(
+            RecordType::UnknownType,
+            FCGI_NULL_REQUEST_ID,
+            content,
+        )
+        .write_to(w);
+    }
+
+    let values: Vec<(String, &str)> = decode_params(&record.content)?
+        .into_iter()
+        .filter_map(|(name, _)| {
+            let val = match name.as_str() {
+                "FCGI_MAX_CONNS" | "FCGI_MAX_REQS" => "1",
+                "FCGI_MPXS_CONNS" => "0",
+                _ => return None,
+            };
+            Some((name, val))
+        })
+        .collect();
+
+    Record::new(
+        RecordType::GetValuesResult,
+        FCGI_NULL_REQUEST_ID,
+        encode_params(&values),
+    )
+    .write_to(w)
+}
@@ -0,0 +1,480 @@
+//! Incremental request/response body decoder for Content-Length and chunked
+//! transfer coding ([rfc9112 6, 7.1](https://www.rfc-editor.org/rfc/rfc9112))
+//!
+//! Like the head parser, bytes after the body are kept for the caller.
+
+use http::HeaderMap;
+
+use super::parser::{parse_field, ParseStatus, MAX_HEADERS};
+use crate::rs_error::HttpKind;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const MAX_BODY: usize = 1024 * 1024;
+/// Chunk size line with extensions
+pub const MAX_CHUNK_LN: usize = 1024;
+pub const MAX_TRAILER_BYTES: usize = 8 * 1024;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// How the body length is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    /// No body
+    None,
+    Length(usize),
+    Chunked,
+    /// Response body ends when the server closes the connection
+    UntilClose,
+}
+
+
+#[derive(Debug, Default)]
+pub struct ReqBody {
+    pub data: Vec<u8>,
+    /// Trailer fields of chunked body
+    pub trailers: HeaderMap,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeState {
+    /// Remaining bytes of the body or the chunk
+    Data(usize),
+    ChunkSize,
+    /// CRLF after chunk data
+    ChunkEnd,
+    Trailers,
+    /// Everything until EOF
+    UntilClose,
+    Done,
+}
+
+
+#[derive(Debug)]
+pub struct BodyDecoder {
+    kind: BodyKind,
+    max: usize,
+    state: DecodeState,
+    buf: Vec<u8>,
+    /// Next byte to be decoded
+    pos: usize,
+    body: ReqBody,
+    trailer_bytes: usize,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl ReqBody {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+
+impl BodyDecoder {
+    pub fn new(kind: BodyKind) -> Self {
+        Self::with_max(kind, MAX_BODY)
+    }
+
+    /// `max` is the limit of decoded body bytes
+    pub fn with_max(kind: BodyKind, max: usize) -> Self {
+        let state = match kind {
+            BodyKind::None => DecodeState::Done,
+            BodyKind::Length(len) => DecodeState::Data(len),
+            BodyKind::Chunked => DecodeState::ChunkSize,
+            BodyKind::UntilClose => DecodeState::UntilClose,
+        };
+
+        Self {
+            kind,
+            max,
+            state,
+            buf: vec![],
+            pos: 0,
+            body: ReqBody::default(),
+            trailer_bytes: 0,
+        }
+    }
+
+    pub fn kind(&self) -> BodyKind {
+        self.kind
+    }
+
+    /// Feed next chunk (may be empty), after `Complete` the leftover bytes
+    /// are in [`Self::rest`].
+    pub fn feed(
+        &mut self,
+        chunk: &[u8],
+    ) -> Result<ParseStatus<ReqBody>, HttpKind> {
+        if let BodyKind::Length(len) = self.kind {
+            if len > self.max {
+                return Err(HttpKind::BodyTooLarge(len));
+            }
+        }
+
+        self.buf.extend_from_slice(chunk);
+
+        let res = self.decode();
+
+        // drop what has been decoded
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+
+        res
+    }
+
+    pub fn rest(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// The peer closed the connection, which only completes the body of
+    /// `UntilClose`
+    pub fn finish(&mut self) -> Result<ReqBody, HttpKind> {
+        match self.state {
+            DecodeState::UntilClose | DecodeState::Done => {
+                self.state = DecodeState::Done;
+                Ok(std::mem::take(&mut self.body))
+            }
+            _ => Err(HttpKind::IncompleteBody),
+        }
+    }
+
+    pub fn take_rest(&mut self) -> Vec<u8> {
+        self.buf.split_off(self.pos)
+    }
+
+    fn decode(&mut self) -> Result<ParseStatus<ReqBody>, HttpKind> {
+        loop {
+            match self.state {
+                DecodeState::Data(remains) => {
+                    let n = remains.min(self.buf.len() - self.pos);
+
+                    self.body
+                        .data
+                        .extend_from_slice(&self.buf[self.pos..self.pos + n]);
+                    self.pos += n;
+
+                    if n < remains {
+                        self.state = DecodeState::Data(remains - n);
+                        return Ok(ParseStatus::Partial);
+                    }
+
+                    self.state = match self.kind {
+                        BodyKind::Chunked => DecodeState::ChunkEnd,
+                        _ => DecodeState::Done,
+                    };
+                }
+                DecodeState::ChunkSize => {
+                    let Some(line) = self.next_line(MAX_CHUNK_LN)?
+                    else {
+                        return Ok(ParseStatus::Partial);
+                    };
+
+                    let size = parse_chunk_size(&line)?;
+
+                    // the size is up to the peer, may be near usize::MAX
+                    if size > self.max.saturating_sub(self.body.len()) {
+                        return Err(HttpKind::BodyTooLarge(
+                            self.body.len().saturating_add(size),
+                        ));
+                    }
+
+                    self.state = if size == 0 {
+                        DecodeState::Trailers
+                    }
+                    else {
+                        DecodeState::Data(size)
+                    };
+                }
+                DecodeState::ChunkEnd => {
+                    let Some(line) = self.next_line(2)?
+                    else {
+                        return Ok(ParseStatus::Partial);
+                    };
+
+                    if !line.is_empty() {
+                        return Err(HttpKind::InvalidChunk(
+                            "no CRLF after chunk data".to_owned(),
+                        ));
+                    }
+
+                    self.state = DecodeState::ChunkSize;
+                }
+                DecodeState::Trailers => {
+                    let limit =
+                        MAX_TRAILER_BYTES.saturating_sub(self.trailer_bytes);
+
+                    let Some(line) = self.next_line(limit).map_err(|_| {
+                        HttpKind::HeaderTooLarge(MAX_TRAILER_BYTES)
+                    })?
+                    else {
+                        return Ok(ParseStatus::Partial);
+                    };
+
+                    self.trailer_bytes += line.len() + 1;
+
+                    if line.is_empty() {
+                        self.state = DecodeState::Done;
+                        continue;
+                    }
+
+                    if self.body.trailers.len() >= MAX_HEADERS {
+                        return Err(HttpKind::TooManyHeaders(
+                            self.body.trailers.len() + 1,
+                        ));
+                    }
+
+                    let (name, value) = parse_field(&line)?;
+                    self.body.trailers.append(name, value);
+                }
+                DecodeState::UntilClose => {
+                    let rest = &self.buf[self.pos..];
+
+                    if self.body.len() + rest.len() > self.max {
+                        return Err(HttpKind::BodyTooLarge(
+                            self.body.len() + rest.len(),
+                        ));
+                    }
+
+                    self.body.data.extend_from_slice(rest);
+                    self.pos = self.buf.len();
+
+                    return Ok(ParseStatus::Partial);
+                }
+                DecodeState::Done => {
+                    return Ok(ParseStatus::Complete(std::mem::take(
+                        &mut self.body,
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Line without (CR)LF, `None` if it hasn't ended yet
+    fn next_line(
+        &mut self,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>, HttpKind> {
+        let rest = &self.buf[self.pos..];
+
+        let Some(nl) = rest.iter().position(|c| *c == b'\n')
+        else {
+            if rest.len() > limit {
+                return Err(HttpKind::InvalidChunk(
+                    "line too long".to_owned(),
+                ));
+            }
+
+            return Ok(None);
+        };
+
+        if nl > limit {
+            return Err(HttpKind::InvalidChunk("line too long".to_owned()));
+        }
+
+        let mut line = &rest[..nl];
+        if let Some(stripped) = line.strip_suffix(b"\r") {
+            line = stripped;
+        }
+        let line = line.to_vec();
+
+        self.pos += nl + 1;
+
+        Ok(Some(line))
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// `1a;name=val`, extensions are ignored
+fn parse_chunk_size(line: &[u8]) -> Result<usize, HttpKind> {
+    let invalid = || {
+        HttpKind::InvalidChunk(format!(
+            "bad chunk size: {}",
+            String::from_utf8_lossy(line)
+        ))
+    };
+
+    let end = line
+        .iter()
+        .position(|c| !c.is_ascii_hexdigit())
+        .unwrap_or(line.len());
+
+    match line[end..].iter().find(|c| **c != b' ' && **c != b'\t') {
+        None | Some(b';') => (),
+        _ => return Err(invalid()),
+    }
+
+    // usize::MAX has 16 hex digits at most
+    if end == 0 || end > 16 {
+        return Err(invalid());
+    }
+
+    let size = std::str::from_utf8(&line[..end]).unwrap();
+
+    usize::from_str_radix(size, 16).map_err(|_| invalid())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use http::header::HeaderName;
+
+    use super::{BodyDecoder, BodyKind, ReqBody};
+    use crate::{
+        application::http::parser::{ParseStatus, ReqParser},
+        rs_error::HttpKind,
+    };
+
+    fn complete(status: ParseStatus<ReqBody>) -> ReqBody {
+        match status {
+            ParseStatus::Complete(body) => body,
+            ParseStatus::Partial => panic!("partial"),
+        }
+    }
+
+    #[test]
+    fn test_body_decoder_length() {
+        let mut parser = ReqParser::new();
+        let raw = b"POST /a HTTP/1.1\r\nHost: h\r\nContent-Length: 5\r\n\r\n\
+hello GET";
+        let ParseStatus::Complete(head) = parser.feed(raw).unwrap()
+        else {
+            panic!("partial")
+        };
+
+        assert_eq!(head.body_kind().unwrap(), BodyKind::Length(5));
+
+        let mut decoder = BodyDecoder::new(head.body_kind().unwrap());
+        let rest = parser.take_rest();
+
+        assert!(!decoder.feed(&rest[..3]).unwrap().is_complete());
+
+        let body = complete(decoder.feed(&rest[3..]).unwrap());
+        assert_eq!(body.data, b"hello");
+        assert_eq!(decoder.rest(), b" GET");
+
+        let mut decoder = BodyDecoder::with_max(BodyKind::Length(10), 8);
+        assert!(matches!(
+            decoder.feed(&[]).unwrap_err(),
+            HttpKind::BodyTooLarge(10)
+        ));
+
+        let mut decoder = BodyDecoder::new(BodyKind::None);
+        assert!(complete(decoder.feed(b"GET").unwrap()).is_empty());
+        assert_eq!(decoder.take_rest(), b"GET");
+
+        let mut decoder = BodyDecoder::with_max(BodyKind::UntilClose, 16);
+        assert!(!decoder.feed(b"until").unwrap().is_complete());
+        assert!(!decoder.feed(b" eof").unwrap().is_complete());
+        assert_eq!(decoder.finish().unwrap().data, b"until eof");
+        assert!(matches!(
+            BodyDecoder::with_max(BodyKind::UntilClose, 8)
+                .feed(b"too large")
+                .unwrap_err(),
+            HttpKind::BodyTooLarge(9)
+        ));
+        assert!(matches!(
+            BodyDecoder::new(BodyKind::Length(3)).finish().unwrap_err(),
+            HttpKind::IncompleteBody
+        ));
+    }
+
+    #[test]
+    fn test_body_decoder_chunked() {
+        let raw = b"4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\nin \r\n\r\nchunks.\n\
+0\r\nExpires: never\r\nX-Sum: 1\r\n\r\nNEXT";
+        let mut decoder = BodyDecoder::new(BodyKind::Chunked);
+
+        for c in raw[..raw.len() - 5].iter() {
+            assert!(!decoder.feed(&[*c]).unwrap().is_complete());
+        }
+
+        let body = complete(decoder.feed(&raw[raw.len() - 5..]).unwrap());
+
+        assert_eq!(body.data, b"Wikipedia in \r\n\r\nchunks.");
+        assert_eq!(body.trailers.len(), 2);
+        assert_eq!(body.trailers[HeaderName::from_static("x-sum")], "1");
+        assert_eq!(decoder.rest(), b"NEXT");
+
+        let err = |raw: &[u8]| {
+            BodyDecoder::with_max(BodyKind::Chunked, 16)
+                .feed(raw)
+                .unwrap_err()
+        };
+
+        assert!(matches!(err(b"x\r\n"), HttpKind::InvalidChunk(_)));
+        assert!(matches!(err(b"1 2\r\n"), HttpKind::InvalidChunk(_)));
+        assert!(matches!(err(b"2\r\nabc\r\n"), HttpKind::InvalidChunk(_)));
+        assert!(matches!(err(b"11\r\n"), HttpKind::BodyTooLarge(17)));
+        assert!(matches!(
+            err(b"1\r\na\r\nffffffffffffffff\r\n"),
+            HttpKind::BodyTooLarge(usize::MAX)
+        ));
+        assert!(matches!(
+            err(b"0\r\nbad trailer\r\n"),
+            HttpKind::InvalidHeader(_)
+        ));
+    }
+
+    #[test]
+    fn test_body_kind() {
+        let kind = |raw: &[u8]| {
+            let ParseStatus::Complete(head) = ReqParser::new().feed(raw)?
+            else {
+                panic!("partial")
+            };
+
+            head.body_kind()
+        };
+
+        assert_eq!(kind(b"GET / HTTP/1.0\r\n\r\n").unwrap(), BodyKind::None);
+        assert_eq!(
+            kind(b"PUT / HTTP/1.0\r\nContent-Length: 3, 3\r\n\r\n").unwrap(),
+            BodyKind::Length(3)
+        );
+        assert_eq!(
+            kind(b"PUT / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap(),
+            BodyKind::Chunked
+        );
+        assert!(matches!(
+            kind(
+                b"PUT / HTTP/1.0\r\nContent-Length: 3\r\nContent-Length: 4\
+\r\n\r\n"
+            ),
+            Err(HttpKind::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            kind(b"PUT / HTTP/1.0\r\nContent-Length: -1\r\n\r\n"),
+            Err(HttpKind::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            kind(
+                b"PUT / HTTP/1.0\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"
+            ),
+            Err(HttpKind::UnsupportedTransferEncoding(_))
+        ));
+        assert!(matches!(
+            kind(
+                b"PUT / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\
+Content-Length: 3\r\n\r\n"
+            ),
+            Err(HttpKind::InvalidHeader(_))
+        ));
+    }
+}
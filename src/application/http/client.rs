@@ -0,0 +1,1047 @@
+//! Blocking HTTP/1.1 client over plain TCP, responses are read by the same
+//! incremental parser and body decoder of the server side.
+//!
+//! Connections are kept alive and pooled by the origin (or the proxy),
+//! redirects are followed and cookies are kept if it's enabled.
+//! [`Client::websocket`] upgrades a new connection to WebSocket.
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use http::{
+    header::{
+        ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING,
+        CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION, SET_COOKIE,
+        TRANSFER_ENCODING, USER_AGENT,
+    },
+    uri::{Authority, Scheme},
+    HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, Version,
+};
+
+use super::{
+    body::{BodyDecoder, BodyKind, MAX_BODY},
+    jar::CookieJar,
+    parser::{ParseStatus, RespHead, RespParser},
+    ws::{ClientHandshake, WebSocket, WsCodec, WsRole},
+};
+use crate::{
+    rs_error::{HttpKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+/// Idle connections kept for each origin by default
+pub const DEFAULT_MAX_IDLE: usize = 4;
+const USER_AGENT_NAME: &str = concat!("netlib/", env!("CARGO_PKG_VERSION"));
+const READ_BUF: usize = 16 * 1024;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug)]
+pub struct Client {
+    /// Idle connections by `host:port` of the origin or the proxy
+    pool: Mutex<HashMap<String, Vec<TcpStream>>>,
+    max_idle: usize,
+    /// Connect, read and write timeout
+    timeout: Option<Duration>,
+    /// Redirects aren't followed if it's 0
+    max_redirects: usize,
+    max_body: usize,
+    /// Forward proxy, requests to it are in absolute-form
+    proxy: Option<Authority>,
+    jar: Mutex<CookieJar>,
+    use_cookies: bool,
+    /// Ask for and decode gzip and deflate content coding
+    decompress: bool,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub uri: Uri,
+    /// Host, Content-Length and the defaults are added if they're absent
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+
+#[derive(Debug)]
+pub struct Response {
+    pub version: Version,
+    pub status: StatusCode,
+    pub reason: String,
+    /// Content-Encoding and Content-Length are removed once decoded
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    /// Trailer fields of chunked body
+    pub trailers: HeaderMap,
+    /// The last one after redirects
+    pub uri: Uri,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Default for Client {
+    fn default() -> Self {
+        Self {
+            pool: Mutex::new(HashMap::new()),
+            max_idle: DEFAULT_MAX_IDLE,
+            timeout: None,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            max_body: MAX_BODY,
+            proxy: None,
+            jar: Mutex::new(CookieJar::new()),
+            use_cookies: false,
+            decompress: true,
+        }
+    }
+}
+
+
+impl Client {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Bytes of decoded body
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// `host:port` or `http://host:port`
+    pub fn with_proxy(mut self, proxy: &str) -> Result<Self> {
+        let uri = Uri::from_str(proxy)
+            .map_err(|_| NetErr::Http(HttpKind::InvalidUri(proxy.into())))?;
+
+        if uri.scheme().is_some_and(|scheme| *scheme != Scheme::HTTP) {
+            return Err(NetErr::Http(HttpKind::UnsupportedScheme(
+                proxy.into(),
+            )));
+        }
+
+        self.proxy = Some(uri.authority().cloned().ok_or_else(|| {
+            NetErr::Http(HttpKind::InvalidUri(proxy.into()))
+        })?);
+
+        Ok(self)
+    }
+
+    /// Keep cookies of responses and send them back
+    pub fn with_cookies(mut self) -> Self {
+        self.use_cookies = true;
+        self
+    }
+
+    pub fn with_decompress(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    pub fn cookie_jar(&self) -> Result<MutexGuard<'_, CookieJar>> {
+        self.jar.lock().map_err(|_| NetErr::MMPoison)
+    }
+
+    /// Connections in the pool
+    pub fn idle_conns(&self) -> usize {
+        self.pool
+            .lock()
+            .map(|pool| pool.values().map(Vec::len).sum())
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, uri: &str) -> Result<Response> {
+        self.send(Request::new(Method::GET, parse_uri(uri)?))
+    }
+
+    pub fn post(
+        &self,
+        uri: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Response> {
+        let req = Request::new(Method::POST, parse_uri(uri)?)
+            .header(CONTENT_TYPE, content_type)?
+            .body(body);
+
+        self.send(req)
+    }
+
+    /// Redirects are followed up to the limit, 301 and 302 of POST and
+    /// 303 are turned into GET without body.
+    pub fn send(&self, mut req: Request) -> Result<Response> {
+        let mut redirects = 0;
+
+        loop {
+            let resp = self.send_once(&req)?;
+
+            let location = resp
+                .headers
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+
+            let Some(location) = location.filter(|_| {
+                self.max_redirects > 0 && is_redirect(resp.status)
+            })
+            else {
+                return Ok(resp);
+            };
+
+            if redirects >= self.max_redirects {
+                return Err(NetErr::Http(HttpKind::TooManyRedirects(
+                    redirects,
+                )));
+            }
+            redirects += 1;
+
+            let uri = resolve_location(&req.uri, location)?;
+
+            let to_get = resp.status == StatusCode::SEE_OTHER
+                && req.method != Method::HEAD
+                || matches!(
+                    resp.status,
+                    StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND
+                ) && req.method == Method::POST;
+
+            if to_get {
+                req.method = Method::GET;
+                req.body.clear();
+                req.headers.remove(CONTENT_TYPE);
+                req.headers.remove(CONTENT_LENGTH);
+                req.headers.remove(TRANSFER_ENCODING);
+            }
+
+            // credentials aren't leaked to another host
+            if uri.authority() != req.uri.authority() {
+                req.headers.remove(AUTHORIZATION);
+                req.headers.remove(COOKIE);
+            }
+
+            req.uri = uri;
+        }
+    }
+
+    /// WebSocket to `ws://` (or `http://`) uri over a new connection, not
+    /// through the proxy. Cookies are sent and permessage-deflate is
+    /// offered if they're enabled.
+    pub fn websocket(
+        &self,
+        uri: &str,
+        protocols: &[&str],
+    ) -> Result<WebSocket<TcpStream>> {
+        let uri = parse_uri(uri)?;
+
+        let scheme = uri.scheme_str().unwrap_or("ws");
+        if !matches!(scheme, "ws" | "http") {
+            return Err(NetErr::Http(HttpKind::UnsupportedScheme(
+                scheme.to_owned(),
+            )));
+        }
+
+        let authority = uri.authority().ok_or_else(|| {
+            NetErr::Http(HttpKind::InvalidUri(uri.to_string()))
+        })?;
+        let peer = format!(
+            "{}:{}",
+            authority.host(),
+            authority.port_u16().unwrap_or(80)
+        );
+
+        let handshake = ClientHandshake::new(protocols, self.decompress);
+        let mut req = Request::new(Method::GET, uri.clone());
+        req.headers = handshake.req_headers();
+
+        let head = self.encode_head(&req, authority, false)?;
+        let mut stream = self.connect(&peer)?;
+        stream.write_all(&head).map_err(NetErr::Write)?;
+
+        let mut parser = RespParser::new();
+        let head = read_head(&mut stream, &mut parser)?
+            .ok_or(NetErr::Http(HttpKind::IncompleteBody))?;
+
+        let (deflate, protocol) = handshake
+            .check_resp(head.status, &head.headers)
+            .map_err(NetErr::WebSocket)?;
+
+        let mut codec = WsCodec::new(WsRole::Client);
+        if let Some(deflate) = deflate {
+            codec = codec.with_deflate(deflate);
+        }
+
+        Ok(WebSocket::new(stream, codec, parser.take_rest())
+            .with_protocol(protocol))
+    }
+
+    /// A pooled connection closed by the server before responding is
+    /// retried with a new one.
+    fn send_once(&self, req: &Request) -> Result<Response> {
+        let scheme = req.uri.scheme().unwrap_or(&Scheme::HTTP);
+        if *scheme != Scheme::HTTP {
+            return Err(NetErr::Http(HttpKind::UnsupportedScheme(
+                scheme.to_string(),
+            )));
+        }
+
+        let authority = req.uri.authority().ok_or_else(|| {
+            NetErr::Http(HttpKind::InvalidUri(req.uri.to_string()))
+        })?;
+        let origin = format!(
+            "{}:{}",
+            authority.host(),
+            authority.port_u16().unwrap_or(80)
+        );
+        let peer = match &self.proxy {
+            Some(proxy) => {
+                format!("{}:{}", proxy.host(), proxy.port_u16().unwrap_or(80))
+            }
+            None => origin,
+        };
+
+        let head = self.encode_head(req, authority, self.proxy.is_some())?;
+
+        loop {
+            let pooled = self
+                .pool
+                .lock()
+                .map_err(|_| NetErr::MMPoison)?
+                .get_mut(&peer)
+                .and_then(Vec::pop);
+            let is_pooled = pooled.is_some();
+
+            let mut stream = match pooled {
+                Some(stream) => stream,
+                None => self.connect(&peer)?,
+            };
+
+            let res = stream
+                .write_all(&head)
+                .and_then(|_| stream.write_all(&req.body))
+                .map_err(NetErr::Write)
+                .and_then(|_| self.read_resp(&mut stream, &req.method));
+
+            let (head, body, trailers, keep_alive) = match res {
+                Ok(Some(resp)) => resp,
+                Ok(None) if is_pooled => continue,
+                Err(NetErr::Write(_)) if is_pooled => continue,
+                Err(NetErr::Read(err))
+                    if is_pooled
+                        && err.kind() == ErrorKind::ConnectionReset =>
+                {
+                    continue
+                }
+                Ok(None) => {
+                    return Err(NetErr::Http(HttpKind::IncompleteBody));
+                }
+                Err(err) => return Err(err),
+            };
+
+            if keep_alive {
+                let mut pool =
+                    self.pool.lock().map_err(|_| NetErr::MMPoison)?;
+                let idle = pool.entry(peer).or_default();
+
+                if idle.len() < self.max_idle {
+                    idle.push(stream);
+                }
+            }
+
+            return self.make_resp(req, head, body, trailers);
+        }
+    }
+
+    fn connect(&self, peer: &str) -> Result<TcpStream> {
+        let addrs = peer
+            .to_socket_addrs()
+            .map_err(|_| NetErr::UnresolvedHost(peer.to_owned()))?;
+
+        let mut last_err = None;
+
+        for addr in addrs {
+            let res = match self.timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+
+            match res {
+                Ok(stream) => {
+                    stream
+                        .set_read_timeout(self.timeout)
+                        .and_then(|_| stream.set_write_timeout(self.timeout))
+                        .and_then(|_| stream.set_nodelay(true))
+                        .map_err(NetErr::SetStreamOpt)?;
+
+                    return Ok(stream);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(match last_err {
+            Some(err) => NetErr::Connect(err),
+            None => NetErr::UnresolvedHost(peer.to_owned()),
+        })
+    }
+
+    /// Request line and headers, the target is in absolute-form for the
+    /// proxy.
+    fn encode_head(
+        &self,
+        req: &Request,
+        authority: &Authority,
+        absolute: bool,
+    ) -> Result<Vec<u8>> {
+        let target = if absolute {
+            req.uri.to_string()
+        }
+        else {
+            req.uri
+                .path_and_query()
+                .map(|paq| paq.as_str())
+                .filter(|paq| !paq.is_empty())
+                .unwrap_or("/")
+                .to_owned()
+        };
+
+        let mut headers = req.headers.clone();
+        let has_body = (!req.body.is_empty()
+            || matches!(req.method, Method::POST | Method::PUT))
+            && !headers.contains_key(TRANSFER_ENCODING);
+
+        let mut set_default = |name: HeaderName, val: &str| {
+            if !headers.contains_key(&name) {
+                if let Ok(val) = HeaderValue::from_str(val) {
+                    headers.insert(name, val);
+                }
+            }
+        };
+
+        set_default(HOST, authority.as_str());
+        set_default(USER_AGENT, USER_AGENT_NAME);
+        set_default(ACCEPT, "*/*");
+
+        if self.decompress {
+            set_default(ACCEPT_ENCODING, "gzip, deflate");
+        }
+
+        if self.use_cookies {
+            if let Some(cookie) = self.cookie_jar()?.header(&req.uri) {
+                set_default(COOKIE, &cookie);
+            }
+        }
+
+        if has_body {
+            set_default(CONTENT_LENGTH, &req.body.len().to_string());
+        }
+
+        let mut head = vec![];
+        write!(head, "{} {target} HTTP/1.1\r\n", req.method).unwrap();
+
+        for (name, val) in headers.iter() {
+            write!(head, "{name}: ").unwrap();
+            head.extend_from_slice(val.as_bytes());
+            write!(head, "\r\n").unwrap();
+        }
+        write!(head, "\r\n").unwrap();
+
+        Ok(head)
+    }
+
+    /// Head, body, trailers and whether the connection can be reused.
+    /// `None` if it's closed before any byte of the response.
+    #[allow(clippy::type_complexity)]
+    fn read_resp(
+        &self,
+        stream: &mut TcpStream,
+        method: &Method,
+    ) -> Result<Option<(RespHead, Vec<u8>, HeaderMap, bool)>> {
+        let mut parser = RespParser::new();
+        let mut buf = vec![0; READ_BUF];
+
+        let Some(head) = read_head(stream, &mut parser)?
+        else {
+            return Ok(None);
+        };
+
+        /* Body */
+        let kind = head.body_kind(method).map_err(NetErr::Http)?;
+        let mut decoder = BodyDecoder::with_max(kind, self.max_body);
+        let mut chunk = parser.take_rest();
+
+        let body = loop {
+            if let ParseStatus::Complete(body) =
+                decoder.feed(&chunk).map_err(NetErr::Http)?
+            {
+                break body;
+            }
+
+            let n = read_some(stream, &mut buf)?;
+            if n == 0 {
+                break decoder.finish().map_err(NetErr::Http)?;
+            }
+            chunk = buf[..n].to_vec();
+        };
+
+        let keep_alive = head.is_keep_alive()
+            && kind != BodyKind::UntilClose
+            && head.status != StatusCode::SWITCHING_PROTOCOLS
+            && decoder.rest().is_empty();
+
+        Ok(Some((head, body.data, body.trailers, keep_alive)))
+    }
+
+    fn make_resp(
+        &self,
+        req: &Request,
+        head: RespHead,
+        body: Vec<u8>,
+        trailers: HeaderMap,
+    ) -> Result<Response> {
+        let RespHead {
+            version,
+            status,
+            reason,
+            mut headers,
+        } = head;
+
+        if self.use_cookies {
+            let mut jar = self.cookie_jar()?;
+
+            for set_cookie in headers.get_all(SET_COOKIE) {
+                if let Ok(set_cookie) = set_cookie.to_str() {
+                    jar.store(&req.uri, set_cookie);
+                }
+            }
+        }
+
+        let body = match headers.get(CONTENT_ENCODING) {
+            Some(encoding) if self.decompress && !body.is_empty() => {
+                let encoding = encoding.to_str().unwrap_or_default().trim();
+                let body = decode_content(encoding, body, self.max_body)?;
+
+                headers.remove(CONTENT_ENCODING);
+                headers.remove(CONTENT_LENGTH);
+
+                body
+            }
+            _ => body,
+        };
+
+        Ok(Response {
+            version,
+            status,
+            reason,
+            headers,
+            body,
+            trailers,
+            uri: req.uri.clone(),
+        })
+    }
+}
+
+
+impl Request {
+    pub fn new(method: Method, uri: Uri) -> Self {
+        Self {
+            method,
+            uri,
+            headers: HeaderMap::new(),
+            body: vec![],
+        }
+    }
+
+    pub fn get(uri: &str) -> Result<Self> {
+        Ok(Self::new(Method::GET, parse_uri(uri)?))
+    }
+
+    pub fn header(mut self, name: HeaderName, val: &str) -> Result<Self> {
+        let val = HeaderValue::from_str(val).map_err(|_| {
+            NetErr::Http(HttpKind::InvalidHeader(format!(
+                "bad value of {name}"
+            )))
+        })?;
+
+        self.headers.append(name, val);
+        Ok(self)
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+
+impl Response {
+    pub fn header_str(&self, name: HeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|val| val.to_str().ok())
+    }
+
+    /// Body decoded lossy as UTF-8
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+fn parse_uri(uri: &str) -> Result<Uri> {
+    Uri::from_str(uri)
+        .map_err(|_| NetErr::Http(HttpKind::InvalidUri(uri.to_owned())))
+}
+
+
+/// Head of the final (or 101) response, interim ones are skipped.
+/// `None` if it's closed before any byte.
+fn read_head(
+    stream: &mut TcpStream,
+    parser: &mut RespParser,
+) -> Result<Option<RespHead>> {
+    let mut buf = vec![0; READ_BUF];
+    let mut received = 0;
+    let mut chunk = vec![];
+
+    loop {
+        match parser.feed(&chunk).map_err(NetErr::Http)? {
+            ParseStatus::Complete(head)
+                if head.status.is_informational()
+                    && head.status != StatusCode::SWITCHING_PROTOCOLS =>
+            {
+                chunk.clear();
+                continue;
+            }
+            ParseStatus::Complete(head) => return Ok(Some(head)),
+            ParseStatus::Partial => (),
+        }
+
+        let n = read_some(stream, &mut buf)?;
+        received += n;
+
+        if n == 0 {
+            if received == 0 {
+                return Ok(None);
+            }
+            return Err(NetErr::Http(HttpKind::IncompleteBody));
+        }
+        chunk = buf[..n].to_vec();
+    }
+}
+
+
+fn read_some(stream: &mut TcpStream, buf: &mut [u8]) -> Result<usize> {
+    loop {
+        match stream.read(buf) {
+            Ok(n) => return Ok(n),
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(NetErr::Read(err)),
+        }
+    }
+}
+
+
+fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+
+/// Location may be relative to the request URI
+fn resolve_location(base: &Uri, location: &str) -> Result<Uri> {
+    let invalid = || NetErr::Http(HttpKind::InvalidUri(location.to_owned()));
+
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority().map(Authority::as_str).unwrap_or("");
+
+    let absolute = if location.contains("://") {
+        location.to_owned()
+    }
+    else if let Some(rest) = location.strip_prefix("//") {
+        format!("{scheme}://{rest}")
+    }
+    else if location.starts_with('/') {
+        format!("{scheme}://{authority}{location}")
+    }
+    else {
+        let dir = match base.path().rfind('/') {
+            Some(i) => &base.path()[..=i],
+            None => "/",
+        };
+        format!("{scheme}://{authority}{dir}{location}")
+    };
+
+    Uri::from_str(&absolute).map_err(|_| invalid())
+}
+
+
+/// gzip and deflate (zlib or raw, both are seen in the wild), at most
+/// `max` bytes are decoded
+fn decode_content(
+    encoding: &str,
+    body: Vec<u8>,
+    max: usize,
+) -> Result<Vec<u8>> {
+    let mut decoded = vec![];
+    let limit = max as u64 + 1;
+
+    let res = match encoding.to_ascii_lowercase().as_str() {
+        "identity" => return Ok(body),
+        "gzip" | "x-gzip" => GzDecoder::new(&body[..])
+            .take(limit)
+            .read_to_end(&mut decoded),
+        "deflate" => ZlibDecoder::new(&body[..])
+            .take(limit)
+            .read_to_end(&mut decoded)
+            .or_else(|_| {
+                decoded.clear();
+                DeflateDecoder::new(&body[..])
+                    .take(limit)
+                    .read_to_end(&mut decoded)
+            }),
+        _ => {
+            return Err(NetErr::Http(HttpKind::UnsupportedContentEncoding(
+                encoding.to_owned(),
+            )))
+        }
+    };
+
+    res.map_err(|_| {
+        NetErr::Http(HttpKind::UnsupportedContentEncoding(format!(
+            "bad {encoding} data"
+        )))
+    })?;
+
+    if decoded.len() > max {
+        return Err(NetErr::Http(HttpKind::BodyTooLarge(decoded.len())));
+    }
+
+    Ok(decoded)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use flate2::{write::GzEncoder, Compression};
+    use http::{header::COOKIE, Method, StatusCode};
+
+    use super::{Client, Request};
+    use crate::{
+        application::http::{
+            body::BodyDecoder,
+            parser::{ParseStatus, ReqHead, ReqParser},
+            ws::{
+                Handshake, Message, WebSocket, WsCodec, WsRole, CLOSE_NORMAL,
+            },
+        },
+        rs_error::{HttpKind, NetErr, WsKind},
+    };
+
+    /// Response of the test server, `None` closes the connection
+    fn route(head: &ReqHead, body: &[u8]) -> Option<Vec<u8>> {
+        let resp = |extra: &str, body: &[u8]| {
+            let mut resp = format!(
+                "HTTP/1.1 200 OK\r\n{extra}Content-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            resp.extend_from_slice(body);
+            resp
+        };
+        let redirect = |status: &str, location: &str| {
+            format!(
+                "HTTP/1.1 {status}\r\nLocation: {location}\r\n\
+Content-Length: 0\r\n\r\n"
+            )
+            .into_bytes()
+        };
+
+        Some(match head.uri.path() {
+            "/plain" => resp("", b"hello"),
+            "/chunked" => b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+\r\n5\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 11\r\n\r\n"
+                .to_vec(),
+            "/gzip" => {
+                let mut e = GzEncoder::new(vec![], Compression::default());
+                e.write_all(&b"zipped ".repeat(100)).unwrap();
+                resp("Content-Encoding: gzip\r\n", &e.finish().unwrap())
+            }
+            "/redirect" => redirect("302 Found", "plain"),
+            "/post" => redirect("303 See Other", "/echo"),
+            "/loop" => redirect("307 Temporary Redirect", "/loop"),
+            "/echo" => {
+                let mut echo = format!("{} ", head.method).into_bytes();
+                echo.extend_from_slice(body);
+                resp("", &echo)
+            }
+            "/cookie/set" => resp("Set-Cookie: sid=42; Path=/\r\n", b""),
+            "/cookie/get" => {
+                let cookie = head.joined(COOKIE, "; ").unwrap_or_default();
+                resp("", cookie.as_bytes())
+            }
+            "/proxy" => resp("", head.uri.to_string().as_bytes()),
+            "/close" => {
+                return Some(b"HTTP/1.0 200 OK\r\n\r\nuntil close".to_vec())
+            }
+            "/ws" => {
+                let hs = Handshake::from_req(
+                    &head.method,
+                    head.version,
+                    &head.headers,
+                )
+                .ok()?;
+                let protocol = hs.protocols.first().map(String::as_str);
+
+                let mut resp =
+                    b"HTTP/1.1 101 Switching Protocols\r\n".to_vec();
+                for (name, val) in hs.resp_headers(protocol).iter() {
+                    resp.extend_from_slice(name.as_str().as_bytes());
+                    resp.extend_from_slice(b": ");
+                    resp.extend_from_slice(val.as_bytes());
+                    resp.extend_from_slice(b"\r\n");
+                }
+                resp.extend_from_slice(b"\r\n");
+                resp
+            }
+            _ => return None,
+        })
+    }
+
+    fn serve(mut stream: TcpStream) {
+        let mut parser = ReqParser::new();
+        let mut buf = [0; 4096];
+        let mut chunk = vec![];
+
+        loop {
+            let head = match parser.feed(&chunk).unwrap() {
+                ParseStatus::Complete(head) => head,
+                ParseStatus::Partial => {
+                    let n = stream.read(&mut buf).unwrap_or(0);
+                    if n == 0 {
+                        return;
+                    }
+                    chunk = buf[..n].to_vec();
+                    continue;
+                }
+            };
+
+            let mut decoder = BodyDecoder::new(head.body_kind().unwrap());
+            let mut rest = parser.take_rest();
+            let body = loop {
+                if let ParseStatus::Complete(body) =
+                    decoder.feed(&rest).unwrap()
+                {
+                    break body;
+                }
+                let n = stream.read(&mut buf).unwrap();
+                rest = buf[..n].to_vec();
+            };
+            chunk = decoder.take_rest();
+
+            let Some(resp) = route(&head, &body.data)
+            else {
+                return;
+            };
+            stream.write_all(&resp).unwrap();
+
+            if head.uri.path() == "/close" {
+                return;
+            }
+
+            // echo data messages until the close frame
+            if head.uri.path() == "/ws" {
+                let hs = Handshake::from_req(
+                    &head.method,
+                    head.version,
+                    &head.headers,
+                )
+                .unwrap();
+                let mut codec = WsCodec::new(WsRole::Server);
+                if let Some(deflate) = hs.deflate {
+                    codec = codec.with_deflate(deflate);
+                }
+
+                let mut ws = WebSocket::new(stream, codec, chunk);
+                while let Ok(msg) = ws.read() {
+                    if matches!(msg, Message::Text(_) | Message::Binary(_)) {
+                        ws.send(&msg).unwrap();
+                    }
+                }
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let accepted2 = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                accepted2.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || serve(stream.unwrap()));
+            }
+        });
+
+        let url = |path: &str| format!("http://{addr}{path}");
+        let client = Client::new().with_cookies();
+
+        let resp = client.get(&url("/plain")).unwrap();
+        assert_eq!(
+            (resp.status, resp.text()),
+            (StatusCode::OK, "hello".into())
+        );
+
+        let resp = client.get(&url("/chunked")).unwrap();
+        assert_eq!(resp.text(), "hello world");
+        assert_eq!(resp.trailers["x-sum"], "11");
+
+        let resp = client.get(&url("/gzip")).unwrap();
+        assert_eq!(resp.body, b"zipped ".repeat(100));
+        assert!(resp.header_str(http::header::CONTENT_ENCODING).is_none());
+
+        // kept alive over one connection
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(client.idle_conns(), 1);
+
+        let resp = client.get(&url("/redirect")).unwrap();
+        assert_eq!(resp.text(), "hello");
+        assert_eq!(resp.uri.path(), "/plain");
+
+        let resp = client
+            .post(&url("/post"), "text/plain", b"x".to_vec())
+            .unwrap();
+        assert_eq!(resp.text(), "GET ");
+
+        let req = Request::new(Method::PUT, url("/echo").parse().unwrap())
+            .body(b"data".to_vec());
+        assert_eq!(client.send(req).unwrap().text(), "PUT data");
+
+        assert!(matches!(
+            client.get(&url("/loop")).unwrap_err(),
+            NetErr::Http(HttpKind::TooManyRedirects(10))
+        ));
+        let resp = Client::new()
+            .with_max_redirects(0)
+            .get(&url("/loop"))
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::TEMPORARY_REDIRECT);
+
+        // limits the decoded body, not only the transferred one
+        assert!(matches!(
+            Client::new()
+                .with_max_body(699)
+                .get(&url("/gzip"))
+                .unwrap_err(),
+            NetErr::Http(HttpKind::BodyTooLarge(700))
+        ));
+        let resp = Client::new().with_max_body(700).get(&url("/gzip"));
+        assert_eq!(resp.unwrap().body.len(), 700);
+
+        client.get(&url("/cookie/set")).unwrap();
+        assert_eq!(client.get(&url("/cookie/get")).unwrap().text(), "sid=42");
+
+        let before = accepted.load(Ordering::SeqCst);
+        let resp = client.get(&url("/close")).unwrap();
+        assert_eq!(resp.text(), "until close");
+        client.get(&url("/plain")).unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), before + 1);
+
+        // closed without response on the pooled one then the new one
+        assert!(matches!(
+            client.get(&url("/unknown")).unwrap_err(),
+            NetErr::Http(HttpKind::IncompleteBody)
+        ));
+        client.get(&url("/plain")).unwrap();
+
+        let proxied = Client::new().with_proxy(&addr.to_string()).unwrap();
+        let resp = proxied.get("http://example.invalid/proxy?a=1").unwrap();
+        assert_eq!(resp.text(), "http://example.invalid/proxy?a=1");
+
+        assert!(matches!(
+            client.get("https://example.invalid/").unwrap_err(),
+            NetErr::Http(HttpKind::UnsupportedScheme(_))
+        ));
+
+        let mut ws = client
+            .websocket(&format!("ws://{addr}/ws"), &["chat", "superchat"])
+            .unwrap();
+        assert_eq!(ws.protocol(), Some("chat"));
+        assert!(ws.codec().deflate().is_some());
+
+        for msg in [
+            Message::Text("hi".to_owned()),
+            Message::Binary((0..=255).cycle().take(200_000).collect()),
+        ] {
+            ws.send(&msg).unwrap();
+            assert_eq!(ws.read().unwrap(), msg);
+        }
+        ws.send(&Message::Ping(b"p".to_vec())).unwrap();
+        assert_eq!(ws.read().unwrap(), Message::Pong(b"p".to_vec()));
+
+        ws.close(CLOSE_NORMAL, "bye").unwrap();
+        assert!(matches!(
+            ws.send(&Message::Text("late".to_owned())).unwrap_err(),
+            NetErr::WebSocket(WsKind::ConnClosed)
+        ));
+
+        assert!(matches!(
+            client.websocket(&url("/plain"), &[]).unwrap_err(),
+            NetErr::WebSocket(WsKind::BadHandshake(_))
+        ));
+        assert!(matches!(
+            client.websocket("wss://example.invalid/", &[]).unwrap_err(),
+            NetErr::Http(HttpKind::UnsupportedScheme(_))
+        ));
+    }
+}
@@ -0,0 +1,189 @@
+//! Validators and HTTP-date for conditional requests
+//! ([rfc9110 8.8, 13](https://www.rfc-editor.org/rfc/rfc9110#section-13))
+
+use std::{fmt::Display, str::FromStr, time::SystemTime};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// IMF-fixdate, the preferred format
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+const RFC850_DATE: &str = "%A, %d-%b-%y %H:%M:%S GMT";
+const ASCTIME_DATE: &str = "%a %b %e %H:%M:%S %Y";
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// `"xyz"` or `W/"xyz"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag {
+    pub weak: bool,
+    /// Without quotes
+    pub tag: String,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl ETag {
+    pub fn strong<S: Into<String>>(tag: S) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn weak<S: Into<String>>(tag: S) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    /// Strong tag of a file from its size and mtime (nanosecond)
+    pub fn from_meta(len: u64, mtime: SystemTime) -> Self {
+        let nanos = mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        Self::strong(format!("{nanos:x}-{len:x}"))
+    }
+
+    /// Both are strong and identical, for If-Match and If-Range
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// For If-None-Match
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+}
+
+
+impl Display for ETag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+
+impl FromStr for ETag {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (weak, quoted) = match s.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        let tag = quoted
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+            .ok_or(())?;
+
+        if tag.contains('"') {
+            return Err(());
+        }
+
+        Ok(Self {
+            weak,
+            tag: tag.to_owned(),
+        })
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Whether the list (or `*`) of If-Match / If-None-Match contains `etag`,
+/// malformed members are skipped.
+pub fn etag_list_matches(val: &str, etag: &ETag, weak: bool) -> bool {
+    if val.trim() == "*" {
+        return true;
+    }
+
+    // etagc allows a comma, such a tag is split into malformed pieces
+    // that are skipped, ours never contain one
+    val.split(',')
+        .filter_map(|item| item.parse::<ETag>().ok())
+        .any(|other| {
+            if weak {
+                other.weak_eq(etag)
+            }
+            else {
+                other.strong_eq(etag)
+            }
+        })
+}
+
+
+pub fn fmt_http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format(IMF_FIXDATE).to_string()
+}
+
+
+/// IMF-fixdate, or the obsolete RFC 850 and asctime format
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let s = s.trim();
+
+    [IMF_FIXDATE, RFC850_DATE, ASCTIME_DATE]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .map(|naive| SystemTime::from(naive.and_utc()))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{etag_list_matches, fmt_http_date, parse_http_date, ETag};
+
+    #[test]
+    fn test_etag() {
+        let strong = ETag::strong("abc");
+
+        assert_eq!(strong.to_string(), "\"abc\"");
+        assert_eq!("W/\"abc\"".parse::<ETag>(), Ok(ETag::weak("abc")));
+        assert!("abc".parse::<ETag>().is_err());
+
+        assert!(etag_list_matches("\"x\", W/\"abc\"", &strong, true));
+        assert!(!etag_list_matches("\"x\", W/\"abc\"", &strong, false));
+        assert!(etag_list_matches(" \"abc\"", &strong, false));
+        assert!(etag_list_matches("*", &strong, false));
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_nanos(0x10);
+        assert_eq!(ETag::from_meta(0x20, mtime).tag, "10-20");
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+
+        assert_eq!(fmt_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        for s in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(s), Some(time), "{s}");
+        }
+
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+}
@@ -0,0 +1,215 @@
+//! Cookie jar of the client
+//! ([rfc6265 5.3, 5.4](https://www.rfc-editor.org/rfc/rfc6265#section-5.3))
+//!
+//! Cookies of a domain are kept in a [`HeaderCookie`], Secure ones are
+//! never sent as the client talks plain HTTP.
+
+use std::collections::HashMap;
+
+use cookie::{time::OffsetDateTime, Cookie};
+use http::Uri;
+
+use super::HeaderCookie;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    /// By domain and whether it's host-only (no Domain attribute)
+    domains: HashMap<(String, bool), HeaderCookie>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set-Cookie of the response to `uri`, invalid one or that of
+    /// another domain is ignored, an expired one removes the old one.
+    pub fn store(&mut self, uri: &Uri, set_cookie: &str) {
+        let Some(host) = uri.host().map(str::to_ascii_lowercase)
+        else {
+            return;
+        };
+        let Ok(mut cookie) = Cookie::parse(set_cookie.to_owned())
+        else {
+            return;
+        };
+
+        let key = match cookie.domain() {
+            Some(domain) => {
+                let domain =
+                    domain.trim_start_matches('.').to_ascii_lowercase();
+
+                if !domain_match(&host, &domain) {
+                    return;
+                }
+                (domain, false)
+            }
+            None => (host, true),
+        };
+
+        if !cookie.path().is_some_and(|path| path.starts_with('/')) {
+            cookie.set_path(default_path(uri.path()));
+        }
+
+        // Max-Age takes precedence
+        if let Some(max_age) = cookie.max_age() {
+            cookie.set_expires(OffsetDateTime::now_utc() + max_age);
+        }
+
+        let cookies = self.domains.entry(key).or_default();
+
+        if is_expired(&cookie) {
+            cookies.remove(cookie.name(), cookie.path());
+        }
+        else {
+            cookies.insert(cookie);
+        }
+    }
+
+    /// Value of Cookie header of the request to `uri`, longer path first
+    pub fn header(&self, uri: &Uri) -> Option<String> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let path = uri.path();
+
+        let mut matched: Vec<&Cookie<'static>> = self
+            .domains
+            .iter()
+            .filter(|((domain, host_only), _)| {
+                if *host_only {
+                    host == *domain
+                }
+                else {
+                    domain_match(&host, domain)
+                }
+            })
+            .flat_map(|(_, cookies)| cookies.iter())
+            .filter(|cookie| {
+                !is_expired(cookie)
+                    && cookie.secure() != Some(true)
+                    && path_match(path, cookie.path().unwrap_or("/"))
+            })
+            .collect();
+
+        if matched.is_empty() {
+            return None;
+        }
+
+        matched.sort_by_key(|cookie| {
+            std::cmp::Reverse(cookie.path().map(str::len))
+        });
+
+        let mut header = HeaderCookie::default();
+        for cookie in matched {
+            header.insert(cookie.clone());
+        }
+
+        Some(header.to_string())
+    }
+
+    /// Drop expired ones
+    pub fn purge(&mut self) {
+        for cookies in self.domains.values_mut() {
+            cookies.retain(|cookie| !is_expired(cookie));
+        }
+
+        self.domains.retain(|_, cookies| !cookies.is_empty());
+    }
+
+    pub fn clear(&mut self) {
+        self.domains.clear();
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+fn is_expired(cookie: &Cookie) -> bool {
+    cookie
+        .expires_datetime()
+        .is_some_and(|expires| expires <= OffsetDateTime::now_utc())
+}
+
+
+/// `host` is the domain or its subdomain
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+}
+
+
+/// Directory of the request path
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(i) => path[..i].to_owned(),
+    }
+}
+
+
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/')
+                || path[cookie_path.len()..].starts_with('/'))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use http::Uri;
+
+    use super::CookieJar;
+
+    #[test]
+    fn test_cookie_jar() {
+        let mut jar = CookieJar::new();
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+
+        jar.store(&uri("http://a.example.com/app/login"), "sid=1");
+        jar.store(
+            &uri("http://a.example.com/"),
+            "lang=en; Domain=.example.com; Path=/",
+        );
+        jar.store(&uri("http://a.example.com/"), "tk=x; Path=/app; Secure");
+        // another domain
+        jar.store(&uri("http://a.example.com/"), "evil=1; Domain=b.com");
+
+        assert_eq!(
+            jar.header(&uri("http://a.example.com/app/x")).unwrap(),
+            "sid=1; lang=en"
+        );
+        assert_eq!(
+            jar.header(&uri("http://b.example.com/app")).unwrap(),
+            "lang=en"
+        );
+        assert!(jar.header(&uri("http://b.com/")).is_none());
+        assert_eq!(
+            jar.header(&uri("http://a.example.com/apple")).unwrap(),
+            "lang=en"
+        );
+
+        // replaced then removed
+        jar.store(&uri("http://a.example.com/app/"), "sid=2");
+        assert_eq!(
+            jar.header(&uri("http://a.example.com/app")).unwrap(),
+            "sid=2; lang=en"
+        );
+        jar.store(&uri("http://a.example.com/app/"), "sid=2; Max-Age=0");
+        jar.purge();
+        assert_eq!(
+            jar.header(&uri("http://a.example.com/app")).unwrap(),
+            "lang=en"
+        );
+    }
+}
@@ -1,5 +1,15 @@
 //! Http 0.9, 1.0, 1.1
 //!
+
+pub mod body;
+pub mod client;
+pub mod cond;
+pub mod jar;
+pub mod parser;
+pub mod range;
+pub mod ws;
+
+
 use std::{str::FromStr, convert::Infallible, fmt::Display};
 
 use cookie::Cookie;
@@ -13,26 +23,38 @@ use crate::rs_error::HttpKind;
 ////////////////////////////////////////////////////////////////////////////////
 //// Structure
 
+/// Accept, items are in the order of the header
 #[derive(Debug, Default)]
 pub struct HeaderAccept {
     items: Vec<AcceptItem>
 }
 
 
-#[derive(Debug)]
+/// Media range, e.g. `text/*;q=0.8`
+#[derive(Debug, Clone, PartialEq)]
 pub struct AcceptItem {
-    names: Vec<Mime>,
-    pub q: Option<f32>,
+    pub mime: Mime,
+    /// Weight in 0..=1
+    pub q: f32,
 }
 
 
+/// Accept-Encoding, an empty one accepts identity only
 #[derive(Debug, Default)]
 pub struct HeaderAcceptEncoding {
-    pub items: Vec<AcceptEncoding>
+    pub items: Vec<EncodingItem>
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingItem {
+    /// `None` is `*`
+    pub encoding: Option<AcceptEncoding>,
+    pub q: f32,
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AcceptEncoding {
     /// using the Lempel-Ziv coding (LZ77), with a 32-bit CRC.
     ///
@@ -46,7 +68,28 @@ pub enum AcceptEncoding {
     Deflate,
 
     /// Using the Brotli algorithm Non Standard
-    Br
+    Br,
+
+    /// Zstandard ([rfc8878](https://www.rfc-editor.org/rfc/rfc8878))
+    Zstd,
+
+    /// No encoding
+    Identity,
+}
+
+
+/// Accept-Language
+#[derive(Debug, Default)]
+pub struct HeaderAcceptLanguage {
+    pub items: Vec<LanguageItem>
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageItem {
+    /// Lowercase language range, e.g. `en-us` or `*`
+    pub range: String,
+    pub q: f32,
 }
 
 
@@ -63,43 +106,72 @@ pub struct HeaderCookie {
 //// Implementation
 
 impl AcceptItem {
-    pub fn contains(&self, mime: &Mime) -> bool {
-        self.names
-        .iter()
-        .find(|name| *name == mime)
-        .is_some()
+    /// `*/*` < `type/*` < `type/subtype` < with parameters
+    pub fn specificity(&self) -> usize {
+        if self.mime.type_() == mime::STAR {
+            0
+        }
+        else if self.mime.subtype() == mime::STAR {
+            1
+        }
+        else {
+            2 + self.mime.params().count()
+        }
+    }
+
+    /// Media range matches the type, and its parameters are all in it
+    pub fn matches(&self, m: &Mime) -> bool {
+        (self.mime.type_() == mime::STAR || self.mime.type_() == m.type_())
+            && (self.mime.subtype() == mime::STAR
+                || self.mime.subtype() == m.subtype())
+            && self
+                .mime
+                .params()
+                .all(|(name, val)| m.get_param(name) == Some(val))
     }
 }
 
 
 impl HeaderAccept {
     pub fn accept(&self, m: &Mime) -> bool {
-        if self.get_by_mime(&m).is_some()
-        || *m != mime::STAR_STAR && self.get_by_mime(&mime::STAR_STAR).is_some() {
-            return true;
-        }
+        self.quality(m) > 0.0
+    }
 
-        if m.type_() == mime::TEXT {
-            self.get_by_mime(&mime::TEXT_STAR).is_some()
-        }
-        else {
-            false
+    /// Weight of the most specific range that matches, anything is
+    /// acceptable without the header
+    pub fn quality(&self, m: &Mime) -> f32 {
+        if self.items.is_empty() {
+            return 1.0;
         }
+
+        self.get_by_mime(m).map(|item| item.q).unwrap_or(0.0)
     }
 
+    /// The most specific range that matches
     pub fn get_by_mime(&self, m: &Mime) -> Option<&AcceptItem> {
         self.items
-        .iter()
-        .find(|x| {
-            if (*x).contains(m) {
-                true
-            }
-            else {
-                false
-            }
-        })
+            .iter()
+            .filter(|item| item.matches(m))
+            .max_by_key(|item| item.specificity())
+    }
+
+    /// By weight then specificity, unacceptable ones (q=0) excluded
+    pub fn ranked(&self) -> Vec<&AcceptItem> {
+        let mut ranked: Vec<&AcceptItem> =
+            self.items.iter().filter(|item| item.q > 0.0).collect();
+
+        ranked.sort_by(|a, b| {
+            b.q.total_cmp(&a.q)
+                .then(b.specificity().cmp(&a.specificity()))
+        });
+
+        ranked
     }
 
+    /// The most preferred of `available`, the earlier one on a tie
+    pub fn negotiate<'a>(&self, available: &'a [Mime]) -> Option<&'a Mime> {
+        best_of(available, |m| self.quality(m))
+    }
 }
 
 
@@ -108,32 +180,15 @@ impl FromStr for HeaderAccept {
     type Err = Infallible;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut items = vec![];
-        for item_s in s.trim_start().split(";") {
-            let mut names = vec![];
-            let mut q = None;
-            for mopts in item_s.split(",") {
-                lazy_static! {
-                    static ref QNUM: Regex = Regex::new("q=((1.0)|(1)|(0.[1-9]))").unwrap();
-                }
-
-                if let Some(cap) = QNUM.captures(mopts) {
-                    if let Some(v) = cap.get(1) {
-                        q = Some(v.as_str().parse().unwrap());
-                        continue;
-                    }
-                }
-
-                if let Ok(mime) = Mime::from_str(mopts) {
-                    names.push(mime);
-                }
-            }
-
-            items.push(AcceptItem {
-                names,
-                q,
+        let items = parse_weighted(s)
+            .into_iter()
+            .filter_map(|(range, q)| {
+                Some(AcceptItem {
+                    mime: Mime::from_str(&range).ok()?,
+                    q,
+                })
             })
-        }
+            .collect();
 
         Ok(HeaderAccept {
             items
@@ -154,6 +209,50 @@ impl HeaderCookie {
     //     .iter_mut()
     //     .find(|cookie| (**cookie).name() == name)
     // }
+
+    /// Replace the one with the same name and path
+    pub fn insert(&mut self, cookie: Cookie<'static>) {
+        let old = self.items.iter_mut().find(|old| {
+            old.name() == cookie.name() && old.path() == cookie.path()
+        });
+
+        match old {
+            Some(old) => *old = cookie,
+            None => self.items.push(cookie),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str, path: Option<&str>) {
+        self.items
+            .retain(|cookie| cookie.name() != name || cookie.path() != path);
+    }
+
+    pub fn retain<F: FnMut(&Cookie<'static>) -> bool>(&mut self, f: F) {
+        self.items.retain(f)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cookie<'static>> {
+        self.items.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+
+/// Value of Cookie header, `a=1; b=2`
+impl Display for HeaderCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, cookie) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}={}", cookie.name(), cookie.value())?;
+        }
+
+        Ok(())
+    }
 }
 
 
@@ -177,16 +276,24 @@ impl FromStr for HeaderCookie {
 }
 
 
+/// Lossy match, invalid field would be ignored.
 impl FromStr for HeaderAcceptEncoding {
     type Err = Infallible;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut items = vec![];
-        for item_s in s.trim_start().split(",") {
-            if let Ok(encoding) = AcceptEncoding::from_str(item_s.trim()) {
-                items.push(encoding);
-            }
-        }
+        let items = parse_weighted(s)
+            .into_iter()
+            .filter_map(|(coding, q)| {
+                let encoding = if coding == "*" {
+                    None
+                }
+                else {
+                    Some(AcceptEncoding::from_str(&coding).ok()?)
+                };
+
+                Some(EncodingItem { encoding, q })
+            })
+            .collect();
 
         Ok(Self {
             items
@@ -197,11 +304,43 @@ impl FromStr for HeaderAcceptEncoding {
 
 impl HeaderAcceptEncoding {
     pub fn contains(&self, encoding: &AcceptEncoding) -> bool {
-        self
-        .items
-        .iter()
-        .find(|x| *x == encoding)
-        .is_some()
+        self.quality(encoding) > 0.0
+    }
+
+    /// Explicit weight, or that of `*`. Identity is acceptable unless it's
+    /// excluded.
+    pub fn quality(&self, encoding: &AcceptEncoding) -> f32 {
+        let explicit = self
+            .items
+            .iter()
+            .find(|item| item.encoding.as_ref() == Some(encoding))
+            .or_else(|| {
+                self.items.iter().find(|item| item.encoding.is_none())
+            });
+
+        match explicit {
+            Some(item) => item.q,
+            None if *encoding == AcceptEncoding::Identity => 1.0,
+            None => 0.0,
+        }
+    }
+
+    /// By weight, unacceptable ones (q=0) and `*` excluded
+    pub fn ranked(&self) -> Vec<AcceptEncoding> {
+        let mut ranked: Vec<&EncodingItem> =
+            self.items.iter().filter(|item| item.q > 0.0).collect();
+
+        ranked.sort_by(|a, b| b.q.total_cmp(&a.q));
+        ranked.into_iter().filter_map(|item| item.encoding).collect()
+    }
+
+    /// Server-side selection among `supported` which is in the order of
+    /// the server preference, `None` if none of them is acceptable.
+    pub fn select(
+        &self,
+        supported: &[AcceptEncoding],
+    ) -> Option<AcceptEncoding> {
+        best_of(supported, |encoding| self.quality(encoding)).copied()
     }
 }
 
@@ -211,10 +350,13 @@ impl FromStr for AcceptEncoding {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(match s.to_lowercase().as_str() {
-            "gzip" => Self::Gzip,
-            "compress" => Self::Compress,
+            // x-gzip is an alias
+            "gzip" | "x-gzip" => Self::Gzip,
+            "compress" | "x-compress" => Self::Compress,
             "deflate" => Self::Deflate,
             "br" => Self::Br,
+            "zstd" => Self::Zstd,
+            "identity" => Self::Identity,
             _ => return Err(())
         })
     }
@@ -229,17 +371,149 @@ impl Display for AcceptEncoding {
                 Self::Compress => "compress",
                 Self::Deflate => "deflate",
                 Self::Br => "br",
+                Self::Zstd => "zstd",
+                Self::Identity => "identity",
             }
         )
     }
 }
 
 
+/// Lossy match, invalid field would be ignored.
+impl FromStr for HeaderAcceptLanguage {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let items = parse_weighted(s)
+            .into_iter()
+            .map(|(range, q)| LanguageItem {
+                range: range.to_lowercase(),
+                q,
+            })
+            .collect();
+
+        Ok(Self { items })
+    }
+}
+
+
+impl HeaderAcceptLanguage {
+    /// Weight of the longest range that matches the tag (basic filtering
+    /// of [rfc4647](https://www.rfc-editor.org/rfc/rfc4647#section-3.3.1)),
+    /// any language is acceptable without the header
+    pub fn quality(&self, tag: &str) -> f32 {
+        if self.items.is_empty() {
+            return 1.0;
+        }
+
+        let tag = tag.to_lowercase();
+
+        self.items
+            .iter()
+            .filter(|item| {
+                item.range == "*"
+                    || tag == item.range
+                    || tag.starts_with(&item.range)
+                        && tag[item.range.len()..].starts_with('-')
+            })
+            .max_by_key(|item| {
+                if item.range == "*" {
+                    0
+                }
+                else {
+                    item.range.len()
+                }
+            })
+            .map(|item| item.q)
+            .unwrap_or(0.0)
+    }
+
+    /// By weight, unacceptable ones (q=0) excluded
+    pub fn ranked(&self) -> Vec<&LanguageItem> {
+        let mut ranked: Vec<&LanguageItem> =
+            self.items.iter().filter(|item| item.q > 0.0).collect();
+
+        ranked.sort_by(|a, b| b.q.total_cmp(&a.q));
+        ranked
+    }
+
+    /// The most preferred of `available`, the earlier one on a tie
+    pub fn negotiate<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        best_of(available, |tag| self.quality(tag)).copied()
+    }
+}
+
+
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Function
 
 
+/// Elements of a comma-separated list and their weight
+/// ([rfc9110 12.4.2](https://www.rfc-editor.org/rfc/rfc9110#section-12.4.2)),
+/// the value keeps its parameters before `q`. An element with invalid
+/// weight is skipped.
+fn parse_weighted(s: &str) -> Vec<(String, f32)> {
+    lazy_static! {
+        static ref QVALUE: Regex =
+            Regex::new(r"^(?:0(?:\.[0-9]{0,3})?|1(?:\.0{0,3})?)$").unwrap();
+    }
+
+    let mut items = vec![];
+
+    'elem: for elem in s.split(',') {
+        let mut parts = elem.split(';').map(str::trim);
+        let mut val = match parts.next() {
+            Some(val) if !val.is_empty() => val.to_owned(),
+            _ => continue,
+        };
+        let mut q = 1.0;
+
+        for param in parts {
+            let Some((name, pval)) = param.split_once('=')
+            else {
+                continue;
+            };
+
+            if name.trim().eq_ignore_ascii_case("q") {
+                let pval = pval.trim();
+
+                if !QVALUE.is_match(pval) {
+                    continue 'elem;
+                }
+
+                q = pval.parse().unwrap();
+                // the rest is accept-ext
+                break;
+            }
+
+            val.push(';');
+            val.push_str(param);
+        }
+
+        items.push((val, q));
+    }
+
+    items
+}
+
+
+/// The first one of the highest weight, which is above 0
+fn best_of<T, F: Fn(&T) -> f32>(available: &[T], quality: F) -> Option<&T> {
+    let mut best: Option<(&T, f32)> = None;
+
+    for item in available {
+        let q = quality(item);
+
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((item, q));
+        }
+    }
+
+    best.map(|(item, _)| item)
+}
+
+
 pub fn parse_content_type(s: &str) -> Result<Mime, ()> {
     let s = s.trim().to_lowercase();
     let prefix = format!("{CONTENT_TYPE}: ");
@@ -260,6 +534,11 @@ mod tests {
 
     use mime::Mime;
 
+    use super::{
+        AcceptEncoding, HeaderAccept, HeaderAcceptEncoding,
+        HeaderAcceptLanguage,
+    };
+
 
     #[test]
     fn test_mime_from() {
@@ -269,4 +548,89 @@ mod tests {
 
         println!("m: {m}");
     }
-}
\ No newline at end of file
+
+
+    #[test]
+    fn test_accept() {
+        let accept: HeaderAccept =
+            "text/*;q=0.3, text/html;q=0.7, text/html;level=1, */*;q=0.5"
+                .parse()
+                .unwrap();
+
+        let html = Mime::from_str("text/html").unwrap();
+        let html1 = Mime::from_str("text/html;level=1").unwrap();
+        let plain = Mime::from_str("text/plain").unwrap();
+        let png = Mime::from_str("image/png").unwrap();
+
+        assert_eq!(accept.quality(&html1), 1.0);
+        assert_eq!(accept.quality(&html), 0.7);
+        assert_eq!(accept.quality(&plain), 0.3);
+        assert_eq!(accept.quality(&png), 0.5);
+
+        let ranked: Vec<String> = accept
+            .ranked()
+            .iter()
+            .map(|item| item.mime.to_string())
+            .collect();
+        assert_eq!(
+            ranked,
+            ["text/html;level=1", "text/html", "*/*", "text/*"]
+        );
+
+        assert_eq!(
+            accept.negotiate(&[plain.clone(), png.clone()]),
+            Some(&png)
+        );
+
+        // invalid weight is skipped, no header accepts anything
+        let accept: HeaderAccept =
+            "text/plain;q=2, image/png;q=0".parse().unwrap();
+        assert!(!accept.accept(&png));
+        assert!(!accept.accept(&plain));
+        assert!(HeaderAccept::default().accept(&png));
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        use AcceptEncoding::*;
+
+        let supported = [Br, Zstd, Gzip, Deflate, Identity];
+
+        let ae: HeaderAcceptEncoding =
+            "gzip;q=0.8, deflate, br;q=0.8, x-unknown".parse().unwrap();
+        assert_eq!(ae.ranked(), [Deflate, Gzip, Br]);
+        assert_eq!(ae.quality(&Identity), 1.0);
+        assert_eq!(ae.select(&supported), Some(Deflate));
+
+        // tie goes to the server preference
+        let ae: HeaderAcceptEncoding = "gzip, br".parse().unwrap();
+        assert_eq!(ae.select(&supported), Some(Br));
+
+        let ae: HeaderAcceptEncoding = "*;q=0.5, gzip;q=0".parse().unwrap();
+        assert!(!ae.contains(&Gzip));
+        assert_eq!(ae.select(&[Gzip, Deflate]), Some(Deflate));
+
+        let ae: HeaderAcceptEncoding = "identity;q=0, *;q=0".parse().unwrap();
+        assert_eq!(ae.select(&supported), None);
+
+        assert_eq!(
+            HeaderAcceptEncoding::default().select(&supported),
+            Some(Identity)
+        );
+    }
+
+    #[test]
+    fn test_accept_language() {
+        let al: HeaderAcceptLanguage =
+            "da, en-GB;q=0.8, en;q=0.7, *;q=0.1".parse().unwrap();
+
+        assert_eq!(al.quality("en-GB"), 0.8);
+        assert_eq!(al.quality("en-US"), 0.7);
+        assert_eq!(al.quality("english"), 0.1);
+        assert_eq!(al.ranked()[0].range, "da");
+        assert_eq!(al.negotiate(&["fr", "en-us", "en-gb"]), Some("en-gb"));
+
+        let al: HeaderAcceptLanguage = "zh-CN".parse().unwrap();
+        assert_eq!(al.negotiate(&["en", "zh"]), None);
+    }
+}
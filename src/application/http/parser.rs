@@ -0,0 +1,900 @@
+//! Incremental HTTP/0.9-1.1 request and HTTP/1.x response head parser
+//! ([rfc9112](https://www.rfc-editor.org/rfc/rfc9112))
+//!
+//! Bytes are fed as they arrive, each one is scanned once. Whatever
+//! follows the head (body, pipelined request) is kept for the caller,
+//! see [`super::body`] for the body.
+
+use std::str::FromStr;
+
+use http::{
+    header::{
+        HeaderName, CONNECTION, CONTENT_LENGTH, EXPECT, HOST,
+        TRANSFER_ENCODING,
+    },
+    uri::{Authority, PathAndQuery, Scheme},
+    HeaderMap, HeaderValue, Method, StatusCode, Uri, Version,
+};
+
+use super::body::BodyKind;
+use crate::rs_error::HttpKind;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const MAX_REQLN: usize = 8 * 1024;
+pub const MAX_HEADER_BYTES: usize = 64 * 1024;
+pub const MAX_HEADERS: usize = 100;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    /// Bytes of request (or status) line without CRLF
+    pub max_reqln: usize,
+    /// Bytes of the header section after request line
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+}
+
+
+/// Request line and headers
+#[derive(Debug)]
+pub struct ReqHead {
+    pub method: Method,
+    /// Origin-form target gets authority from Host header
+    pub uri: Uri,
+    pub version: Version,
+    /// Repeated fields are kept in order
+    pub headers: HeaderMap,
+}
+
+
+/// Status line and headers
+#[derive(Debug)]
+pub struct RespHead {
+    pub version: Version,
+    pub status: StatusCode,
+    pub reason: String,
+    /// Repeated fields are kept in order
+    pub headers: HeaderMap,
+}
+
+
+#[derive(Debug)]
+pub enum ParseStatus<T> {
+    /// Need more bytes
+    Partial,
+    Complete(T),
+}
+
+
+#[derive(Debug, Default)]
+pub struct ReqParser {
+    limits: ParseLimits,
+    buf: Vec<u8>,
+    /// Next byte to be scanned
+    pos: usize,
+    /// Start of the line being scanned
+    line_start: usize,
+    reqln: Option<(Method, Vec<u8>, Version)>,
+    headers: HeaderMap,
+    /// Start of the header section
+    headers_start: usize,
+}
+
+
+/// Same as [`ReqParser`] but the head starts with a status line
+#[derive(Debug, Default)]
+pub struct RespParser {
+    limits: ParseLimits,
+    buf: Vec<u8>,
+    pos: usize,
+    line_start: usize,
+    statusln: Option<(Version, StatusCode, String)>,
+    headers: HeaderMap,
+    headers_start: usize,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_reqln: MAX_REQLN,
+            max_header_bytes: MAX_HEADER_BYTES,
+            max_headers: MAX_HEADERS,
+        }
+    }
+}
+
+
+impl ReqHead {
+    /// All values of `name` joined by `sep`, for list-based fields
+    /// (Accept, Cookie ...)
+    pub fn joined(&self, name: HeaderName, sep: &str) -> Option<String> {
+        joined(&self.headers, name, sep)
+    }
+
+    /// Transfer-Encoding takes precedence, but a request with both of
+    /// them is rejected as it's likely to be smuggling.
+    pub fn body_kind(&self) -> Result<BodyKind, HttpKind> {
+        let te = self.joined(TRANSFER_ENCODING, ",");
+        let cl = self.joined(CONTENT_LENGTH, ",");
+
+        if let Some(te) = te {
+            if cl.is_some() {
+                return Err(HttpKind::InvalidHeader(
+                    "both Transfer-Encoding and Content-Length".to_owned(),
+                ));
+            }
+
+            // chunked must be the final one and applied only once
+            let codings: Vec<String> = te
+                .split(',')
+                .map(|coding| coding.trim().to_ascii_lowercase())
+                .filter(|coding| !coding.is_empty())
+                .collect();
+
+            return match &codings[..] {
+                [coding] if coding == "chunked" => Ok(BodyKind::Chunked),
+                _ => Err(HttpKind::UnsupportedTransferEncoding(te)),
+            };
+        }
+
+        match cl {
+            Some(cl) => content_length(&cl).map(BodyKind::Length),
+            None => Ok(BodyKind::None),
+        }
+    }
+
+    /// `Expect: 100-continue` of HTTP/1.1, other expectations are errors
+    pub fn expects_continue(&self) -> Result<bool, HttpKind> {
+        let Some(expect) = self.joined(EXPECT, ",")
+        else {
+            return Ok(false);
+        };
+
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return Err(HttpKind::ExpectationFailed(expect));
+        }
+
+        Ok(self.version == Version::HTTP_11)
+    }
+}
+
+
+impl RespHead {
+    /// All values of `name` joined by `sep`, for list-based fields
+    pub fn joined(&self, name: HeaderName, sep: &str) -> Option<String> {
+        joined(&self.headers, name, sep)
+    }
+
+    /// Framing of the response to `method`
+    /// ([rfc9112 6.3](https://www.rfc-editor.org/rfc/rfc9112#section-6.3))
+    pub fn body_kind(&self, method: &Method) -> Result<BodyKind, HttpKind> {
+        if *method == Method::HEAD
+            || self.status.is_informational()
+            || self.status == StatusCode::NO_CONTENT
+            || self.status == StatusCode::NOT_MODIFIED
+            || *method == Method::CONNECT && self.status.is_success()
+        {
+            return Ok(BodyKind::None);
+        }
+
+        if let Some(te) = self.joined(TRANSFER_ENCODING, ",") {
+            let codings: Vec<String> = te
+                .split(',')
+                .map(|coding| coding.trim().to_ascii_lowercase())
+                .filter(|coding| !coding.is_empty())
+                .collect();
+
+            // other codings before chunked aren't supported
+            return match &codings[..] {
+                [coding] if coding == "chunked" => Ok(BodyKind::Chunked),
+                [.., coding] if coding == "chunked" => {
+                    Err(HttpKind::UnsupportedTransferEncoding(te))
+                }
+                _ => Ok(BodyKind::UntilClose),
+            };
+        }
+
+        match self.joined(CONTENT_LENGTH, ",") {
+            Some(cl) => content_length(&cl).map(BodyKind::Length),
+            None => Ok(BodyKind::UntilClose),
+        }
+    }
+
+    /// Persistent by default since HTTP/1.1, HTTP/1.0 needs keep-alive
+    pub fn is_keep_alive(&self) -> bool {
+        let conn = self.joined(CONNECTION, ",").unwrap_or_default();
+        let has = |opt: &str| {
+            conn.split(',')
+                .any(|val| val.trim().eq_ignore_ascii_case(opt))
+        };
+
+        if self.version == Version::HTTP_11 {
+            !has("close")
+        }
+        else {
+            has("keep-alive")
+        }
+    }
+}
+
+
+impl<T> ParseStatus<T> {
+    pub fn is_complete(&self) -> bool {
+        matches!(self, Self::Complete(_))
+    }
+}
+
+
+impl ReqParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: ParseLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Feed next chunk (may be empty to parse what's left), the state is
+    /// reset after `Complete` so that the parser goes on with next request.
+    pub fn feed(
+        &mut self,
+        chunk: &[u8],
+    ) -> Result<ParseStatus<ReqHead>, HttpKind> {
+        // drop the empty lines skipped so far, or a peer sending nothing
+        // else would grow the buffer without bound
+        if self.reqln.is_none() && self.line_start > 0 {
+            self.buf.drain(..self.line_start);
+            self.pos -= self.line_start;
+            self.line_start = 0;
+        }
+
+        self.buf.extend_from_slice(chunk);
+
+        loop {
+            let Some(nl) = self.buf[self.pos..]
+                .iter()
+                .position(|c| *c == b'\n')
+                .map(|i| self.pos + i)
+            else {
+                self.pos = self.buf.len();
+                self.check_limits(self.buf.len())?;
+
+                return Ok(ParseStatus::Partial);
+            };
+
+            self.check_limits(nl)?;
+
+            let mut line = &self.buf[self.line_start..nl];
+            if let Some(stripped) = line.strip_suffix(b"\r") {
+                line = stripped;
+            }
+            let line = line.to_vec();
+
+            self.pos = nl + 1;
+            self.line_start = self.pos;
+
+            if self.reqln.is_none() {
+                // empty lines ahead of request line are ignored
+                if line.is_empty() {
+                    continue;
+                }
+
+                let (method, target, version) = parse_reqln(&line)?;
+
+                self.headers_start = self.pos;
+
+                if version == Version::HTTP_09 {
+                    self.reqln = Some((method, target, version));
+                    return self.complete().map(ParseStatus::Complete);
+                }
+
+                self.reqln = Some((method, target, version));
+            }
+            else if line.is_empty() {
+                return self.complete().map(ParseStatus::Complete);
+            }
+            else {
+                self.parse_header(&line)?;
+            }
+        }
+    }
+
+    /// Bytes after the parsed head(s)
+    pub fn rest(&self) -> &[u8] {
+        &self.buf[self.line_start..]
+    }
+
+    pub fn take_rest(&mut self) -> Vec<u8> {
+        let rest = self.buf.split_off(self.line_start);
+        self.reset();
+
+        rest
+    }
+
+    /// Nothing buffered or parsed partially
+    pub fn is_idle(&self) -> bool {
+        self.reqln.is_none() && self.buf.len() == self.line_start
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::with_limits(self.limits);
+    }
+
+    /// `end` is the end of the line being scanned
+    fn check_limits(&self, end: usize) -> Result<(), HttpKind> {
+        if self.reqln.is_none() {
+            let len = end - self.line_start;
+
+            if len > self.limits.max_reqln + 1 {
+                return Err(HttpKind::ReqLnTooLong(len));
+            }
+        }
+        else {
+            let len = end - self.headers_start;
+
+            if len > self.limits.max_header_bytes {
+                return Err(HttpKind::HeaderTooLarge(len));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_header(&mut self, line: &[u8]) -> Result<(), HttpKind> {
+        if self.headers.len() >= self.limits.max_headers {
+            return Err(HttpKind::TooManyHeaders(self.headers.len() + 1));
+        }
+
+        let (name, value) = parse_field(line)?;
+        self.headers.append(name, value);
+
+        Ok(())
+    }
+
+    /// Take the head and drop its bytes
+    fn complete(&mut self) -> Result<ReqHead, HttpKind> {
+        let (method, target, version) = self.reqln.take().unwrap();
+        let headers = std::mem::take(&mut self.headers);
+
+        self.buf.drain(..self.line_start);
+        self.pos -= self.line_start;
+        self.line_start = 0;
+        self.headers_start = 0;
+
+        let uri = build_uri(&target, &headers, version)?;
+
+        Ok(ReqHead {
+            method,
+            uri,
+            version,
+            headers,
+        })
+    }
+}
+
+
+impl RespParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: ParseLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Feed next chunk (may be empty to parse what's left), the state is
+    /// reset after `Complete` like [`ReqParser::feed`], so that an interim
+    /// (1xx) response is followed by the final one.
+    pub fn feed(
+        &mut self,
+        chunk: &[u8],
+    ) -> Result<ParseStatus<RespHead>, HttpKind> {
+        self.buf.extend_from_slice(chunk);
+
+        loop {
+            let Some(nl) = self.buf[self.pos..]
+                .iter()
+                .position(|c| *c == b'\n')
+                .map(|i| self.pos + i)
+            else {
+                self.pos = self.buf.len();
+                self.check_limits(self.buf.len())?;
+
+                return Ok(ParseStatus::Partial);
+            };
+
+            self.check_limits(nl)?;
+
+            let mut line = &self.buf[self.line_start..nl];
+            if let Some(stripped) = line.strip_suffix(b"\r") {
+                line = stripped;
+            }
+            let line = line.to_vec();
+
+            self.pos = nl + 1;
+            self.line_start = self.pos;
+
+            if self.statusln.is_none() {
+                self.statusln = Some(parse_statusln(&line)?);
+                self.headers_start = self.pos;
+            }
+            else if line.is_empty() {
+                return Ok(ParseStatus::Complete(self.complete()));
+            }
+            else {
+                if self.headers.len() >= self.limits.max_headers {
+                    return Err(HttpKind::TooManyHeaders(
+                        self.headers.len() + 1,
+                    ));
+                }
+
+                let (name, value) = parse_field(&line)?;
+                self.headers.append(name, value);
+            }
+        }
+    }
+
+    /// Bytes after the parsed head(s)
+    pub fn rest(&self) -> &[u8] {
+        &self.buf[self.line_start..]
+    }
+
+    pub fn take_rest(&mut self) -> Vec<u8> {
+        let rest = self.buf.split_off(self.line_start);
+        self.reset();
+
+        rest
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::with_limits(self.limits);
+    }
+
+    fn check_limits(&self, end: usize) -> Result<(), HttpKind> {
+        if self.statusln.is_none() {
+            let len = end - self.line_start;
+
+            if len > self.limits.max_reqln + 1 {
+                return Err(HttpKind::InvalidStatusLn(format!("{len} bytes")));
+            }
+        }
+        else {
+            let len = end - self.headers_start;
+
+            if len > self.limits.max_header_bytes {
+                return Err(HttpKind::HeaderTooLarge(len));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> RespHead {
+        let (version, status, reason) = self.statusln.take().unwrap();
+        let headers = std::mem::take(&mut self.headers);
+
+        self.buf.drain(..self.line_start);
+        self.pos -= self.line_start;
+        self.line_start = 0;
+        self.headers_start = 0;
+
+        RespHead {
+            version,
+            status,
+            reason,
+            headers,
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// All values of `name` joined by `sep`
+pub(crate) fn joined(
+    headers: &HeaderMap,
+    name: HeaderName,
+    sep: &str,
+) -> Option<String> {
+    let vals: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .collect();
+
+    if vals.is_empty() {
+        None
+    }
+    else {
+        Some(vals.join(sep))
+    }
+}
+
+
+/// Identical duplicates `3, 3` are allowed
+fn content_length(cl: &str) -> Result<usize, HttpKind> {
+    let mut lens = cl.split(',').map(|len| {
+        let len = len.trim();
+
+        if len.is_empty() || !len.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        len.parse::<usize>().ok()
+    });
+
+    let len = lens.next().flatten();
+
+    match len {
+        Some(len) if lens.all(|other| other == Some(len)) => Ok(len),
+        _ => Err(HttpKind::InvalidHeader(format!("bad Content-Length: {cl}"))),
+    }
+}
+
+
+/// `HTTP/1.1 200 OK`, the reason phrase may be empty
+fn parse_statusln(
+    line: &[u8],
+) -> Result<(Version, StatusCode, String), HttpKind> {
+    let invalid = || {
+        HttpKind::InvalidStatusLn(String::from_utf8_lossy(line).into_owned())
+    };
+
+    let mut parts = line.splitn(3, |c| *c == b' ');
+    let version = match parts.next().ok_or_else(invalid)? {
+        b"HTTP/1.0" => Version::HTTP_10,
+        b"HTTP/1.1" => Version::HTTP_11,
+        _ => return Err(invalid()),
+    };
+
+    let code = parts.next().ok_or_else(invalid)?;
+    if code.len() != 3 {
+        return Err(invalid());
+    }
+    let status = StatusCode::from_bytes(code).map_err(|_| invalid())?;
+
+    let reason = String::from_utf8_lossy(parts.next().unwrap_or_default());
+
+    Ok((version, status, reason.into_owned()))
+}
+
+
+/// `GET /path HTTP/1.1`, or `GET /path` of HTTP/0.9
+fn parse_reqln(line: &[u8]) -> Result<(Method, Vec<u8>, Version), HttpKind> {
+    let parts: Vec<&[u8]> = line.split(|c| *c == b' ').collect();
+
+    let (mb, target, version) = match parts[..] {
+        [mb, target, verb] => (mb, target, parse_version(verb)?),
+        // A request line containing only the path name is accepted by
+        // servers to maintain compatibility with HTTP clients before the
+        // HTTP/1.0 specification in RFC 1945.
+        [mb @ b"GET", target] => (mb, target, Version::HTTP_09),
+        _ => return Err(HttpKind::InvalidReqLn),
+    };
+
+    if target.is_empty() {
+        return Err(HttpKind::InvalidReqLn);
+    }
+
+    let method = Method::from_bytes(mb).map_err(|_| {
+        HttpKind::InvalidMethod(String::from_utf8_lossy(mb).into_owned())
+    })?;
+
+    Ok((method, target.to_vec(), version))
+}
+
+
+fn parse_version(verb: &[u8]) -> Result<Version, HttpKind> {
+    Ok(match verb {
+        b"HTTP/0.9" => Version::HTTP_09,
+        b"HTTP/1.0" => Version::HTTP_10,
+        b"HTTP/1.1" => Version::HTTP_11,
+        _ => {
+            return Err(HttpKind::UnRecognizedVerStr(
+                String::from_utf8_lossy(verb).into_owned(),
+            ))
+        }
+    })
+}
+
+
+/// `name: value` of header or trailer section, `line` is not empty
+pub(crate) fn parse_field(
+    line: &[u8],
+) -> Result<(HeaderName, HeaderValue), HttpKind> {
+    if line[0] == b' ' || line[0] == b'\t' {
+        return Err(HttpKind::InvalidHeader(format!(
+            "obsolete line folding: {}",
+            String::from_utf8_lossy(line)
+        )));
+    }
+
+    let colon = line.iter().position(|c| *c == b':').ok_or_else(|| {
+        HttpKind::InvalidHeader(format!(
+            "no colon: {}",
+            String::from_utf8_lossy(line)
+        ))
+    })?;
+
+    // no whitespace is allowed between name and colon
+    let name = HeaderName::from_bytes(&line[..colon]).map_err(|_| {
+        HttpKind::InvalidHeader(format!(
+            "bad name: {}",
+            String::from_utf8_lossy(&line[..colon])
+        ))
+    })?;
+
+    let value = trim_ows(&line[colon + 1..]);
+    let value = HeaderValue::from_bytes(value).map_err(|_| {
+        HttpKind::InvalidHeader(format!("bad value of {name}"))
+    })?;
+
+    Ok((name, value))
+}
+
+
+fn build_uri(
+    target: &[u8],
+    headers: &HeaderMap,
+    version: Version,
+) -> Result<Uri, HttpKind> {
+    let invalid_uri =
+        || HttpKind::InvalidUri(String::from_utf8_lossy(target).into_owned());
+
+    let mut hosts = headers.get_all(HOST).iter();
+    let host = hosts.next();
+
+    if hosts.next().is_some() {
+        return Err(HttpKind::InvalidHeader("multiple Host".to_owned()));
+    }
+
+    if host.is_none() && version == Version::HTTP_11 {
+        return Err(HttpKind::MissingHost);
+    }
+
+    // absolute-form, authority-form and asterisk-form stay as is
+    if target[0] != b'/' {
+        return Uri::try_from(target).map_err(|_| invalid_uri());
+    }
+
+    let pq = PathAndQuery::try_from(target).map_err(|_| invalid_uri())?;
+
+    let Some(host) = host.filter(|host| !host.is_empty())
+    else {
+        return Ok(Uri::from(pq));
+    };
+
+    let authority = host
+        .to_str()
+        .ok()
+        .and_then(|host| Authority::from_str(host).ok())
+        .ok_or_else(|| HttpKind::InvalidHeader("bad Host".to_owned()))?;
+
+    Uri::builder()
+        .scheme(Scheme::HTTP)
+        .authority(authority)
+        .path_and_query(pq)
+        .build()
+        .map_err(|_| invalid_uri())
+}
+
+
+fn trim_ows(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
+
+    bytes
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use http::{header::ACCEPT, Method, StatusCode, Version};
+
+    use super::{ParseLimits, ParseStatus, ReqHead, ReqParser, RespParser};
+    use crate::{application::http::body::BodyKind, rs_error::HttpKind};
+
+    fn complete(status: ParseStatus<ReqHead>) -> ReqHead {
+        match status {
+            ParseStatus::Complete(head) => head,
+            ParseStatus::Partial => panic!("partial"),
+        }
+    }
+
+    #[test]
+    fn test_req_parser_bytewise() {
+        let raw = b"\r\nGET /a/b?c=1 HTTP/1.1\r\nHost: example.com:8080\r\n\
+Accept: text/html\r\naccept:  */* \r\n\r\nBODY";
+        let mut parser = ReqParser::new();
+
+        for c in raw[..raw.len() - 5].iter() {
+            assert!(!parser.feed(&[*c]).unwrap().is_complete());
+        }
+
+        let head = complete(parser.feed(&raw[raw.len() - 5..]).unwrap());
+
+        assert_eq!(head.method, Method::GET);
+        assert_eq!(head.version, Version::HTTP_11);
+        assert_eq!(head.uri.to_string(), "http://example.com:8080/a/b?c=1");
+        assert_eq!(head.uri.query(), Some("c=1"));
+        assert_eq!(head.headers.get_all(ACCEPT).iter().count(), 2);
+        assert_eq!(
+            head.joined(ACCEPT, ", ").as_deref(),
+            Some("text/html, */*")
+        );
+        assert_eq!(parser.rest(), b"BODY");
+    }
+
+    #[test]
+    fn test_req_parser_pipelined() {
+        let mut parser = ReqParser::new();
+        let raw = b"GET /1 HTTP/1.0\n\nGET /2 HTTP/1.1\nHost: h\n\nGET /3";
+
+        let head = complete(parser.feed(raw).unwrap());
+        assert_eq!(head.uri.to_string(), "/1");
+        assert_eq!(head.version, Version::HTTP_10);
+
+        let head = complete(parser.feed(&[]).unwrap());
+        assert_eq!(head.uri.to_string(), "http://h/2");
+
+        assert!(!parser.feed(&[]).unwrap().is_complete());
+        assert!(!parser.is_idle());
+
+        // HTTP/0.9 has no header section
+        let head = complete(parser.feed(b"\r\n").unwrap());
+        assert_eq!(head.version, Version::HTTP_09);
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn test_req_parser_empty_lines() {
+        let mut parser = ReqParser::new();
+        let crlfs = b"\r\n".repeat(512);
+
+        for _ in 0..1024 {
+            assert!(!parser.feed(&crlfs).unwrap().is_complete());
+            assert!(parser.is_idle());
+            assert!(parser.buf.len() <= crlfs.len());
+        }
+
+        let head =
+            complete(parser.feed(b"\nGET /a HTTP/1.0\r\n\r\n").unwrap());
+        assert_eq!(head.uri.to_string(), "/a");
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn test_req_parser_errors() {
+        let err = |raw: &[u8]| ReqParser::new().feed(raw).unwrap_err();
+
+        assert!(matches!(err(b"GET\r\n"), HttpKind::InvalidReqLn));
+        assert!(matches!(
+            err(b"G(T / HTTP/1.1\r\n"),
+            HttpKind::InvalidMethod(_)
+        ));
+        assert!(matches!(
+            err(b"GET / HTTP/2.0\r\n"),
+            HttpKind::UnRecognizedVerStr(_)
+        ));
+        assert!(matches!(
+            err(b"GET / HTTP/1.1\r\nHost : x\r\n"),
+            HttpKind::InvalidHeader(_)
+        ));
+        assert!(matches!(
+            err(b"GET / HTTP/1.1\r\nA: 1\r\n folded\r\n"),
+            HttpKind::InvalidHeader(_)
+        ));
+        assert!(matches!(
+            err(b"GET / HTTP/1.1\r\n\r\n"),
+            HttpKind::MissingHost
+        ));
+        assert!(matches!(
+            err(b"GET /a b HTTP/1.0\r\n"),
+            HttpKind::InvalidReqLn
+        ));
+
+        let limits = ParseLimits {
+            max_reqln: 16,
+            max_header_bytes: 32,
+            max_headers: 2,
+        };
+
+        // detected before the line ends
+        let mut parser = ReqParser::with_limits(limits);
+        assert!(matches!(
+            parser.feed(&[b'a'; 20]).unwrap_err(),
+            HttpKind::ReqLnTooLong(20)
+        ));
+
+        let mut parser = ReqParser::with_limits(limits);
+        parser.feed(b"GET / HTTP/1.0\r\n").unwrap();
+        assert!(matches!(
+            parser.feed(&[b'a'; 40]).unwrap_err(),
+            HttpKind::HeaderTooLarge(40)
+        ));
+
+        let mut parser = ReqParser::with_limits(limits);
+        assert!(matches!(
+            parser.feed(b"GET / HTTP/1.0\r\nA: 1\r\nB: 2\r\nC: 3\r\n"),
+            Err(HttpKind::TooManyHeaders(3))
+        ));
+    }
+
+    #[test]
+    fn test_resp_parser() {
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n\
+Content-Length: 5\r\n\r\nhello";
+        let mut parser = RespParser::new();
+
+        let ParseStatus::Complete(interim) = parser.feed(raw).unwrap()
+        else {
+            panic!("partial")
+        };
+        assert_eq!(interim.status, StatusCode::CONTINUE);
+
+        let ParseStatus::Complete(head) = parser.feed(&[]).unwrap()
+        else {
+            panic!("partial")
+        };
+        assert_eq!(head.status, StatusCode::OK);
+        assert_eq!(head.reason, "OK");
+        assert_eq!(head.body_kind(&Method::GET).unwrap(), BodyKind::Length(5));
+        assert_eq!(head.body_kind(&Method::HEAD).unwrap(), BodyKind::None);
+        assert!(head.is_keep_alive());
+        assert_eq!(parser.take_rest(), b"hello");
+
+        let head = |raw: &[u8]| {
+            let ParseStatus::Complete(head) = RespParser::new().feed(raw)?
+            else {
+                panic!("partial")
+            };
+
+            Ok::<_, HttpKind>(head)
+        };
+
+        let h =
+            head(b"HTTP/1.0 204 \r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert_eq!(h.body_kind(&Method::GET).unwrap(), BodyKind::None);
+        assert!(h.is_keep_alive());
+
+        let h = head(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+        assert_eq!(h.body_kind(&Method::GET).unwrap(), BodyKind::UntilClose);
+        assert!(!h.is_keep_alive());
+
+        let h = head(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n")
+            .unwrap();
+        assert_eq!(h.body_kind(&Method::GET).unwrap(), BodyKind::UntilClose);
+
+        assert!(matches!(
+            head(b"HTTP/2 200 OK\r\n\r\n"),
+            Err(HttpKind::InvalidStatusLn(_))
+        ));
+        assert!(matches!(
+            head(b"HTTP/1.1 20 OK\r\n\r\n"),
+            Err(HttpKind::InvalidStatusLn(_))
+        ));
+    }
+}
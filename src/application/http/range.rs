@@ -0,0 +1,206 @@
+//! Range requests
+//! ([rfc9110 14](https://www.rfc-editor.org/rfc/rfc9110#section-14))
+
+use std::fmt::Display;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// More ranges than that are ignored instead of served part by part
+pub const MAX_RANGES: usize = 16;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Satisfiable range of a representation, both ends are inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeStatus {
+    /// Range is absent, malformed or of other unit, send all
+    Full,
+    Partial(Vec<ByteRange>),
+    /// 416 with `Content-Range: bytes */len`
+    Unsatisfiable,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Never empty, just to pair with `len`
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Value of Content-Range
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+
+impl Display for ByteRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Value of Content-Range for 416
+pub fn unsatisfied_range(total: u64) -> String {
+    format!("bytes */{total}")
+}
+
+
+/// `bytes=0-99, 200-, -50` against a representation of `total` bytes,
+/// unsatisfiable specs are dropped.
+pub fn parse_range(val: &str, total: u64) -> RangeStatus {
+    let Some((unit, specs)) = val.split_once('=')
+    else {
+        return RangeStatus::Full;
+    };
+
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeStatus::Full;
+    }
+
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return RangeStatus::Full;
+    }
+
+    let mut ranges = vec![];
+
+    for spec in specs {
+        match parse_spec(spec, total) {
+            Ok(Some(range)) => ranges.push(range),
+            Ok(None) => (),
+            Err(()) => return RangeStatus::Full,
+        }
+    }
+
+    if ranges.is_empty() {
+        RangeStatus::Unsatisfiable
+    }
+    else {
+        RangeStatus::Partial(ranges)
+    }
+}
+
+
+/// `Err` for bad syntax, `None` if it's unsatisfiable
+fn parse_spec(spec: &str, total: u64) -> Result<Option<ByteRange>, ()> {
+    let (first, last) = spec.split_once('-').ok_or(())?;
+    let num = |s: &str| {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(());
+        }
+
+        // too large to be satisfiable
+        Ok(s.parse::<u64>().unwrap_or(u64::MAX))
+    };
+
+    // suffix-range: last n bytes
+    if first.is_empty() {
+        let n = num(last)?;
+
+        if n == 0 || total == 0 {
+            return Ok(None);
+        }
+
+        return Ok(Some(ByteRange {
+            start: total.saturating_sub(n),
+            end: total - 1,
+        }));
+    }
+
+    let start = num(first)?;
+    let end = if last.is_empty() {
+        u64::MAX
+    }
+    else {
+        num(last)?
+    };
+
+    if end < start {
+        return Err(());
+    }
+
+    if start >= total {
+        return Ok(None);
+    }
+
+    Ok(Some(ByteRange {
+        start,
+        end: end.min(total - 1),
+    }))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, ByteRange, RangeStatus};
+
+    #[test]
+    fn test_parse_range() {
+        let r = |start, end| ByteRange { start, end };
+
+        assert_eq!(
+            parse_range("bytes=0-99, 200-,-50", 1000),
+            RangeStatus::Partial(vec![r(0, 99), r(200, 999), r(950, 999)])
+        );
+        assert_eq!(
+            parse_range("Bytes = 10-2000", 1000),
+            RangeStatus::Partial(vec![r(10, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-5000", 1000),
+            RangeStatus::Partial(vec![r(0, 999)])
+        );
+        assert_eq!(r(10, 999).content_range(1000), "bytes 10-999/1000");
+
+        // the satisfiable ones are kept
+        assert_eq!(
+            parse_range("bytes=1000-,5-5", 1000),
+            RangeStatus::Partial(vec![r(5, 5)])
+        );
+        assert_eq!(
+            parse_range("bytes=1000-1001", 1000),
+            RangeStatus::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeStatus::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeStatus::Unsatisfiable);
+
+        // malformed ones are ignored
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeStatus::Full);
+        assert_eq!(parse_range("bytes=a-1", 1000), RangeStatus::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeStatus::Full);
+        assert_eq!(parse_range("bytes=", 1000), RangeStatus::Full);
+        assert_eq!(
+            parse_range(&format!("bytes={}", ["0-0"; 17].join(",")), 1000),
+            RangeStatus::Full
+        );
+    }
+}
@@ -0,0 +1,1504 @@
+//! WebSocket ([rfc6455](https://www.rfc-editor.org/rfc/rfc6455)) with the
+//! permessage-deflate extension
+//! ([rfc7692](https://www.rfc-editor.org/rfc/rfc7692))
+//!
+//! [`Handshake`] checks the upgrade request on the server side and
+//! [`ClientHandshake`] the 101 response on the client side. [`WsCodec`]
+//! turns bytes into messages and back without doing I/O, fragments are
+//! reassembled and control frames may come between them. [`WebSocket`]
+//! drives it over a blocking stream.
+
+use std::io::{ErrorKind, Read, Write};
+
+use flate2::{
+    Compress, Compression, Decompress, FlushCompress, FlushDecompress,
+};
+use http::{
+    header::{
+        HeaderName, CONNECTION, SEC_WEBSOCKET_ACCEPT,
+        SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL,
+        SEC_WEBSOCKET_VERSION, UPGRADE,
+    },
+    HeaderMap, HeaderValue, Method, StatusCode, Version,
+};
+
+use super::parser::{joined, ParseStatus};
+use crate::{
+    enum_try_from_int,
+    rs_error::{NetErr, WsKind},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Appended to Sec-WebSocket-Key before hashing
+pub const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const WS_VERSION: &str = "13";
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+/// Bytes of a (reassembled and inflated) message by default
+pub const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+/// Payload of a frame sent, larger message is fragmented
+pub const FRAGMENT_SIZE: usize = 64 * 1024;
+pub const MAX_CONTROL_PAYLOAD: usize = 125;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// Sync flush of deflate ends with it, it's stripped from the payload
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const BASE64: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const READ_BUF: usize = 16 * 1024;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+enum_try_from_int! {
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum OpCode {
+        Continuation = 0x0,
+        Text = 0x1,
+        Binary = 0x2,
+        Close = 0x8,
+        Ping = 0x9,
+        Pong = 0xa,
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    /// First frame of a compressed message
+    pub rsv1: bool,
+    pub opcode: OpCode,
+    /// Unmasked
+    pub payload: Vec<u8>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Without status code if it's `None`
+    Close(Option<CloseFrame>),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+
+/// Server receives masked frames and sends unmasked ones, client does
+/// the opposite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsRole {
+    Server,
+    Client,
+}
+
+
+/// Negotiated permessage-deflate, both sides compress with the max
+/// window (15 bits)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// Server resets its compressor after each message
+    pub server_no_context_takeover: bool,
+    /// Client resets its compressor after each message
+    pub client_no_context_takeover: bool,
+}
+
+
+/// Valid upgrade request
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    /// Sec-WebSocket-Accept of the response
+    pub accept: String,
+    /// Subprotocols of the client in the order of preference
+    pub protocols: Vec<String>,
+    /// The first permessage-deflate offer we can accept
+    pub deflate: Option<DeflateParams>,
+}
+
+
+/// Upgrade request of the client, to check the response
+#[derive(Debug, Clone)]
+pub struct ClientHandshake {
+    /// Sec-WebSocket-Key, 16 random bytes in base64
+    pub key: String,
+    pub protocols: Vec<String>,
+    /// Offer permessage-deflate
+    pub deflate: bool,
+}
+
+
+/// Incremental frame decoder, what follows the frame is kept
+#[derive(Debug)]
+pub struct FrameDecoder {
+    role: WsRole,
+    max_payload: usize,
+    buf: Vec<u8>,
+}
+
+
+/// Messages from bytes and back, for one side of a connection
+#[derive(Debug)]
+pub struct WsCodec {
+    role: WsRole,
+    decoder: FrameDecoder,
+    /// Opcode, whether it's compressed and payload of the fragmented
+    /// message so far
+    partial: Option<(OpCode, bool, Vec<u8>)>,
+    max_message: usize,
+    fragment_size: usize,
+    deflate: Option<Deflate>,
+}
+
+
+/// Compressor and decompressor kept across messages unless the context
+/// takeover is off
+#[derive(Debug)]
+struct Deflate {
+    params: DeflateParams,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+
+/// WebSocket over a blocking stream, pings are answered and the close
+/// frame is echoed while reading.
+#[derive(Debug)]
+pub struct WebSocket<S> {
+    stream: S,
+    codec: WsCodec,
+    /// Bytes read but not fed to the codec yet
+    rest: Vec<u8>,
+    /// Subprotocol agreed in the handshake
+    protocol: Option<String>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl OpCode {
+    pub fn is_control(&self) -> bool {
+        *self as u8 & 0x8 != 0
+    }
+}
+
+
+impl Frame {
+    pub fn new(opcode: OpCode, payload: Vec<u8>) -> Self {
+        Self {
+            fin: true,
+            rsv1: false,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Masked by `mask` if it's sent by the client
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut out = Vec::with_capacity(len + 14);
+
+        out.push(
+            (self.fin as u8) << 7 | (self.rsv1 as u8) << 6 | self.opcode as u8,
+        );
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+        if len < 126 {
+            out.push(mask_bit | len as u8);
+        }
+        else if len <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        match mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+
+                let start = out.len();
+                out.extend_from_slice(&self.payload);
+                apply_mask(&mut out[start..], key);
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+
+        out
+    }
+}
+
+
+impl CloseFrame {
+    pub fn new(code: u16, reason: &str) -> Self {
+        Self {
+            code,
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+
+impl DeflateParams {
+    /// Parameters of a permessage-deflate offer if we can accept it, the
+    /// client may compress with a smaller window as it's inflated with
+    /// the max one anyway.
+    fn from_offer(params: &[(String, Option<String>)]) -> Option<Self> {
+        let mut deflate = Self::default();
+
+        for (i, (name, val)) in params.iter().enumerate() {
+            if params[..i].iter().any(|(prev, _)| prev == name) {
+                return None;
+            }
+
+            match (name.as_str(), val.as_deref()) {
+                ("server_no_context_takeover", None) => {
+                    deflate.server_no_context_takeover = true
+                }
+                ("client_no_context_takeover", None) => {
+                    deflate.client_no_context_takeover = true
+                }
+                ("server_max_window_bits", Some("15"))
+                | ("client_max_window_bits", None) => (),
+                ("client_max_window_bits", Some(bits))
+                    if is_window_bits(bits) => {}
+                _ => return None,
+            }
+        }
+
+        Some(deflate)
+    }
+
+    /// Parameters accepted by the server, which can't ask us for a
+    /// smaller window as it isn't offered.
+    fn from_resp(params: &[(String, Option<String>)]) -> Option<Self> {
+        let mut deflate = Self::default();
+
+        for (name, val) in params.iter() {
+            match (name.as_str(), val.as_deref()) {
+                ("server_no_context_takeover", None) => {
+                    deflate.server_no_context_takeover = true
+                }
+                ("client_no_context_takeover", None) => {
+                    deflate.client_no_context_takeover = true
+                }
+                ("server_max_window_bits", Some(bits))
+                    if is_window_bits(bits) => {}
+                ("client_max_window_bits", Some("15")) => (),
+                _ => return None,
+            }
+        }
+
+        Some(deflate)
+    }
+
+    /// Value of Sec-WebSocket-Extensions of the response
+    pub fn header(&self) -> String {
+        let mut s = PERMESSAGE_DEFLATE.to_owned();
+
+        if self.server_no_context_takeover {
+            s.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            s.push_str("; client_no_context_takeover");
+        }
+
+        s
+    }
+}
+
+
+impl Handshake {
+    /// GET of HTTP/1.1 with `Upgrade: websocket`, `Connection: upgrade`,
+    /// version 13 and a key of 16 bytes
+    pub fn from_req(
+        method: &Method,
+        version: Version,
+        headers: &HeaderMap,
+    ) -> std::result::Result<Self, WsKind> {
+        let bad = |reason: &str| WsKind::BadHandshake(reason.to_owned());
+
+        if method != Method::GET {
+            return Err(bad("method isn't GET"));
+        }
+        if version != Version::HTTP_11 {
+            return Err(bad("version isn't HTTP/1.1"));
+        }
+        if !is_upgrade(headers) {
+            return Err(bad("no upgrade to websocket"));
+        }
+
+        let ws_version =
+            joined(headers, SEC_WEBSOCKET_VERSION, ",").unwrap_or_default();
+        if ws_version.trim() != WS_VERSION {
+            return Err(WsKind::UnsupportedVersion(ws_version));
+        }
+
+        let key = headers
+            .get(SEC_WEBSOCKET_KEY)
+            .and_then(|key| key.to_str().ok())
+            .map(str::trim)
+            .filter(|key| {
+                base64_decode(key).is_some_and(|raw| raw.len() == 16)
+            })
+            .ok_or_else(|| bad("invalid Sec-WebSocket-Key"))?;
+
+        let protocols = joined(headers, SEC_WEBSOCKET_PROTOCOL, ",")
+            .map(|protocols| split_list(&protocols))
+            .unwrap_or_default();
+
+        let deflate = joined(headers, SEC_WEBSOCKET_EXTENSIONS, ",")
+            .map(|exts| parse_extensions(&exts))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(name, _)| name == PERMESSAGE_DEFLATE)
+            .find_map(|(_, params)| DeflateParams::from_offer(&params));
+
+        Ok(Self {
+            accept: accept_key(key),
+            protocols,
+            deflate,
+        })
+    }
+
+    /// Headers of the 101 response, `protocol` should be one of the
+    /// client.
+    pub fn resp_headers(&self, protocol: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        insert_str(&mut headers, SEC_WEBSOCKET_ACCEPT, &self.accept);
+
+        if let Some(protocol) = protocol {
+            insert_str(&mut headers, SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        if let Some(deflate) = &self.deflate {
+            insert_str(
+                &mut headers,
+                SEC_WEBSOCKET_EXTENSIONS,
+                &deflate.header(),
+            );
+        }
+
+        headers
+    }
+}
+
+
+impl ClientHandshake {
+    pub fn new(protocols: &[&str], deflate: bool) -> Self {
+        Self {
+            key: base64_encode(&rand::random::<[u8; 16]>()),
+            protocols: protocols.iter().map(|s| s.to_string()).collect(),
+            deflate,
+        }
+    }
+
+    /// Headers of the upgrade request besides Host
+    pub fn req_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        insert_str(&mut headers, SEC_WEBSOCKET_KEY, &self.key);
+
+        if !self.protocols.is_empty() {
+            insert_str(
+                &mut headers,
+                SEC_WEBSOCKET_PROTOCOL,
+                &self.protocols.join(", "),
+            );
+        }
+        if self.deflate {
+            insert_str(
+                &mut headers,
+                SEC_WEBSOCKET_EXTENSIONS,
+                PERMESSAGE_DEFLATE,
+            );
+        }
+
+        headers
+    }
+
+    /// The negotiated deflate and subprotocol if it's a valid 101 response
+    #[allow(clippy::type_complexity)]
+    pub fn check_resp(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> std::result::Result<(Option<DeflateParams>, Option<String>), WsKind>
+    {
+        let bad = |reason: String| WsKind::BadHandshake(reason);
+
+        if status != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(bad(format!("status {status}")));
+        }
+        if !is_upgrade(headers) {
+            return Err(bad("no upgrade to websocket".to_owned()));
+        }
+
+        let accept = headers
+            .get(SEC_WEBSOCKET_ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+        if accept.trim() != accept_key(&self.key) {
+            return Err(bad(format!("Sec-WebSocket-Accept {accept}")));
+        }
+
+        let protocol = headers
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|protocol| protocol.to_str().ok())
+            .map(|protocol| protocol.trim().to_owned());
+        if let Some(protocol) = &protocol {
+            if !self.protocols.contains(protocol) {
+                return Err(bad(format!("subprotocol {protocol}")));
+            }
+        }
+
+        let exts = joined(headers, SEC_WEBSOCKET_EXTENSIONS, ",")
+            .map(|exts| parse_extensions(&exts))
+            .unwrap_or_default();
+        let mut deflate = None;
+
+        // only the offered one, once
+        for (name, params) in exts {
+            let offered = name == PERMESSAGE_DEFLATE
+                && self.deflate
+                && deflate.is_none();
+            deflate =
+                offered.then(|| DeflateParams::from_resp(&params)).flatten();
+
+            if deflate.is_none() {
+                return Err(bad(format!("extension {name}")));
+            }
+        }
+
+        Ok((deflate, protocol))
+    }
+}
+
+
+impl FrameDecoder {
+    pub fn new(role: WsRole, max_payload: usize) -> Self {
+        Self {
+            role,
+            max_payload,
+            buf: vec![],
+        }
+    }
+
+    /// Bytes are buffered until a whole frame, call it again with no input
+    /// for the next one already buffered.
+    pub fn feed(
+        &mut self,
+        input: &[u8],
+    ) -> std::result::Result<ParseStatus<Frame>, WsKind> {
+        self.buf.extend_from_slice(input);
+
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(ParseStatus::Partial);
+        }
+
+        if buf[0] & 0x30 != 0 {
+            return Err(WsKind::Protocol("RSV2 or RSV3 is set".to_owned()));
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+        let rsv1 = buf[0] & 0x40 != 0;
+        let opcode = OpCode::try_from(buf[0] & 0x0f).map_err(|opcode| {
+            WsKind::Protocol(format!("unknown opcode {opcode:#x}"))
+        })?;
+
+        let masked = buf[1] & 0x80 != 0;
+        if masked != (self.role == WsRole::Server) {
+            return Err(WsKind::BadMask);
+        }
+
+        let (len, mut pos) = match buf[1] & 0x7f {
+            126 if buf.len() < 4 => return Ok(ParseStatus::Partial),
+            126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() < 10 => return Ok(ParseStatus::Partial),
+            127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(WsKind::Protocol(format!(
+                "control frame {opcode:?} is fragmented or too long"
+            )));
+        }
+        if len > self.max_payload as u64 {
+            return Err(WsKind::TooLarge(len as usize));
+        }
+        let len = len as usize;
+
+        let mask = if masked {
+            if buf.len() < pos + 4 {
+                return Ok(ParseStatus::Partial);
+            }
+            pos += 4;
+
+            Some(buf[pos - 4..pos].try_into().unwrap())
+        }
+        else {
+            None
+        };
+
+        if buf.len() < pos + len {
+            return Ok(ParseStatus::Partial);
+        }
+
+        let mut payload = buf[pos..pos + len].to_vec();
+        if let Some(key) = mask {
+            apply_mask(&mut payload, key);
+        }
+
+        self.buf.drain(..pos + len);
+
+        Ok(ParseStatus::Complete(Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        }))
+    }
+
+    pub fn rest(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+
+impl WsCodec {
+    pub fn new(role: WsRole) -> Self {
+        Self {
+            role,
+            decoder: FrameDecoder::new(role, MAX_MESSAGE),
+            partial: None,
+            max_message: MAX_MESSAGE,
+            fragment_size: FRAGMENT_SIZE,
+            deflate: None,
+        }
+    }
+
+    /// Compress data messages and inflate those with RSV1
+    pub fn with_deflate(mut self, params: DeflateParams) -> Self {
+        self.deflate = Some(Deflate::new(params));
+        self
+    }
+
+    pub fn with_max_message(mut self, max_message: usize) -> Self {
+        self.max_message = max_message;
+        self.decoder.max_payload = max_message;
+        self
+    }
+
+    pub fn with_fragment_size(mut self, fragment_size: usize) -> Self {
+        self.fragment_size = fragment_size.max(1);
+        self
+    }
+
+    pub fn role(&self) -> WsRole {
+        self.role
+    }
+
+    pub fn deflate(&self) -> Option<DeflateParams> {
+        self.deflate.as_ref().map(|deflate| deflate.params)
+    }
+
+    /// Next message, call it again with no input for the next one
+    /// already buffered. It can't go on after an error, which should be
+    /// sent in the close frame (see [`close_code`]).
+    pub fn feed(
+        &mut self,
+        input: &[u8],
+    ) -> std::result::Result<ParseStatus<Message>, WsKind> {
+        let mut input = input;
+
+        loop {
+            let ParseStatus::Complete(frame) = self.decoder.feed(input)?
+            else {
+                return Ok(ParseStatus::Partial);
+            };
+            input = &[];
+
+            if let Some(msg) = self.on_frame(frame)? {
+                return Ok(ParseStatus::Complete(msg));
+            }
+        }
+    }
+
+    fn on_frame(
+        &mut self,
+        frame: Frame,
+    ) -> std::result::Result<Option<Message>, WsKind> {
+        let Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        } = frame;
+        let protocol = |reason: &str| WsKind::Protocol(reason.to_owned());
+
+        if rsv1 && (opcode.is_control() || opcode == OpCode::Continuation) {
+            return Err(protocol("RSV1 of control or continuation frame"));
+        }
+        if rsv1 && self.deflate.is_none() {
+            return Err(protocol("RSV1 without permessage-deflate"));
+        }
+
+        let (opcode, compressed, payload) = match opcode {
+            OpCode::Ping => return Ok(Some(Message::Ping(payload))),
+            OpCode::Pong => return Ok(Some(Message::Pong(payload))),
+            OpCode::Close => return parse_close(&payload).map(Some),
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(protocol("new message before the last ends"));
+                }
+
+                (opcode, rsv1, payload)
+            }
+            OpCode::Continuation => {
+                let Some((opcode, compressed, mut data)) = self.partial.take()
+                else {
+                    return Err(protocol("continuation of nothing"));
+                };
+
+                if data.len() + payload.len() > self.max_message {
+                    return Err(WsKind::TooLarge(data.len() + payload.len()));
+                }
+                data.extend_from_slice(&payload);
+
+                (opcode, compressed, data)
+            }
+        };
+
+        if !fin {
+            self.partial = Some((opcode, compressed, payload));
+            return Ok(None);
+        }
+
+        let data = match (&mut self.deflate, compressed) {
+            (Some(deflate), true) => {
+                deflate.inflate(&payload, self.role, self.max_message)?
+            }
+            _ => payload,
+        };
+
+        Ok(Some(match opcode {
+            OpCode::Text => Message::Text(
+                String::from_utf8(data).map_err(|_| WsKind::InvalidUtf8)?,
+            ),
+            _ => Message::Binary(data),
+        }))
+    }
+
+    /// Frames of the message, data ones are compressed if deflate is on
+    /// and fragmented by the fragment size.
+    pub fn encode(&mut self, msg: &Message) -> Vec<u8> {
+        let (opcode, payload) = match msg {
+            Message::Text(text) => (OpCode::Text, text.as_bytes()),
+            Message::Binary(data) => (OpCode::Binary, &data[..]),
+            Message::Ping(payload) => {
+                return self.encode_frame(&Frame::new(
+                    OpCode::Ping,
+                    truncated(payload, MAX_CONTROL_PAYLOAD).to_vec(),
+                ))
+            }
+            Message::Pong(payload) => {
+                return self.encode_frame(&Frame::new(
+                    OpCode::Pong,
+                    truncated(payload, MAX_CONTROL_PAYLOAD).to_vec(),
+                ))
+            }
+            Message::Close(close) => {
+                return self.encode_frame(&Frame::new(
+                    OpCode::Close,
+                    encode_close(close.as_ref()),
+                ))
+            }
+        };
+
+        let compressed = self
+            .deflate
+            .as_mut()
+            .and_then(|deflate| deflate.deflate(payload, self.role));
+        let (payload, rsv1) = match &compressed {
+            Some(compressed) => (&compressed[..], true),
+            None => (payload, false),
+        };
+
+        let mut out = vec![];
+        let mut rest = payload;
+        let mut first = true;
+
+        loop {
+            let n = rest.len().min(self.fragment_size);
+            let frame = Frame {
+                fin: n == rest.len(),
+                rsv1: rsv1 && first,
+                opcode: if first { opcode } else { OpCode::Continuation },
+                payload: rest[..n].to_vec(),
+            };
+
+            out.extend(self.encode_frame(&frame));
+            rest = &rest[n..];
+            first = false;
+
+            if rest.is_empty() {
+                return out;
+            }
+        }
+    }
+
+    fn encode_frame(&self, frame: &Frame) -> Vec<u8> {
+        let mask = match self.role {
+            WsRole::Server => None,
+            WsRole::Client => Some(rand::random()),
+        };
+
+        frame.encode(mask)
+    }
+}
+
+
+impl Deflate {
+    fn new(params: DeflateParams) -> Self {
+        Self {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    /// Raw deflate of `data` ending with a sync flush whose tail is
+    /// stripped, `None` if it fails so that it's sent uncompressed.
+    fn deflate(&mut self, data: &[u8], role: WsRole) -> Option<Vec<u8>> {
+        let no_takeover = match role {
+            WsRole::Server => self.params.server_no_context_takeover,
+            WsRole::Client => self.params.client_no_context_takeover,
+        };
+
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+
+        loop {
+            out.reserve(1024);
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .ok()?;
+
+            // flushed if there is room left
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if no_takeover {
+            self.compress.reset();
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+
+        Some(out)
+    }
+
+    /// The tail is appended back before inflating
+    fn inflate(
+        &mut self,
+        payload: &[u8],
+        role: WsRole,
+        max: usize,
+    ) -> std::result::Result<Vec<u8>, WsKind> {
+        // context of the peer
+        let no_takeover = match role {
+            WsRole::Server => self.params.client_no_context_takeover,
+            WsRole::Client => self.params.server_no_context_takeover,
+        };
+
+        let mut input = payload.to_vec();
+        input.extend_from_slice(&DEFLATE_TAIL);
+
+        let start = self.decompress.total_in();
+        let mut out = Vec::with_capacity(payload.len() * 2 + 64);
+
+        loop {
+            out.reserve(4096);
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let written = out.len();
+
+            self.decompress
+                .decompress_vec(
+                    &input[consumed..],
+                    &mut out,
+                    FlushDecompress::Sync,
+                )
+                .map_err(|err| WsKind::BadDeflate(err.to_string()))?;
+
+            if out.len() > max {
+                return Err(WsKind::TooLarge(out.len()));
+            }
+
+            let now_consumed = (self.decompress.total_in() - start) as usize;
+            if now_consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            if now_consumed == consumed && out.len() == written {
+                return Err(WsKind::BadDeflate("no progress".to_owned()));
+            }
+        }
+
+        if no_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(out)
+    }
+}
+
+
+impl<S: Read + Write> WebSocket<S> {
+    /// `rest` is what has been read after the handshake
+    pub fn new(stream: S, codec: WsCodec, rest: Vec<u8>) -> Self {
+        Self {
+            stream,
+            codec,
+            rest,
+            protocol: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    pub fn with_protocol(mut self, protocol: Option<String>) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn codec(&self) -> &WsCodec {
+        &self.codec
+    }
+
+    /// Next message including control ones. A ping is answered with pong
+    /// and a close frame is echoed, after which it's
+    /// [`WsKind::ConnClosed`]. On a protocol error the close frame is sent
+    /// before returning it.
+    pub fn read(&mut self) -> Result<Message> {
+        let mut buf = vec![0; READ_BUF];
+        let mut input = std::mem::take(&mut self.rest);
+
+        loop {
+            if self.close_received {
+                return Err(NetErr::WebSocket(WsKind::ConnClosed));
+            }
+
+            match self.codec.feed(&input) {
+                Ok(ParseStatus::Complete(msg)) => {
+                    match &msg {
+                        Message::Ping(payload) if !self.close_sent => {
+                            self.send(&Message::Pong(payload.clone()))?
+                        }
+                        Message::Close(close) => {
+                            self.close_received = true;
+
+                            if !self.close_sent {
+                                let code = close.as_ref().map(|c| c.code);
+                                let echo =
+                                    code.map(|code| CloseFrame::new(code, ""));
+                                self.send(&Message::Close(echo))?;
+                            }
+                        }
+                        _ => (),
+                    }
+
+                    return Ok(msg);
+                }
+                Ok(ParseStatus::Partial) => (),
+                Err(kind) => {
+                    if !self.close_sent {
+                        let close = CloseFrame::new(close_code(&kind), "");
+                        let _ = self.send(&Message::Close(Some(close)));
+                    }
+                    self.close_received = true;
+
+                    return Err(NetErr::WebSocket(kind));
+                }
+            }
+
+            let n = loop {
+                match self.stream.read(&mut buf) {
+                    Ok(n) => break n,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                    Err(err) => return Err(NetErr::Read(err)),
+                }
+            };
+
+            if n == 0 {
+                self.close_received = true;
+                return Err(NetErr::WebSocket(WsKind::ConnClosed));
+            }
+            input = buf[..n].to_vec();
+        }
+    }
+
+    /// Nothing can be sent after the close frame
+    pub fn send(&mut self, msg: &Message) -> Result<()> {
+        if self.close_sent {
+            return Err(NetErr::WebSocket(WsKind::ConnClosed));
+        }
+        if let Message::Close(_) = msg {
+            self.close_sent = true;
+        }
+
+        let bytes = self.codec.encode(msg);
+
+        self.stream
+            .write_all(&bytes)
+            .and_then(|_| self.stream.flush())
+            .map_err(NetErr::Write)
+    }
+
+    /// Send the close frame and wait for that of the peer, messages in
+    /// between are dropped.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        if !self.close_sent {
+            self.send(&Message::Close(Some(CloseFrame::new(code, reason))))?;
+        }
+
+        while !self.close_received {
+            match self.read() {
+                Ok(_) => (),
+                Err(NetErr::WebSocket(WsKind::ConnClosed)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Sec-WebSocket-Accept of the key
+pub fn accept_key(key: &str) -> String {
+    let mut raw = key.as_bytes().to_vec();
+    raw.extend_from_slice(WS_GUID.as_bytes());
+
+    base64_encode(&sha1(&raw))
+}
+
+
+/// `Upgrade: websocket` and `Connection: upgrade`
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    has_token(headers, UPGRADE, "websocket")
+        && has_token(headers, CONNECTION, "upgrade")
+}
+
+
+/// Status code of the close frame sent on the error
+pub fn close_code(kind: &WsKind) -> u16 {
+    match kind {
+        WsKind::TooLarge(_) => CLOSE_TOO_BIG,
+        WsKind::InvalidUtf8 | WsKind::BadDeflate(_) => CLOSE_INVALID_DATA,
+        WsKind::ConnClosed => CLOSE_NORMAL,
+        _ => CLOSE_PROTOCOL_ERROR,
+    }
+}
+
+
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .any(|val| val.trim().eq_ignore_ascii_case(token))
+}
+
+
+fn insert_str(headers: &mut HeaderMap, name: HeaderName, val: &str) {
+    if let Ok(val) = HeaderValue::from_str(val) {
+        headers.insert(name, val);
+    }
+}
+
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+
+/// `name; param; param=value, name ...` in order, names are lowercased
+#[allow(clippy::type_complexity)]
+fn parse_extensions(s: &str) -> Vec<(String, Vec<(String, Option<String>)>)> {
+    s.split(',')
+        .filter_map(|ext| {
+            let mut parts = ext.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.split_once('=') {
+                    Some((name, val)) => (
+                        name.trim().to_ascii_lowercase(),
+                        Some(val.trim().trim_matches('"').to_owned()),
+                    ),
+                    None => (param.to_ascii_lowercase(), None),
+                })
+                .collect();
+
+            Some((name.to_ascii_lowercase(), params))
+        })
+        .collect()
+}
+
+
+fn is_window_bits(bits: &str) -> bool {
+    bits.parse::<u8>()
+        .is_ok_and(|bits| (8..=15).contains(&bits))
+}
+
+
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+
+/// At most `max` bytes
+fn truncated(payload: &[u8], max: usize) -> &[u8] {
+    &payload[..payload.len().min(max)]
+}
+
+
+/// Status code and reason, the reason is cut at a char boundary to fit
+/// in a control frame
+fn encode_close(close: Option<&CloseFrame>) -> Vec<u8> {
+    let Some(close) = close
+    else {
+        return vec![];
+    };
+
+    let mut end = close.reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+    while !close.reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut payload = close.code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&close.reason.as_bytes()[..end]);
+    payload
+}
+
+
+fn parse_close(payload: &[u8]) -> std::result::Result<Message, WsKind> {
+    if payload.is_empty() {
+        return Ok(Message::Close(None));
+    }
+    if payload.len() < 2 {
+        return Err(WsKind::Protocol("close frame of 1 byte".to_owned()));
+    }
+
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+
+    // 1004-1006 and 1015 are reserved, never sent in the frame
+    let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+    if !valid {
+        return Err(WsKind::Protocol(format!("close code {code}")));
+    }
+
+    let reason = std::str::from_utf8(&payload[2..])
+        .map_err(|_| WsKind::InvalidUtf8)?
+        .to_owned();
+
+    Ok(Message::Close(Some(CloseFrame { code, reason })))
+}
+
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] =
+        [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks_exact(64) {
+        let mut w = [0u32; 80];
+
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] =
+                (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        for (hi, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hi = hi.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, hi) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&hi.to_be_bytes());
+    }
+
+    digest
+}
+
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+            else {
+                s.push('=');
+            }
+        }
+    }
+
+    s
+}
+
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut data = vec![];
+    let last = s.len() / 4;
+
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let pad = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if pad > 2 || pad > 0 && i + 1 != last {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for (j, c) in chunk[..4 - pad].iter().enumerate() {
+            let v = BASE64.iter().position(|b| b == c)? as u32;
+            n |= v << (18 - 6 * j);
+        }
+
+        data.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+    }
+
+    Some(data)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ws_handshake() {
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_decode("Zm9vYmE=").unwrap(), b"fooba");
+        assert!(base64_decode("Zm9=YmE=").is_none());
+        assert_eq!(
+            sha1(b"abc")
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+
+        // example of rfc6455 1.3
+        let mut headers = ClientHandshake {
+            key: "dGhlIHNhbXBsZSBub25jZQ==".to_owned(),
+            protocols: vec!["chat".to_owned()],
+            deflate: true,
+        }
+        .req_headers();
+        headers.insert(
+            SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static(
+                "permessage-deflate; server_max_window_bits=10, \
+                 permessage-deflate; client_no_context_takeover",
+            ),
+        );
+
+        let hs = Handshake::from_req(&Method::GET, Version::HTTP_11, &headers)
+            .unwrap();
+        assert_eq!(hs.accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(hs.protocols, ["chat"]);
+        assert_eq!(
+            hs.deflate,
+            Some(DeflateParams {
+                server_no_context_takeover: false,
+                client_no_context_takeover: true,
+            })
+        );
+
+        let client = ClientHandshake::new(&["chat"], true);
+        let mut req = client.req_headers();
+        req.remove(SEC_WEBSOCKET_EXTENSIONS);
+        let hs =
+            Handshake::from_req(&Method::GET, Version::HTTP_11, &req).unwrap();
+        assert!(hs.deflate.is_none());
+
+        let resp = hs.resp_headers(Some("chat"));
+        assert_eq!(
+            client
+                .check_resp(StatusCode::SWITCHING_PROTOCOLS, &resp)
+                .unwrap(),
+            (None, Some("chat".to_owned()))
+        );
+        assert!(client.check_resp(StatusCode::OK, &resp).is_err());
+
+        let mut resp = Handshake {
+            accept: "bad".to_owned(),
+            ..hs
+        }
+        .resp_headers(None);
+        assert!(client
+            .check_resp(StatusCode::SWITCHING_PROTOCOLS, &resp)
+            .is_err());
+
+        // unknown extension from the server
+        resp.insert(
+            SEC_WEBSOCKET_ACCEPT,
+            accept_key(&client.key).parse().unwrap(),
+        );
+        resp.insert(SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static("foo"));
+        assert!(client
+            .check_resp(StatusCode::SWITCHING_PROTOCOLS, &resp)
+            .is_err());
+
+        let mut bad = req.clone();
+        bad.insert(SEC_WEBSOCKET_KEY, HeaderValue::from_static("c2hvcnQ="));
+        assert!(matches!(
+            Handshake::from_req(&Method::GET, Version::HTTP_11, &bad),
+            Err(WsKind::BadHandshake(_))
+        ));
+        bad = req.clone();
+        bad.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("8"));
+        assert!(matches!(
+            Handshake::from_req(&Method::GET, Version::HTTP_11, &bad),
+            Err(WsKind::UnsupportedVersion(_))
+        ));
+        assert!(Handshake::from_req(&Method::POST, Version::HTTP_11, &req)
+            .is_err());
+        assert!(Handshake::from_req(
+            &Method::GET,
+            Version::HTTP_11,
+            &HeaderMap::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ws_codec() {
+        let mut client = WsCodec::new(WsRole::Client).with_fragment_size(4);
+        let mut server = WsCodec::new(WsRole::Server);
+
+        // fragmented text with a ping in between, fed byte by byte
+        let text = Message::Text("hello 世界".to_owned());
+        let mut bytes = client.encode(&text);
+        let ping = client.encode(&Message::Ping(b"p".to_vec()));
+        // after the first frame of 2 + 4 (mask) + 4 bytes
+        bytes.splice(10..10, ping);
+        assert_eq!(bytes[1] & 0x80, 0x80);
+
+        let mut msgs = vec![];
+        for b in bytes.iter() {
+            if let ParseStatus::Complete(msg) =
+                server.feed(std::slice::from_ref(b)).unwrap()
+            {
+                msgs.push(msg);
+            }
+        }
+        assert_eq!(
+            msgs,
+            [
+                Message::Ping(b"p".to_vec()),
+                Message::Text("hello 世界".to_owned())
+            ]
+        );
+
+        // server sends unmasked, the length of 16 bits
+        let data = vec![7; 300];
+        let bytes = server.encode(&Message::Binary(data.clone()));
+        assert_eq!(&bytes[..4], [0x82, 126, 1, 44]);
+        let ParseStatus::Complete(msg) = client.feed(&bytes).unwrap()
+        else {
+            panic!("partial")
+        };
+        assert_eq!(msg, Message::Binary(data));
+
+        let close = CloseFrame::new(CLOSE_GOING_AWAY, "bye");
+        let bytes = client.encode(&Message::Close(Some(close.clone())));
+        let ParseStatus::Complete(msg) = server.feed(&bytes).unwrap()
+        else {
+            panic!("partial")
+        };
+        assert_eq!(msg, Message::Close(Some(close)));
+
+        // errors
+        let mut server = WsCodec::new(WsRole::Server);
+        let unmasked = Frame::new(OpCode::Text, b"x".to_vec()).encode(None);
+        assert!(matches!(server.feed(&unmasked), Err(WsKind::BadMask)));
+
+        let mut server = WsCodec::new(WsRole::Server).with_max_message(4);
+        let big = client.encode(&Message::Binary(vec![0; 9]));
+        assert!(matches!(server.feed(&big), Err(WsKind::TooLarge(_))));
+
+        let mut server = WsCodec::new(WsRole::Server);
+        let cont =
+            Frame::new(OpCode::Continuation, vec![]).encode(Some([1; 4]));
+        let err = server.feed(&cont).unwrap_err();
+        assert_eq!(close_code(&err), CLOSE_PROTOCOL_ERROR);
+
+        let mut server = WsCodec::new(WsRole::Server);
+        let frame = Frame::new(OpCode::Text, vec![0xff, 0xfe]);
+        let err = server.feed(&frame.encode(Some([9; 4]))).unwrap_err();
+        assert_eq!(close_code(&err), CLOSE_INVALID_DATA);
+
+        let mut server = WsCodec::new(WsRole::Server);
+        let frame = Frame::new(OpCode::Close, 1005u16.to_be_bytes().to_vec());
+        assert!(server.feed(&frame.encode(Some([0; 4]))).is_err());
+    }
+
+    #[test]
+    fn test_ws_deflate() {
+        let params = DeflateParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: false,
+        };
+        let mut client = WsCodec::new(WsRole::Client)
+            .with_deflate(params)
+            .with_fragment_size(16);
+        let mut server = WsCodec::new(WsRole::Server).with_deflate(params);
+
+        let text = "live capture stats ".repeat(20);
+
+        for _ in 0..3 {
+            let bytes = client.encode(&Message::Text(text.clone()));
+            assert!(bytes.len() < text.len());
+            assert_eq!(bytes[0], 0x40 | OpCode::Text as u8);
+
+            let ParseStatus::Complete(msg) = server.feed(&bytes).unwrap()
+            else {
+                panic!("partial")
+            };
+            assert_eq!(msg, Message::Text(text.clone()));
+
+            let bytes = server.encode(&Message::Binary(vec![]));
+            let ParseStatus::Complete(msg) = client.feed(&bytes).unwrap()
+            else {
+                panic!("partial")
+            };
+            assert_eq!(msg, Message::Binary(vec![]));
+        }
+
+        // the example of rfc7692 7.2.3.1
+        let mut client = WsCodec::new(WsRole::Client).with_deflate(params);
+        let frame = Frame {
+            fin: true,
+            rsv1: true,
+            opcode: OpCode::Text,
+            payload: vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+        };
+        let ParseStatus::Complete(msg) =
+            client.feed(&frame.encode(None)).unwrap()
+        else {
+            panic!("partial")
+        };
+        assert_eq!(msg, Message::Text("Hello".to_owned()));
+
+        // compressed without the extension
+        let mut server = WsCodec::new(WsRole::Server);
+        let bytes = WsCodec::new(WsRole::Client)
+            .with_deflate(params)
+            .encode(&Message::Text(text));
+        assert!(matches!(server.feed(&bytes), Err(WsKind::Protocol(_))));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod http;
-
+pub mod dhcp;
+pub mod dns;
+pub mod fastcgi;
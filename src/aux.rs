@@ -1,11 +1,19 @@
 ////////////////////////////////////////////////////////////////////////////////
 //// Macros
 
-use std::{cell::RefCell, error::Error, net::Ipv4Addr, str::FromStr, cmp::min};
+use std::{
+    cell::RefCell,
+    cmp::min,
+    error::Error,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
 
 use either::Either;
 use libc::{c_char, in_addr, in_addr_t, memcpy};
 
+use crate::application::dns::resolver::Resolver;
+
 #[macro_export]
 macro_rules! bincode_options {
     () => {
@@ -464,19 +472,17 @@ impl TryInto<Ipv4Addr> for HostOrIPv4 {
         Ok(match self.0 {
             Either::Left(ip) => ip,
             Either::Right(hostname) => {
-                let addrs =
-                    dns_lookup::getaddrinfo(Some(&hostname), None, None)
-                        .or_else(|_lkerr| {
-                            Err(Box::new(CliError::UnresolvedHost(hostname)))
-                        })?
-                        .collect::<std::io::Result<Vec<_>>>()
-                        .unwrap();
-
-                let addr1st = &addrs[0];
-
-                match addr1st.sockaddr.ip() {
-                    std::net::IpAddr::V4(ip) => ip,
-                    std::net::IpAddr::V6(_) => unreachable!(),
+                let addrs = Resolver::system()?
+                    .lookup_ipv4(&hostname)
+                    .unwrap_or_default();
+
+                match addrs.first() {
+                    Some(ip) => *ip,
+                    None => {
+                        return Err(Box::new(CliError::UnresolvedHost(
+                            hostname,
+                        )))
+                    }
                 }
             }
         })
@@ -484,6 +490,71 @@ impl TryInto<Ipv4Addr> for HostOrIPv4 {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+/// Host name or IP address of either family
+pub struct HostOrIP(Either<IpAddr, String>);
+
+impl FromStr for HostOrIP {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(Box::new(CliError::ParseInAddrFailed("".to_string())));
+        }
+
+        Ok(match IpAddr::from_str(s) {
+            Ok(ip) => HostOrIP(Either::Left(ip)),
+            Err(_) => HostOrIP(Either::Right(s.to_owned())),
+        })
+    }
+}
+
+impl HostOrIP {
+    /// Restrict to `family` if it's specified, otherwise the first IPv4
+    /// address is preferred.
+    pub fn resolve(
+        self,
+        family: Option<IpFamily>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        let hostname = match self.0 {
+            Either::Left(ip) => {
+                return match (family, ip) {
+                    (Some(IpFamily::V4), IpAddr::V6(_))
+                    | (Some(IpFamily::V6), IpAddr::V4(_)) => Err(Box::new(
+                        CliError::ParseInAddrFailed(ip.to_string()),
+                    )),
+                    _ => Ok(ip),
+                };
+            }
+            Either::Right(hostname) => hostname,
+        };
+
+        let resolver = Resolver::system()?;
+
+        let addrs = match family {
+            Some(IpFamily::V4) => resolver
+                .lookup_ipv4(&hostname)
+                .map(|addrs| addrs.into_iter().map(IpAddr::V4).collect()),
+            Some(IpFamily::V6) => resolver
+                .lookup_ipv6(&hostname)
+                .map(|addrs| addrs.into_iter().map(IpAddr::V6).collect()),
+            None => resolver.lookup_ip(&hostname),
+        }
+        .unwrap_or_default();
+
+        match addrs.first() {
+            Some(ip) => Ok(*ip),
+            None => Err(Box::new(CliError::UnresolvedHost(hostname))),
+        }
+    }
+}
+
+
 ////////////////////////////////////////////////////////////////////////////////
 //// Helper Function
 
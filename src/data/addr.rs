@@ -1,17 +1,22 @@
 ////////////////////////////////////////////////////////////////////////////////
 //// Data Structures
 
-use std::{fmt::Debug, mem::transmute, net::Ipv4Addr};
+use std::{
+    fmt::Debug,
+    mem::transmute,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-use default_net::Gateway;
-use libc::sockaddr_in;
+use libc::{sockaddr_in, sockaddr_in6};
 
 use crate::{
     aux::{htonl, ntohl},
     datalink::{EthTypeN, PacType},
     defraw, deftransparent,
+    data::route_to,
     network::arp::ARPHT,
-    view::U16N, rs_error::{ NetErr, Result }, s,
+    view::U16N, rs_error::{ NetErr, Result }, s, or2s, RawResult,
 };
 
 
@@ -54,6 +59,16 @@ defraw! {
         zero_pading: [u8; 8],
     }
 
+    /// Synonym libc::sockaddr_in6
+    pub struct SockAddrIn6 {
+        family: SAFamily,
+        port: U16N,
+        flowinfo: u32,
+        /// IPv6 Address (network bytes order)
+        addr: [u8; 16],
+        scope_id: u32,
+    }
+
     pub struct SockAddrLL {
         family: u16,
         proto: EthTypeN,
@@ -78,6 +93,15 @@ deftransparent! {
 // pub struct InAddr(pub u32);
 
 
+/// IPv4 network in CIDR notation, e.g. `10.0.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    /// 0-32
+    pub prefix: u8,
+}
+
+
 ////////////////////////////////////////////////////////////////////////////////
 //// Implementation
 
@@ -152,6 +176,35 @@ impl From<sockaddr_in> for SockAddrIn {
 }
 
 
+impl From<Ipv6Addr> for SockAddrIn6 {
+    fn from(ipv6: Ipv6Addr) -> Self {
+        Self {
+            family: SAFamily::Inet6,
+            addr: ipv6.octets(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Into<sockaddr_in6> for SockAddrIn6 {
+    fn into(self) -> sockaddr_in6 {
+        unsafe { transmute(self) }
+    }
+}
+
+impl From<sockaddr_in6> for SockAddrIn6 {
+    fn from(addr: sockaddr_in6) -> Self {
+        unsafe { transmute(addr) }
+    }
+}
+
+impl SockAddrIn6 {
+    pub fn ipv6(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.addr)
+    }
+}
+
+
 impl Subnet for Ipv4Addr {
     fn subnet(&self, mask: &Self) -> Self {
         let addru: u32 = self.clone().into();
@@ -169,16 +222,76 @@ impl Subnet for Ipv4Addr {
 }
 
 
+impl Ipv4Cidr {
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+        debug_assert!(prefix <= 32);
+
+        Self { addr, prefix }
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0),
+        )
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.addr.subnet(&self.netmask())
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Subnet::broadcast(&self.addr, &self.netmask())
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        addr.subnet(&self.netmask()) == self.network()
+    }
+
+    /// Usable host addresses (exclude network and broadcast address
+    /// except /31 and /32)
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let network: u32 = self.network().into();
+        let broadcast: u32 = self.broadcast().into();
+
+        let (first, last) = if self.prefix >= 31 {
+            (network, broadcast)
+        }
+        else {
+            (network + 1, broadcast - 1)
+        };
+
+        (first..=last).map(Ipv4Addr::from)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> RawResult<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, or2s!(prefix.parse::<u8>())?),
+            None => (s, 32),
+        };
+
+        if prefix > 32 {
+            return Err(format!("prefix too long: {prefix}"));
+        }
+
+        Ok(Self::new(or2s!(addr.parse())?, prefix))
+    }
+}
+
+
 ////////////////////////////////////////////////////////////////////////////////
 //// Function
 
-pub fn getgateway() -> Result<Gateway> {
-    match default_net::get_default_interface() {
-        Ok(default_interface) => {
-            default_interface.gateway.ok_or(NetErr::GetGateway(s!("")))
-        },
-        Err(msg) => Err(NetErr::GetGateway(msg))
-    }
+/// Gateway of the default IPv4 route
+pub fn getgateway() -> Result<IpAddr> {
+    let route = unsafe { route_to(IpAddr::V4(Ipv4Addr::UNSPECIFIED))? };
+
+    route
+        .and_then(|route| route.gateway)
+        .ok_or(NetErr::GetGateway(s!("no default route")))
 }
 
 
@@ -212,6 +325,24 @@ mod tests {
         fn __h_errno_location() -> *mut c_int;
     }
 
+    #[test]
+    fn test_ipv4_cidr() {
+        let cidr: super::Ipv4Cidr = "10.0.0.7/24".parse().unwrap();
+
+        assert_eq!(cidr.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(cidr.network(), Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(cidr.broadcast(), Ipv4Addr::new(10, 0, 0, 255));
+        assert!(cidr.contains(Ipv4Addr::new(10, 0, 0, 200)));
+        assert!(!cidr.contains(Ipv4Addr::new(10, 0, 1, 1)));
+        assert_eq!(cidr.hosts().count(), 254);
+        assert_eq!(cidr.hosts().next(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+        let cidr: super::Ipv4Cidr = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(cidr.netmask(), Ipv4Addr::UNSPECIFIED);
+
+        assert!("10.0.0.0/33".parse::<super::Ipv4Cidr>().is_err());
+    }
+
     #[test]
     fn test_info_addr() {
         println!("sizeof struct in_addr: {}", size_of::<libc::in_addr>());
@@ -265,4 +396,23 @@ mod tests {
             // }
         }
     }
+
+
+    #[test]
+    fn test_sockaddr_in6() {
+        use std::net::Ipv6Addr;
+
+        use libc::{sockaddr_in6, AF_INET6};
+
+        use crate::data::SockAddrIn6;
+
+        assert_eq!(size_of::<SockAddrIn6>(), size_of::<sockaddr_in6>());
+
+        let ipv6: Ipv6Addr = "fe80::1".parse().unwrap();
+        let sin6: sockaddr_in6 = SockAddrIn6::from(ipv6).into();
+
+        assert_eq!(sin6.sin6_family, AF_INET6 as u16);
+        assert_eq!(sin6.sin6_addr.s6_addr, ipv6.octets());
+        assert_eq!(SockAddrIn6::from(sin6).ipv6(), ipv6);
+    }
 }
@@ -1,20 +1,28 @@
 use std::{
     ffi::CStr,
-    net::{Ipv4Addr, Ipv6Addr},
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     ptr::null_mut,
 };
 
 use ifstructs::ifreq;
 use libc::{
-    freeifaddrs, getifaddrs as cgetifaddrs, ioctl, sockaddr_in, sockaddr_in6,
-    socket, AF_PACKET, SOCK_RAW
+    close, freeifaddrs, getifaddrs as cgetifaddrs, if_indextoname, ifaddrs,
+    ioctl, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_ll, socket, AF_INET,
+    AF_INET6, ARPHRD_ETHER, IFA_ADDRESS, IFA_LOCAL, IFF_ALLMULTI,
+    IFF_BROADCAST, IFF_LOOPBACK, IFF_MULTICAST, IFF_NOARP, IFF_POINTOPOINT,
+    IFF_PROMISC, IFF_RUNNING, IFF_UP, IFNAMSIZ, NLM_F_CREATE, NLM_F_EXCL,
+    RTM_DELADDR, RTM_NEWADDR, SOCK_CLOEXEC, SOCK_DGRAM,
 };
 
 use crate::{
     aux::ntohl,
-    data::{rtnl_link_stats, SAFamily, SockAddrIn},
-    rs_error::Result,
-    throw_errno, datalink::{EthTypeE, Mac},
+    data::{
+        as_bytes, ifaddrmsg, nla_put, rtnl_link_stats, NlSock, SAFamily,
+        SockAddrIn,
+    },
+    rs_error::{NetErr, Result},
+    throw_errno, datalink::Mac,
 };
 
 
@@ -23,7 +31,18 @@ use crate::{
 
 /* SIOC G(et) IF INDEX */
 pub const SIOCGIFINDEX: u64 = 0x8933;
-pub const SIOCGIFHWADDR: u64 = 0x8933;
+pub const SIOCGIFHWADDR: u64 = 0x8927;
+pub const SIOCSIFHWADDR: u64 = 0x8924;
+/* SIOC S(et) IF ADDR */
+pub const SIOCSIFADDR: u64 = 0x8916;
+pub const SIOCSIFNETMASK: u64 = 0x891c;
+pub const SIOCGIFMTU: u64 = 0x8921;
+pub const SIOCSIFMTU: u64 = 0x8922;
+pub const SIOCGIFFLAGS: u64 = 0x8913;
+pub const SIOCSIFFLAGS: u64 = 0x8914;
+pub const SIOCSIFNAME: u64 = 0x8923;
+pub const SIOCGIFTXQLEN: u64 = 0x8942;
+pub const SIOCSIFTXQLEN: u64 = 0x8943;
 
 
 
@@ -34,40 +53,92 @@ pub const SIOCGIFHWADDR: u64 = 0x8933;
 #[repr(transparent)]
 pub struct IfAddrs(Vec<IfAddrItem>);
 
-/// Support AF_INET, AF_INET6, AF_PACKET
-#[derive(Debug)]
+/// One entry of `getifaddrs(3)`, unknown families are kept as `Other`
+#[derive(Debug, Clone)]
 pub enum IfAddrItem {
     Inet {
         name: String,
+        flags: IfFlags,
         addr: Ipv4Addr,
         mask: Ipv4Addr,
+        prefix_len: u8,
+        /// Only with IFF_BROADCAST
+        broadcast: Option<Ipv4Addr>,
+        /// Peer address, only with IFF_POINTOPOINT
+        dstaddr: Option<Ipv4Addr>,
     },
     Inet6 {
         name: String,
+        flags: IfFlags,
         addr: Ipv6Addr,
         mask: Ipv6Addr,
+        prefix_len: u8,
+        /// Interface index for link-local address, 0 otherwise
+        scope_id: u32,
+        /// Peer address, only with IFF_POINTOPOINT
+        dstaddr: Option<Ipv6Addr>,
     },
-    #[cfg(target_os = "linux")]
     Packet {
         name: String,
-        stats: rtnl_link_stats,
+        flags: IfFlags,
+        index: i32,
+        /// Link layer address, None unless it's 6 bytes long
+        mac: Option<Mac>,
+        stats: Option<rtnl_link_stats>,
+    },
+    Other {
+        name: String,
+        flags: IfFlags,
+        family: u16,
     },
 }
 
 
+/// All entries of one interface, see [`IfAddrs::interfaces`]
+#[derive(Debug)]
+pub struct IfAddrGroup<'a> {
+    pub name: &'a str,
+    pub flags: IfFlags,
+    pub items: Vec<&'a IfAddrItem>,
+}
+
+
+/// Interface flags (IFF_*) of `SIOCGIFFLAGS`
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct IfFlags(pub i32);
+
+
+/// Handle of a network interface, configured by ioctl on its own control
+/// socket and rtnetlink for addresses.
+///
+/// Setters require CAP_NET_ADMIN.
+pub struct Interface {
+    name: String,
+    /// Control socket, closed on drop
+    sock: i32,
+}
+
+
 ////////////////////////////////////////////////////////////////////////////////
 //// Implementation
 
 impl IfAddrs {
+    pub fn iter(&self) -> impl Iterator<Item = &IfAddrItem> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     /// Get first sockaddr_in from list (exclude 127.0.0.1)
     pub fn get_sockaddr_in(&self) -> Option<SockAddrIn> {
         for item in self.0.iter() {
-            if let IfAddrItem::Inet {
-                name: _,
-                addr,
-                mask: _,
-            } = item
-            {
+            if let IfAddrItem::Inet { addr, .. } = item {
                 if !addr.is_loopback() {
                     return Some(SockAddrIn::from(*addr));
                 }
@@ -77,154 +148,578 @@ impl IfAddrs {
         None
     }
 
-    pub fn get_inet_items(&self) -> impl Iterator<Item=(&str, &Ipv4Addr, &Ipv4Addr)> {
-        let mut iter = self.0.iter();
+    pub fn get_inet_items(
+        &self,
+    ) -> impl Iterator<Item = (&str, &Ipv4Addr, &Ipv4Addr)> {
+        self.0.iter().filter_map(|item| match item {
+            IfAddrItem::Inet {
+                name, addr, mask, ..
+            } => Some((name.as_str(), addr, mask)),
+            _ => None,
+        })
+    }
 
-        std::iter::from_fn(move || {
-            loop {
-                if let Some(item) = iter.next() {
-                    if let IfAddrItem::Inet { name, addr, mask }
-                    = item {
-                        return Some((name.as_str(), addr, mask));
-                    }
-                }
-                else {
-                    break;
-                }
+    /// Group the entries by interface, in the order they first show up
+    pub fn interfaces(&self) -> Vec<IfAddrGroup<'_>> {
+        let mut groups: Vec<IfAddrGroup> = vec![];
+
+        for item in self.0.iter() {
+            if let Some(group) =
+                groups.iter_mut().find(|group| group.name == item.name())
+            {
+                group.items.push(item);
+            }
+            else {
+                groups.push(IfAddrGroup {
+                    name: item.name(),
+                    flags: item.flags(),
+                    items: vec![item],
+                });
             }
+        }
+
+        groups
+    }
+
+    pub fn interface(&self, name: &str) -> Option<IfAddrGroup<'_>> {
+        self.interfaces()
+            .into_iter()
+            .find(|group| group.name == name)
+    }
+}
+
+
+impl IfAddrItem {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Inet { name, .. }
+            | Self::Inet6 { name, .. }
+            | Self::Packet { name, .. }
+            | Self::Other { name, .. } => name,
+        }
+    }
+
+    pub fn flags(&self) -> IfFlags {
+        match self {
+            Self::Inet { flags, .. }
+            | Self::Inet6 { flags, .. }
+            | Self::Packet { flags, .. }
+            | Self::Other { flags, .. } => *flags,
+        }
+    }
+
+    /// Address with prefix length for INET/INET6
+    pub fn ip(&self) -> Option<(IpAddr, u8)> {
+        match self {
+            Self::Inet {
+                addr, prefix_len, ..
+            } => Some((IpAddr::V4(*addr), *prefix_len)),
+            Self::Inet6 {
+                addr, prefix_len, ..
+            } => Some((IpAddr::V6(*addr), *prefix_len)),
+            _ => None,
+        }
+    }
+}
+
 
-            None
+impl<'a> IfAddrGroup<'a> {
+    pub fn ips(&self) -> impl Iterator<Item = (IpAddr, u8)> + '_ {
+        self.items.iter().filter_map(|item| item.ip())
+    }
+
+    pub fn ipv4(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.ips().filter_map(|(ip, _)| match ip {
+            IpAddr::V4(ipv4) => Some(ipv4),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    pub fn ipv6(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        self.ips().filter_map(|(ip, _)| match ip {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ipv6) => Some(ipv6),
+        })
+    }
+
+    /// Index and link layer address from the AF_PACKET entry
+    pub fn link(&self) -> Option<(i32, Option<Mac>)> {
+        self.items.iter().find_map(|item| match item {
+            IfAddrItem::Packet { index, mac, .. } => Some((*index, *mac)),
+            _ => None,
         })
     }
 
+    pub fn stats(&self) -> Option<&'a rtnl_link_stats> {
+        self.items.iter().find_map(|item| match item {
+            IfAddrItem::Packet { stats, .. } => stats.as_ref(),
+            _ => None,
+        })
+    }
 }
 
 
+impl IfFlags {
+    pub const UP: Self = Self(IFF_UP);
+    pub const BROADCAST: Self = Self(IFF_BROADCAST);
+    pub const LOOPBACK: Self = Self(IFF_LOOPBACK);
+    pub const POINTOPOINT: Self = Self(IFF_POINTOPOINT);
+    pub const RUNNING: Self = Self(IFF_RUNNING);
+    pub const NOARP: Self = Self(IFF_NOARP);
+    pub const PROMISC: Self = Self(IFF_PROMISC);
+    pub const ALLMULTI: Self = Self(IFF_ALLMULTI);
+    pub const MULTICAST: Self = Self(IFF_MULTICAST);
+
+    const NAMES: [(Self, &'static str); 9] = [
+        (Self::UP, "UP"),
+        (Self::BROADCAST, "BROADCAST"),
+        (Self::LOOPBACK, "LOOPBACK"),
+        (Self::POINTOPOINT, "POINTOPOINT"),
+        (Self::RUNNING, "RUNNING"),
+        (Self::NOARP, "NOARP"),
+        (Self::PROMISC, "PROMISC"),
+        (Self::ALLMULTI, "ALLMULTI"),
+        (Self::MULTICAST, "MULTICAST"),
+    ];
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn set(&mut self, other: Self, on: bool) {
+        if on {
+            self.0 |= other.0;
+        }
+        else {
+            self.0 &= !other.0;
+        }
+    }
+}
+
+
+impl Debug for IfFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+
+        write!(f, "<{}>", names.join(","))
+    }
+}
+
+
+impl Interface {
+    pub unsafe fn open(name: &str) -> Result<Self> {
+        let sock = throw_errno!(
+            socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) throws CreateSocket
+        );
+        let it = Self {
+            name: name.to_owned(),
+            sock,
+        };
+
+        // fail early for nonexistent one
+        it.index()?;
+
+        Ok(it)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ifreq(&self) -> Result<ifreq> {
+        ifreq::from_name(&self.name)
+            .map_err(|err| NetErr::GetIf(format!("{}: {err}", self.name)))
+    }
+
+    unsafe fn ioctl(&self, req: u64, ifr: &mut ifreq) -> Result<()> {
+        throw_errno!(ioctl(self.sock, req, ifr as *mut ifreq) throws CIOCtl);
+
+        Ok(())
+    }
+
+    unsafe fn get(&self, req: u64) -> Result<ifreq> {
+        let mut ifr = self.ifreq()?;
+        self.ioctl(req, &mut ifr)?;
+
+        Ok(ifr)
+    }
+
+    pub unsafe fn index(&self) -> Result<i32> {
+        let mut ifr = self.ifreq()?;
+
+        if ioctl(self.sock, SIOCGIFINDEX, &mut ifr as *mut ifreq) == -1 {
+            return Err(NetErr::GetIf(format!("No such if {}", self.name)));
+        }
+
+        Ok(ifr.ifr_ifru.ifr_ifindex)
+    }
+
+    pub unsafe fn flags(&self) -> Result<IfFlags> {
+        Ok(IfFlags(self.get(SIOCGIFFLAGS)?.ifr_ifru.ifr_flags as u16 as i32))
+    }
+
+    pub unsafe fn set_flags(&self, flags: IfFlags) -> Result<()> {
+        let mut ifr = self.ifreq()?;
+        ifr.ifr_ifru.ifr_flags = flags.0 as i16;
+
+        self.ioctl(SIOCSIFFLAGS, &mut ifr)
+    }
+
+    unsafe fn switch_flag(&self, flag: IfFlags, on: bool) -> Result<()> {
+        let mut flags = self.flags()?;
+        flags.set(flag, on);
+
+        self.set_flags(flags)
+    }
+
+    pub unsafe fn is_up(&self) -> Result<bool> {
+        Ok(self.flags()?.contains(IfFlags::UP))
+    }
+
+    pub unsafe fn up(&self) -> Result<()> {
+        self.switch_flag(IfFlags::UP, true)
+    }
+
+    pub unsafe fn down(&self) -> Result<()> {
+        self.switch_flag(IfFlags::UP, false)
+    }
+
+    pub unsafe fn set_promisc(&self, on: bool) -> Result<()> {
+        self.switch_flag(IfFlags::PROMISC, on)
+    }
+
+    pub unsafe fn set_allmulti(&self, on: bool) -> Result<()> {
+        self.switch_flag(IfFlags::ALLMULTI, on)
+    }
+
+    pub unsafe fn mtu(&self) -> Result<u32> {
+        Ok(self.get(SIOCGIFMTU)?.ifr_ifru.ifr_mtu as u32)
+    }
+
+    pub unsafe fn set_mtu(&self, mtu: u32) -> Result<()> {
+        let mut ifr = self.ifreq()?;
+        ifr.ifr_ifru.ifr_mtu = mtu as i32;
+
+        self.ioctl(SIOCSIFMTU, &mut ifr)
+    }
+
+    pub unsafe fn mac(&self) -> Result<Mac> {
+        let ifr = self.get(SIOCGIFHWADDR)?;
+
+        Ok(Mac::from_slice(&ifr.ifr_ifru.ifr_hwaddr.sa_data))
+    }
+
+    /// The interface should be down for most of drivers
+    pub unsafe fn set_mac(&self, mac: Mac) -> Result<()> {
+        let mut ifr = self.ifreq()?;
+        ifr.ifr_ifru.ifr_hwaddr.sa_family = ARPHRD_ETHER;
+
+        let arr8 = mac.into_arr8();
+        for (i, b) in arr8[..6].iter().enumerate() {
+            ifr.ifr_ifru.ifr_hwaddr.sa_data[i] = *b as _;
+        }
+
+        self.ioctl(SIOCSIFHWADDR, &mut ifr)
+    }
+
+    pub unsafe fn txqueuelen(&self) -> Result<u32> {
+        // ifr_qlen shares the int with ifr_ifindex
+        Ok(self.get(SIOCGIFTXQLEN)?.ifr_ifru.ifr_ifindex as u32)
+    }
+
+    pub unsafe fn set_txqueuelen(&self, qlen: u32) -> Result<()> {
+        let mut ifr = self.ifreq()?;
+        ifr.ifr_ifru.ifr_ifindex = qlen as i32;
+
+        self.ioctl(SIOCSIFTXQLEN, &mut ifr)
+    }
+
+    /// The interface should be down
+    pub unsafe fn rename(&mut self, newname: &str) -> Result<()> {
+        if newname.is_empty() || newname.len() >= IFNAMSIZ {
+            return Err(NetErr::InvalidParam);
+        }
+
+        let mut ifr = self.ifreq()?;
+        for (i, b) in newname.bytes().enumerate() {
+            ifr.ifr_ifru.ifr_newname[i] = b as _;
+        }
+
+        self.ioctl(SIOCSIFNAME, &mut ifr)?;
+        self.name = newname.to_owned();
+
+        Ok(())
+    }
+
+    /// Add address with prefix length (`ip addr add`)
+    pub unsafe fn add_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<()> {
+        self.change_addr(
+            RTM_NEWADDR,
+            (NLM_F_CREATE | NLM_F_EXCL) as u16,
+            addr,
+            prefix_len,
+        )
+    }
+
+    /// Remove address (`ip addr del`)
+    pub unsafe fn del_addr(&self, addr: IpAddr, prefix_len: u8) -> Result<()> {
+        self.change_addr(RTM_DELADDR, 0, addr, prefix_len)
+    }
+
+    unsafe fn change_addr(
+        &self,
+        ty: u16,
+        flags: u16,
+        addr: IpAddr,
+        prefix_len: u8,
+    ) -> Result<()> {
+        let (family, octets, max_prefix) = match addr {
+            IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec(), 32),
+            IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec(), 128),
+        };
+
+        if prefix_len > max_prefix {
+            return Err(NetErr::InvalidParam);
+        }
+
+        let ifa = ifaddrmsg {
+            ifa_family: family as u8,
+            ifa_prefixlen: prefix_len,
+            ifa_flags: 0,
+            ifa_scope: 0,
+            ifa_index: self.index()? as u32,
+        };
+        let mut body = as_bytes(&ifa).to_vec();
+
+        nla_put(&mut body, IFA_LOCAL, &octets);
+        nla_put(&mut body, IFA_ADDRESS, &octets);
+
+        NlSock::route()?.request(ty, flags, &body)?;
+
+        Ok(())
+    }
+
+    /// Addresses with prefix length
+    pub unsafe fn addrs(&self) -> Result<Vec<(IpAddr, u8)>> {
+        let ifaddrs = getifaddrs()?;
+
+        Ok(ifaddrs
+            .iter()
+            .filter(|item| item.name() == self.name)
+            .filter_map(|item| item.ip())
+            .collect())
+    }
+}
+
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.sock);
+        }
+    }
+}
+
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Function
 
 pub unsafe fn getifaddrs() -> Result<IfAddrs> {
-    let mut ifa = null_mut();
+    let mut head = null_mut();
 
-    throw_errno!(cgetifaddrs(&mut ifa) throws GetIfAddrs);
+    throw_errno!(cgetifaddrs(&mut head) throws GetIfAddrs);
 
     let mut items = vec![];
+    let mut ifa: *mut ifaddrs = head;
 
     while !ifa.is_null() {
-        if (*ifa).ifa_addr.is_null() {
-            ifa = (*ifa).ifa_next;
+        let it = &*ifa;
+        ifa = it.ifa_next;
+
+        if it.ifa_addr.is_null() {
             continue;
         }
 
-        let family = (*(*ifa).ifa_addr).sa_family;
-        let name =
-            CStr::from_ptr((*ifa).ifa_name).to_str().unwrap().to_owned();
+        items.push(ifaddr_item(it));
+    }
+
+    freeifaddrs(head);
 
-        let item = if family == SAFamily::Inet as u16 {
-            IfAddrItem::Inet {
-                name,
-                addr: Ipv4Addr::from(ntohl(
-                    (*((*ifa).ifa_addr as *mut sockaddr_in)).sin_addr.s_addr,
-                )),
-                mask: Ipv4Addr::from(ntohl(
-                    (*((*ifa).ifa_netmask as *mut sockaddr_in))
-                        .sin_addr
-                        .s_addr,
-                )),
+    Ok(IfAddrs(items))
+}
+
+
+unsafe fn ifaddr_item(it: &ifaddrs) -> IfAddrItem {
+    let family = (*it.ifa_addr).sa_family;
+    let name = CStr::from_ptr(it.ifa_name).to_string_lossy().into_owned();
+    let flags = IfFlags(it.ifa_flags as i32);
+    // broadcast and destination address share the union
+    let ifu = it.ifa_ifu;
+
+    if family == SAFamily::Inet as u16 {
+        let inet = |sa: *mut sockaddr| {
+            if sa.is_null() {
+                None
+            }
+            else {
+                Some(Ipv4Addr::from(ntohl(
+                    (*(sa as *const sockaddr_in)).sin_addr.s_addr,
+                )))
+            }
+        };
+        let mask = inet(it.ifa_netmask).unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+        IfAddrItem::Inet {
+            name,
+            flags,
+            addr: inet(it.ifa_addr).unwrap(),
+            mask,
+            prefix_len: u32::from(mask).leading_ones() as u8,
+            broadcast: if flags.contains(IfFlags::BROADCAST) {
+                inet(ifu)
             }
+            else {
+                None
+            },
+            dstaddr: if flags.contains(IfFlags::POINTOPOINT) {
+                inet(ifu)
+            }
+            else {
+                None
+            },
         }
-        else if family == SAFamily::Inet6 as u16 {
-            IfAddrItem::Inet6 {
-                name,
-                addr: Ipv6Addr::from(
-                    (*((*ifa).ifa_addr as *mut sockaddr_in6))
-                        .sin6_addr
-                        .s6_addr,
-                ),
-                mask: Ipv6Addr::from(
-                    (*((*ifa).ifa_netmask as *mut sockaddr_in6))
-                        .sin6_addr
-                        .s6_addr,
-                ),
+    }
+    else if family == SAFamily::Inet6 as u16 {
+        let inet6 = |sa: *mut sockaddr| {
+            if sa.is_null() {
+                None
             }
+            else {
+                Some(*(sa as *const sockaddr_in6))
+            }
+        };
+        let sin6 = inet6(it.ifa_addr).unwrap();
+        let mask = inet6(it.ifa_netmask)
+            .map(|sin6| Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            .unwrap_or(Ipv6Addr::UNSPECIFIED);
+
+        IfAddrItem::Inet6 {
+            name,
+            flags,
+            addr: Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+            mask,
+            prefix_len: u128::from(mask).leading_ones() as u8,
+            scope_id: sin6.sin6_scope_id,
+            dstaddr: if flags.contains(IfFlags::POINTOPOINT) {
+                inet6(ifu).map(|sin6| Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            else {
+                None
+            },
         }
-        else if family == SAFamily::Packet as u16
-            && !(*ifa).ifa_data.is_null()
-        {
-            IfAddrItem::Packet {
-                name,
-                stats: *((*ifa).ifa_data as *const rtnl_link_stats),
+    }
+    else if family == SAFamily::Packet as u16 {
+        let sll = *(it.ifa_addr as *const sockaddr_ll);
+
+        IfAddrItem::Packet {
+            name,
+            flags,
+            index: sll.sll_ifindex,
+            mac: if sll.sll_halen == 6 {
+                Some(Mac::from_slice(&sll.sll_addr[..6]))
+            }
+            else {
+                None
+            },
+            stats: if it.ifa_data.is_null() {
+                None
             }
+            else {
+                Some((it.ifa_data as *const rtnl_link_stats).read_unaligned())
+            },
+        }
+    }
+    else {
+        IfAddrItem::Other {
+            name,
+            flags,
+            family,
         }
-        else {
-            unimplemented!()
-        };
-
-        items.push(item);
-
-        ifa = (*ifa).ifa_next;
     }
-
-    freeifaddrs(ifa);
-
-    Ok(IfAddrs(items))
 }
 
 
-pub unsafe fn getifnth(ifname: &str) -> Option<i32> {
-    let sock = socket(AF_PACKET, SOCK_RAW, EthTypeE::AVTP.net().val() as i32);
+pub unsafe fn getifname(index: u32) -> Option<String> {
+    let mut buf = [0u8; IFNAMSIZ];
 
-    if sock <= 0 {
+    if if_indextoname(index, buf.as_mut_ptr() as *mut _).is_null() {
         return None;
     }
 
-    let mut ifr = if let Ok(ifr) = ifreq::from_name(ifname) {
-        ifr
-    }
-    else {
-        return None;
-    };
+    Some(
+        CStr::from_bytes_until_nul(&buf)
+            .ok()?
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
 
-    if ioctl(sock, SIOCGIFINDEX, &mut ifr) == -1 {
-        return None;
-    }
 
-    Some(ifr.ifr_ifru.ifr_ifindex)
+pub unsafe fn getifnth(ifname: &str) -> Option<i32> {
+    Interface::open(ifname).ok()?.index().ok()
 }
 
 pub unsafe fn getifmac(ifname: &str) -> Option<Mac> {
-    let sock = socket(AF_PACKET, SOCK_RAW, EthTypeE::AVTP.net().val() as i32);
+    Interface::open(ifname).ok()?.mac().ok()
+}
 
-    if sock <= 0 {
-        return None;
-    }
 
-    let mut ifr = if let Ok(ifr) = ifreq::from_name(ifname) {
-        ifr
-    }
-    else {
-        return None;
-    };
+pub unsafe fn getifmtu(ifname: &str) -> Option<u32> {
+    Interface::open(ifname).ok()?.mtu().ok()
+}
 
-    if ioctl(sock, SIOCGIFHWADDR, &mut ifr) == -1 {
-        return None;
-    }
 
-    let mac = Mac::from_slice(
-        &ifr.ifr_ifru.ifr_hwaddr.sa_data
-    );
+/// Assign IPv4 address and netmask to `ifname`, requires CAP_NET_ADMIN
+pub unsafe fn setifaddr(ifname: &str, addr: Ipv4Addr, mask: Ipv4Addr) -> Result<()> {
+    let sock = throw_errno!(socket(AF_INET, SOCK_DGRAM, 0) throws CreateSocket);
+
+    let res = (|| {
+        for (req, ip) in [(SIOCSIFADDR, addr), (SIOCSIFNETMASK, mask)] {
+            let mut ifr = ifreq::from_name(ifname)
+                .map_err(|err| NetErr::GetIf(format!("{err}")))?;
+
+            let sin: sockaddr_in = SockAddrIn::from(ip).into();
+            *(&mut ifr.ifr_ifru.ifr_addr as *mut _ as *mut sockaddr_in) = sin;
 
-    Some(mac)
+            throw_errno!(ioctl(sock, req, &mut ifr) throws CIOCtl);
+        }
+
+        Ok(())
+    })();
+
+    close(sock);
+
+    res
 }
 
 
 
 #[cfg(test)]
 mod tests {
-    use std::mem::transmute;
+    use std::{
+        mem::transmute,
+        net::{Ipv4Addr, Ipv6Addr},
+    };
 
-    use super::{getifaddrs, IfAddrItem, IfAddrs, getifnth};
+    use super::{
+        getifaddrs, getifmac, getifmtu, getifnth, IfAddrItem, IfAddrs,
+        IfFlags, Interface,
+    };
+    use crate::data::{getgateway, proc_routes};
 
     #[test]
     fn test_getifaddrs() {
@@ -247,46 +742,106 @@ mod tests {
     }
 
     #[test]
-    fn test_getgateway() {
+    fn test_getifaddrs_lo() {
+        unsafe {
+            let ifaddrs = getifaddrs().unwrap();
+            let groups = ifaddrs.interfaces();
 
-        // let searchopt = SearchOptions {
-        //     ..Default::default()
-        // };
-
-        // let gateway = search_gateway(searchopt).unwrap();
-
-        // println!("gateway: {:?}", gateway.get_external_ip());
-
-        match default_net::get_default_interface() {
-            Ok(default_interface) => {
-                println!("Default Interface");
-                println!("\tIndex: {}", default_interface.index);
-                println!("\tName: {}", default_interface.name);
-                println!("\tFriendly Name: {:?}", default_interface.friendly_name);
-                println!("\tDescription: {:?}", default_interface.description);
-                println!("\tType: {}", default_interface.if_type.name());
-                if let Some(mac_addr) = default_interface.mac_addr {
-                    println!("\tMAC: {}", mac_addr);
-                }else{
-                    println!("\tMAC: (Failed to get mac address)");
-                }
-                println!("\tIPv4: {:?}", default_interface.ipv4);
-                println!("\tIPv6: {:?}", default_interface.ipv6);
-                println!("\tFlags: {:?}", default_interface.flags);
-                println!("\tTransmit Speed: {:?}", default_interface.transmit_speed);
-                println!("\tReceive Speed: {:?}", default_interface.receive_speed);
-                if let Some(gateway) = default_interface.gateway {
-                    println!("Default Gateway");
-                    println!("\tMAC: {}", gateway.mac_addr);
-                    println!("\tIP: {}", gateway.ip_addr);
-                }else {
-                    println!("Default Gateway: (Not found)");
+            // one group per interface
+            for (i, group) in groups.iter().enumerate() {
+                assert!(groups[i + 1..].iter().all(|x| x.name != group.name));
+            }
+
+            let lo = ifaddrs.interface("lo").unwrap();
+            assert!(lo.flags.contains(IfFlags::LOOPBACK));
+            assert!(lo.items.iter().all(|item| item.name() == "lo"));
+            assert_eq!(lo.link().unwrap().0, getifnth("lo").unwrap());
+            assert!(lo.stats().is_some());
+
+            if lo.flags.contains(IfFlags::UP) {
+                assert!(lo
+                    .ips()
+                    .any(|ip| ip == (Ipv4Addr::LOCALHOST.into(), 8)));
+            }
+
+            for item in lo.items.iter() {
+                match item {
+                    IfAddrItem::Inet {
+                        mask,
+                        prefix_len,
+                        broadcast,
+                        dstaddr,
+                        ..
+                    } => {
+                        assert_eq!(
+                            u32::from(*mask).count_ones(),
+                            *prefix_len as u32
+                        );
+                        // loopback is neither broadcast nor point-to-point
+                        assert_eq!(*broadcast, None);
+                        assert_eq!(*dstaddr, None);
+                    }
+                    IfAddrItem::Inet6 {
+                        addr,
+                        prefix_len,
+                        scope_id,
+                        ..
+                    } => {
+                        assert_eq!(*addr, Ipv6Addr::LOCALHOST);
+                        assert_eq!(*prefix_len, 128);
+                        assert_eq!(*scope_id, 0);
+                    }
+                    _ => (),
                 }
-            },
-            Err(e) => {
-                println!("{}", e);
-            },
+            }
         }
     }
 
+    #[test]
+    fn test_getifmtu() {
+        unsafe {
+            assert!(getifmtu("lo").unwrap() >= 1280);
+            assert_eq!(getifmtu("no-such-if0"), None);
+        }
+    }
+
+    #[test]
+    fn test_interface() {
+        unsafe {
+            let lo = Interface::open("lo").unwrap();
+            let flags = lo.flags().unwrap();
+
+            assert!(flags.contains(IfFlags::UP), "{flags:?}");
+            assert!(flags.contains(IfFlags::LOOPBACK), "{flags:?}");
+            let flags = IfFlags(IfFlags::UP.0 | IfFlags::RUNNING.0);
+            assert_eq!(format!("{flags:?}"), "<UP,RUNNING>");
+
+            assert_eq!(getifnth("lo"), Some(lo.index().unwrap()));
+            assert!(getifmac("lo").unwrap().is_empty());
+            assert_eq!(getifmtu("lo"), Some(lo.mtu().unwrap()));
+
+            assert!(Interface::open("no-such-if0").is_err());
+        }
+    }
+
+    #[test]
+    fn test_getgateway() {
+        // the one of the lowest metric
+        let default = proc_routes()
+            .unwrap()
+            .into_iter()
+            .filter(|route| route.is_default() && route.dst.is_ipv4())
+            .min_by_key(|route| route.metric);
+
+        match getgateway() {
+            Ok(gateway) => {
+                println!("Default Gateway: {gateway}");
+                assert_eq!(Some(gateway), default.and_then(|r| r.gateway));
+            }
+            Err(err) => {
+                println!("Default Gateway: (Not found) {err:?}");
+                assert!(default.is_none_or(|r| r.gateway.is_none()));
+            }
+        }
+    }
 }
@@ -0,0 +1,240 @@
+//! 64-bit interface statistics (IFLA_STATS64) and per-second rates
+
+use std::{collections::HashMap, ffi::CStr, mem::size_of, time::Instant};
+
+use libc::{IFLA_IFNAME, IFLA_STATS64, NLM_F_DUMP, RTM_GETLINK, RTM_NEWLINK};
+use serde::Serialize;
+
+use crate::{
+    data::{
+        as_bytes, ifinfomsg, nla_iter, rtnl_link_stats64, IfFlags, NlSock,
+    },
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Counters of one interface from link dump
+#[derive(Debug, Clone)]
+pub struct LinkStats {
+    pub index: i32,
+    pub name: String,
+    pub flags: IfFlags,
+    pub stats: rtnl_link_stats64,
+}
+
+
+/// Per-second rates of one interface between two samples
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IfRate {
+    pub index: i32,
+    pub name: String,
+    pub rx_packets: f64,
+    pub tx_packets: f64,
+    pub rx_bytes: f64,
+    pub tx_bytes: f64,
+    pub rx_errors: f64,
+    pub tx_errors: f64,
+    pub rx_dropped: f64,
+    pub tx_dropped: f64,
+}
+
+
+/// Keep the previous snapshot to compute rates across all interfaces
+#[derive(Debug, Default)]
+pub struct IfStatSampler {
+    last: Option<(Instant, HashMap<i32, rtnl_link_stats64>)>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl IfRate {
+    /// A counter going backwards (interface reset) counts as zero
+    pub fn between(
+        prev: &rtnl_link_stats64,
+        cur: &LinkStats,
+        secs: f64,
+    ) -> Self {
+        let rate = |prev: u64, cur: u64| {
+            if secs > 0.0 {
+                cur.saturating_sub(prev) as f64 / secs
+            }
+            else {
+                0.0
+            }
+        };
+        let st = &cur.stats;
+
+        Self {
+            index: cur.index,
+            name: cur.name.clone(),
+            rx_packets: rate(prev.rx_packets, st.rx_packets),
+            tx_packets: rate(prev.tx_packets, st.tx_packets),
+            rx_bytes: rate(prev.rx_bytes, st.rx_bytes),
+            tx_bytes: rate(prev.tx_bytes, st.tx_bytes),
+            rx_errors: rate(prev.rx_errors, st.rx_errors),
+            tx_errors: rate(prev.tx_errors, st.tx_errors),
+            rx_dropped: rate(prev.rx_dropped, st.rx_dropped),
+            tx_dropped: rate(prev.tx_dropped, st.tx_dropped),
+        }
+    }
+}
+
+
+impl IfStatSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Dump the links and get rates since the previous call
+    /// (empty on the first call)
+    pub unsafe fn sample(&mut self) -> Result<Vec<IfRate>> {
+        let links = link_stats()?;
+
+        Ok(self.update(Instant::now(), &links))
+    }
+
+    /// Feed a snapshot taken at `now`, new interfaces have no rate
+    /// until the next snapshot
+    pub fn update(
+        &mut self,
+        now: Instant,
+        links: &[LinkStats],
+    ) -> Vec<IfRate> {
+        let mut rates = vec![];
+
+        if let Some((then, prev)) = &self.last {
+            let secs = now.saturating_duration_since(*then).as_secs_f64();
+
+            for link in links.iter() {
+                if let Some(prev) = prev.get(&link.index) {
+                    rates.push(IfRate::between(prev, link, secs));
+                }
+            }
+        }
+
+        let snapshot =
+            links.iter().map(|link| (link.index, link.stats)).collect();
+        self.last = Some((now, snapshot));
+
+        rates
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Dump all links with their 64-bit counters
+pub unsafe fn link_stats() -> Result<Vec<LinkStats>> {
+    let ifi = ifinfomsg::default();
+    let replies = NlSock::route()?.request(
+        RTM_GETLINK,
+        NLM_F_DUMP as u16,
+        as_bytes(&ifi),
+    )?;
+
+    let mut links = vec![];
+
+    for (ty, body) in replies.iter() {
+        if *ty != RTM_NEWLINK || body.len() < size_of::<ifinfomsg>() {
+            continue;
+        }
+
+        let ifi = (body.as_ptr() as *const ifinfomsg).read_unaligned();
+        let mut link = LinkStats {
+            index: ifi.ifi_index,
+            name: String::new(),
+            flags: IfFlags(ifi.ifi_flags as i32),
+            stats: rtnl_link_stats64::default(),
+        };
+
+        for (attr, val) in nla_iter(&body[size_of::<ifinfomsg>()..]) {
+            match attr {
+                IFLA_IFNAME => {
+                    if let Ok(name) = CStr::from_bytes_until_nul(val) {
+                        link.name = name.to_string_lossy().into_owned();
+                    }
+                }
+                IFLA_STATS64 => {
+                    // older kernels send a shorter struct
+                    let n = val.len().min(size_of::<rtnl_link_stats64>());
+
+                    std::ptr::copy_nonoverlapping(
+                        val.as_ptr(),
+                        &mut link.stats as *mut rtnl_link_stats64 as *mut u8,
+                        n,
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        links.push(link);
+    }
+
+    Ok(links)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{link_stats, IfStatSampler, LinkStats};
+    use crate::data::rtnl_link_stats64;
+
+    fn link(index: i32, rx_bytes: u64, tx_packets: u64) -> LinkStats {
+        LinkStats {
+            index,
+            name: format!("if{index}"),
+            flags: Default::default(),
+            stats: rtnl_link_stats64 {
+                rx_bytes,
+                tx_packets,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_ifstat_sampler() {
+        let mut sampler = IfStatSampler::new();
+        let t0 = Instant::now();
+
+        assert!(sampler.update(t0, &[link(1, 1000, 10)]).is_empty());
+
+        // beyond 32 bits, and if2 shows up
+        let rates = sampler.update(
+            t0 + Duration::from_secs(2),
+            &[link(1, (1 << 32) + 1000, 30), link(2, 0, 0)],
+        );
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[0].name, "if1");
+        assert_eq!(rates[0].rx_bytes, (1u64 << 31) as f64);
+        assert_eq!(rates[0].tx_packets, 10.0);
+
+        // counter reset
+        let rates = sampler.update(
+            t0 + Duration::from_secs(3),
+            &[link(1, 0, 0), link(2, 500, 0)],
+        );
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates[0].rx_bytes, 0.0);
+        assert_eq!(rates[1].rx_bytes, 500.0);
+    }
+
+    #[test]
+    fn test_link_stats() {
+        let links = unsafe { link_stats() }.unwrap();
+
+        let lo = links.iter().find(|link| link.name == "lo").unwrap();
+        assert!(lo.index > 0);
+        assert!(lo.flags.contains(crate::data::IfFlags::LOOPBACK));
+    }
+}
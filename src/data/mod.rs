@@ -2,9 +2,15 @@ pub mod addr;
 pub mod netlink;
 pub mod arr;
 pub mod if_;
+pub mod ifstat;
+pub mod route;
+pub mod sockdiag;
 
 
 pub use addr::*;
 pub use netlink::*;
 pub use arr::*;
 pub use if_::*;
+pub use ifstat::*;
+pub use route::*;
+pub use sockdiag::*;
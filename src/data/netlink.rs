@@ -1,5 +1,33 @@
-use crate::defraw;
+//! Minimal rtnetlink ([rfc3549](https://www.rfc-editor.org/rfc/rfc3549))
+//! request/response over `NETLINK_ROUTE`
 
+use std::{
+    mem::{size_of, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use libc::{
+    bind, close, nlmsgerr, nlmsghdr, recv, sendto, sockaddr, sockaddr_nl,
+    socket, socklen_t, AF_NETLINK, NETLINK_ROUTE, NETLINK_SOCK_DIAG,
+    NLMSG_DONE, NLMSG_ERROR, NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST,
+    SOCK_CLOEXEC, SOCK_RAW,
+};
+
+use crate::{c_error::ErrNo, defraw, rs_error::NetErr, throw_errno, Result};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const NLMSG_HDRLEN: usize = size_of::<nlmsghdr>();
+pub const NLA_HDRLEN: usize = 4;
+
+/// Attribute type flags (NLA_F_NESTED, NLA_F_NET_BYTEORDER)
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
 
 defraw! {
     pub struct rtnl_link_stats {
@@ -31,5 +59,320 @@ defraw! {
         tx_compressed: u32,
         rx_nohandler: u32,
     }
+
+    /// linux/if_link.h, IFLA_STATS64, counters don't wrap on busy links
+    pub struct rtnl_link_stats64 {
+        rx_packets: u64,
+        tx_packets: u64,
+        rx_bytes: u64,
+        tx_bytes: u64,
+        rx_errors: u64,
+        tx_errors: u64,
+        rx_dropped: u64,
+        tx_dropped: u64,
+        multicast: u64,
+        collisions: u64,
+        /* detailed rx_errors: */
+        rx_length_errors: u64,
+        rx_over_errors: u64,
+        rx_crc_errors: u64,
+        rx_frame_errors: u64,
+        rx_fifo_errors: u64,
+        rx_missed_errors: u64,
+        /* detailed tx_errors */
+        tx_aborted_errors: u64,
+        tx_carrier_errors: u64,
+        tx_fifo_errors: u64,
+        tx_heartbeat_errors: u64,
+        tx_window_errors: u64,
+        /* for cslip etc */
+        rx_compressed: u64,
+        tx_compressed: u64,
+        rx_nohandler: u64,
+    }
+
+    /// linux/if_addr.h, body of RTM_{NEW,DEL,GET}ADDR
+    pub struct ifaddrmsg {
+        ifa_family: u8,
+        ifa_prefixlen: u8,
+        ifa_flags: u8,
+        ifa_scope: u8,
+        ifa_index: u32,
+    }
+
+    /// linux/rtnetlink.h, body of RTM_{NEW,DEL,GET,SET}LINK
+    pub struct ifinfomsg {
+        ifi_family: u8,
+        __ifi_pad: u8,
+        ifi_type: u16,
+        ifi_index: i32,
+        ifi_flags: u32,
+        ifi_change: u32,
+    }
+
+    /// linux/rtnetlink.h, body of RTM_{NEW,DEL,GET}ROUTE
+    pub struct rtmsg {
+        rtm_family: u8,
+        rtm_dst_len: u8,
+        rtm_src_len: u8,
+        rtm_tos: u8,
+        rtm_table: u8,
+        rtm_protocol: u8,
+        rtm_scope: u8,
+        rtm_type: u8,
+        rtm_flags: u32,
+    }
+
+    /// linux/neighbour.h, body of RTM_{NEW,DEL,GET}NEIGH
+    pub struct ndmsg {
+        ndm_family: u8,
+        __ndm_pad1: u8,
+        __ndm_pad2: u16,
+        ndm_ifindex: i32,
+        ndm_state: u16,
+        ndm_flags: u8,
+        ndm_type: u8,
+    }
+}
+
+
+/// Netlink socket (`NETLINK_ROUTE`, `NETLINK_SOCK_DIAG`), closed on drop
+#[derive(Debug)]
+pub struct NlSock {
+    fd: i32,
+    seq: u32,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl NlSock {
+    pub unsafe fn route() -> Result<Self> {
+        Self::open(NETLINK_ROUTE)
+    }
+
+    pub unsafe fn sock_diag() -> Result<Self> {
+        Self::open(NETLINK_SOCK_DIAG)
+    }
+
+    pub unsafe fn open(proto: i32) -> Result<Self> {
+        let fd = throw_errno!(
+            socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, proto)
+            throws CreateSocket
+        );
+        let it = Self { fd, seq: 0 };
+
+        let mut nl: sockaddr_nl = zeroed();
+        nl.nl_family = AF_NETLINK as u16;
+
+        throw_errno!(bind(
+            fd,
+            &nl as *const sockaddr_nl as *const sockaddr,
+            size_of::<sockaddr_nl>() as socklen_t
+        ) throws Bind);
+
+        Ok(it)
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Send a request and collect the replies until it's acknowledged
+    /// (or the dump is done).
+    ///
+    /// -> (type, body) of each reply, header stripped
+    pub unsafe fn request(
+        &mut self,
+        ty: u16,
+        flags: u16,
+        body: &[u8],
+    ) -> Result<Vec<(u16, Vec<u8>)>> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let dump = flags & NLM_F_DUMP as u16 == NLM_F_DUMP as u16;
+        let mut flags = flags | NLM_F_REQUEST as u16;
+        if !dump {
+            flags |= NLM_F_ACK as u16;
+        }
+
+        let hdr = nlmsghdr {
+            nlmsg_len: (NLMSG_HDRLEN + body.len()) as u32,
+            nlmsg_type: ty,
+            nlmsg_flags: flags,
+            nlmsg_seq: self.seq,
+            nlmsg_pid: 0,
+        };
+        let mut msg = Vec::with_capacity(hdr.nlmsg_len as usize);
+        msg.extend_from_slice(as_bytes(&hdr));
+        msg.extend_from_slice(body);
+
+        let mut kernel: sockaddr_nl = zeroed();
+        kernel.nl_family = AF_NETLINK as u16;
+
+        throw_errno!(sendto(
+            self.fd,
+            msg.as_ptr() as *const _,
+            msg.len(),
+            0,
+            &kernel as *const sockaddr_nl as *const sockaddr,
+            size_of::<sockaddr_nl>() as socklen_t
+        ) throws SendTo);
+
+        let mut replies = vec![];
+        let mut buf = vec![0u8; 64 * 1024];
+
+        loop {
+            let n = throw_errno!(
+                recv(self.fd, buf.as_mut_ptr() as *mut _, buf.len(), 0)
+                throws RecvFrom
+            ) as usize;
+            let mut pos = 0;
+
+            while pos + NLMSG_HDRLEN <= n {
+                let hdr =
+                    (buf[pos..].as_ptr() as *const nlmsghdr).read_unaligned();
+                let len = hdr.nlmsg_len as usize;
+
+                if len < NLMSG_HDRLEN || pos + len > n {
+                    return Err(NetErr::Bug(format!(
+                        "truncated netlink message {len}"
+                    )));
+                }
+
+                let payload = &buf[pos + NLMSG_HDRLEN..pos + len];
+                pos += nlmsg_align(len);
+
+                if hdr.nlmsg_seq != self.seq {
+                    continue;
+                }
+
+                match hdr.nlmsg_type as i32 {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        let err = (payload.as_ptr() as *const nlmsgerr)
+                            .read_unaligned();
+
+                        if err.error == 0 {
+                            return Ok(replies);
+                        }
+
+                        return Err(NetErr::Netlink(ErrNo::from(-err.error)));
+                    }
+                    _ => replies.push((hdr.nlmsg_type, payload.to_vec())),
+                }
+            }
+        }
+    }
+}
+
+
+impl Drop for NlSock {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+pub fn nlmsg_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+
+/// View plain C struct as bytes
+pub fn as_bytes<T: Copy>(val: &T) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            val as *const T as *const u8,
+            size_of::<T>(),
+        )
+    }
+}
+
+
+/// Append attribute (type-length-value, aligned to 4 bytes)
+pub fn nla_put(buf: &mut Vec<u8>, ty: u16, data: &[u8]) {
+    let len = NLA_HDRLEN + data.len();
+
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&ty.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + nlmsg_align(len) - len, 0);
+}
+
+
+/// Iterate attributes as (type, value), flags of type are masked out
+pub fn nla_iter(mut bytes: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if bytes.len() < NLA_HDRLEN {
+            return None;
+        }
+
+        let len = u16::from_ne_bytes([bytes[0], bytes[1]]) as usize;
+        let ty = u16::from_ne_bytes([bytes[2], bytes[3]]) & NLA_TYPE_MASK;
+
+        if len < NLA_HDRLEN || len > bytes.len() {
+            return None;
+        }
+
+        let val = &bytes[NLA_HDRLEN..len];
+        bytes = &bytes[nlmsg_align(len).min(bytes.len())..];
+
+        Some((ty, val))
+    })
+}
+
+
+/// Address attribute value (RTA_DST, NDA_DST ...) by its length
+pub fn nla_ip(val: &[u8]) -> Option<IpAddr> {
+    match val.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(
+            <[u8; 4]>::try_from(val).unwrap(),
+        ))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(val).unwrap(),
+        ))),
+        _ => None,
+    }
 }
 
+
+/// Struct from the head of message body, None if it's too short.
+///
+/// Any bit pattern must be a valid `T`, as for the plain C structs of
+/// netlink (`rtmsg`, `ndmsg` ...), not `bool`, enums or references.
+pub unsafe fn read_body<T: Copy>(body: &[u8]) -> Option<T> {
+    if body.len() < size_of::<T>() {
+        return None;
+    }
+
+    Some((body.as_ptr() as *const T).read_unaligned())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{nla_iter, nla_put};
+
+    #[test]
+    fn test_nla() {
+        let mut buf = vec![];
+        nla_put(&mut buf, 3, b"eth0\0");
+        nla_put(&mut buf, 4, &1500u32.to_ne_bytes());
+
+        assert_eq!(buf.len(), 12 + 8);
+
+        let attrs: Vec<_> = nla_iter(&buf).collect();
+        assert_eq!(
+            attrs,
+            vec![(3, &b"eth0\0"[..]), (4, &1500u32.to_ne_bytes()[..])]
+        );
+    }
+}
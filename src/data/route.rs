@@ -0,0 +1,632 @@
+//! Kernel routing table and neighbour (ARP/NDP) cache, by rtnetlink dump
+//! or parsing `/proc/net`
+
+use std::{
+    fmt::{Debug, Display},
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use libc::{
+    if_nametoindex, AF_INET, AF_INET6, NDA_DST, NDA_LLADDR, NLM_F_DUMP,
+    NTF_ROUTER, NUD_DELAY, NUD_FAILED, NUD_INCOMPLETE, NUD_NOARP,
+    NUD_PERMANENT, NUD_PROBE, NUD_REACHABLE, NUD_STALE, RTA_DST, RTA_GATEWAY,
+    RTA_OIF, RTA_PREFSRC, RTA_PRIORITY, RTA_TABLE, RTM_GETNEIGH, RTM_GETROUTE,
+    RTM_NEWNEIGH, RTM_NEWROUTE, RTN_UNICAST, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE,
+    RT_TABLE_MAIN,
+};
+
+use crate::{
+    data::{
+        as_bytes, getifname, ndmsg, nla_ip, nla_iter, read_body, rtmsg, NlSock,
+    },
+    datalink::Mac,
+    NetErr, Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// linux/route.h, flags of `/proc/net/route` and `/proc/net/ipv6_route`
+const RTF_GATEWAY: u32 = 0x0002;
+
+/// linux/if_arp.h, flags of `/proc/net/arp`
+const ATF_COM: u32 = 0x02;
+const ATF_PERM: u32 = 0x04;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub dst: IpAddr,
+    pub dst_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// Preferred source address
+    pub prefsrc: Option<IpAddr>,
+    pub ifindex: u32,
+    pub dev: Option<String>,
+    pub metric: u32,
+    /// RT_TABLE_*
+    pub table: u32,
+    /// RTPROT_*
+    pub protocol: u8,
+    /// RT_SCOPE_*
+    pub scope: u8,
+    /// RTN_*
+    pub ty: u8,
+}
+
+
+/// Neighbour Unreachability Detection state (NUD_*)
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct NudState(pub u16);
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbour {
+    pub addr: IpAddr,
+    /// None until it's resolved
+    pub mac: Option<Mac>,
+    pub ifindex: u32,
+    pub dev: Option<String>,
+    pub state: NudState,
+    pub router: bool,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Route {
+    pub fn is_default(&self) -> bool {
+        self.dst_len == 0
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.dst, ip) {
+            (IpAddr::V4(dst), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32u32.saturating_sub(self.dst_len as u32))
+                    .unwrap_or(0);
+
+                u32::from(dst) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(dst), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128u32.saturating_sub(self.dst_len as u32))
+                    .unwrap_or(0);
+
+                u128::from(dst) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+
+impl Display for Route {
+    /// In the way of `ip route`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_default() {
+            write!(f, "default")?;
+        }
+        else {
+            write!(f, "{}/{}", self.dst, self.dst_len)?;
+        }
+
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+
+        if let Some(dev) = &self.dev {
+            write!(f, " dev {dev}")?;
+        }
+
+        if self.scope == RT_SCOPE_LINK {
+            write!(f, " scope link")?;
+        }
+
+        if let Some(prefsrc) = self.prefsrc {
+            write!(f, " src {prefsrc}")?;
+        }
+
+        if self.metric != 0 {
+            write!(f, " metric {}", self.metric)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl NudState {
+    pub const INCOMPLETE: Self = Self(NUD_INCOMPLETE);
+    pub const REACHABLE: Self = Self(NUD_REACHABLE);
+    pub const STALE: Self = Self(NUD_STALE);
+    pub const DELAY: Self = Self(NUD_DELAY);
+    pub const PROBE: Self = Self(NUD_PROBE);
+    pub const FAILED: Self = Self(NUD_FAILED);
+    pub const NOARP: Self = Self(NUD_NOARP);
+    pub const PERMANENT: Self = Self(NUD_PERMANENT);
+
+    const NAMES: [(Self, &'static str); 8] = [
+        (Self::INCOMPLETE, "INCOMPLETE"),
+        (Self::REACHABLE, "REACHABLE"),
+        (Self::STALE, "STALE"),
+        (Self::DELAY, "DELAY"),
+        (Self::PROBE, "PROBE"),
+        (Self::FAILED, "FAILED"),
+        (Self::NOARP, "NOARP"),
+        (Self::PERMANENT, "PERMANENT"),
+    ];
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Has usable link layer address
+    pub fn is_valid(&self) -> bool {
+        self.0
+            & (NUD_REACHABLE
+                | NUD_STALE
+                | NUD_DELAY
+                | NUD_PROBE
+                | NUD_NOARP
+                | NUD_PERMANENT)
+            != 0
+    }
+}
+
+
+impl Debug for NudState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(state, _)| self.contains(*state))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "NONE")
+        }
+        else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+
+impl Display for Neighbour {
+    /// In the way of `ip neigh`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.addr)?;
+
+        if let Some(dev) = &self.dev {
+            write!(f, " dev {dev}")?;
+        }
+
+        if let Some(mac) = self.mac {
+            write!(f, " lladdr {mac}")?;
+        }
+
+        if self.router {
+            write!(f, " router")?;
+        }
+
+        write!(f, " {:?}", self.state)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Dump the routes of all tables for both families
+pub unsafe fn routes() -> Result<Vec<Route>> {
+    let rtm = rtmsg::default();
+    let replies = NlSock::route()?.request(
+        RTM_GETROUTE,
+        NLM_F_DUMP as u16,
+        as_bytes(&rtm),
+    )?;
+
+    let mut routes = vec![];
+
+    for (ty, body) in replies.iter() {
+        if *ty != RTM_NEWROUTE {
+            continue;
+        }
+
+        let Some(rtm) = read_body::<rtmsg>(body)
+        else {
+            continue;
+        };
+
+        let unspec = match rtm.rtm_family as i32 {
+            AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => continue,
+        };
+
+        let mut route = Route {
+            dst: unspec,
+            dst_len: rtm.rtm_dst_len,
+            gateway: None,
+            prefsrc: None,
+            ifindex: 0,
+            dev: None,
+            metric: 0,
+            table: rtm.rtm_table as u32,
+            protocol: rtm.rtm_protocol,
+            scope: rtm.rtm_scope,
+            ty: rtm.rtm_type,
+        };
+
+        for (attr, val) in nla_iter(&body[size_of_val(&rtm)..]) {
+            match attr {
+                RTA_DST => route.dst = nla_ip(val).unwrap_or(unspec),
+                RTA_GATEWAY => route.gateway = nla_ip(val),
+                RTA_PREFSRC => route.prefsrc = nla_ip(val),
+                RTA_OIF => route.ifindex = nla_u32(val),
+                RTA_PRIORITY => route.metric = nla_u32(val),
+                RTA_TABLE => route.table = nla_u32(val),
+                _ => (),
+            }
+        }
+
+        if route.ifindex != 0 {
+            route.dev = getifname(route.ifindex);
+        }
+
+        routes.push(route);
+    }
+
+    Ok(routes)
+}
+
+
+/// Most specific route of the main table to `ip`
+pub unsafe fn route_to(ip: IpAddr) -> Result<Option<Route>> {
+    Ok(routes()?
+        .into_iter()
+        .filter(|route| {
+            route.table == RT_TABLE_MAIN as u32 && route.contains(ip)
+        })
+        .max_by_key(|route| (route.dst_len, u32::MAX - route.metric)))
+}
+
+
+/// Routes of `/proc/net/route` and `/proc/net/ipv6_route`
+pub fn proc_routes() -> Result<Vec<Route>> {
+    let mut routes = parse_proc_route(&read_proc("/proc/net/route")?);
+
+    // IPv6 might be disabled
+    if let Ok(text) = fs::read_to_string("/proc/net/ipv6_route") {
+        routes.extend(parse_proc_ipv6_route(&text));
+    }
+
+    Ok(routes)
+}
+
+
+/// Parse `/proc/net/route`, whose addresses are hex of network order u32
+pub fn parse_proc_route(text: &str) -> Vec<Route> {
+    text.lines().skip(1).filter_map(proc_route_line).collect()
+}
+
+
+/// Parse `/proc/net/ipv6_route`, which has no header line
+pub fn parse_proc_ipv6_route(text: &str) -> Vec<Route> {
+    text.lines().filter_map(proc_ipv6_route_line).collect()
+}
+
+
+/// Dump the neighbour cache for both families
+pub unsafe fn neighbours() -> Result<Vec<Neighbour>> {
+    let ndm = ndmsg::default();
+    let replies = NlSock::route()?.request(
+        RTM_GETNEIGH,
+        NLM_F_DUMP as u16,
+        as_bytes(&ndm),
+    )?;
+
+    let mut neighs = vec![];
+
+    for (ty, body) in replies.iter() {
+        if *ty != RTM_NEWNEIGH {
+            continue;
+        }
+
+        let Some(ndm) = read_body::<ndmsg>(body)
+        else {
+            continue;
+        };
+
+        if ![AF_INET, AF_INET6].contains(&(ndm.ndm_family as i32)) {
+            continue;
+        }
+
+        let mut addr = None;
+        let mut mac = None;
+
+        for (attr, val) in nla_iter(&body[size_of_val(&ndm)..]) {
+            match attr {
+                NDA_DST => addr = nla_ip(val),
+                NDA_LLADDR if val.len() == 6 => {
+                    mac = Some(Mac::from_slice(val))
+                }
+                _ => (),
+            }
+        }
+
+        let Some(addr) = addr
+        else {
+            continue;
+        };
+
+        let ifindex = ndm.ndm_ifindex as u32;
+
+        neighs.push(Neighbour {
+            addr,
+            mac,
+            ifindex,
+            dev: getifname(ifindex),
+            state: NudState(ndm.ndm_state),
+            router: ndm.ndm_flags & NTF_ROUTER != 0,
+        });
+    }
+
+    Ok(neighs)
+}
+
+
+/// IPv4 neighbours of `/proc/net/arp`
+pub fn proc_neighbours() -> Result<Vec<Neighbour>> {
+    Ok(parse_proc_arp(&read_proc("/proc/net/arp")?))
+}
+
+
+/// Parse `/proc/net/arp`, state is approximated from ATF_* flags
+pub fn parse_proc_arp(text: &str) -> Vec<Neighbour> {
+    text.lines().skip(1).filter_map(proc_arp_line).collect()
+}
+
+
+fn proc_route_line(line: &str) -> Option<Route> {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    let dev = *cols.first()?;
+    let dst = hex_ipv4(cols.get(1)?)?;
+    let gateway = hex_ipv4(cols.get(2)?)?;
+    let flags = u32::from_str_radix(cols.get(3)?, 16).ok()?;
+    let metric = cols.get(6)?.parse::<u32>().ok()?;
+    let mask = hex_ipv4(cols.get(7)?)?;
+
+    let has_gateway = flags & RTF_GATEWAY != 0;
+
+    Some(Route {
+        dst: IpAddr::V4(dst),
+        dst_len: u32::from(mask).leading_ones() as u8,
+        gateway: has_gateway.then_some(IpAddr::V4(gateway)),
+        prefsrc: None,
+        ifindex: ifindex(dev),
+        dev: Some(dev.to_owned()),
+        metric,
+        table: RT_TABLE_MAIN as u32,
+        protocol: 0,
+        scope: proc_route_scope(has_gateway),
+        ty: RTN_UNICAST,
+    })
+}
+
+
+fn proc_ipv6_route_line(line: &str) -> Option<Route> {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    let dst = hex_ipv6(cols.first()?)?;
+    let dst_len = u8::from_str_radix(cols.get(1)?, 16)
+        .ok()
+        .filter(|len| *len <= 128)?;
+    let nexthop = hex_ipv6(cols.get(4)?)?;
+    let metric = u32::from_str_radix(cols.get(5)?, 16).ok()?;
+    let flags = u32::from_str_radix(cols.get(8)?, 16).ok()?;
+    let dev = *cols.get(9)?;
+
+    let has_gateway = flags & RTF_GATEWAY != 0;
+
+    Some(Route {
+        dst: IpAddr::V6(dst),
+        dst_len,
+        gateway: has_gateway.then_some(IpAddr::V6(nexthop)),
+        prefsrc: None,
+        ifindex: ifindex(dev),
+        dev: Some(dev.to_owned()),
+        metric,
+        table: RT_TABLE_MAIN as u32,
+        protocol: 0,
+        scope: proc_route_scope(has_gateway),
+        ty: RTN_UNICAST,
+    })
+}
+
+
+fn proc_route_scope(has_gateway: bool) -> u8 {
+    if has_gateway {
+        RT_SCOPE_UNIVERSE
+    }
+    else {
+        RT_SCOPE_LINK
+    }
+}
+
+
+fn proc_arp_line(line: &str) -> Option<Neighbour> {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    let addr = cols.first()?.parse::<Ipv4Addr>().ok()?;
+    let flags =
+        u32::from_str_radix(cols.get(2)?.strip_prefix("0x")?, 16).ok()?;
+    let dev = *cols.get(5)?;
+
+    let state = if flags & ATF_PERM != 0 {
+        NudState::PERMANENT
+    }
+    else if flags & ATF_COM != 0 {
+        NudState::REACHABLE
+    }
+    else {
+        NudState::INCOMPLETE
+    };
+
+    Some(Neighbour {
+        addr: IpAddr::V4(addr),
+        mac: if flags & ATF_COM != 0 {
+            cols.get(3)?.parse().ok()
+        }
+        else {
+            None
+        },
+        ifindex: ifindex(dev),
+        dev: Some(dev.to_owned()),
+        state,
+        router: false,
+    })
+}
+
+
+fn nla_u32(val: &[u8]) -> u32 {
+    val.get(..4)
+        .map(|val| u32::from_ne_bytes(val.try_into().unwrap()))
+        .unwrap_or_default()
+}
+
+
+/// `0100007F` -> 127.0.0.1
+pub(crate) fn hex_ipv4(hex: &str) -> Option<Ipv4Addr> {
+    let word = u32::from_str_radix(hex, 16).ok()?;
+
+    Some(Ipv4Addr::from(word.to_ne_bytes()))
+}
+
+
+/// 32 hex digits in network order
+fn hex_ipv6(hex: &str) -> Option<Ipv6Addr> {
+    Some(Ipv6Addr::from(u128::from_str_radix(hex, 16).ok()?))
+}
+
+
+fn ifindex(name: &str) -> u32 {
+    let Ok(name) = std::ffi::CString::new(name)
+    else {
+        return 0;
+    };
+
+    unsafe { if_nametoindex(name.as_ptr()) }
+}
+
+
+pub(crate) fn read_proc(path: &str) -> Result<String> {
+    fs::read_to_string(path).map_err(NetErr::Open)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{
+        neighbours, parse_proc_arp, parse_proc_ipv6_route, parse_proc_route,
+        route_to, routes, NudState,
+    };
+
+    #[test]
+    fn test_parse_proc_route() {
+        let text = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+        let routes = parse_proc_route(text);
+
+        assert_eq!(routes.len(), 2);
+        assert!(routes[0].is_default());
+        assert_eq!(
+            routes[0].gateway,
+            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)))
+        );
+        assert_eq!(routes[0].metric, 100);
+        assert_eq!(routes[1].dst, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)));
+        assert_eq!(routes[1].dst_len, 24);
+        assert_eq!(routes[1].gateway, None);
+        assert!(routes[1].contains(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 9))));
+        assert_eq!(
+            routes[1].to_string(),
+            "192.168.1.0/24 dev eth0 scope link metric 100"
+        );
+    }
+
+    #[test]
+    fn test_parse_proc_ipv6_route() {
+        let text = "\
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00450003     eth0
+fe800000000000000000000000000000 ff 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+";
+        let mut routes = parse_proc_ipv6_route(text);
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].dst, "fe80::".parse::<IpAddr>().unwrap());
+        assert_eq!(routes[0].dst_len, 64);
+        assert_eq!(routes[0].metric, 256);
+        assert!(routes[1].is_default());
+        assert_eq!(
+            routes[1].gateway,
+            Some(IpAddr::V6("fe80::1".parse::<Ipv6Addr>().unwrap()))
+        );
+
+        // out of range prefix length is a host route
+        let fe80_1 = "fe80::1".parse::<IpAddr>().unwrap();
+        assert!(routes[0].contains(fe80_1));
+        routes[0].dst_len = 200;
+        assert!(!routes[0].contains(fe80_1));
+    }
+
+    #[test]
+    fn test_parse_proc_arp() {
+        let text = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         00:11:22:33:44:55     *        eth0
+192.168.1.7      0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        let neighs = parse_proc_arp(text);
+
+        assert_eq!(neighs.len(), 2);
+        assert_eq!(neighs[0].state, NudState::REACHABLE);
+        assert_eq!(neighs[0].mac.unwrap().to_string(), "00:11:22:33:44:55");
+        assert_eq!(neighs[1].state, NudState::INCOMPLETE);
+        assert_eq!(neighs[1].mac, None);
+        assert_eq!(format!("{:?}", neighs[1].state), "INCOMPLETE");
+    }
+
+    #[test]
+    fn test_kernel_routes() {
+        unsafe {
+            let routes = routes().unwrap();
+
+            // local table always has the loopback
+            assert!(routes
+                .iter()
+                .any(|route| route.dst == IpAddr::V4(Ipv4Addr::LOCALHOST)
+                    && route.dev.as_deref() == Some("lo")));
+
+            if let Some(route) =
+                route_to(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))).unwrap()
+            {
+                assert!(route.contains(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+            }
+
+            neighbours().unwrap();
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! Open TCP/UDP sockets by `NETLINK_SOCK_DIAG` (inet_diag) or parsing
+//! `/proc/net/{tcp,udp}{,6}`
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use libc::{AF_INET, AF_INET6, IPPROTO_TCP, IPPROTO_UDP, NLM_F_DUMP};
+
+use crate::{
+    aux::IpFamily,
+    data::{as_bytes, read_body, route::hex_ipv4, route::read_proc, NlSock},
+    defraw, enum_try_from_int, Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// linux/sock_diag.h
+pub const SOCK_DIAG_BY_FAMILY: u16 = 20;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+defraw! {
+    /// linux/inet_diag.h, ports and addresses are in network order
+    pub struct inet_diag_sockid {
+        idiag_sport: u16,
+        idiag_dport: u16,
+        idiag_src: [u32; 4],
+        idiag_dst: [u32; 4],
+        idiag_if: u32,
+        idiag_cookie: [u32; 2],
+    }
+
+    pub struct inet_diag_req_v2 {
+        sdiag_family: u8,
+        sdiag_protocol: u8,
+        idiag_ext: u8,
+        pad: u8,
+        idiag_states: u32,
+        id: inet_diag_sockid,
+    }
+
+    pub struct inet_diag_msg {
+        idiag_family: u8,
+        idiag_state: u8,
+        idiag_timer: u8,
+        idiag_retrans: u8,
+        id: inet_diag_sockid,
+        idiag_expires: u32,
+        idiag_rqueue: u32,
+        idiag_wqueue: u32,
+        idiag_uid: u32,
+        idiag_inode: u32,
+    }
+}
+
+
+enum_try_from_int! {
+    /// net/tcp_states.h, UDP is `Close` unless connected
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TcpState {
+        Established = 1,
+        SynSent = 2,
+        SynRecv = 3,
+        FinWait1 = 4,
+        FinWait2 = 5,
+        TimeWait = 6,
+        Close = 7,
+        CloseWait = 8,
+        LastAck = 9,
+        Listen = 10,
+        Closing = 11,
+        NewSynRecv = 12,
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SockProto {
+    Tcp,
+    Udp,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SockInfo {
+    pub proto: SockProto,
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    pub state: TcpState,
+    /// Unread bytes, or pending connections of listener
+    pub recv_q: u32,
+    /// Unacked bytes, or backlog of listener (not in `/proc`)
+    pub send_q: u32,
+    pub uid: u32,
+    pub inode: u32,
+    /// Bound device, 0 if none (not in `/proc`)
+    pub ifindex: u32,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl TcpState {
+    /// Name as `ss` shows
+    pub fn name(&self, proto: SockProto) -> &'static str {
+        match self {
+            Self::Established => "ESTAB",
+            Self::SynSent => "SYN-SENT",
+            Self::SynRecv => "SYN-RECV",
+            Self::FinWait1 => "FIN-WAIT-1",
+            Self::FinWait2 => "FIN-WAIT-2",
+            Self::TimeWait => "TIME-WAIT",
+            Self::Close if proto == SockProto::Udp => "UNCONN",
+            Self::Close => "CLOSE",
+            Self::CloseWait => "CLOSE-WAIT",
+            Self::LastAck => "LAST-ACK",
+            Self::Listen => "LISTEN",
+            Self::Closing => "CLOSING",
+            Self::NewSynRecv => "NEW-SYN-RECV",
+        }
+    }
+}
+
+
+impl SockProto {
+    fn ipproto(&self) -> i32 {
+        match self {
+            Self::Tcp => IPPROTO_TCP,
+            Self::Udp => IPPROTO_UDP,
+        }
+    }
+}
+
+
+impl Display for SockProto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+
+impl SockInfo {
+    /// Listening TCP or unconnected UDP
+    pub fn is_listening(&self) -> bool {
+        match self.proto {
+            SockProto::Tcp => self.state == TcpState::Listen,
+            SockProto::Udp => self.state == TcpState::Close,
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Dump sockets of `proto` and `family` in all states
+pub unsafe fn sockets(
+    proto: SockProto,
+    family: IpFamily,
+) -> Result<Vec<SockInfo>> {
+    let req = inet_diag_req_v2 {
+        sdiag_family: family_af(family) as u8,
+        sdiag_protocol: proto.ipproto() as u8,
+        idiag_states: !0,
+        ..Default::default()
+    };
+
+    let replies = NlSock::sock_diag()?.request(
+        SOCK_DIAG_BY_FAMILY,
+        NLM_F_DUMP as u16,
+        as_bytes(&req),
+    )?;
+
+    Ok(replies
+        .iter()
+        .filter(|(ty, _)| *ty == SOCK_DIAG_BY_FAMILY)
+        .filter_map(|(_, body)| read_body::<inet_diag_msg>(body))
+        .filter_map(|msg| diag_sock_info(proto, &msg))
+        .collect())
+}
+
+
+/// TCP and UDP sockets of both families
+pub unsafe fn inet_sockets() -> Result<Vec<SockInfo>> {
+    let mut socks = vec![];
+
+    for proto in [SockProto::Tcp, SockProto::Udp] {
+        for family in [IpFamily::V4, IpFamily::V6] {
+            socks.extend(sockets(proto, family)?);
+        }
+    }
+
+    Ok(socks)
+}
+
+
+/// Sockets of `/proc/net/{tcp,udp}{,6}`
+pub fn proc_sockets(
+    proto: SockProto,
+    family: IpFamily,
+) -> Result<Vec<SockInfo>> {
+    let path = format!(
+        "/proc/net/{proto}{}",
+        if family == IpFamily::V6 { "6" } else { "" }
+    );
+
+    Ok(parse_proc_net_sock(&read_proc(&path)?, proto))
+}
+
+
+/// Parse `/proc/net/{tcp,udp}{,6}`, the family is told by address length
+pub fn parse_proc_net_sock(text: &str, proto: SockProto) -> Vec<SockInfo> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| proc_sock_line(line, proto))
+        .collect()
+}
+
+
+fn proc_sock_line(line: &str, proto: SockProto) -> Option<SockInfo> {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    let local = hex_sockaddr(cols.get(1)?)?;
+    let peer = hex_sockaddr(cols.get(2)?)?;
+    let state = u8::from_str_radix(cols.get(3)?, 16).ok()?;
+    let (tx_queue, rx_queue) = cols.get(4)?.split_once(':')?;
+    let uid = cols.get(7)?.parse().ok()?;
+    let inode = cols.get(9)?.parse().ok()?;
+
+    Some(SockInfo {
+        proto,
+        local,
+        peer,
+        state: TcpState::try_from(state).ok()?,
+        recv_q: u32::from_str_radix(rx_queue, 16).ok()?,
+        send_q: u32::from_str_radix(tx_queue, 16).ok()?,
+        uid,
+        inode,
+        ifindex: 0,
+    })
+}
+
+
+fn diag_sock_info(proto: SockProto, msg: &inet_diag_msg) -> Option<SockInfo> {
+    let id = &msg.id;
+    let (src, dst) = match msg.idiag_family as i32 {
+        AF_INET => (
+            IpAddr::V4(Ipv4Addr::from(id.idiag_src[0].to_ne_bytes())),
+            IpAddr::V4(Ipv4Addr::from(id.idiag_dst[0].to_ne_bytes())),
+        ),
+        AF_INET6 => (
+            IpAddr::V6(words_ipv6(id.idiag_src)),
+            IpAddr::V6(words_ipv6(id.idiag_dst)),
+        ),
+        _ => return None,
+    };
+
+    Some(SockInfo {
+        proto,
+        local: SocketAddr::new(src, u16::from_be(id.idiag_sport)),
+        peer: SocketAddr::new(dst, u16::from_be(id.idiag_dport)),
+        state: TcpState::try_from(msg.idiag_state).ok()?,
+        recv_q: msg.idiag_rqueue,
+        send_q: msg.idiag_wqueue,
+        uid: msg.idiag_uid,
+        inode: msg.idiag_inode,
+        ifindex: id.idiag_if,
+    })
+}
+
+
+fn family_af(family: IpFamily) -> i32 {
+    match family {
+        IpFamily::V4 => AF_INET,
+        IpFamily::V6 => AF_INET6,
+    }
+}
+
+
+/// Network order address kept in u32 words
+fn words_ipv6(words: [u32; 4]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+
+    for (i, word) in words.iter().enumerate() {
+        octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+    }
+
+    Ipv6Addr::from(octets)
+}
+
+
+/// `0100007F:0050` or 32 hex digits IPv6 (as four u32 words) and port
+fn hex_sockaddr(hex: &str) -> Option<SocketAddr> {
+    let (addr, port) = hex.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let ip = match addr.len() {
+        8 => IpAddr::V4(hex_ipv4(addr)?),
+        32 => {
+            let mut words = [0u32; 4];
+
+            for (i, word) in words.iter_mut().enumerate() {
+                *word =
+                    u32::from_str_radix(&addr[i * 8..i * 8 + 8], 16).ok()?;
+            }
+
+            IpAddr::V6(words_ipv6(words))
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream, UdpSocket},
+        os::{fd::AsRawFd, unix::fs::MetadataExt},
+    };
+
+    use super::{
+        parse_proc_net_sock, proc_sockets, sockets, SockProto, TcpState,
+    };
+    use crate::aux::IpFamily;
+
+    fn inode(fd: i32) -> u32 {
+        std::fs::metadata(format!("/proc/self/fd/{fd}"))
+            .unwrap()
+            .ino() as u32
+    }
+
+    #[test]
+    fn test_parse_proc_net_sock() {
+        let text = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000002 00:00000000 00000000  1000        0 4242 1 0000000000000000 100 0 0 10 0
+   1: 00000000000000000000000001000000:0035 00000000000000000000000000000000:0000 07 00000010:00000000 00:00000000 00000000     0        0 77 2 0000000000000000 0
+";
+        let socks = parse_proc_net_sock(text, SockProto::Tcp);
+
+        assert_eq!(socks.len(), 2);
+        assert_eq!(socks[0].local, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(socks[0].state, TcpState::Listen);
+        assert_eq!(socks[0].recv_q, 2);
+        assert_eq!(socks[0].uid, 1000);
+        assert_eq!(socks[0].inode, 4242);
+        assert_eq!(socks[1].local, "[::1]:53".parse().unwrap());
+        assert_eq!(socks[1].send_q, 16);
+        assert_eq!(socks[1].state.name(SockProto::Udp), "UNCONN");
+    }
+
+    #[test]
+    fn test_sock_diag() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let udp = UdpSocket::bind("[::1]:0").unwrap();
+
+        let tcp = unsafe { sockets(SockProto::Tcp, IpFamily::V4) }.unwrap();
+
+        let it = tcp
+            .iter()
+            .find(|sock| sock.local == listener.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(it.state, TcpState::Listen);
+        assert!(it.is_listening());
+        assert_eq!(it.inode, inode(listener.as_raw_fd()));
+
+        let it = tcp
+            .iter()
+            .find(|sock| sock.local == client.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(it.state, TcpState::Established);
+        assert_eq!(it.peer, listener.local_addr().unwrap());
+
+        let it = unsafe { sockets(SockProto::Udp, IpFamily::V6) }
+            .unwrap()
+            .into_iter()
+            .find(|sock| sock.local == udp.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(it.inode, inode(udp.as_raw_fd()));
+
+        // /proc agrees
+        let it = proc_sockets(SockProto::Tcp, IpFamily::V4)
+            .unwrap()
+            .into_iter()
+            .find(|sock| sock.local == listener.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(it.inode, inode(listener.as_raw_fd()));
+    }
+}
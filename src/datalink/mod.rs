@@ -1,6 +1,7 @@
 use std::{
     fmt::{Debug, Display},
     mem::{transmute, size_of},
+    net::{Ipv4Addr, Ipv6Addr},
     str::FromStr,
 };
 
@@ -132,6 +133,33 @@ impl Mac {
         Self::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff)
     }
 
+    /// 01:00:5e + low 23 bits of the group address (rfc1112 section 6.4)
+    pub fn from_ipv4_multicast(group: Ipv4Addr) -> Option<Self> {
+        if !group.is_multicast() {
+            return None;
+        }
+
+        let [_, o1, o2, o3] = group.octets();
+
+        Some(Self::new(0x01, 0x00, 0x5e, o1 & 0x7f, o2, o3))
+    }
+
+    /// 33:33 + low 32 bits of the group address (rfc2464 section 7)
+    pub fn from_ipv6_multicast(group: Ipv6Addr) -> Option<Self> {
+        if !group.is_multicast() {
+            return None;
+        }
+
+        let [.., o12, o13, o14, o15] = group.octets();
+
+        Some(Self::new(0x33, 0x33, o12, o13, o14, o15))
+    }
+
+    /// Group bit (I/G) of the first octet, broadcast included
+    pub fn is_multicast(&self) -> bool {
+        self.0[0].0 & 0x01 != 0
+    }
+
     pub fn from_slice<T: Copy>(src: &[T]) -> Self {
         let mut arr = [Hex8(0); 6];
 
@@ -203,7 +231,7 @@ impl FromStr for Mac {
 
 #[cfg(test)]
 mod tests {
-    use std::{mem::size_of, ptr::write};
+    use std::{mem::size_of, net::Ipv4Addr, ptr::write};
 
     use crate::datalink::{Eth, Mac};
 
@@ -247,4 +275,24 @@ mod tests {
         }
         println!("{eth:#?}, {} bytes", size_of::<Eth>());
     }
+
+    #[test]
+    fn test_multicast_mac() {
+        assert_eq!(
+            Mac::from_ipv4_multicast(Ipv4Addr::new(239, 129, 2, 3)),
+            Some(Mac::new(0x01, 0x00, 0x5e, 0x01, 0x02, 0x03))
+        );
+        assert_eq!(
+            Mac::from_ipv4_multicast(Ipv4Addr::new(224, 0, 0, 251)),
+            Some(Mac::new(0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb))
+        );
+        assert_eq!(Mac::from_ipv4_multicast(Ipv4Addr::new(10, 0, 0, 1)), None);
+        assert_eq!(
+            Mac::from_ipv6_multicast("ff02::1:ff00:1234".parse().unwrap()),
+            Some(Mac::new(0x33, 0x33, 0xff, 0x00, 0x12, 0x34))
+        );
+
+        assert!(Mac::broadcast().is_multicast());
+        assert!(!Mac::new(0x00, 0x0c, 0x29, 0x73, 0x9d, 0x15).is_multicast());
+    }
 }
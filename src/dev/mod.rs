@@ -1,4 +1,5 @@
 pub mod tun;
+pub mod netns;
 
 
 use std::ffi::c_int;
@@ -38,6 +39,16 @@ pub unsafe fn set_tuntap_group(fd: i32, group: i32) -> Result<()> {
     Ok(())
 }
 
+/// Non-persistent device is removed when the last fd is closed
+pub unsafe fn set_tuntap_persist(fd: i32, persist: bool) -> Result<()> {
+    throw_errno!(
+        ioctl(fd, TUNSETPERSIST, persist as c_int)
+        throws CIOCtl
+    );
+
+    Ok(())
+}
+
 
 
 
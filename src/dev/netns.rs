@@ -0,0 +1,532 @@
+//! Network namespaces and veth pairs, for isolated topologies in tests
+//!
+//! Named namespaces live under `/run/netns` like those of `ip netns`, so
+//! `ip netns exec` can run tools inside them. Anonymous ones last as long
+//! as their fd.
+
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    net::{IpAddr, Ipv4Addr},
+    os::fd::IntoRawFd,
+    panic::resume_unwind,
+    path::PathBuf,
+    ptr::null,
+    thread,
+};
+
+use libc::{
+    close, getgid, getuid, mount, setns, umount2, unshare, CLONE_NEWNET,
+    CLONE_NEWUSER, IFLA_IFNAME, IFLA_INFO_DATA, IFLA_INFO_KIND, IFLA_LINKINFO,
+    IFLA_NET_NS_FD, MNT_DETACH, MS_BIND, NLA_F_NESTED, NLM_F_CREATE,
+    NLM_F_EXCL, RTM_DELLINK, RTM_NEWLINK,
+};
+
+use crate::{
+    data::{as_bytes, ifinfomsg, nla_put, Interface, NlSock},
+    throw_errno, NetErr, Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// linux/veth.h
+const VETH_INFO_PEER: u16 = 1;
+
+const NESTED: u16 = NLA_F_NESTED as u16;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Handle of a network namespace, fd is closed on drop
+#[derive(Debug)]
+pub struct NetNs {
+    name: Option<String>,
+    fd: i32,
+}
+
+
+/// Two namespaces `a` and `b` linked by a veth pair, with lo up and an
+/// IPv4 address on each end
+#[derive(Debug)]
+pub struct TwoHosts {
+    pub a: NetNs,
+    pub b: NetNs,
+    pub a_if: String,
+    pub b_if: String,
+    pub a_addr: Ipv4Addr,
+    pub b_addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl NetNs {
+    /// New anonymous namespace
+    pub unsafe fn new() -> Result<Self> {
+        let fd = in_thread(|| {
+            throw_errno!(unshare(CLONE_NEWNET) throws NetNs withs);
+
+            open_fd(&PathBuf::from("/proc/thread-self/ns/net"))
+        })?;
+
+        Ok(Self { name: None, fd })
+    }
+
+    /// New namespace pinned at `/run/netns/<name>`
+    pub unsafe fn create(name: &str) -> Result<Self> {
+        fs::create_dir_all(NETNS_RUN_DIR).map_err(NetErr::CreateDirAll)?;
+
+        let path = netns_path(name);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(NetErr::Open)?;
+
+        let res = in_thread(|| {
+            throw_errno!(unshare(CLONE_NEWNET) throws NetNs withs);
+
+            let src = CString::new("/proc/thread-self/ns/net").unwrap();
+            let dst = CString::new(path.to_str().unwrap()).unwrap();
+
+            throw_errno!(mount(
+                src.as_ptr(),
+                dst.as_ptr(),
+                null(),
+                MS_BIND,
+                null()
+            ) throws NetNs withs);
+
+            Ok(())
+        });
+
+        if let Err(err) = res {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+
+        Self::open(name)
+    }
+
+    /// Existing namespace of `/run/netns/<name>`
+    pub unsafe fn open(name: &str) -> Result<Self> {
+        Ok(Self {
+            name: Some(name.to_owned()),
+            fd: open_fd(&netns_path(name))?,
+        })
+    }
+
+    /// Namespace of the calling thread
+    pub unsafe fn current() -> Result<Self> {
+        Ok(Self {
+            name: None,
+            fd: open_fd(&PathBuf::from("/proc/thread-self/ns/net"))?,
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Move the calling thread into the namespace
+    pub unsafe fn enter(&self) -> Result<()> {
+        throw_errno!(setns(self.fd, CLONE_NEWNET) throws NetNs withs);
+
+        Ok(())
+    }
+
+    /// Run `f` on a thread inside the namespace, panic of `f` is resumed.
+    ///
+    /// Sockets opened by `f` stay in the namespace after it returns.
+    pub fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send,
+        T: Send,
+    {
+        in_thread(|| unsafe { self.enter() }.map(|_| f()))
+    }
+
+    /// Unpin the named namespace, it's gone when the last user leaves
+    pub unsafe fn delete(self) -> Result<()> {
+        if let Some(name) = &self.name {
+            let path = netns_path(name);
+            let dst = CString::new(path.to_str().unwrap()).unwrap();
+
+            throw_errno!(
+                umount2(dst.as_ptr(), MNT_DETACH) throws NetNs withs
+            );
+            fs::remove_file(&path).map_err(NetErr::Write)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Drop for NetNs {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+
+impl TwoHosts {
+    /// `veth0` 10.200.0.1/24 in `a`, `veth1` 10.200.0.2/24 in `b`,
+    /// both namespaces anonymous
+    pub unsafe fn new() -> Result<Self> {
+        Self::build(
+            NetNs::new()?,
+            NetNs::new()?,
+            Ipv4Addr::new(10, 200, 0, 1),
+            Ipv4Addr::new(10, 200, 0, 2),
+            24,
+        )
+    }
+
+    /// Same as [`TwoHosts::new`] but pinned as `<prefix>a` and `<prefix>b`,
+    /// so that external tools can enter them
+    pub unsafe fn named(prefix: &str) -> Result<Self> {
+        let a = NetNs::create(&format!("{prefix}a"))?;
+        let b = match NetNs::create(&format!("{prefix}b")) {
+            Ok(b) => b,
+            Err(err) => {
+                a.delete()?;
+                return Err(err);
+            }
+        };
+
+        Self::build(
+            a,
+            b,
+            Ipv4Addr::new(10, 200, 0, 1),
+            Ipv4Addr::new(10, 200, 0, 2),
+            24,
+        )
+    }
+
+    /// Named namespaces are unpinned if the setup fails
+    pub unsafe fn build(
+        a: NetNs,
+        b: NetNs,
+        a_addr: Ipv4Addr,
+        b_addr: Ipv4Addr,
+        prefix_len: u8,
+    ) -> Result<Self> {
+        let it = Self {
+            a,
+            b,
+            a_if: "veth0".to_owned(),
+            b_if: "veth1".to_owned(),
+            a_addr,
+            b_addr,
+            prefix_len,
+        };
+
+        match it.setup() {
+            Ok(()) => Ok(it),
+            Err(err) => {
+                it.delete()?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Unpin the named namespaces, the veth pair goes with them
+    pub unsafe fn delete(self) -> Result<()> {
+        self.a.delete()?;
+        self.b.delete()
+    }
+
+    unsafe fn setup(&self) -> Result<()> {
+        let (a_if, b_if, prefix_len) =
+            (&self.a_if, &self.b_if, self.prefix_len);
+
+        self.a.run(|| add_veth(a_if, b_if, Some(&self.b)))??;
+        self.a.run(|| setup_host(a_if, self.a_addr, prefix_len))??;
+        self.b.run(|| setup_host(b_if, self.b_addr, prefix_len))??;
+
+        Ok(())
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Create veth pair `name` <-> `peer` in the namespace of the calling
+/// thread, `peer` is put into `peer_ns` if given.
+pub unsafe fn add_veth(
+    name: &str,
+    peer: &str,
+    peer_ns: Option<&NetNs>,
+) -> Result<()> {
+    let mut peer_body = as_bytes(&ifinfomsg::default()).to_vec();
+    nla_put(&mut peer_body, IFLA_IFNAME, &cstr_bytes(peer));
+
+    if let Some(ns) = peer_ns {
+        nla_put(
+            &mut peer_body,
+            IFLA_NET_NS_FD,
+            &(ns.fd as u32).to_ne_bytes(),
+        );
+    }
+
+    let mut data = vec![];
+    nla_put(&mut data, VETH_INFO_PEER, &peer_body);
+
+    let mut linkinfo = vec![];
+    nla_put(&mut linkinfo, IFLA_INFO_KIND, b"veth");
+    nla_put(&mut linkinfo, IFLA_INFO_DATA | NESTED, &data);
+
+    let mut body = as_bytes(&ifinfomsg::default()).to_vec();
+    nla_put(&mut body, IFLA_IFNAME, &cstr_bytes(name));
+    nla_put(&mut body, IFLA_LINKINFO | NESTED, &linkinfo);
+
+    NlSock::route()?.request(
+        RTM_NEWLINK,
+        (NLM_F_CREATE | NLM_F_EXCL) as u16,
+        &body,
+    )?;
+
+    Ok(())
+}
+
+
+/// Move link `name` into `ns`
+pub unsafe fn move_link(name: &str, ns: &NetNs) -> Result<()> {
+    let mut body = as_bytes(&ifinfomsg::default()).to_vec();
+    nla_put(&mut body, IFLA_IFNAME, &cstr_bytes(name));
+    nla_put(&mut body, IFLA_NET_NS_FD, &(ns.fd as u32).to_ne_bytes());
+
+    NlSock::route()?.request(RTM_NEWLINK, 0, &body)?;
+
+    Ok(())
+}
+
+
+/// Delete link `name` (both ends for veth)
+pub unsafe fn del_link(name: &str) -> Result<()> {
+    let mut body = as_bytes(&ifinfomsg::default()).to_vec();
+    nla_put(&mut body, IFLA_IFNAME, &cstr_bytes(name));
+
+    NlSock::route()?.request(RTM_DELLINK, 0, &body)?;
+
+    Ok(())
+}
+
+
+/// Become root of new user and network namespaces, so that raw sockets and
+/// link configuration work for an unprivileged user.
+///
+/// The kernel refuses it once the process has more than one thread, call it
+/// first thing in `main`.
+pub unsafe fn unshare_user_net() -> Result<()> {
+    let uid = getuid();
+    let gid = getgid();
+
+    throw_errno!(unshare(CLONE_NEWUSER | CLONE_NEWNET) throws NetNs withs);
+
+    fs::write("/proc/self/setgroups", "deny").map_err(NetErr::Write)?;
+    fs::write("/proc/self/uid_map", format!("0 {uid} 1"))
+        .map_err(NetErr::Write)?;
+    fs::write("/proc/self/gid_map", format!("0 {gid} 1"))
+        .map_err(NetErr::Write)?;
+
+    Interface::open("lo")?.up()
+}
+
+
+unsafe fn setup_host(
+    ifname: &str,
+    addr: Ipv4Addr,
+    prefix_len: u8,
+) -> Result<()> {
+    Interface::open("lo")?.up()?;
+
+    let iface = Interface::open(ifname)?;
+    iface.add_addr(IpAddr::V4(addr), prefix_len)?;
+    iface.up()
+}
+
+
+/// Namespace switch is per thread, do it on a scoped one
+fn in_thread<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    thread::scope(|s| {
+        s.spawn(f).join().unwrap_or_else(|err| resume_unwind(err))
+    })
+}
+
+
+fn open_fd(path: &PathBuf) -> Result<i32> {
+    Ok(File::open(path).map_err(NetErr::Open)?.into_raw_fd())
+}
+
+
+fn netns_path(name: &str) -> PathBuf {
+    PathBuf::from(NETNS_RUN_DIR).join(name)
+}
+
+
+fn cstr_bytes(s: &str) -> Vec<u8> {
+    CString::new(s).unwrap().into_bytes_with_nul()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        time::Duration,
+    };
+
+    use super::{add_veth, del_link, NetNs, TwoHosts};
+    use crate::data::{getifaddrs, getifnth};
+
+    /// Unpins the named namespace even if the test fails
+    struct NamedGuard(&'static str);
+
+    impl Drop for NamedGuard {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = NetNs::open(self.0).and_then(|ns| ns.delete());
+            }
+        }
+    }
+
+    /// Requires CAP_SYS_ADMIN
+    #[test]
+    fn test_netns_veth() {
+        unsafe {
+            let ns = match NetNs::new() {
+                Ok(ns) => ns,
+                Err(err) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+
+            // fresh namespace has only lo
+            let names = ns
+                .run(|| {
+                    getifaddrs()
+                        .unwrap()
+                        .interfaces()
+                        .iter()
+                        .map(|group| group.name.to_owned())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap();
+            assert_eq!(names, vec!["lo"]);
+
+            let peer = NetNs::new().unwrap();
+            ns.run(|| add_veth("nsv0", "nsv1", Some(&peer)))
+                .unwrap()
+                .unwrap();
+
+            assert!(ns.run(|| getifnth("nsv0")).unwrap().is_some());
+            assert!(ns.run(|| getifnth("nsv1")).unwrap().is_none());
+            assert!(peer.run(|| getifnth("nsv1")).unwrap().is_some());
+
+            ns.run(|| del_link("nsv0")).unwrap().unwrap();
+            assert!(peer.run(|| getifnth("nsv1")).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_two_hosts() {
+        unsafe {
+            let net = match TwoHosts::new() {
+                Ok(net) => net,
+                Err(err) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+
+            let b = net
+                .b
+                .run(|| UdpSocket::bind((net.b_addr, 0)))
+                .unwrap()
+                .unwrap();
+            let a = net
+                .a
+                .run(|| UdpSocket::bind((net.a_addr, 0)))
+                .unwrap()
+                .unwrap();
+
+            b.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+            a.send_to(b"hello", b.local_addr().unwrap()).unwrap();
+
+            let mut buf = [0u8; 16];
+            let (n, from) = b.recv_from(&mut buf).unwrap();
+
+            assert_eq!(&buf[..n], b"hello");
+            assert_eq!(from, a.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_named_netns() {
+        unsafe {
+            let _guard = NamedGuard("netlib-test-ns");
+
+            let ns = match NetNs::create("netlib-test-ns") {
+                Ok(ns) => ns,
+                Err(err) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+            assert!(NetNs::open("netlib-test-ns").is_ok());
+
+            ns.delete().unwrap();
+            assert!(NetNs::open("netlib-test-ns").is_err());
+        }
+    }
+
+    #[test]
+    fn test_two_hosts_failed_unpinned() {
+        unsafe {
+            let _guards = (
+                NamedGuard("netlib-test-faila"),
+                NamedGuard("netlib-test-failb"),
+            );
+
+            let (a, b) = match (
+                NetNs::create("netlib-test-faila"),
+                NetNs::create("netlib-test-failb"),
+            ) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(err), _) | (_, Err(err)) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+
+            // no such prefix length, it fails after the veth is created
+            let addr = Ipv4Addr::new(10, 200, 0, 1);
+            assert!(TwoHosts::build(a, b, addr, addr, 33).is_err());
+
+            assert!(NetNs::open("netlib-test-faila").is_err());
+            assert!(NetNs::open("netlib-test-failb").is_err());
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::mem::zeroed;
 
 use ifstructs::ifreq;
-use libc::{c_void, ioctl, open, IFF_NO_PI, IFF_TUN, O_RDWR};
+use libc::{c_void, ioctl, open, IFF_NO_PI, IFF_TAP, IFF_TUN, O_RDWR};
 
 use crate::{
     dev::{copy_if_name, TUNSETIFF, TUNSETPERSIST}, throw_errno, Result
@@ -9,6 +9,15 @@ use crate::{
 
 
 pub unsafe fn open_tun(dev: &str) -> Result<i32> {
+    open_tuntap(dev, IFF_TUN)
+}
+
+/// TAP device carries ethernet frames instead of IP packets
+pub unsafe fn open_tap(dev: &str) -> Result<i32> {
+    open_tuntap(dev, IFF_TAP)
+}
+
+unsafe fn open_tuntap(dev: &str, mode: i32) -> Result<i32> {
     let fd = throw_errno!(
         open(c"/dev/net/tun".as_ptr(), O_RDWR)
         throws COpen
@@ -21,7 +30,7 @@ pub unsafe fn open_tun(dev: &str) -> Result<i32> {
     //  Flags [2 bytes]
     //  Proto [2 bytes]
     //  Raw protocol(IP, IPv6, etc) frame
-    let ifr_flags = IFF_TUN | IFF_NO_PI;
+    let ifr_flags = mode | IFF_NO_PI;
     ifr.set_flags(ifr_flags as i16);
 
     throw_errno!(
@@ -41,11 +50,18 @@ pub unsafe fn open_tun(dev: &str) -> Result<i32> {
 
 #[cfg(test)]
 mod tests {
-    use std::ptr;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        ptr,
+    };
 
-    use libc::read;
+    use libc::{close, read};
 
-    use crate::network::ip::IP;
+    use crate::{
+        data::{IfFlags, Interface},
+        dev::set_tuntap_persist,
+        network::ip::IP,
+    };
 
     use super::open_tun;
 
@@ -77,5 +93,48 @@ mod tests {
             }
         }
     }
-}
 
+    /// Configure without `ip` command, requires CAP_NET_ADMIN
+    #[test]
+    fn test_configure_tun() {
+        unsafe {
+            let fd = match open_tun("nltun0") {
+                Ok(fd) => fd,
+                Err(err) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+            // gone with the fd
+            set_tuntap_persist(fd, false).unwrap();
+
+            let mut tun = Interface::open("nltun0").unwrap();
+
+            tun.set_mtu(1400).unwrap();
+            tun.set_txqueuelen(100).unwrap();
+            tun.rename("nltun1").unwrap();
+            assert!(Interface::open("nltun0").is_err());
+
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 123, 0, 1));
+            tun.add_addr(ip, 24).unwrap();
+            tun.add_addr("fd00:123::1".parse().unwrap(), 64).unwrap();
+            tun.up().unwrap();
+
+            let flags = tun.flags().unwrap();
+            assert!(flags.contains(IfFlags::UP), "{flags:?}");
+            assert_eq!(tun.mtu().unwrap(), 1400);
+            assert_eq!(tun.txqueuelen().unwrap(), 100);
+            assert!(tun.addrs().unwrap().contains(&(ip, 24)));
+
+            // duplicated
+            assert!(tun.add_addr(ip, 24).is_err());
+            tun.del_addr(ip, 24).unwrap();
+            assert!(!tun.addrs().unwrap().contains(&(ip, 24)));
+
+            tun.down().unwrap();
+            assert!(!tun.is_up().unwrap());
+
+            close(fd);
+        }
+    }
+}
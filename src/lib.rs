@@ -10,6 +10,7 @@ pub mod aux;
 pub mod data;
 pub mod view;
 pub mod dev;
+pub mod reactor;
 
 
 pub use rs_error::{ Result, NetErr };
@@ -0,0 +1,186 @@
+//! ICMPv6 ([rfc4443](https://www.rfc-editor.org/rfc/rfc4443))
+//!
+//! The 8 bytes header shares the layout of `ICMP`, but the checksum covers
+//! an IPv6 pseudo header, so it's left to the kernel (`IPV6_CHECKSUM`).
+
+use std::{
+    mem::{size_of, zeroed},
+    net::Ipv6Addr,
+};
+
+use libc::{
+    c_void, cmsghdr, iovec, msghdr, recvmsg, setsockopt, sockaddr_in6,
+    socklen_t, CMSG_DATA, CMSG_FIRSTHDR, CMSG_NXTHDR, CMSG_SPACE, EINVAL,
+    IPPROTO_IPV6, IPV6_CHECKSUM, IPV6_HOPLIMIT, IPV6_RECVHOPLIMIT,
+};
+
+use super::icmp::ICMP;
+use crate::{
+    c_error::ErrNo, data::SockAddrIn6, enum_try_from_int, rs_error::NetErr,
+    throw_errno, Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Offset of checksum field in ICMPv6 header
+pub const ICMP6_CKSUM_OFFSET: i32 = 2;
+
+/// Size of IPv6 fixed header
+pub const IPV6_HDR_LEN: usize = 40;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Data Structure
+
+enum_try_from_int! {
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ICMP6Type {
+        DestinationUnreachable = 1,
+        PacketTooBig = 2,
+        TimeExceeded = 3,
+        ParamProblem = 4,
+        EchoRequest = 128,
+        EchoReply = 129,
+        /// Multicast Listener Discovery (rfc2710)
+        MLDQuery = 130,
+        MLDReport = 131,
+        MLDDone = 132,
+        /// Neighbor Discovery (rfc4861)
+        RouterSolicitation = 133,
+        RouterAdvertisement = 134,
+        NeighborSolicitation = 135,
+        NeighborAdvertisement = 136,
+        Redirect = 137,
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implements
+
+impl ICMP {
+    /// Err with the raw type if it's unknown
+    pub fn parse_cm6_type(&self) -> std::result::Result<ICMP6Type, u8> {
+        ICMP6Type::try_from(self.ty)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Echo request header, checksum is filled by kernel
+pub fn icmp6_echo_request(id: u16, seq: u16) -> ICMP {
+    ICMP {
+        ty: ICMP6Type::EchoRequest as u8,
+        code: 0,
+        cksum: 0,
+        un: ICMP::un_as_echo(id, seq),
+    }
+}
+
+
+/// Let kernel compute the checksum at `offset` for outgoing packets and
+/// verify incoming ones.
+///
+/// Linux always does it for `IPPROTO_ICMPV6` raw sockets and refuses the
+/// option with EINVAL, which is accepted here.
+pub unsafe fn set_ipv6_checksum(sock: i32, offset: i32) -> Result<()> {
+    let ret = setsockopt(
+        sock,
+        IPPROTO_IPV6,
+        IPV6_CHECKSUM,
+        &offset as *const i32 as *const c_void,
+        size_of::<i32>() as socklen_t,
+    );
+
+    if ret == -1 {
+        let errno = ErrNo::fetch();
+
+        if errno as i32 != EINVAL {
+            eprintln!("setsockopt IPV6_CHECKSUM: {errno:?}");
+            return Err(NetErr::SetSockOpt);
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Enable `IPV6_HOPLIMIT` ancillary data for `recv_with_hoplimit`
+pub unsafe fn set_recv_hoplimit(sock: i32) -> Result<()> {
+    let on = 1i32;
+
+    throw_errno!(setsockopt(
+        sock,
+        IPPROTO_IPV6,
+        IPV6_RECVHOPLIMIT,
+        &on as *const i32 as *const c_void,
+        size_of::<i32>() as socklen_t
+    ) throws SetSockOpt);
+
+    Ok(())
+}
+
+
+/// -> (size, source, hop limit of the received packet)
+pub unsafe fn recv_with_hoplimit(
+    sock: i32,
+    buf: &mut [u8],
+) -> Result<(usize, Ipv6Addr, Option<u8>)> {
+    let mut src: sockaddr_in6 = zeroed();
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u8; 64];
+    debug_assert!(
+        CMSG_SPACE(size_of::<i32>() as u32) as usize <= control.len()
+    );
+
+    let mut msg: msghdr = zeroed();
+    msg.msg_name = &mut src as *mut sockaddr_in6 as *mut c_void;
+    msg.msg_namelen = size_of::<sockaddr_in6>() as socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = control.len();
+
+    let n = throw_errno!(recvmsg(sock, &mut msg, 0) throws RecvFrom);
+
+    let mut hoplimit = None;
+    let mut cmsg: *mut cmsghdr = CMSG_FIRSTHDR(&msg);
+
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == IPPROTO_IPV6
+            && (*cmsg).cmsg_type == IPV6_HOPLIMIT
+        {
+            let v = (CMSG_DATA(cmsg) as *const i32).read_unaligned();
+            hoplimit = Some(v as u8);
+        }
+
+        cmsg = CMSG_NXTHDR(&msg, cmsg);
+    }
+
+    Ok((n as usize, SockAddrIn6::from(src).ipv6(), hoplimit))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{icmp6_echo_request, ICMP6Type};
+
+    #[test]
+    fn test_icmp6_echo() {
+        let icmp = icmp6_echo_request(0x1234, 7);
+
+        assert_eq!(icmp.parse_cm6_type(), Ok(ICMP6Type::EchoRequest));
+        assert_eq!(icmp.get_idseq(), (0x1234, 7));
+        assert_eq!(ICMP6Type::try_from(129), Ok(ICMP6Type::EchoReply));
+        assert_eq!(ICMP6Type::try_from(200), Err(200));
+    }
+}
@@ -0,0 +1,777 @@
+//! Internet Group Management Protocol, the host part of
+//! IGMPv2 ([rfc2236](https://www.rfc-editor.org/rfc/rfc2236)) and
+//! IGMPv3 ([rfc3376](https://www.rfc-editor.org/rfc/rfc3376)),
+//! IGMPv1 ([rfc1112](https://www.rfc-editor.org/rfc/rfc1112)) queriers are
+//! answered for compatibility.
+//!
+//! `IgmpMsg` is the codec of messages (IP payload), and `IgmpHost` keeps the
+//! group membership of an interface, it produces the reports to send and
+//! leaves the I/O to the caller.
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use super::inet_cksum;
+use crate::{
+    aux::random_u32,
+    data::InAddrN,
+    defraw, enum_try_from_int,
+    rs_error::{IgmpKind, NetErr},
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+pub const IGMP_ALL_HOSTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+pub const IGMP_ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+/// Destination of IGMPv3 reports
+pub const IGMPV3_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+
+/// IP Router Alert option (rfc2113), IGMP messages carry it with TTL 1
+pub const IP_ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+
+/// Header of v1/v2 messages and the fixed part of v3 ones
+pub const IGMP_HDR_LEN: usize = 8;
+
+/// Robustness Variable (rfc3376 section 8.1)
+pub const IGMP_ROBUSTNESS: u8 = 2;
+
+/// Max response time of IGMPv1 queries which leave it zero
+pub const IGMPV1_MAX_RESP: Duration = Duration::from_secs(10);
+
+/// Older Version Querier Present Timeout: robustness x query interval
+/// (125s) + query response interval (10s)
+pub const IGMP_OLDER_QUERIER_TIMEOUT: Duration = Duration::from_secs(260);
+
+/// Unsolicited Report Interval of IGMPv2
+pub const IGMPV2_UNSOLICITED_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Unsolicited Report Interval of IGMPv3
+pub const IGMPV3_UNSOLICITED_INTERVAL: Duration = Duration::from_secs(1);
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+defraw! {
+    /// IGMP Header
+    ///
+    /// 8 bytes
+    pub struct IGMP {
+        ty: u8,
+        /// Max Resp Time/Code (1/10 s), unused (zero) for reports
+        max_resp: u8,
+        cksum: u16,
+        group: InAddrN
+    }
+}
+
+
+enum_try_from_int! {
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum IGMPType {
+        MembershipQuery = 0x11,
+        V1MembershipReport = 0x12,
+        V2MembershipReport = 0x16,
+        LeaveGroup = 0x17,
+        V3MembershipReport = 0x22,
+    }
+
+    #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum GroupRecordType {
+        ModeIsInclude = 1,
+        ModeIsExclude = 2,
+        ChangeToInclude = 3,
+        ChangeToExclude = 4,
+        AllowNewSources = 5,
+        BlockOldSources = 6,
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IgmpVersion {
+    V1,
+    V2,
+    V3,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IgmpMsg {
+    Query(IgmpQuery),
+    ReportV1(Ipv4Addr),
+    ReportV2(Ipv4Addr),
+    Leave(Ipv4Addr),
+    ReportV3(Vec<GroupRecord>),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgmpQuery {
+    pub max_resp: Duration,
+    /// Unspecified for general query
+    pub group: Ipv4Addr,
+    /// Fields of IGMPv3 query
+    pub v3: Option<QueryV3>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryV3 {
+    /// Suppress Router-Side Processing
+    pub suppress: bool,
+    /// Querier's Robustness Variable
+    pub qrv: u8,
+    /// Querier's Query Interval
+    pub qqi: Duration,
+    pub sources: Vec<Ipv4Addr>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupRecord {
+    pub ty: GroupRecordType,
+    pub group: Ipv4Addr,
+    pub sources: Vec<Ipv4Addr>,
+}
+
+
+/// Group membership of an interface (any-source, that is EXCLUDE {} mode)
+pub struct IgmpHost {
+    groups: HashMap<Ipv4Addr, Membership>,
+    /// Older version querier present, until the instant
+    older_querier: Option<(IgmpVersion, Instant)>,
+    /// Pending response to IGMPv3 general query
+    general_timer: Option<Instant>,
+    /// Retransmissions of unsolicited reports
+    pending: Vec<(Instant, IgmpMsg)>,
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct Membership {
+    /// Pending response to query
+    timer: Option<Instant>,
+    /// We sent the last report, so leave is our business (IGMPv2)
+    last_reporter: bool,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl IgmpQuery {
+    pub fn is_general(&self) -> bool {
+        self.group.is_unspecified()
+    }
+
+    /// Version of the querier (rfc3376 section 7.1)
+    pub fn version(&self) -> IgmpVersion {
+        if self.v3.is_some() {
+            IgmpVersion::V3
+        }
+        else if self.max_resp.is_zero() {
+            IgmpVersion::V1
+        }
+        else {
+            IgmpVersion::V2
+        }
+    }
+}
+
+
+impl IgmpMsg {
+    /// Parse IGMP message (IP payload), checksum is verified
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < IGMP_HDR_LEN {
+            return Err(NetErr::Igmp(IgmpKind::TooShort(bytes.len())));
+        }
+
+        if unsafe { inet_cksum(bytes.as_ptr(), bytes.len()) } != 0 {
+            return Err(NetErr::Igmp(IgmpKind::BadChecksum));
+        }
+
+        let ty = IGMPType::try_from(bytes[0])
+            .map_err(|ty| NetErr::Igmp(IgmpKind::UnknownType(ty)))?;
+        let group = ipv4_at(bytes, 4);
+
+        Ok(match ty {
+            IGMPType::MembershipQuery => {
+                Self::Query(Self::parse_query(bytes, group)?)
+            }
+            IGMPType::V1MembershipReport => Self::ReportV1(group),
+            IGMPType::V2MembershipReport => Self::ReportV2(group),
+            IGMPType::LeaveGroup => Self::Leave(group),
+            IGMPType::V3MembershipReport => {
+                Self::ReportV3(Self::parse_records(bytes)?)
+            }
+        })
+    }
+
+    fn parse_query(bytes: &[u8], group: Ipv4Addr) -> Result<IgmpQuery> {
+        // IGMPv1/v2 query is exactly 8 bytes, IGMPv3 one is at least 12
+        if bytes.len() == IGMP_HDR_LEN {
+            return Ok(IgmpQuery {
+                max_resp: Duration::from_millis(bytes[1] as u64 * 100),
+                group,
+                v3: None,
+            });
+        }
+
+        if bytes.len() < IGMP_HDR_LEN + 4 {
+            return Err(NetErr::Igmp(IgmpKind::TooShort(bytes.len())));
+        }
+
+        let nsrc = u16::from_be_bytes([bytes[10], bytes[11]]) as usize;
+        let need = IGMP_HDR_LEN + 4 + nsrc * 4;
+
+        if bytes.len() < need {
+            return Err(NetErr::Igmp(IgmpKind::TooShort(bytes.len())));
+        }
+
+        let sources = (0..nsrc)
+            .map(|i| ipv4_at(bytes, IGMP_HDR_LEN + 4 + i * 4))
+            .collect();
+
+        Ok(IgmpQuery {
+            max_resp: Duration::from_millis(
+                decode_code(bytes[1]) as u64 * 100,
+            ),
+            group,
+            v3: Some(QueryV3 {
+                suppress: bytes[8] & 0x08 != 0,
+                qrv: bytes[8] & 0x07,
+                qqi: Duration::from_secs(decode_code(bytes[9]) as u64),
+                sources,
+            }),
+        })
+    }
+
+    fn parse_records(bytes: &[u8]) -> Result<Vec<GroupRecord>> {
+        let nrec = u16::from_be_bytes([bytes[6], bytes[7]]) as usize;
+        let mut records = Vec::with_capacity(nrec);
+        let mut pos = IGMP_HDR_LEN;
+
+        for _ in 0..nrec {
+            if bytes.len() < pos + 8 {
+                return Err(NetErr::Igmp(IgmpKind::TooShort(bytes.len())));
+            }
+
+            let ty = GroupRecordType::try_from(bytes[pos])
+                .map_err(|ty| NetErr::Igmp(IgmpKind::UnknownType(ty)))?;
+            let aux_len = bytes[pos + 1] as usize * 4;
+            let nsrc =
+                u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+            let group = ipv4_at(bytes, pos + 4);
+            let next = pos + 8 + nsrc * 4 + aux_len;
+
+            if bytes.len() < next {
+                return Err(NetErr::Igmp(IgmpKind::TooShort(bytes.len())));
+            }
+
+            let sources =
+                (0..nsrc).map(|i| ipv4_at(bytes, pos + 8 + i * 4)).collect();
+
+            records.push(GroupRecord { ty, group, sources });
+            pos = next;
+        }
+
+        Ok(records)
+    }
+
+    pub fn ty(&self) -> IGMPType {
+        match self {
+            Self::Query(_) => IGMPType::MembershipQuery,
+            Self::ReportV1(_) => IGMPType::V1MembershipReport,
+            Self::ReportV2(_) => IGMPType::V2MembershipReport,
+            Self::Leave(_) => IGMPType::LeaveGroup,
+            Self::ReportV3(_) => IGMPType::V3MembershipReport,
+        }
+    }
+
+    /// Destination address of the message
+    pub fn dst(&self) -> Ipv4Addr {
+        match self {
+            Self::Query(query) if query.is_general() => IGMP_ALL_HOSTS,
+            Self::Query(query) => query.group,
+            Self::ReportV1(group) | Self::ReportV2(group) => *group,
+            Self::Leave(_) => IGMP_ALL_ROUTERS,
+            Self::ReportV3(_) => IGMPV3_ROUTERS,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.ty() as u8, 0, 0, 0];
+
+        match self {
+            Self::Query(query) => {
+                let deci = query.max_resp.as_millis() / 100;
+                bytes[1] = match query.v3 {
+                    Some(_) => encode_code(deci.min(u16::MAX as u128) as u16),
+                    None => deci.min(u8::MAX as u128) as u8,
+                };
+                bytes.extend_from_slice(&query.group.octets());
+
+                if let Some(ref v3) = query.v3 {
+                    bytes.push((v3.suppress as u8) << 3 | v3.qrv.min(7));
+                    bytes.push(encode_code(
+                        v3.qqi.as_secs().min(u16::MAX as u64) as u16,
+                    ));
+                    bytes.extend_from_slice(
+                        &(v3.sources.len() as u16).to_be_bytes(),
+                    );
+
+                    for src in v3.sources.iter() {
+                        bytes.extend_from_slice(&src.octets());
+                    }
+                }
+            }
+            Self::ReportV1(group)
+            | Self::ReportV2(group)
+            | Self::Leave(group) => {
+                bytes.extend_from_slice(&group.octets());
+            }
+            Self::ReportV3(records) => {
+                bytes.extend_from_slice(&[0, 0]);
+                bytes.extend_from_slice(&(records.len() as u16).to_be_bytes());
+
+                for rec in records.iter() {
+                    bytes.push(rec.ty as u8);
+                    bytes.push(0);
+                    bytes.extend_from_slice(
+                        &(rec.sources.len() as u16).to_be_bytes(),
+                    );
+                    bytes.extend_from_slice(&rec.group.octets());
+
+                    for src in rec.sources.iter() {
+                        bytes.extend_from_slice(&src.octets());
+                    }
+                }
+            }
+        }
+
+        let cksum = unsafe { inet_cksum(bytes.as_ptr(), bytes.len()) };
+        bytes[2..4].copy_from_slice(&cksum.to_ne_bytes());
+
+        bytes
+    }
+}
+
+
+impl IgmpHost {
+    pub fn new() -> Self {
+        Self {
+            groups: HashMap::new(),
+            older_querier: None,
+            general_timer: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Version in use, the oldest querier seen lately (rfc3376 section 7.2.1)
+    pub fn version(&mut self, now: Instant) -> IgmpVersion {
+        match self.older_querier {
+            Some((version, until)) if now < until => version,
+            _ => {
+                self.older_querier = None;
+                IgmpVersion::V3
+            }
+        }
+    }
+
+    pub fn is_member(&self, group: Ipv4Addr) -> bool {
+        self.groups.contains_key(&group)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &Ipv4Addr> {
+        self.groups.keys()
+    }
+
+    /// -> unsolicited reports to send now, the retransmissions are left
+    /// to `poll`
+    pub fn join(&mut self, group: Ipv4Addr, now: Instant) -> Vec<IgmpMsg> {
+        // 224.0.0.1 is never reported (rfc2236 section 6)
+        if !group.is_multicast()
+            || group == IGMP_ALL_HOSTS
+            || self.groups.contains_key(&group)
+        {
+            return vec![];
+        }
+
+        self.groups.insert(
+            group,
+            Membership {
+                timer: None,
+                last_reporter: true,
+            },
+        );
+
+        let (report, interval) = match self.version(now) {
+            IgmpVersion::V1 => {
+                (IgmpMsg::ReportV1(group), IGMPV2_UNSOLICITED_INTERVAL)
+            }
+            IgmpVersion::V2 => {
+                (IgmpMsg::ReportV2(group), IGMPV2_UNSOLICITED_INTERVAL)
+            }
+            IgmpVersion::V3 => (
+                IgmpMsg::ReportV3(vec![GroupRecord {
+                    ty: GroupRecordType::ChangeToExclude,
+                    group,
+                    sources: vec![],
+                }]),
+                IGMPV3_UNSOLICITED_INTERVAL,
+            ),
+        };
+
+        for _ in 1..IGMP_ROBUSTNESS {
+            self.pending
+                .push((now + random_delay(interval), report.clone()));
+        }
+
+        vec![report]
+    }
+
+    /// -> messages to send now
+    pub fn leave(&mut self, group: Ipv4Addr, now: Instant) -> Vec<IgmpMsg> {
+        let membership = match self.groups.remove(&group) {
+            Some(membership) => membership,
+            None => return vec![],
+        };
+
+        // forget the retransmissions of join
+        self.pending.retain(|(_, msg)| !msg_is_about(msg, group));
+
+        match self.version(now) {
+            IgmpVersion::V1 => vec![],
+            IgmpVersion::V2 if membership.last_reporter => {
+                vec![IgmpMsg::Leave(group)]
+            }
+            IgmpVersion::V2 => vec![],
+            IgmpVersion::V3 => vec![IgmpMsg::ReportV3(vec![GroupRecord {
+                ty: GroupRecordType::ChangeToInclude,
+                group,
+                sources: vec![],
+            }])],
+        }
+    }
+
+    /// Handle received message, responses are scheduled for `poll`
+    pub fn handle(&mut self, msg: &IgmpMsg, now: Instant) {
+        match msg {
+            IgmpMsg::Query(query) => self.handle_query(query, now),
+            // report suppression of IGMPv1/v2 (rfc2236 section 3)
+            IgmpMsg::ReportV1(group) | IgmpMsg::ReportV2(group) => {
+                if self.version(now) == IgmpVersion::V3 {
+                    return;
+                }
+
+                if let Some(membership) = self.groups.get_mut(group) {
+                    membership.timer = None;
+                    membership.last_reporter = false;
+                }
+            }
+            IgmpMsg::Leave(_) | IgmpMsg::ReportV3(_) => (),
+        }
+    }
+
+    fn handle_query(&mut self, query: &IgmpQuery, now: Instant) {
+        let querier = query.version();
+
+        if querier < IgmpVersion::V3 {
+            self.older_querier =
+                Some((querier, now + IGMP_OLDER_QUERIER_TIMEOUT));
+            self.general_timer = None;
+        }
+
+        let max_resp = if querier == IgmpVersion::V1 {
+            IGMPV1_MAX_RESP
+        }
+        else {
+            query.max_resp
+        };
+        let deadline = now + random_delay(max_resp);
+
+        // IGMPv3 answers general query with a single report
+        if self.version(now) == IgmpVersion::V3 && query.is_general() {
+            if self.general_timer.is_none_or(|timer| deadline < timer) {
+                self.general_timer = Some(deadline);
+            }
+
+            return;
+        }
+
+        for (group, membership) in self.groups.iter_mut() {
+            if !query.is_general() && *group != query.group {
+                continue;
+            }
+
+            if membership.timer.is_none_or(|timer| deadline < timer) {
+                membership.timer = Some(deadline);
+            }
+        }
+    }
+
+    /// -> messages due
+    pub fn poll(&mut self, now: Instant) -> Vec<IgmpMsg> {
+        let version = self.version(now);
+        let mut msgs = vec![];
+
+        let (due, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|(at, _)| *at <= now);
+        self.pending = pending;
+        msgs.extend(due.into_iter().map(|(_, msg)| msg));
+
+        if self.general_timer.is_some_and(|timer| timer <= now) {
+            self.general_timer = None;
+
+            let records: Vec<GroupRecord> = self
+                .groups
+                .keys()
+                .map(|group| GroupRecord {
+                    ty: GroupRecordType::ModeIsExclude,
+                    group: *group,
+                    sources: vec![],
+                })
+                .collect();
+
+            if !records.is_empty() {
+                msgs.push(IgmpMsg::ReportV3(records));
+            }
+        }
+
+        for (group, membership) in self.groups.iter_mut() {
+            if !membership.timer.is_some_and(|timer| timer <= now) {
+                continue;
+            }
+
+            membership.timer = None;
+            membership.last_reporter = true;
+
+            msgs.push(match version {
+                IgmpVersion::V1 => IgmpMsg::ReportV1(*group),
+                IgmpVersion::V2 => IgmpMsg::ReportV2(*group),
+                IgmpVersion::V3 => IgmpMsg::ReportV3(vec![GroupRecord {
+                    ty: GroupRecordType::ModeIsExclude,
+                    group: *group,
+                    sources: vec![],
+                }]),
+            });
+        }
+
+        msgs
+    }
+
+    /// When `poll` should be called next
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.pending
+            .iter()
+            .map(|(at, _)| *at)
+            .chain(self.general_timer)
+            .chain(self.groups.values().filter_map(|m| m.timer))
+            .min()
+    }
+}
+
+
+impl Default for IgmpHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+fn ipv4_at(bytes: &[u8], pos: usize) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3])
+}
+
+
+/// Max Resp Code / QQIC -> value (rfc3376 section 4.1.1)
+pub fn decode_code(code: u8) -> u16 {
+    if code < 128 {
+        return code as u16;
+    }
+
+    let exp = (code >> 4) & 0x07;
+    let mant = (code & 0x0f) as u16;
+
+    (mant | 0x10) << (exp + 3)
+}
+
+
+/// Value -> Max Resp Code / QQIC, rounded down
+pub fn encode_code(value: u16) -> u8 {
+    if value < 128 {
+        return value as u8;
+    }
+
+    let value = value.min(decode_code(0xff));
+    // the position of the highest bit is exp + 7
+    let exp = (15 - value.leading_zeros() as u16) - 7;
+    let mant = (value >> (exp + 3)) & 0x0f;
+
+    0x80 | (exp as u8) << 4 | mant as u8
+}
+
+
+fn random_delay(max: Duration) -> Duration {
+    let max_ms = max.as_millis().min(u32::MAX as u128) as u32;
+
+    Duration::from_millis((random_u32() % (max_ms + 1)) as u64)
+}
+
+
+fn msg_is_about(msg: &IgmpMsg, group: Ipv4Addr) -> bool {
+    match msg {
+        IgmpMsg::Query(query) => query.group == group,
+        IgmpMsg::ReportV1(g) | IgmpMsg::ReportV2(g) | IgmpMsg::Leave(g) => {
+            *g == group
+        }
+        IgmpMsg::ReportV3(records) => {
+            records.iter().any(|rec| rec.group == group)
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        decode_code, encode_code, GroupRecord, GroupRecordType, IgmpHost,
+        IgmpMsg, IgmpQuery, IgmpVersion, QueryV3, IGMPV3_ROUTERS,
+    };
+
+    #[test]
+    fn test_igmp_codec() {
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+
+        // captured IGMPv2 membership report
+        let bytes = [0x16, 0x00, 0xf8, 0xfa, 0xef, 0x01, 0x02, 0x03];
+        assert_eq!(IgmpMsg::parse(&bytes).unwrap(), IgmpMsg::ReportV2(group));
+        assert_eq!(IgmpMsg::ReportV2(group).to_bytes(), bytes);
+
+        let mut bad = bytes;
+        bad[7] = 4;
+        assert!(IgmpMsg::parse(&bad).is_err());
+
+        let query = IgmpMsg::Query(IgmpQuery {
+            max_resp: Duration::from_secs(10),
+            group: Ipv4Addr::UNSPECIFIED,
+            v3: Some(QueryV3 {
+                suppress: false,
+                qrv: 2,
+                qqi: Duration::from_secs(125),
+                sources: vec![Ipv4Addr::new(10, 0, 0, 1)],
+            }),
+        });
+        let bytes = query.to_bytes();
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(IgmpMsg::parse(&bytes).unwrap(), query);
+
+        let report = IgmpMsg::ReportV3(vec![
+            GroupRecord {
+                ty: GroupRecordType::ModeIsExclude,
+                group,
+                sources: vec![],
+            },
+            GroupRecord {
+                ty: GroupRecordType::AllowNewSources,
+                group: Ipv4Addr::new(232, 1, 1, 1),
+                sources: vec![
+                    Ipv4Addr::new(10, 0, 0, 1),
+                    Ipv4Addr::new(10, 0, 0, 2),
+                ],
+            },
+        ]);
+        assert_eq!(report.dst(), IGMPV3_ROUTERS);
+        assert_eq!(IgmpMsg::parse(&report.to_bytes()).unwrap(), report);
+
+        assert_eq!(decode_code(100), 100);
+        assert_eq!(decode_code(0x80), 128);
+        assert_eq!(decode_code(0xff), 31744);
+        for value in [0, 127, 128, 1000, 3200, 31744] {
+            let code = encode_code(value);
+            assert!(decode_code(code) <= value);
+            assert_eq!(encode_code(decode_code(code)), code);
+        }
+    }
+
+    #[test]
+    fn test_igmp_host() {
+        let now = Instant::now();
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let mut host = IgmpHost::new();
+
+        let msgs = host.join(group, now);
+        assert!(matches!(msgs[..], [IgmpMsg::ReportV3(_)]));
+        assert!(host.join(group, now).is_empty());
+
+        // retransmission of the unsolicited report
+        let later = now + Duration::from_secs(2);
+        assert_eq!(host.poll(later), msgs);
+        assert!(host.next_timeout().is_none());
+
+        // IGMPv2 querier turns us into IGMPv2 host
+        let query = IgmpMsg::Query(IgmpQuery {
+            max_resp: Duration::from_secs(1),
+            group: Ipv4Addr::UNSPECIFIED,
+            v3: None,
+        });
+        host.handle(&query, later);
+        assert_eq!(host.version(later), IgmpVersion::V2);
+
+        let later = later + Duration::from_secs(2);
+        assert_eq!(host.poll(later), vec![IgmpMsg::ReportV2(group)]);
+
+        // suppressed by report of others
+        host.handle(&query, later);
+        host.handle(&IgmpMsg::ReportV2(group), later);
+        assert!(host.poll(later + Duration::from_secs(2)).is_empty());
+
+        // someone else reported last, so no leave
+        assert!(host.leave(group, later).is_empty());
+        assert!(!host.is_member(group));
+
+        // IGMPv3 general query after the older querier is gone
+        let later = later + Duration::from_secs(300);
+        host.join(group, later);
+        host.poll(later + Duration::from_secs(2));
+        host.handle(
+            &IgmpMsg::Query(IgmpQuery {
+                max_resp: Duration::from_secs(1),
+                group: Ipv4Addr::UNSPECIFIED,
+                v3: Some(QueryV3 {
+                    suppress: false,
+                    qrv: 2,
+                    qqi: Duration::from_secs(125),
+                    sources: vec![],
+                }),
+            }),
+            later,
+        );
+        assert_eq!(
+            host.poll(later + Duration::from_secs(2)),
+            vec![IgmpMsg::ReportV3(vec![GroupRecord {
+                ty: GroupRecordType::ModeIsExclude,
+                group,
+                sources: vec![],
+            }])]
+        );
+    }
+}
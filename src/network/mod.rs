@@ -14,8 +14,14 @@ use crate::{
 
 pub mod arp;
 pub mod icmp;
+pub mod icmp6;
+pub mod igmp;
 mod icmp_spec;
 pub mod ip;
+pub mod ping;
+pub mod ping_sock;
+pub mod pmtu;
+pub mod traceroute;
 mod ip_spec;
 
 
@@ -31,7 +37,8 @@ pub unsafe fn inet_cksum(mut data: *const u8, mut len: usize) -> u16 {
 
     // sum every two bytes
     while len & 0xfffe > 0 {
-        sum += *(data as *const u16) as u32;
+        // packet buffers are not necessarily 2 bytes aligned
+        sum += (data as *const u16).read_unaligned() as u32;
         data = data.add(2);
         len -= 2;
     }
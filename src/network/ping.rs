@@ -0,0 +1,796 @@
+//! Ping session, ICMP / ICMPv6 echo with iputils-like pacing and statistics
+//!
+//! `PingSession` owns the socket and the statistics, `bin/ping.rs` is a thin
+//! CLI over it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    mem::size_of,
+    net::IpAddr,
+    ptr::write_unaligned,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use libc::getpid;
+
+use super::{
+    icmp::{ICMPType, ICMP},
+    icmp6::{icmp6_echo_request, ICMP6Type, IPV6_HDR_LEN},
+    inet_cksum,
+    ping_sock::{PingMode, PingSock, PingSockKind},
+};
+use crate::{rs_error::NetErr, Result};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Default payload size, same with iputils
+pub const PING_DEFAULT_PAYLOAD: usize = 56;
+
+/// Send timestamp (nanoseconds since session start) at the head of payload,
+/// RTT falls back to local bookkeeping for smaller payload.
+pub const PING_TIMESTAMP_LEN: usize = 8;
+
+/// Max ICMP payload over IPv4 (65535 - 20 - 8)
+pub const PING_MAX_PAYLOAD: usize = 65507;
+
+/// Gap of flood mode, with or without the reply
+const FLOOD_GAP: Duration = Duration::from_millis(10);
+
+/// Minimum gap of adaptive mode (unprivileged minimum of iputils)
+const ADAPTIVE_GAP: Duration = Duration::from_millis(200);
+
+const IPV4_HDR_LEN: usize = 20;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// When the next echo request is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pacing {
+    /// Every `interval`
+    #[default]
+    Fixed,
+    /// As soon as the reply arrives, at least every `interval`, no faster
+    /// than 200ms (`-A`)
+    Adaptive,
+    /// As soon as the reply arrives, at least every 10ms (`-f`)
+    Flood,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PingConf {
+    /// Stop after sending (and receiving) `count` packets
+    pub count: Option<usize>,
+    pub interval: Duration,
+    /// Bytes of ICMP payload
+    pub payload_size: usize,
+    /// IP TTL or IPv6 hop limit
+    pub ttl: Option<u8>,
+    /// Stop after this time regardless of how many packets are sent
+    pub deadline: Option<Duration>,
+    /// Time to wait for the last reply
+    pub timeout: Duration,
+    pub pacing: Pacing,
+    pub mode: PingMode,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingReply {
+    pub seq: u16,
+    pub from: IpAddr,
+    /// ICMP bytes (header + payload)
+    pub bytes: usize,
+    /// TTL or hop limit of the reply
+    pub ttl: Option<u8>,
+    pub rtt: Duration,
+    pub dup: bool,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PingEvent {
+    Reply(PingReply),
+    /// ICMP error quoting our echo request, e.g. TTL exceeded on the path
+    Error {
+        seq: u16,
+        from: IpAddr,
+        reason: String,
+    },
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PingStats {
+    pub transmitted: usize,
+    pub received: usize,
+    pub duplicates: usize,
+    pub errors: usize,
+    pub rtt_min: Duration,
+    pub rtt_max: Duration,
+    rtt_sum: f64,
+    rtt_sum2: f64,
+    pub elapsed: Duration,
+}
+
+
+/// Echo request / reply exchange with one destination
+pub struct PingSession {
+    sock: PingSock,
+    dst: IpAddr,
+    conf: PingConf,
+    id: u16,
+    next_seq: u16,
+    /// Base of embedded timestamps
+    epoch: Instant,
+    sent: HashMap<u16, Instant>,
+    received: HashSet<u16>,
+    stats: PingStats,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Default for PingConf {
+    fn default() -> Self {
+        Self {
+            count: None,
+            interval: Duration::from_secs(1),
+            payload_size: PING_DEFAULT_PAYLOAD,
+            ttl: None,
+            deadline: None,
+            timeout: Duration::from_secs(10),
+            pacing: Pacing::default(),
+            mode: PingMode::default(),
+        }
+    }
+}
+
+
+impl Default for PingStats {
+    fn default() -> Self {
+        Self {
+            transmitted: 0,
+            received: 0,
+            duplicates: 0,
+            errors: 0,
+            rtt_min: Duration::MAX,
+            rtt_max: Duration::ZERO,
+            rtt_sum: 0.0,
+            rtt_sum2: 0.0,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+
+impl PingStats {
+    fn add_rtt(&mut self, rtt: Duration) {
+        let ms = rtt.as_secs_f64() * 1000.0;
+
+        self.rtt_min = self.rtt_min.min(rtt);
+        self.rtt_max = self.rtt_max.max(rtt);
+        self.rtt_sum += ms;
+        self.rtt_sum2 += ms * ms;
+    }
+
+    /// Percent of transmitted packets without reply
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+
+        let lost = self.transmitted.saturating_sub(self.received);
+
+        lost as f64 * 100.0 / self.transmitted as f64
+    }
+
+    /// Milliseconds
+    pub fn rtt_avg(&self) -> f64 {
+        if self.received == 0 {
+            return 0.0;
+        }
+
+        self.rtt_sum / self.received as f64
+    }
+
+    /// Standard deviation of RTT in milliseconds
+    pub fn rtt_mdev(&self) -> f64 {
+        if self.received == 0 {
+            return 0.0;
+        }
+
+        let avg = self.rtt_avg();
+        let var = self.rtt_sum2 / self.received as f64 - avg * avg;
+
+        var.max(0.0).sqrt()
+    }
+}
+
+
+impl Display for PingStats {
+    /// Same with the summary lines of iputils ping
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packets transmitted, {} received",
+            self.transmitted, self.received
+        )?;
+
+        if self.duplicates > 0 {
+            write!(f, ", +{} duplicates", self.duplicates)?;
+        }
+        if self.errors > 0 {
+            write!(f, ", +{} errors", self.errors)?;
+        }
+
+        write!(
+            f,
+            ", {}% packet loss, time {}ms",
+            fmt_g(self.loss()),
+            self.elapsed.as_millis()
+        )?;
+
+        if self.received > 0 {
+            write!(
+                f,
+                "\nrtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+                self.rtt_min.as_secs_f64() * 1000.0,
+                self.rtt_avg(),
+                self.rtt_max.as_secs_f64() * 1000.0,
+                self.rtt_mdev()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Display for PingReply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes from {}: icmp_seq={}",
+            self.bytes, self.from, self.seq
+        )?;
+
+        if let Some(ttl) = self.ttl {
+            write!(f, " ttl={ttl}")?;
+        }
+
+        write!(f, " time={:.3} ms", self.rtt.as_secs_f64() * 1000.0)?;
+
+        if self.dup {
+            write!(f, " (DUP!)")?;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Display for PingEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reply(reply) => write!(f, "{reply}"),
+            Self::Error { seq, from, reason } => {
+                write!(f, "From {from} icmp_seq={seq} {reason}")
+            }
+        }
+    }
+}
+
+
+impl PingSession {
+    /// Open an ICMP (or ICMPv6) socket for `dst` according to `conf.mode`
+    pub unsafe fn new(dst: IpAddr, conf: PingConf) -> Result<Self> {
+        if conf.payload_size > PING_MAX_PAYLOAD {
+            return Err(NetErr::InvalidParam);
+        }
+
+        let sock = PingSock::open(dst.is_ipv6(), conf.mode, conf.ttl)?;
+        let id = sock.ident().unwrap_or((getpid() & 0xffff) as u16);
+
+        Ok(Self {
+            sock,
+            dst,
+            conf,
+            id,
+            next_seq: 1,
+            epoch: Instant::now(),
+            sent: HashMap::new(),
+            received: HashSet::new(),
+            stats: PingStats::default(),
+        })
+    }
+
+    pub fn dst(&self) -> IpAddr {
+        self.dst
+    }
+
+    pub fn conf(&self) -> &PingConf {
+        &self.conf
+    }
+
+    pub fn stats(&self) -> &PingStats {
+        &self.stats
+    }
+
+    pub fn sock_kind(&self) -> PingSockKind {
+        self.sock.kind()
+    }
+
+    /// ICMP bytes and IP packet bytes of an echo request
+    pub fn packet_size(&self) -> (usize, usize) {
+        let icmp_len = size_of::<ICMP>() + self.conf.payload_size;
+        let ip_hdr_len = match self.dst {
+            IpAddr::V4(_) => IPV4_HDR_LEN,
+            IpAddr::V6(_) => IPV6_HDR_LEN,
+        };
+
+        (icmp_len, icmp_len + ip_hdr_len)
+    }
+
+    /// Send one echo request, return its sequence
+    pub unsafe fn send(&mut self) -> Result<u16> {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let now = Instant::now();
+        let buf = self.pack(seq, now);
+
+        let res = self.sock.send_to(&buf, self.dst);
+
+        // the sequence is still consumed as iputils does
+        self.stats.transmitted += 1;
+        self.sent.insert(seq, now);
+        self.received.remove(&seq);
+
+        res.map(|_| seq)
+    }
+
+    /// Wait at most `timeout` for a reply or an error belonging to this
+    /// session, None for timeout.
+    pub unsafe fn recv(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<PingEvent>> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0u8; PING_MAX_PAYLOAD + 128];
+
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+
+            if !self.sock.wait_readable(left)? {
+                return Ok(None);
+            }
+
+            let recv = match self.sock.recv(&mut buf)? {
+                Some(recv) => recv,
+                None => continue,
+            };
+
+            if recv.icmp.len() < size_of::<ICMP>() {
+                continue;
+            }
+
+            let event = match recv.error {
+                Some((ty, code)) => {
+                    self.unpack_error(ty, code, &buf[recv.icmp], recv.from)
+                }
+                None => self.unpack(
+                    &buf[recv.icmp],
+                    recv.from,
+                    recv.ttl,
+                    Instant::now(),
+                ),
+            };
+
+            if let Some(event) = event {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Send and receive according to the configuration until it's done or
+    /// `stop` is set (e.g. by SIGINT).
+    pub unsafe fn run<F: FnMut(&PingEvent)>(
+        &mut self,
+        stop: &AtomicBool,
+        mut on_event: F,
+    ) -> Result<&PingStats> {
+        let start = Instant::now();
+        let deadline = self.conf.deadline.map(|dl| start + dl);
+
+        let mut next_send = start;
+        let mut last_send = start;
+
+        loop {
+            let now = Instant::now();
+
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            if deadline.map(|dl| now >= dl).unwrap_or(false) {
+                break;
+            }
+
+            let all_sent = self
+                .conf
+                .count
+                .map(|c| self.stats.transmitted >= c)
+                .unwrap_or(false);
+
+            if all_sent {
+                let answered = self.stats.received + self.stats.errors;
+                let count = self.conf.count.unwrap();
+
+                if answered >= count || now >= last_send + self.conf.timeout {
+                    break;
+                }
+            }
+            else if now >= next_send {
+                // send failure (e.g. no route) is counted as lost
+                let _ = self.send();
+
+                // flood doesn't wait for the reply longer than its gap
+                let interval = match self.conf.pacing {
+                    Pacing::Flood => self.conf.interval.min(FLOOD_GAP),
+                    _ => self.conf.interval,
+                };
+
+                last_send = now;
+                next_send = now + interval;
+                continue;
+            }
+
+            let mut wake = if all_sent {
+                last_send + self.conf.timeout
+            }
+            else {
+                next_send
+            };
+            if let Some(dl) = deadline {
+                wake = wake.min(dl);
+            }
+
+            let event = self.recv(wake.saturating_duration_since(now))?;
+
+            if let Some(event) = event {
+                if let PingEvent::Reply(ref reply) = event {
+                    if !reply.dup {
+                        let gap = match self.conf.pacing {
+                            Pacing::Fixed => None,
+                            Pacing::Adaptive => Some(ADAPTIVE_GAP),
+                            Pacing::Flood => Some(FLOOD_GAP),
+                        };
+
+                        if let Some(gap) = gap {
+                            next_send = next_send.min(last_send + gap);
+                        }
+                    }
+                }
+
+                on_event(&event);
+            }
+        }
+
+        self.stats.elapsed = start.elapsed();
+
+        Ok(&self.stats)
+    }
+
+    fn pack(&self, seq: u16, now: Instant) -> Vec<u8> {
+        let mut buf = vec![0u8; size_of::<ICMP>() + self.conf.payload_size];
+        let payload = &mut buf[size_of::<ICMP>()..];
+
+        // pattern like iputils, after the timestamp
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        if payload.len() >= PING_TIMESTAMP_LEN {
+            let ts = (now - self.epoch).as_nanos() as u64;
+            payload[..PING_TIMESTAMP_LEN].copy_from_slice(&ts.to_be_bytes());
+        }
+
+        unsafe {
+            match self.dst {
+                IpAddr::V4(_) => {
+                    let mut icmp = ICMP {
+                        ty: ICMPType::EchoRequest.into(),
+                        code: 0,
+                        cksum: 0,
+                        un: ICMP::un_as_echo(self.id, seq),
+                    };
+                    write_unaligned(buf.as_mut_ptr() as *mut ICMP, icmp);
+
+                    icmp.cksum = inet_cksum(buf.as_ptr(), buf.len());
+                    write_unaligned(buf.as_mut_ptr() as *mut ICMP, icmp);
+                }
+                IpAddr::V6(_) => {
+                    let icmp = icmp6_echo_request(self.id, seq);
+                    write_unaligned(buf.as_mut_ptr() as *mut ICMP, icmp);
+                }
+            }
+        }
+
+        buf
+    }
+
+    fn unpack(
+        &mut self,
+        icmp_bytes: &[u8],
+        from: IpAddr,
+        ttl: Option<u8>,
+        now: Instant,
+    ) -> Option<PingEvent> {
+        let icmp: ICMP =
+            unsafe { (icmp_bytes.as_ptr() as *const ICMP).read_unaligned() };
+
+        match classify(&icmp, self.dst.is_ipv6()) {
+            Kind::EchoReply => {
+                let (id, seq) = icmp.get_idseq();
+
+                // any responder is accepted for broadcast / multicast ping,
+                // but only for what has been sent
+                if id != self.id || !self.sent.contains_key(&seq) {
+                    return None;
+                }
+
+                let payload = &icmp_bytes[size_of::<ICMP>()..];
+                let sent = if payload.len() >= PING_TIMESTAMP_LEN
+                    && self.conf.payload_size >= PING_TIMESTAMP_LEN
+                {
+                    let mut ts = [0u8; PING_TIMESTAMP_LEN];
+                    ts.copy_from_slice(&payload[..PING_TIMESTAMP_LEN]);
+
+                    self.epoch.checked_add(Duration::from_nanos(
+                        u64::from_be_bytes(ts),
+                    ))
+                }
+                else {
+                    self.sent.get(&seq).cloned()
+                };
+                let rtt = now.saturating_duration_since(sent?);
+
+                let dup = !self.received.insert(seq);
+
+                if dup {
+                    self.stats.duplicates += 1;
+                }
+                else {
+                    self.stats.received += 1;
+                    self.stats.add_rtt(rtt);
+                }
+
+                Some(PingEvent::Reply(PingReply {
+                    seq,
+                    from,
+                    bytes: icmp_bytes.len(),
+                    ttl,
+                    rtt,
+                    dup,
+                }))
+            }
+            Kind::Error(reason) => {
+                // quoted: IP header of the original datagram + 8 bytes
+                let quoted = &icmp_bytes[size_of::<ICMP>()..];
+                let inner_off = if self.dst.is_ipv6() {
+                    IPV6_HDR_LEN
+                }
+                else {
+                    (*quoted.first()? & 0x0f) as usize * 4
+                };
+
+                if quoted.len() < inner_off + size_of::<ICMP>() {
+                    return None;
+                }
+
+                let inner: ICMP = unsafe {
+                    (quoted[inner_off..].as_ptr() as *const ICMP)
+                        .read_unaligned()
+                };
+
+                self.on_error(&inner, from, reason)
+            }
+            Kind::Other => None,
+        }
+    }
+
+    /// Error from the queue of datagram socket, `request` is our echo
+    /// request instead of the quoted datagram.
+    fn unpack_error(
+        &mut self,
+        ty: u8,
+        code: u8,
+        request: &[u8],
+        from: IpAddr,
+    ) -> Option<PingEvent> {
+        let icmp = ICMP {
+            ty,
+            code,
+            ..Default::default()
+        };
+        let Kind::Error(reason) = classify(&icmp, self.dst.is_ipv6())
+        else {
+            return None;
+        };
+
+        let inner: ICMP =
+            unsafe { (request.as_ptr() as *const ICMP).read_unaligned() };
+
+        self.on_error(&inner, from, reason)
+    }
+
+    /// `inner` is the echo request that the error is about
+    fn on_error(
+        &mut self,
+        inner: &ICMP,
+        from: IpAddr,
+        reason: String,
+    ) -> Option<PingEvent> {
+        let (id, seq) = inner.get_idseq();
+
+        if id != self.id || !self.sent.contains_key(&seq) {
+            return None;
+        }
+
+        self.stats.errors += 1;
+
+        Some(PingEvent::Error { seq, from, reason })
+    }
+}
+
+
+enum Kind {
+    EchoReply,
+    Error(String),
+    Other,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+fn classify(icmp: &ICMP, v6: bool) -> Kind {
+    if v6 {
+        match icmp.parse_cm6_type() {
+            Ok(ICMP6Type::EchoReply) => Kind::EchoReply,
+            Ok(
+                ty @ (ICMP6Type::DestinationUnreachable
+                | ICMP6Type::PacketTooBig
+                | ICMP6Type::TimeExceeded
+                | ICMP6Type::ParamProblem),
+            ) => Kind::Error(format!("{ty:?} (code {})", icmp.code)),
+            _ => Kind::Other,
+        }
+    }
+    else {
+        match icmp.parse_cm_type() {
+            Ok(ICMPType::EchoReply) => Kind::EchoReply,
+            Ok(
+                ty @ (ICMPType::DestinationUnreachable(_)
+                | ICMPType::TimeExceeded(_)
+                | ICMPType::BadParam(_)
+                | ICMPType::RedirectMessage(_)),
+            ) => Kind::Error(format!("{ty:?}")),
+            _ => Kind::Other,
+        }
+    }
+}
+
+
+/// Like printf `%g`, at most 4 decimals without tailing zeros
+fn fmt_g(v: f64) -> String {
+    let s = format!("{v:.4}");
+
+    s.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        mem::size_of,
+        net::{IpAddr, Ipv4Addr},
+        ptr::write_unaligned,
+        time::{Duration, Instant},
+    };
+
+    use super::{fmt_g, PingConf, PingEvent, PingSession, PingStats};
+    use crate::network::icmp::{ICMPType, ICMP};
+
+    fn echo_reply(id: u16, seq: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; size_of::<ICMP>() + 56];
+        let icmp = ICMP {
+            ty: ICMPType::EchoReply.into(),
+            code: 0,
+            cksum: 0,
+            un: ICMP::un_as_echo(id, seq),
+        };
+
+        unsafe { write_unaligned(buf.as_mut_ptr() as *mut ICMP, icmp) };
+
+        buf
+    }
+
+    #[test]
+    fn test_ping_stats() {
+        let mut stats = PingStats {
+            transmitted: 4,
+            ..Default::default()
+        };
+
+        for ms in [10, 20, 30] {
+            stats.received += 1;
+            stats.add_rtt(Duration::from_millis(ms));
+        }
+        stats.duplicates = 1;
+        stats.elapsed = Duration::from_millis(3003);
+
+        assert_eq!(stats.loss(), 25.0);
+        assert!((stats.rtt_avg() - 20.0).abs() < 1e-9);
+        assert!((stats.rtt_mdev() - 8.164966).abs() < 1e-6);
+
+        assert_eq!(
+            stats.to_string(),
+            "4 packets transmitted, 3 received, +1 duplicates, \
+             25% packet loss, time 3003ms\n\
+             rtt min/avg/max/mdev = 10.000/20.000/30.000/8.165 ms"
+        );
+
+        assert_eq!(fmt_g(100.0 / 7.0), "14.2857");
+        assert_eq!(fmt_g(0.0), "0");
+        assert_eq!(
+            PingStats::default().to_string(),
+            "0 packets transmitted, 0 received, 0% packet loss, time 0ms"
+        );
+    }
+
+    #[test]
+    fn test_ping_unsent_seq() {
+        let dst = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let conf = PingConf::default();
+        let mut session = match unsafe { PingSession::new(dst, conf) } {
+            Ok(session) => session,
+            Err(err) => {
+                eprintln!("skip, {err:?}");
+                return;
+            }
+        };
+        let id = session.id;
+        let now = Instant::now();
+
+        // stray reply before anything is sent
+        assert!(session.unpack(&echo_reply(id, 1), dst, None, now).is_none());
+
+        session.sent.insert(1, now);
+
+        let event = session.unpack(&echo_reply(id, 1), dst, None, now);
+        assert!(matches!(event, Some(PingEvent::Reply(ref r)) if !r.dup));
+        let event = session.unpack(&echo_reply(id, 1), dst, None, now);
+        assert!(matches!(event, Some(PingEvent::Reply(ref r)) if r.dup));
+
+        // never sent, e.g. a reply to another process of the same id
+        assert!(session.unpack(&echo_reply(id, 2), dst, None, now).is_none());
+        assert!(session
+            .unpack_error(3, 1, &echo_reply(id, 2), dst)
+            .is_none());
+
+        let stats = session.stats();
+        assert_eq!(
+            (stats.received, stats.duplicates, stats.errors),
+            (1, 1, 0)
+        );
+    }
+}
@@ -0,0 +1,659 @@
+//! ICMP echo socket, unprivileged datagram socket preferred
+//!
+//! `socket(AF_INET, SOCK_DGRAM, IPPROTO_ICMP)` (and the ICMPv6 counterpart)
+//! is allowed when the gid is in `net.ipv4.ping_group_range`. The kernel then
+//! takes over the echo id (the local "port") and the checksum, and only
+//! delivers replies belonging to the socket, without the IP header.
+//!
+//! ICMP errors (e.g. TTL exceeded) of the datagram socket come from its
+//! error queue (`IP_RECVERR`) with our echo request, the raw socket sees
+//! them as they are.
+
+use std::{
+    mem::{size_of, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Range,
+    time::Duration,
+};
+
+use libc::{
+    bind, c_void, close, cmsghdr, getsockname, iovec, msghdr, poll, pollfd,
+    recvfrom, recvmsg, sendto, setsockopt, sock_extended_err, sockaddr,
+    sockaddr_in, sockaddr_in6, socket, socklen_t, AF_INET, AF_INET6,
+    CMSG_DATA, CMSG_FIRSTHDR, CMSG_NXTHDR, EAGAIN, EINTR, IPPROTO_ICMP,
+    IPPROTO_ICMPV6, IPPROTO_IP, IPPROTO_IPV6, IPV6_MULTICAST_HOPS,
+    IPV6_RECVERR, IPV6_UNICAST_HOPS, IP_MULTICAST_TTL, IP_RECVERR, IP_RECVTTL,
+    IP_TTL, MSG_DONTWAIT, MSG_ERRQUEUE, POLLIN, SOCK_DGRAM, SOCK_RAW,
+    SOL_SOCKET, SO_BROADCAST, SO_EE_OFFENDER, SO_EE_ORIGIN_ICMP,
+    SO_EE_ORIGIN_ICMP6, SO_RCVBUF,
+};
+
+use super::icmp6::{
+    recv_with_hoplimit, set_ipv6_checksum, set_recv_hoplimit,
+    ICMP6_CKSUM_OFFSET,
+};
+use crate::{
+    c_error::ErrNo,
+    data::{SockAddrIn, SockAddrIn6},
+    rs_error::NetErr,
+    throw_errno, Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+const IPV4_HDR_MIN: usize = 20;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Which kind of socket to open
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PingMode {
+    /// Datagram socket, fall back to raw socket if it's not permitted
+    #[default]
+    Auto,
+    Dgram,
+    Raw,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingSockKind {
+    Dgram,
+    /// Requires CAP_NET_RAW
+    Raw,
+}
+
+
+/// Received ICMP message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingRecv {
+    /// ICMP message in the receive buffer
+    pub icmp: Range<usize>,
+    pub from: IpAddr,
+    /// TTL or hop limit
+    pub ttl: Option<u8>,
+    /// ICMP type and code of the error from `from`, `icmp` is then our
+    /// echo request (datagram socket)
+    pub error: Option<(u8, u8)>,
+}
+
+
+pub struct PingSock {
+    fd: i32,
+    v6: bool,
+    kind: PingSockKind,
+    /// Echo id assigned by kernel for datagram socket
+    ident: Option<u16>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl PingSock {
+    /// `ttl` is applied for both unicast and multicast
+    pub unsafe fn open(
+        v6: bool,
+        mode: PingMode,
+        ttl: Option<u8>,
+    ) -> Result<Self> {
+        let sock = match mode {
+            PingMode::Dgram => Self::open_dgram(v6)?,
+            PingMode::Raw => Self::open_raw(v6)?,
+            PingMode::Auto => match Self::open_dgram(v6) {
+                Ok(sock) => sock,
+                Err(_) => Self::open_raw(v6)?,
+            },
+        };
+
+        set_int_opt(sock.fd, SOL_SOCKET, SO_RCVBUF, 128 * 1024)?;
+
+        if let Some(ttl) = ttl {
+            let ttl = ttl as i32;
+
+            if v6 {
+                set_int_opt(sock.fd, IPPROTO_IPV6, IPV6_UNICAST_HOPS, ttl)?;
+                set_int_opt(sock.fd, IPPROTO_IPV6, IPV6_MULTICAST_HOPS, ttl)?;
+            }
+            else {
+                set_int_opt(sock.fd, IPPROTO_IP, IP_TTL, ttl)?;
+                set_int_opt(sock.fd, IPPROTO_IP, IP_MULTICAST_TTL, ttl)?;
+            }
+        }
+
+        Ok(sock)
+    }
+
+    unsafe fn open_dgram(v6: bool) -> Result<Self> {
+        let fd = if v6 {
+            socket(AF_INET6, SOCK_DGRAM, IPPROTO_ICMPV6)
+        }
+        else {
+            socket(AF_INET, SOCK_DGRAM, IPPROTO_ICMP)
+        };
+
+        if fd < 0 {
+            return Err(NetErr::CreateSocket);
+        }
+
+        let mut sock = Self {
+            fd,
+            v6,
+            kind: PingSockKind::Dgram,
+            ident: None,
+        };
+
+        // bind to get the ident before sending
+        sock.ident = Some(if v6 {
+            set_recv_hoplimit(fd)?;
+            set_int_opt(fd, IPPROTO_IPV6, IPV6_RECVERR, 1)?;
+            bind_any_v6(fd)?
+        }
+        else {
+            set_int_opt(fd, IPPROTO_IP, IP_RECVTTL, 1)?;
+            set_int_opt(fd, IPPROTO_IP, IP_RECVERR, 1)?;
+            bind_any_v4(fd)?
+        });
+
+        Ok(sock)
+    }
+
+    unsafe fn open_raw(v6: bool) -> Result<Self> {
+        let fd = if v6 {
+            throw_errno!(
+                socket(AF_INET6, SOCK_RAW, IPPROTO_ICMPV6) throws SocketRaw
+            )
+        }
+        else {
+            throw_errno!(
+                socket(AF_INET, SOCK_RAW, IPPROTO_ICMP) throws SocketRaw
+            )
+        };
+
+        let sock = Self {
+            fd,
+            v6,
+            kind: PingSockKind::Raw,
+            ident: None,
+        };
+
+        if v6 {
+            set_ipv6_checksum(fd, ICMP6_CKSUM_OFFSET)?;
+            set_recv_hoplimit(fd)?;
+        }
+        else {
+            // enable broadcast pings
+            set_int_opt(fd, SOL_SOCKET, SO_BROADCAST, 1)?;
+        }
+
+        Ok(sock)
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    pub fn kind(&self) -> PingSockKind {
+        self.kind
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.v6
+    }
+
+    /// Echo id on the wire, None means it's up to the caller (raw socket)
+    pub fn ident(&self) -> Option<u16> {
+        self.ident
+    }
+
+    /// `buf` is an ICMP message, id and checksum are overwritten by kernel
+    /// for datagram socket.
+    pub unsafe fn send_to(&self, buf: &[u8], dst: IpAddr) -> Result<usize> {
+        let n = match dst {
+            IpAddr::V4(ip) => {
+                let sin: sockaddr_in = SockAddrIn::from(ip).into();
+
+                sendto(
+                    self.fd,
+                    buf.as_ptr() as *const c_void,
+                    buf.len(),
+                    0,
+                    &sin as *const sockaddr_in as *const sockaddr,
+                    size_of::<sockaddr_in>() as socklen_t,
+                )
+            }
+            IpAddr::V6(ip) => {
+                let sin6: sockaddr_in6 = SockAddrIn6::from(ip).into();
+
+                sendto(
+                    self.fd,
+                    buf.as_ptr() as *const c_void,
+                    buf.len(),
+                    0,
+                    &sin6 as *const sockaddr_in6 as *const sockaddr,
+                    size_of::<sockaddr_in6>() as socklen_t,
+                )
+            }
+        };
+
+        if n < 0 {
+            eprintln!("sendto {dst}: {:?}", ErrNo::fetch());
+            return Err(NetErr::SendTo);
+        }
+
+        Ok(n as usize)
+    }
+
+    /// None for a packet too short to be an IPv4 datagram or an error
+    /// not from ICMP. Queued errors come first.
+    pub unsafe fn recv(&self, buf: &mut [u8]) -> Result<Option<PingRecv>> {
+        if self.kind == PingSockKind::Dgram {
+            if let Some(recv) = recv_err(self.fd, self.v6, buf)? {
+                return Ok(recv);
+            }
+        }
+
+        if self.v6 {
+            // no IP header for both kinds of ICMPv6 socket
+            let (n, src, ttl) = recv_with_hoplimit(self.fd, buf)?;
+
+            return Ok(Some(PingRecv {
+                icmp: 0..n,
+                from: IpAddr::V6(src),
+                ttl,
+                error: None,
+            }));
+        }
+
+        match self.kind {
+            PingSockKind::Dgram => {
+                let (n, src, ttl) = recv_with_ttl(self.fd, buf)?;
+
+                Ok(Some(PingRecv {
+                    icmp: 0..n,
+                    from: IpAddr::V4(src),
+                    ttl,
+                    error: None,
+                }))
+            }
+            PingSockKind::Raw => {
+                let n = throw_errno!(recvfrom(
+                    self.fd,
+                    buf.as_mut_ptr() as *mut c_void,
+                    buf.len(),
+                    0,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut()
+                ) throws RecvFrom) as usize;
+
+                // raw IPv4 socket receives the IP header
+                if n < IPV4_HDR_MIN {
+                    return Ok(None);
+                }
+                let ihl = (buf[0] & 0x0f) as usize * 4;
+
+                if n < ihl {
+                    return Ok(None);
+                }
+
+                Ok(Some(PingRecv {
+                    icmp: ihl..n,
+                    from: IpAddr::V4(Ipv4Addr::new(
+                        buf[12], buf[13], buf[14], buf[15],
+                    )),
+                    ttl: Some(buf[8]),
+                    error: None,
+                }))
+            }
+        }
+    }
+
+    /// false for timeout, EINTR is treated as timeout to let caller check for
+    /// stop flag.
+    pub unsafe fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+        let mut pfd = pollfd {
+            fd: self.fd,
+            events: POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+        match poll(&mut pfd, 1, ms) {
+            -1 => {
+                let errno = ErrNo::fetch();
+
+                if errno as i32 == EINTR {
+                    Ok(false)
+                }
+                else {
+                    eprintln!("poll: {errno:?}");
+                    Err(NetErr::Poll)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+
+impl Drop for PingSock {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+pub(crate) unsafe fn set_int_opt(
+    fd: i32,
+    level: i32,
+    name: i32,
+    val: i32,
+) -> Result<()> {
+    throw_errno!(setsockopt(
+        fd,
+        level,
+        name,
+        &val as *const i32 as *const c_void,
+        size_of::<i32>() as socklen_t
+    ) throws SetSockOpt);
+
+    Ok(())
+}
+
+
+/// -> ident
+unsafe fn bind_any_v4(fd: i32) -> Result<u16> {
+    let mut sin: sockaddr_in = SockAddrIn::from(Ipv4Addr::UNSPECIFIED).into();
+    let mut len = size_of::<sockaddr_in>() as socklen_t;
+
+    throw_errno!(bind(
+        fd,
+        &sin as *const sockaddr_in as *const sockaddr,
+        len
+    ) throws Bind);
+    throw_errno!(getsockname(
+        fd,
+        &mut sin as *mut sockaddr_in as *mut sockaddr,
+        &mut len
+    ) throws GetSockName);
+
+    Ok(u16::from_be(sin.sin_port))
+}
+
+
+/// -> ident
+unsafe fn bind_any_v6(fd: i32) -> Result<u16> {
+    let mut sin6: sockaddr_in6 =
+        SockAddrIn6::from(Ipv6Addr::UNSPECIFIED).into();
+    let mut len = size_of::<sockaddr_in6>() as socklen_t;
+
+    throw_errno!(bind(
+        fd,
+        &sin6 as *const sockaddr_in6 as *const sockaddr,
+        len
+    ) throws Bind);
+    throw_errno!(getsockname(
+        fd,
+        &mut sin6 as *mut sockaddr_in6 as *mut sockaddr,
+        &mut len
+    ) throws GetSockName);
+
+    Ok(u16::from_be(sin6.sin6_port))
+}
+
+
+/// IPv4 counterpart of `recv_with_hoplimit`, requires `IP_RECVTTL`
+///
+/// -> (size, source, TTL of the received packet)
+pub unsafe fn recv_with_ttl(
+    sock: i32,
+    buf: &mut [u8],
+) -> Result<(usize, Ipv4Addr, Option<u8>)> {
+    let mut src: sockaddr_in = zeroed();
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u8; 64];
+
+    let mut msg: msghdr = zeroed();
+    msg.msg_name = &mut src as *mut sockaddr_in as *mut c_void;
+    msg.msg_namelen = size_of::<sockaddr_in>() as socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = control.len();
+
+    let n = throw_errno!(recvmsg(sock, &mut msg, 0) throws RecvFrom);
+
+    let mut ttl = None;
+    let mut cmsg: *mut cmsghdr = CMSG_FIRSTHDR(&msg);
+
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == IPPROTO_IP && (*cmsg).cmsg_type == IP_TTL {
+            let v = (CMSG_DATA(cmsg) as *const i32).read_unaligned();
+            ttl = Some(v as u8);
+        }
+
+        cmsg = CMSG_NXTHDR(&msg, cmsg);
+    }
+
+    Ok((n as usize, SockAddrIn::from(src).addr.ipv4(), ttl))
+}
+
+
+/// One error of the queue, requires `IP_RECVERR` or `IPV6_RECVERR`
+///
+/// -> None if the queue is empty, Some(None) for a local error
+unsafe fn recv_err(
+    sock: i32,
+    v6: bool,
+    buf: &mut [u8],
+) -> Result<Option<Option<PingRecv>>> {
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u8; 128];
+
+    let mut msg: msghdr = zeroed();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = control.len();
+
+    let n = recvmsg(sock, &mut msg, MSG_ERRQUEUE | MSG_DONTWAIT);
+
+    if n < 0 {
+        let errno = ErrNo::fetch();
+
+        if errno as i32 == EAGAIN {
+            return Ok(None);
+        }
+
+        eprintln!("recvmsg MSG_ERRQUEUE: {errno:?}");
+        return Err(NetErr::RecvFrom);
+    }
+
+    let (level, ty, origin) = if v6 {
+        (IPPROTO_IPV6, IPV6_RECVERR, SO_EE_ORIGIN_ICMP6)
+    }
+    else {
+        (IPPROTO_IP, IP_RECVERR, SO_EE_ORIGIN_ICMP)
+    };
+    let mut cmsg: *mut cmsghdr = CMSG_FIRSTHDR(&msg);
+
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == level && (*cmsg).cmsg_type == ty {
+            let ee = CMSG_DATA(cmsg) as *const sock_extended_err;
+            let ee_val = ee.read_unaligned();
+
+            if ee_val.ee_origin != origin {
+                return Ok(Some(None));
+            }
+
+            let offender = SO_EE_OFFENDER(ee) as *const u8;
+            let from = if v6 {
+                let sin6 = (offender as *const sockaddr_in6).read_unaligned();
+                IpAddr::V6(SockAddrIn6::from(sin6).ipv6())
+            }
+            else {
+                let sin = (offender as *const sockaddr_in).read_unaligned();
+                IpAddr::V4(SockAddrIn::from(sin).addr.ipv4())
+            };
+
+            return Ok(Some(Some(PingRecv {
+                icmp: 0..n as usize,
+                from,
+                ttl: None,
+                error: Some((ee_val.ee_type, ee_val.ee_code)),
+            })));
+        }
+
+        cmsg = CMSG_NXTHDR(&msg, cmsg);
+    }
+
+    Ok(Some(None))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use super::{PingMode, PingSock, PingSockKind};
+    use crate::network::{
+        icmp::{ICMPType, ICMP},
+        icmp6::icmp6_echo_request,
+        inet_cksum,
+    };
+
+    /// Echo request to 127.0.0.1, the reply must come back with `id`
+    unsafe fn echo_loopback(sock: &PingSock, id: u16) {
+        let mut icmp = ICMP {
+            ty: ICMPType::EchoRequest.into(),
+            code: 0,
+            cksum: 0,
+            un: ICMP::un_as_echo(sock.ident().unwrap_or(0x4321), 7),
+        };
+        icmp.cksum =
+            inet_cksum(&icmp as *const _ as *const u8, size_of_val(&icmp));
+
+        let buf = std::slice::from_raw_parts(
+            &icmp as *const _ as *const u8,
+            size_of_val(&icmp),
+        );
+        let dst = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        sock.send_to(buf, dst).unwrap();
+
+        let echo_reply: u8 = ICMPType::EchoReply.into();
+        let mut rbuf = [0u8; 256];
+
+        // raw socket sees our own request and others' echo on loopback too
+        while sock.wait_readable(Duration::from_secs(1)).unwrap() {
+            let recv = sock.recv(&mut rbuf).unwrap().unwrap();
+            let reply: ICMP = (rbuf[recv.icmp.start..].as_ptr()
+                as *const ICMP)
+                .read_unaligned();
+
+            assert!(recv.error.is_none());
+
+            if reply.ty == echo_reply && reply.get_idseq() == (id, 7) {
+                assert_eq!(recv.from, dst);
+                return;
+            }
+        }
+
+        panic!("no echo reply {id} from 127.0.0.1");
+    }
+
+    /// Either kind works, depends on privilege and ping_group_range
+    #[test]
+    fn test_ping_sock_auto() {
+        unsafe {
+            let sock = match PingSock::open(true, PingMode::Auto, None) {
+                Ok(sock) => sock,
+                Err(err) => {
+                    eprintln!("skip, no ICMPv6 socket: {err:?}");
+                    return;
+                }
+            };
+
+            if sock.kind() == PingSockKind::Dgram {
+                assert!(sock.ident().is_some());
+            }
+
+            let id = sock.ident().unwrap_or(0x4321);
+            let icmp = icmp6_echo_request(id, 1);
+            let buf = std::slice::from_raw_parts(
+                &icmp as *const _ as *const u8,
+                std::mem::size_of_val(&icmp),
+            );
+
+            let dst: IpAddr = "::1".parse().unwrap();
+            if sock.send_to(buf, dst).is_err() {
+                eprintln!("skip, no IPv6 loopback");
+                return;
+            }
+
+            let mut rbuf = [0u8; 256];
+
+            // raw socket sees our own request on loopback too
+            while sock.wait_readable(Duration::from_secs(1)).unwrap() {
+                let recv = sock.recv(&mut rbuf).unwrap().unwrap();
+
+                assert_eq!(recv.from, dst);
+                if rbuf[recv.icmp.start] == 129 {
+                    return;
+                }
+            }
+
+            panic!("no echo reply from ::1");
+        }
+    }
+
+    #[test]
+    fn test_ping_sock_loopback() {
+        unsafe {
+            let dgram = PingSock::open(false, PingMode::Dgram, None);
+            let raw = PingSock::open(false, PingMode::Raw, None);
+
+            if let (Err(err), Err(_)) = (&dgram, &raw) {
+                eprintln!("skip, {err:?}");
+                return;
+            }
+
+            // falls back to raw when ping_group_range forbids datagram
+            let auto = PingSock::open(false, PingMode::Auto, None).unwrap();
+            let kind = match dgram {
+                Ok(_) => PingSockKind::Dgram,
+                Err(_) => PingSockKind::Raw,
+            };
+            assert_eq!(auto.kind(), kind);
+
+            if let Ok(sock) = dgram {
+                // the id is replaced by the kernel's one
+                echo_loopback(&sock, sock.ident().unwrap());
+            }
+
+            if let Ok(sock) = raw {
+                assert_eq!(sock.kind(), PingSockKind::Raw);
+                echo_loopback(&sock, 0x4321);
+            }
+        }
+    }
+}
@@ -0,0 +1,873 @@
+//! Path MTU discovery
+//!
+//! `PmtuCache` keeps the path MTU per destination learned from ICMP
+//! "fragmentation needed" ([rfc1191](https://www.rfc-editor.org/rfc/rfc1191))
+//! and ICMPv6 "packet too big"
+//! ([rfc8201](https://www.rfc-editor.org/rfc/rfc8201)), entries age out to
+//! the default MTU.
+//!
+//! `probe_pmtu` searches the path MTU with DF-marked probes that need an
+//! answer from the destination, so a black hole (filtered ICMP) is detected
+//! by loss like packetization layer PMTUD
+//! ([rfc4821](https://www.rfc-editor.org/rfc/rfc4821)).
+
+use std::{
+    collections::HashMap,
+    mem::{size_of, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use libc::{
+    c_void, cmsghdr, getpid, getsockopt, iovec, msghdr, poll, pollfd, recvmsg,
+    send, sock_extended_err, socklen_t, CMSG_DATA, CMSG_FIRSTHDR, CMSG_NXTHDR,
+    EMSGSIZE, IPPROTO_IP, IPPROTO_IPV6, IPV6_MTU, IPV6_MTU_DISCOVER,
+    IPV6_PMTUDISC_PROBE, IPV6_RECVERR, IP_MTU, IP_MTU_DISCOVER,
+    IP_PMTUDISC_PROBE, IP_RECVERR, MSG_DONTWAIT, MSG_ERRQUEUE, POLLERR,
+    POLLIN, SO_EE_ORIGIN_ICMP, SO_EE_ORIGIN_ICMP6, SO_EE_ORIGIN_LOCAL,
+};
+
+use super::{
+    icmp::ICMP,
+    icmp6::{icmp6_echo_request, ICMP6Type, IPV6_HDR_LEN},
+    inet_cksum,
+    ping_sock::{set_int_opt, PingMode, PingSock},
+};
+use crate::{c_error::ErrNo, rs_error::NetErr, Result};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Minimum MTU of IPv4 (rfc791)
+pub const IPV4_MIN_MTU: u16 = 68;
+
+/// Minimum MTU of IPv6 (rfc8200)
+pub const IPV6_MIN_MTU: u16 = 1280;
+
+/// Cached PMTU is forgotten after 10 minutes (rfc1191 section 6.3)
+pub const PMTU_EXPIRES: Duration = Duration::from_secs(600);
+
+/// Plateau table (rfc1191 section 7), for routers not reporting the
+/// next-hop MTU
+pub const MTU_PLATEAUS: [u16; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
+const IPV4_HDR_LEN: usize = 20;
+const ICMPSZ: usize = size_of::<ICMP>();
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMP_PORT_UNREACH: u8 = 3;
+const ICMP6_PORT_UNREACH: u8 = 4;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+pub struct PmtuCache {
+    entries: HashMap<IpAddr, PmtuEntry>,
+    default_mtu: u16,
+    expires: Duration,
+}
+
+struct PmtuEntry {
+    mtu: u16,
+    updated: Instant,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PmtuProbeMethod {
+    /// Echo request, answered by echo reply
+    #[default]
+    Icmp,
+    /// UDP to a (probably) closed port, answered by port unreachable
+    Udp,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PmtuProbeConf {
+    pub method: PmtuProbeMethod,
+    /// Lower bound assumed to work, default to the minimum MTU
+    pub min: Option<u16>,
+    /// Upper bound, default to the MTU of the route
+    pub max: Option<u16>,
+    /// Tries of each size before it's considered too big
+    pub tries: usize,
+    pub timeout: Duration,
+    /// Destination port of UDP probes
+    pub port: u16,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    /// Answered by the destination
+    Ok,
+    /// Fragmentation needed / packet too big, with the reported MTU
+    TooBig(Option<u16>),
+    /// Larger than the MTU of the outgoing interface
+    LocalTooBig(u16),
+    /// No answer, black hole or loss
+    Timeout,
+    /// Other ICMP error (type, code)
+    Unreachable(u8, u8),
+}
+
+
+enum ProbeSock {
+    Icmp(PingSock),
+    Udp(UdpSocket),
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl PmtuCache {
+    /// `default_mtu` is used for unknown destinations, normally the MTU of
+    /// the outgoing interface
+    pub fn new(default_mtu: u16) -> Self {
+        Self {
+            entries: HashMap::new(),
+            default_mtu,
+            expires: PMTU_EXPIRES,
+        }
+    }
+
+    pub fn with_expires(mut self, expires: Duration) -> Self {
+        self.expires = expires;
+        self
+    }
+
+    pub fn default_mtu(&self) -> u16 {
+        self.default_mtu
+    }
+
+    /// PMTU of `dst`, aged entries are removed
+    pub fn get(&mut self, dst: IpAddr, now: Instant) -> u16 {
+        if let Some(entry) = self.entries.get(&dst) {
+            if now.saturating_duration_since(entry.updated) < self.expires {
+                return entry.mtu.min(self.default_mtu);
+            }
+
+            self.entries.remove(&dst);
+        }
+
+        self.default_mtu
+    }
+
+    /// Lower the PMTU of `dst` as reported by ICMP, an increase is ignored
+    /// (rfc1191 section 6.1).
+    ///
+    /// Return whether it's changed
+    pub fn update(&mut self, dst: IpAddr, mtu: u16, now: Instant) -> bool {
+        let mtu = mtu.max(min_mtu_of(dst));
+
+        if mtu >= self.get(dst, now) {
+            return false;
+        }
+
+        self.set(dst, mtu, now);
+
+        true
+    }
+
+    /// Set the PMTU of `dst` regardless of the current one, e.g. a probed one
+    pub fn set(&mut self, dst: IpAddr, mtu: u16, now: Instant) {
+        self.entries.insert(dst, PmtuEntry { mtu, updated: now });
+    }
+
+    pub fn remove(&mut self, dst: IpAddr) {
+        self.entries.remove(&dst);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drop aged entries
+    pub fn expire(&mut self, now: Instant) {
+        let expires = self.expires;
+
+        self.entries.retain(|_, entry| {
+            now.saturating_duration_since(entry.updated) < expires
+        });
+    }
+
+    /// Learn from an IPv4 datagram carrying ICMP "fragmentation needed"
+    ///
+    /// -> (destination, new PMTU) if it's lowered
+    pub fn handle_icmp(
+        &mut self,
+        pkt: &[u8],
+        now: Instant,
+    ) -> Option<(IpAddr, u16)> {
+        let icmp = ipv4_payload(pkt)?;
+        let (dst, mtu) = parse_frag_needed(icmp)?;
+
+        self.update(IpAddr::V4(dst), mtu, now)
+            .then(|| (IpAddr::V4(dst), self.get(IpAddr::V4(dst), now)))
+    }
+
+    /// Learn from an ICMPv6 "packet too big" message (without IPv6 header)
+    ///
+    /// -> (destination, new PMTU) if it's lowered
+    pub fn handle_icmp6(
+        &mut self,
+        icmp: &[u8],
+        now: Instant,
+    ) -> Option<(IpAddr, u16)> {
+        let (dst, mtu) = parse_packet_too_big(icmp)?;
+
+        self.update(IpAddr::V6(dst), mtu, now)
+            .then(|| (IpAddr::V6(dst), self.get(IpAddr::V6(dst), now)))
+    }
+}
+
+
+impl Default for PmtuProbeConf {
+    fn default() -> Self {
+        Self {
+            method: PmtuProbeMethod::default(),
+            min: None,
+            max: None,
+            tries: 2,
+            timeout: Duration::from_secs(1),
+            port: 33434,
+        }
+    }
+}
+
+
+impl ProbeSock {
+    unsafe fn open(dst: IpAddr, conf: &PmtuProbeConf) -> Result<Self> {
+        let sock = match conf.method {
+            PmtuProbeMethod::Icmp => Self::Icmp(PingSock::open(
+                dst.is_ipv6(),
+                PingMode::Auto,
+                None,
+            )?),
+            PmtuProbeMethod::Udp => {
+                let unspecified: IpAddr = if dst.is_ipv6() {
+                    Ipv6Addr::UNSPECIFIED.into()
+                }
+                else {
+                    Ipv4Addr::UNSPECIFIED.into()
+                };
+
+                let sock = UdpSocket::bind((unspecified, 0))
+                    .map_err(NetErr::Connect)?;
+                sock.connect((dst, conf.port)).map_err(NetErr::Connect)?;
+
+                Self::Udp(sock)
+            }
+        };
+
+        // DF is set, and the size is not limited by the cached PMTU
+        if dst.is_ipv6() {
+            set_int_opt(
+                sock.fd(),
+                IPPROTO_IPV6,
+                IPV6_MTU_DISCOVER,
+                IPV6_PMTUDISC_PROBE,
+            )?;
+            set_int_opt(sock.fd(), IPPROTO_IPV6, IPV6_RECVERR, 1)?;
+        }
+        else {
+            set_int_opt(
+                sock.fd(),
+                IPPROTO_IP,
+                IP_MTU_DISCOVER,
+                IP_PMTUDISC_PROBE,
+            )?;
+            set_int_opt(sock.fd(), IPPROTO_IP, IP_RECVERR, 1)?;
+        }
+
+        Ok(sock)
+    }
+
+    fn fd(&self) -> i32 {
+        match self {
+            Self::Icmp(sock) => sock.fd(),
+            Self::Udp(sock) => sock.as_raw_fd(),
+        }
+    }
+
+    /// Send a probe of `size` bytes IP datagram
+    ///
+    /// -> Err(outcome) if it's refused locally
+    unsafe fn send(
+        &self,
+        dst: IpAddr,
+        size: u16,
+        seq: u16,
+    ) -> Result<std::result::Result<(), ProbeOutcome>> {
+        let ip_hdr_len = if dst.is_ipv6() {
+            IPV6_HDR_LEN
+        }
+        else {
+            IPV4_HDR_LEN
+        };
+        // both ICMP and UDP headers are 8 bytes
+        let mut buf = vec![0u8; (size as usize).saturating_sub(ip_hdr_len)];
+
+        if buf.len() < ICMPSZ {
+            return Err(NetErr::InvalidParam);
+        }
+
+        let res = match self {
+            Self::Icmp(sock) => {
+                let id = sock.ident().unwrap_or((getpid() & 0xffff) as u16);
+                let mut icmp = if dst.is_ipv6() {
+                    icmp6_echo_request(id, seq)
+                }
+                else {
+                    ICMP {
+                        ty: 8,
+                        code: 0,
+                        cksum: 0,
+                        un: ICMP::un_as_echo(id, seq),
+                    }
+                };
+
+                (buf.as_mut_ptr() as *mut ICMP).write_unaligned(icmp);
+
+                if !dst.is_ipv6() {
+                    icmp.cksum = inet_cksum(buf.as_ptr(), buf.len());
+                    (buf.as_mut_ptr() as *mut ICMP).write_unaligned(icmp);
+                }
+
+                sock.send_to(&buf, dst).map(|_| ())
+            }
+            Self::Udp(sock) => {
+                let payload = &buf[ICMPSZ..];
+                let n = send(
+                    sock.as_raw_fd(),
+                    payload.as_ptr() as *const c_void,
+                    payload.len(),
+                    0,
+                );
+
+                if n < 0 {
+                    eprintln!("send {dst}: {:?}", ErrNo::fetch());
+                    Err(NetErr::SendTo)
+                }
+                else {
+                    Ok(())
+                }
+            }
+        };
+
+        match res {
+            Ok(()) => Ok(Ok(())),
+            Err(NetErr::SendTo) if last_errno() == EMSGSIZE => {
+                Ok(Err(ProbeOutcome::LocalTooBig(route_mtu(dst)?)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wait the answer of probe `seq`
+    unsafe fn wait(
+        &self,
+        dst: IpAddr,
+        seq: u16,
+        timeout: Duration,
+    ) -> Result<ProbeOutcome> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0u8; 65536];
+
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut pfd = pollfd {
+                fd: self.fd(),
+                events: POLLIN,
+                revents: 0,
+            };
+            let ms = left.as_millis().min(i32::MAX as u128) as i32;
+
+            if poll(&mut pfd, 1, ms) <= 0 {
+                return Ok(ProbeOutcome::Timeout);
+            }
+
+            if pfd.revents & POLLERR != 0 {
+                if let Some(outcome) = recv_err(self.fd(), dst.is_ipv6())? {
+                    return Ok(outcome);
+                }
+            }
+
+            if pfd.revents & POLLIN == 0 {
+                continue;
+            }
+
+            match self {
+                Self::Icmp(sock) => {
+                    let recv = match sock.recv(&mut buf)? {
+                        Some(recv) => recv,
+                        None => continue,
+                    };
+
+                    if recv.from != dst || recv.icmp.len() < ICMPSZ {
+                        continue;
+                    }
+
+                    let icmp: ICMP = (buf[recv.icmp].as_ptr() as *const ICMP)
+                        .read_unaligned();
+                    let echo_reply = if dst.is_ipv6() {
+                        icmp.parse_cm6_type() == Ok(ICMP6Type::EchoReply)
+                    }
+                    else {
+                        icmp.ty == 0
+                    };
+
+                    if echo_reply && icmp.get_idseq().1 == seq {
+                        return Ok(ProbeOutcome::Ok);
+                    }
+                }
+                Self::Udp(sock) => {
+                    // any answer means it's got through
+                    if sock.recv(&mut buf).is_ok() {
+                        return Ok(ProbeOutcome::Ok);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forget answers of earlier probes
+    unsafe fn drain(&self, v6: bool) {
+        let mut buf = [0u8; 2048];
+
+        while recv_err(self.fd(), v6).ok().flatten().is_some() {}
+
+        while libc::recv(
+            self.fd(),
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            MSG_DONTWAIT,
+        ) >= 0
+        {}
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// Largest plateau less than `len` (the total length of the datagram that
+/// was too big)
+pub fn plateau_below(len: u16) -> u16 {
+    MTU_PLATEAUS
+        .iter()
+        .cloned()
+        .find(|mtu| *mtu < len)
+        .unwrap_or(IPV4_MIN_MTU)
+}
+
+
+fn min_mtu_of(dst: IpAddr) -> u16 {
+    match dst {
+        IpAddr::V4(_) => IPV4_MIN_MTU,
+        IpAddr::V6(_) => IPV6_MIN_MTU,
+    }
+}
+
+
+fn ipv4_payload(pkt: &[u8]) -> Option<&[u8]> {
+    if pkt.len() < IPV4_HDR_LEN || pkt[0] >> 4 != 4 {
+        return None;
+    }
+
+    let ihl = (pkt[0] & 0x0f) as usize * 4;
+
+    pkt.get(ihl..)
+}
+
+
+/// ICMP message -> (original destination, next-hop MTU)
+pub fn parse_frag_needed(icmp: &[u8]) -> Option<(Ipv4Addr, u16)> {
+    if icmp.len() < ICMPSZ
+        || icmp[0] != ICMP_DEST_UNREACH
+        || icmp[1] != ICMP_FRAG_NEEDED
+    {
+        return None;
+    }
+
+    let quoted = &icmp[ICMPSZ..];
+
+    if quoted.len() < IPV4_HDR_LEN {
+        return None;
+    }
+
+    let dst = Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]);
+    let mut mtu = u16::from_be_bytes([icmp[6], icmp[7]]);
+
+    // old router leaves it zero (rfc1191 section 5)
+    if mtu == 0 {
+        mtu = plateau_below(u16::from_be_bytes([quoted[2], quoted[3]]));
+    }
+
+    Some((dst, mtu))
+}
+
+
+/// ICMPv6 message -> (original destination, MTU)
+pub fn parse_packet_too_big(icmp: &[u8]) -> Option<(Ipv6Addr, u16)> {
+    if icmp.len() < ICMPSZ + IPV6_HDR_LEN
+        || icmp[0] != ICMP6Type::PacketTooBig as u8
+    {
+        return None;
+    }
+
+    let mtu = u32::from_be_bytes([icmp[4], icmp[5], icmp[6], icmp[7]]);
+    let mut dst = [0u8; 16];
+    dst.copy_from_slice(&icmp[ICMPSZ + 24..ICMPSZ + 40]);
+
+    Some((Ipv6Addr::from(dst), mtu.min(u16::MAX as u32) as u16))
+}
+
+
+fn last_errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+
+/// MTU of the route to `dst` known by kernel
+pub fn route_mtu(dst: IpAddr) -> Result<u16> {
+    let sock = match dst {
+        IpAddr::V4(_) => UdpSocket::bind("0.0.0.0:0"),
+        IpAddr::V6(_) => UdpSocket::bind("[::]:0"),
+    }
+    .map_err(NetErr::Connect)?;
+    sock.connect(SocketAddr::new(dst, 9))
+        .map_err(NetErr::Connect)?;
+
+    let (level, name) = if dst.is_ipv6() {
+        (IPPROTO_IPV6, IPV6_MTU)
+    }
+    else {
+        (IPPROTO_IP, IP_MTU)
+    };
+
+    let mut mtu = 0i32;
+    let mut len = size_of::<i32>() as socklen_t;
+
+    let ret = unsafe {
+        getsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut i32 as *mut c_void,
+            &mut len,
+        )
+    };
+
+    if ret == -1 {
+        eprintln!("getsockopt IP_MTU: {:?}", ErrNo::fetch());
+        return Err(NetErr::SetSockOpt);
+    }
+
+    Ok(mtu.clamp(0, u16::MAX as i32) as u16)
+}
+
+
+/// Read one error from error queue (`IP_RECVERR`)
+///
+/// Ok(None) if it's empty or not relevant.
+unsafe fn recv_err(fd: i32, v6: bool) -> Result<Option<ProbeOutcome>> {
+    let mut data = [0u8; 2048];
+    let mut iov = iovec {
+        iov_base: data.as_mut_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let mut name = [0u8; 128];
+    let mut control = [0u8; 512];
+
+    let mut msg: msghdr = zeroed();
+    msg.msg_name = name.as_mut_ptr() as *mut c_void;
+    msg.msg_namelen = name.len() as socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = control.len();
+
+    if recvmsg(fd, &mut msg, MSG_ERRQUEUE | MSG_DONTWAIT) < 0 {
+        return Ok(None);
+    }
+
+    let (level, ty) = if v6 {
+        (IPPROTO_IPV6, IPV6_RECVERR)
+    }
+    else {
+        (IPPROTO_IP, IP_RECVERR)
+    };
+    let mut cmsg: *mut cmsghdr = CMSG_FIRSTHDR(&msg);
+
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == level && (*cmsg).cmsg_type == ty {
+            let ee =
+                (CMSG_DATA(cmsg) as *const sock_extended_err).read_unaligned();
+
+            return Ok(Some(outcome_of(&ee, v6)));
+        }
+
+        cmsg = CMSG_NXTHDR(&msg, cmsg);
+    }
+
+    Ok(None)
+}
+
+
+fn outcome_of(ee: &sock_extended_err, v6: bool) -> ProbeOutcome {
+    let mtu = (ee.ee_info > 0).then(|| ee.ee_info.min(u16::MAX as u32) as u16);
+
+    match ee.ee_origin {
+        SO_EE_ORIGIN_LOCAL if ee.ee_errno == EMSGSIZE as u32 => {
+            ProbeOutcome::LocalTooBig(mtu.unwrap_or(0))
+        }
+        SO_EE_ORIGIN_ICMP if !v6 => match (ee.ee_type, ee.ee_code) {
+            (ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED) => ProbeOutcome::TooBig(mtu),
+            (ICMP_DEST_UNREACH, ICMP_PORT_UNREACH) => ProbeOutcome::Ok,
+            (ty, code) => ProbeOutcome::Unreachable(ty, code),
+        },
+        SO_EE_ORIGIN_ICMP6 if v6 => match (ee.ee_type, ee.ee_code) {
+            (2, _) => ProbeOutcome::TooBig(mtu),
+            (1, ICMP6_PORT_UNREACH) => ProbeOutcome::Ok,
+            (ty, code) => ProbeOutcome::Unreachable(ty, code),
+        },
+        _ => ProbeOutcome::Unreachable(ee.ee_type, ee.ee_code),
+    }
+}
+
+
+/// Binary search the PMTU of `dst` with DF-marked probes of IP datagram
+/// size, the result is stored into `cache`.
+///
+/// `on_probe` is called with each probed size and its outcome.
+pub unsafe fn probe_pmtu<F: FnMut(u16, &ProbeOutcome)>(
+    dst: IpAddr,
+    conf: &PmtuProbeConf,
+    cache: &mut PmtuCache,
+    mut on_probe: F,
+) -> Result<u16> {
+    let sock = ProbeSock::open(dst, conf)?;
+
+    let mut lo = conf.min.unwrap_or(min_mtu_of(dst));
+    let mut hi = match conf.max {
+        Some(max) => max,
+        None => route_mtu(dst)?,
+    };
+
+    if lo > hi || conf.tries == 0 {
+        return Err(NetErr::InvalidParam);
+    }
+
+    let mut seq = 0u16;
+    let mut confirmed = false;
+    // the upper bound is tried first, it's the answer mostly
+    let mut size = hi;
+
+    loop {
+        let mut outcome = ProbeOutcome::Timeout;
+
+        for _ in 0..conf.tries {
+            seq = seq.wrapping_add(1);
+            sock.drain(dst.is_ipv6());
+
+            outcome = match sock.send(dst, size, seq)? {
+                Ok(()) => sock.wait(dst, seq, conf.timeout)?,
+                Err(outcome) => outcome,
+            };
+
+            if outcome != ProbeOutcome::Timeout {
+                break;
+            }
+        }
+
+        on_probe(size, &outcome);
+
+        match outcome {
+            ProbeOutcome::Ok => {
+                lo = size;
+                confirmed = true;
+            }
+            ProbeOutcome::TooBig(Some(mtu))
+            | ProbeOutcome::LocalTooBig(mtu)
+                if mtu >= lo && mtu < size =>
+            {
+                cache.update(dst, mtu, Instant::now());
+                hi = mtu;
+            }
+            ProbeOutcome::TooBig(_)
+            | ProbeOutcome::LocalTooBig(_)
+            | ProbeOutcome::Timeout => {
+                hi = size - 1;
+            }
+            ProbeOutcome::Unreachable(ty, code) => {
+                return Err(NetErr::AnyWay(format!(
+                    "{dst} unreachable (type {ty}, code {code})"
+                )));
+            }
+        }
+
+        if lo >= hi {
+            break;
+        }
+
+        // the reported MTU is tried directly
+        size = if matches!(
+            outcome,
+            ProbeOutcome::TooBig(Some(_)) | ProbeOutcome::LocalTooBig(_)
+        ) && hi < size
+        {
+            hi
+        }
+        else {
+            lo + (hi - lo).div_ceil(2)
+        };
+    }
+
+    // the lower bound itself
+    if !confirmed {
+        seq = seq.wrapping_add(1);
+        sock.drain(dst.is_ipv6());
+
+        let outcome = match sock.send(dst, lo, seq)? {
+            Ok(()) => sock.wait(dst, seq, conf.timeout)?,
+            Err(outcome) => outcome,
+        };
+        on_probe(lo, &outcome);
+
+        if outcome != ProbeOutcome::Ok {
+            return Err(NetErr::AnyWay(format!("No answer from {dst}")));
+        }
+    }
+
+    cache.set(dst, lo, Instant::now());
+
+    Ok(lo)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        parse_frag_needed, plateau_below, probe_pmtu, PmtuCache,
+        PmtuProbeConf, ProbeOutcome, IPV4_MIN_MTU,
+    };
+
+    fn frag_needed(dst: Ipv4Addr, mtu: u16, orig_len: u16) -> Vec<u8> {
+        let mut pkt = vec![0u8; 20];
+        pkt[0] = 0x45;
+        pkt[9] = 1;
+
+        pkt.extend_from_slice(&[3, 4, 0, 0, 0, 0]);
+        pkt.extend_from_slice(&mtu.to_be_bytes());
+
+        let mut quoted = vec![0u8; 28];
+        quoted[0] = 0x45;
+        quoted[2..4].copy_from_slice(&orig_len.to_be_bytes());
+        quoted[16..20].copy_from_slice(&dst.octets());
+        pkt.extend_from_slice(&quoted);
+
+        pkt
+    }
+
+    #[test]
+    fn test_pmtu_cache() {
+        let now = Instant::now();
+        let dst = Ipv4Addr::new(10, 0, 0, 9);
+        let mut cache =
+            PmtuCache::new(1500).with_expires(Duration::from_secs(60));
+
+        assert_eq!(cache.get(IpAddr::V4(dst), now), 1500);
+
+        assert_eq!(
+            cache.handle_icmp(&frag_needed(dst, 1400, 1500), now),
+            Some((IpAddr::V4(dst), 1400))
+        );
+        assert_eq!(cache.get(IpAddr::V4(dst), now), 1400);
+
+        // increase is ignored
+        assert_eq!(
+            cache.handle_icmp(&frag_needed(dst, 1450, 1500), now),
+            None
+        );
+
+        // no next-hop MTU, plateau below 1400
+        assert_eq!(
+            cache.handle_icmp(&frag_needed(dst, 0, 1400), now),
+            Some((IpAddr::V4(dst), 1006))
+        );
+
+        // bogus value is clamped
+        assert!(cache.update(IpAddr::V4(dst), 10, now));
+        assert_eq!(cache.get(IpAddr::V4(dst), now), IPV4_MIN_MTU);
+
+        // aging
+        let later = now + Duration::from_secs(61);
+        assert_eq!(cache.get(IpAddr::V4(dst), later), 1500);
+        assert!(cache.is_empty());
+
+        // ICMPv6 packet too big
+        let dst6: Ipv6Addr = "2001:db8::9".parse().unwrap();
+        let mut icmp6 = vec![2, 0, 0, 0];
+        icmp6.extend_from_slice(&1280u32.to_be_bytes());
+        let mut quoted = vec![0u8; 40];
+        quoted[24..40].copy_from_slice(&dst6.octets());
+        icmp6.extend_from_slice(&quoted);
+
+        assert_eq!(
+            cache.handle_icmp6(&icmp6, now),
+            Some((IpAddr::V6(dst6), 1280))
+        );
+
+        assert_eq!(plateau_below(1500), 1492);
+        assert_eq!(plateau_below(68), IPV4_MIN_MTU);
+        assert_eq!(
+            parse_frag_needed(&frag_needed(dst, 576, 1500)[20..]),
+            Some((dst, 576))
+        );
+    }
+
+    /// Loopback takes the whole MTU of lo
+    #[test]
+    fn test_probe_pmtu_loopback() {
+        let dst = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut cache = PmtuCache::new(1500);
+        let conf = PmtuProbeConf {
+            max: Some(9000),
+            ..Default::default()
+        };
+        let mut outcomes = vec![];
+
+        let res = unsafe {
+            probe_pmtu(dst, &conf, &mut cache, |size, outcome| {
+                outcomes.push((size, *outcome))
+            })
+        };
+
+        match res {
+            Ok(mtu) => {
+                assert_eq!(mtu, 9000);
+                assert_eq!(outcomes, vec![(9000, ProbeOutcome::Ok)]);
+            }
+            Err(err) => eprintln!("skip, {err:?}"),
+        }
+    }
+}
@@ -0,0 +1,1025 @@
+//! Traceroute, IPv4 with UDP, ICMP echo or TCP SYN probes
+//!
+//! Probes are sent through an `IPPROTO_RAW` socket, so every field of the IP
+//! header is under control. Responses are ICMP errors quoting the original
+//! datagram, and the reply of the destination itself (port unreachable, echo
+//! reply, SYN-ACK / RST).
+//!
+//! Probe id lives in the field that is quoted by ICMP errors and doesn't
+//! take part in load balancing: IP id for UDP, echo sequence for ICMP and
+//! sequence number for TCP. In Paris mode (`paris`) the flow identifier (ports,
+//! ICMP type / code / checksum) is kept the same for all probes, so per-flow
+//! ECMP routers put them on one path.
+
+use std::{
+    fmt::{Display, Formatter},
+    mem::size_of,
+    net::{Ipv4Addr, UdpSocket},
+    ptr::write_unaligned,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use libc::{
+    c_void, close, getpid, poll, pollfd, recvfrom, sendto, sockaddr,
+    sockaddr_in, socket, socklen_t, AF_INET, EINTR, IPPROTO_ICMP, IPPROTO_RAW,
+    IPPROTO_TCP, MSG_DONTWAIT, POLLIN, SOCK_RAW,
+};
+
+use super::{
+    icmp::ICMP,
+    inet_cksum,
+    ip::{FragOff, Protocol, ToS, HLV, IP, PL},
+};
+use crate::{
+    aux::htons,
+    c_error::ErrNo,
+    data::{InAddrN, SockAddrIn},
+    rs_error::NetErr,
+    throw_errno,
+    transport::{
+        tcp::{TcpFlag, TCP},
+        udp::UDP,
+    },
+    view::U16N,
+    Result,
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+/// Default base destination port of UDP probes
+pub const TRACE_UDP_PORT: u16 = 33434;
+
+/// Default destination port of TCP probes
+pub const TRACE_TCP_PORT: u16 = 80;
+
+/// Default IP datagram size of a probe, same with traceroute
+pub const TRACE_PACKET_LEN: usize = 60;
+
+const IPSZ: usize = size_of::<IP>();
+const ICMPSZ: usize = size_of::<ICMP>();
+const UDPSZ: usize = size_of::<UDP>();
+const TCPSZ: usize = size_of::<TCP>();
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_ECHO: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+
+const UNREACH_PORT: u8 = 3;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProbeMethod {
+    #[default]
+    Udp,
+    Icmp,
+    TcpSyn,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct TraceConf {
+    pub method: ProbeMethod,
+    pub first_ttl: u8,
+    pub max_ttl: u8,
+    pub probes_per_hop: usize,
+    /// Destination port of UDP (base port) or TCP probes, None for default
+    pub port: Option<u16>,
+    /// Time to wait for a response of each probe
+    pub wait: Duration,
+    /// Max number of probes on the way
+    pub parallel: usize,
+    /// Keep the flow identifier constant for all probes
+    pub paris: bool,
+    /// IP datagram size of UDP and ICMP probes
+    pub packet_len: usize,
+}
+
+
+/// Everything needed to build and match probes, without socket
+#[derive(Debug, Clone)]
+pub struct ProbeSpec {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub method: ProbeMethod,
+    pub paris: bool,
+    /// ICMP echo id, or UDP / TCP (base) source port
+    pub ident: u16,
+    pub port: u16,
+    pub packet_len: usize,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyKind {
+    /// From a router on the path
+    TimeExceeded,
+    /// From the destination
+    Reached,
+    /// Destination unreachable with the ICMP code
+    Unreachable(u8),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeReply {
+    pub from: Ipv4Addr,
+    pub rtt: Duration,
+    pub kind: ReplyKind,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub ttl: u8,
+    /// None for timeout
+    pub probes: Vec<Option<ProbeReply>>,
+}
+
+
+struct Probe {
+    ttl: u8,
+    sent: Option<Instant>,
+    reply: Option<ProbeReply>,
+    done: bool,
+}
+
+
+pub struct Tracer {
+    spec: ProbeSpec,
+    conf: TraceConf,
+    send_fd: i32,
+    icmp_fd: i32,
+    /// Receives SYN-ACK / RST for TCP probes
+    tcp_fd: Option<i32>,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Default for TraceConf {
+    fn default() -> Self {
+        Self {
+            method: ProbeMethod::default(),
+            first_ttl: 1,
+            max_ttl: 30,
+            probes_per_hop: 3,
+            port: None,
+            wait: Duration::from_secs(5),
+            parallel: 16,
+            paris: false,
+            packet_len: TRACE_PACKET_LEN,
+        }
+    }
+}
+
+
+impl ReplyKind {
+    /// Annotation of traceroute, e.g. `!H`
+    pub fn annotation(&self) -> Option<String> {
+        match self {
+            Self::Unreachable(code) => Some(match code {
+                0 => "!N".to_owned(),
+                1 => "!H".to_owned(),
+                2 => "!P".to_owned(),
+                4 => "!F".to_owned(),
+                5 => "!S".to_owned(),
+                13 => "!X".to_owned(),
+                code => format!("!<{code}>"),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+impl Hop {
+    /// Destination reached or unreachable
+    pub fn is_last(&self) -> bool {
+        self.probes
+            .iter()
+            .flatten()
+            .any(|reply| reply.kind != ReplyKind::TimeExceeded)
+    }
+}
+
+
+impl Display for Hop {
+    /// `ttl  addr  rtt ms ...` without name resolution
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:2} ", self.ttl)?;
+
+        let mut last = None;
+
+        for probe in self.probes.iter() {
+            match probe {
+                Some(reply) => {
+                    if last != Some(reply.from) {
+                        write!(f, " {}", reply.from)?;
+                        last = Some(reply.from);
+                    }
+                    write!(f, "  {:.3} ms", reply.rtt.as_secs_f64() * 1000.0)?;
+
+                    if let Some(anno) = reply.kind.annotation() {
+                        write!(f, " {anno}")?;
+                    }
+                }
+                None => write!(f, " *")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl ProbeSpec {
+    /// IP datagram of probe `id` with `ttl`
+    pub fn pack(&self, id: u16, ttl: u8) -> Vec<u8> {
+        let (protocol, l4_len) = match self.method {
+            ProbeMethod::Udp => {
+                (Protocol::UDP, self.packet_len.max(IPSZ + UDPSZ) - IPSZ)
+            }
+            ProbeMethod::Icmp => {
+                // the Paris checksum compensation takes 2 bytes of payload
+                (
+                    Protocol::ICMP,
+                    self.packet_len.max(IPSZ + ICMPSZ + 2) - IPSZ,
+                )
+            }
+            ProbeMethod::TcpSyn => (Protocol::TCP, TCPSZ),
+        };
+
+        let mut buf = vec![0u8; IPSZ + l4_len];
+
+        let mut iphdr = IP {
+            ihl_v: HLV::new(5, 4),
+            tos: ToS::default(),
+            len: PL::from_native(buf.len() as u16),
+            // zero id is filled by kernel
+            id: U16N::from_native(id.wrapping_add(1)),
+            frag_off: FragOff::default(),
+            ttl,
+            protocol,
+            checksum: 0,
+            ip_src: InAddrN::from_ipv4addr(self.src),
+            ip_dst: InAddrN::from_ipv4addr(self.dst),
+        };
+
+        unsafe {
+            write_unaligned(buf.as_mut_ptr() as *mut IP, iphdr);
+            iphdr.checksum = inet_cksum(buf.as_ptr(), IPSZ);
+            write_unaligned(buf.as_mut_ptr() as *mut IP, iphdr);
+        }
+
+        let l4 = &mut buf[IPSZ..];
+
+        for (i, b) in l4.iter_mut().enumerate() {
+            *b = 0x40 + (i % 0x20) as u8;
+        }
+
+        match self.method {
+            ProbeMethod::Udp => {
+                let udphdr = UDP {
+                    source: U16N::from_native(self.ident),
+                    dest: U16N::from_native(self.udp_dport(id)),
+                    len: U16N::from_native(l4_len as u16),
+                    // optional for IPv4, and constant for Paris mode
+                    checksum: 0,
+                };
+
+                unsafe {
+                    write_unaligned(l4.as_mut_ptr() as *mut UDP, udphdr);
+                }
+            }
+            ProbeMethod::Icmp => {
+                let seq = id.wrapping_add(1);
+                let mut icmp = ICMP {
+                    ty: ICMP_ECHO,
+                    code: 0,
+                    cksum: 0,
+                    un: ICMP::un_as_echo(self.ident, seq),
+                };
+
+                if self.paris {
+                    // seq + !seq is 0xffff in one's complement sum, so is
+                    // the checksum for all probes
+                    l4[ICMPSZ..ICMPSZ + 2]
+                        .copy_from_slice(&(!seq).to_be_bytes());
+                }
+
+                unsafe {
+                    write_unaligned(l4.as_mut_ptr() as *mut ICMP, icmp);
+                    icmp.cksum = inet_cksum(l4.as_ptr(), l4.len());
+                    write_unaligned(l4.as_mut_ptr() as *mut ICMP, icmp);
+                }
+            }
+            ProbeMethod::TcpSyn => {
+                let mut tcphdr = TCP {
+                    source: unsafe { htons(self.tcp_sport(id)) },
+                    dest: unsafe { htons(self.port) },
+                    seq: (id as u32 + 1).to_be(),
+                    ack_seq: 0,
+                    doff_flags: TCP::doff_flags(5, &[TcpFlag::Syn]),
+                    window: unsafe { htons(5840) },
+                    check: 0,
+                    urgptr: 0,
+                };
+
+                let mut cksum_buf = [0u8; 12 + TCPSZ];
+
+                unsafe {
+                    iphdr.write_pseudo_iphdr(&mut cksum_buf, TCPSZ as u16);
+                    write_unaligned(
+                        cksum_buf[12..].as_mut_ptr() as *mut TCP,
+                        tcphdr,
+                    );
+                    tcphdr.check =
+                        inet_cksum(cksum_buf.as_ptr(), cksum_buf.len());
+                    write_unaligned(l4.as_mut_ptr() as *mut TCP, tcphdr);
+                }
+            }
+        }
+
+        buf
+    }
+
+    fn udp_dport(&self, id: u16) -> u16 {
+        if self.paris {
+            self.port
+        }
+        else {
+            self.port.wrapping_add(id)
+        }
+    }
+
+    fn tcp_sport(&self, id: u16) -> u16 {
+        if self.paris {
+            self.ident
+        }
+        else {
+            self.ident.wrapping_add(id)
+        }
+    }
+
+    /// Match an ICMP datagram (with IP header) received from raw socket
+    ///
+    /// -> (probe id, source, kind)
+    pub fn match_icmp(
+        &self,
+        pkt: &[u8],
+    ) -> Option<(u16, Ipv4Addr, ReplyKind)> {
+        let (from, icmp_bytes) = split_ipv4(pkt)?;
+
+        if icmp_bytes.len() < ICMPSZ {
+            return None;
+        }
+
+        let ty = icmp_bytes[0];
+        let code = icmp_bytes[1];
+
+        match ty {
+            ICMP_ECHO_REPLY => {
+                if self.method != ProbeMethod::Icmp || from != self.dst {
+                    return None;
+                }
+
+                let icmp: ICMP = unsafe {
+                    (icmp_bytes.as_ptr() as *const ICMP).read_unaligned()
+                };
+                let (id, seq) = icmp.get_idseq();
+
+                if id != self.ident || seq == 0 {
+                    return None;
+                }
+
+                Some((seq - 1, from, ReplyKind::Reached))
+            }
+            ICMP_TIME_EXCEEDED | ICMP_DEST_UNREACH => {
+                let probe_id = self.match_quoted(&icmp_bytes[ICMPSZ..])?;
+
+                let kind = if ty == ICMP_TIME_EXCEEDED {
+                    ReplyKind::TimeExceeded
+                }
+                else if code == UNREACH_PORT && from == self.dst {
+                    ReplyKind::Reached
+                }
+                else {
+                    ReplyKind::Unreachable(code)
+                };
+
+                Some((probe_id, from, kind))
+            }
+            _ => None,
+        }
+    }
+
+    /// Quoted original datagram: IP header and at least 8 bytes
+    fn match_quoted(&self, quoted: &[u8]) -> Option<u16> {
+        let (_, l4) = split_ipv4(quoted)?;
+
+        if l4.len() < 8 {
+            return None;
+        }
+
+        let ip_dst =
+            Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]);
+        let protocol = quoted[9];
+        let ip_id = u16::from_be_bytes([quoted[4], quoted[5]]);
+
+        if ip_dst != self.dst {
+            return None;
+        }
+
+        let sport = u16::from_be_bytes([l4[0], l4[1]]);
+
+        match self.method {
+            ProbeMethod::Udp => {
+                if protocol != Protocol::UDP as u8
+                    || sport != self.ident
+                    || ip_id == 0
+                {
+                    return None;
+                }
+
+                Some(ip_id - 1)
+            }
+            ProbeMethod::Icmp => {
+                let id = u16::from_be_bytes([l4[4], l4[5]]);
+                let seq = u16::from_be_bytes([l4[6], l4[7]]);
+
+                if protocol != Protocol::ICMP as u8
+                    || l4[0] != ICMP_ECHO
+                    || id != self.ident
+                    || seq == 0
+                {
+                    return None;
+                }
+
+                Some(seq - 1)
+            }
+            ProbeMethod::TcpSyn => {
+                let seq = u32::from_be_bytes([l4[4], l4[5], l4[6], l4[7]]);
+
+                if protocol != Protocol::TCP as u8 || seq == 0 {
+                    return None;
+                }
+
+                let id = (seq - 1) as u16;
+
+                if sport != self.tcp_sport(id) {
+                    return None;
+                }
+
+                Some(id)
+            }
+        }
+    }
+
+    /// Match SYN-ACK or RST of the destination for TCP probes
+    pub fn match_tcp(&self, pkt: &[u8]) -> Option<(u16, Ipv4Addr, ReplyKind)> {
+        let (from, l4) = split_ipv4(pkt)?;
+
+        if from != self.dst || l4.len() < TCPSZ {
+            return None;
+        }
+
+        let sport = u16::from_be_bytes([l4[0], l4[1]]);
+        let dport = u16::from_be_bytes([l4[2], l4[3]]);
+        let ack = u32::from_be_bytes([l4[8], l4[9], l4[10], l4[11]]);
+        let flags = l4[13];
+
+        let syn_ack = TcpFlag::Syn as u8 | TcpFlag::Ack as u8;
+
+        if sport != self.port
+            || flags & TcpFlag::Ack as u8 == 0
+            || flags & syn_ack != syn_ack && flags & TcpFlag::Rst as u8 == 0
+            || ack < 2
+        {
+            return None;
+        }
+
+        let id = (ack - 2) as u16;
+
+        if dport != self.tcp_sport(id) {
+            return None;
+        }
+
+        Some((id, from, ReplyKind::Reached))
+    }
+}
+
+
+impl Tracer {
+    /// Requires CAP_NET_RAW
+    pub unsafe fn new(dst: Ipv4Addr, conf: TraceConf) -> Result<Self> {
+        if conf.first_ttl == 0
+            || conf.first_ttl > conf.max_ttl
+            || conf.probes_per_hop == 0
+            || conf.parallel == 0
+            || conf.packet_len > u16::MAX as usize
+        {
+            return Err(NetErr::InvalidParam);
+        }
+
+        let src = source_for(dst)?;
+        let pid = getpid() as u16;
+
+        let (ident, port) = match conf.method {
+            ProbeMethod::Udp => {
+                (0x8000 | pid & 0x7fff, conf.port.unwrap_or(TRACE_UDP_PORT))
+            }
+            ProbeMethod::Icmp => (pid, 0),
+            ProbeMethod::TcpSyn => {
+                (0x8000 | pid & 0x7fff, conf.port.unwrap_or(TRACE_TCP_PORT))
+            }
+        };
+
+        let send_fd = throw_errno!(socket(AF_INET, SOCK_RAW, IPPROTO_RAW) throws SocketRaw);
+        let icmp_fd = match open_raw(IPPROTO_ICMP) {
+            Ok(fd) => fd,
+            Err(err) => {
+                close(send_fd);
+                return Err(err);
+            }
+        };
+        let tcp_fd = if conf.method == ProbeMethod::TcpSyn {
+            match open_raw(IPPROTO_TCP) {
+                Ok(fd) => Some(fd),
+                Err(err) => {
+                    close(send_fd);
+                    close(icmp_fd);
+                    return Err(err);
+                }
+            }
+        }
+        else {
+            None
+        };
+
+        Ok(Self {
+            spec: ProbeSpec {
+                src,
+                dst,
+                method: conf.method,
+                paris: conf.paris,
+                ident,
+                port,
+                packet_len: conf.packet_len,
+            },
+            conf,
+            send_fd,
+            icmp_fd,
+            tcp_fd,
+        })
+    }
+
+    pub fn spec(&self) -> &ProbeSpec {
+        &self.spec
+    }
+
+    /// Probe hop by hop until the destination (or an unreachable) hop is
+    /// done, `max_ttl` is exceeded or `stop` is set.
+    ///
+    /// Hops are passed to `on_hop` in TTL order as soon as they're done.
+    pub unsafe fn run<F: FnMut(&Hop)>(
+        &mut self,
+        stop: &AtomicBool,
+        mut on_hop: F,
+    ) -> Result<Vec<Hop>> {
+        let per_hop = self.conf.probes_per_hop;
+        let mut probes: Vec<Probe> = (self.conf.first_ttl..=self.conf.max_ttl)
+            .flat_map(|ttl| {
+                (0..per_hop).map(move |_| Probe {
+                    ttl,
+                    sent: None,
+                    reply: None,
+                    done: false,
+                })
+            })
+            .collect();
+
+        let mut hops = vec![];
+        let mut next_send = 0;
+        let mut next_hop = 0;
+        // ttl of the last hop found so far
+        let mut last_ttl = self.conf.max_ttl;
+
+        let mut buf = vec![0u8; 4096];
+
+        while !stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+
+            // expire
+            for probe in probes.iter_mut() {
+                if let Some(sent) = probe.sent {
+                    if !probe.done && now >= sent + self.conf.wait {
+                        probe.done = true;
+                    }
+                }
+            }
+
+            // emit done hops in order
+            while next_hop * per_hop < probes.len() {
+                let hop_probes = &probes[next_hop * per_hop..][..per_hop];
+
+                if hop_probes.iter().any(|probe| !probe.done) {
+                    break;
+                }
+
+                let hop = Hop {
+                    ttl: hop_probes[0].ttl,
+                    probes: hop_probes
+                        .iter()
+                        .map(|probe| probe.reply.clone())
+                        .collect(),
+                };
+                on_hop(&hop);
+
+                let is_last = hop.is_last();
+                hops.push(hop);
+                next_hop += 1;
+
+                if is_last {
+                    return Ok(hops);
+                }
+            }
+
+            if next_hop * per_hop >= probes.len() {
+                break;
+            }
+
+            // fill the window
+            let inflight = probes
+                .iter()
+                .filter(|probe| probe.sent.is_some() && !probe.done)
+                .count();
+
+            for _ in inflight..self.conf.parallel {
+                if next_send >= probes.len()
+                    || probes[next_send].ttl > last_ttl
+                {
+                    break;
+                }
+
+                let probe = &mut probes[next_send];
+                let pkt = self.spec.pack(next_send as u16, probe.ttl);
+
+                // the response may be queued before `sendto` returns
+                probe.sent = Some(Instant::now());
+
+                if let Err(err) = self.send(&pkt) {
+                    // e.g. no route, shown as timeout
+                    eprintln!("{err:?}");
+                }
+
+                next_send += 1;
+            }
+
+            // wait for responses until the earliest expiration
+            let wake = probes
+                .iter()
+                .filter(|probe| !probe.done)
+                .filter_map(|probe| probe.sent)
+                .min()
+                .map(|sent| sent + self.conf.wait)
+                .unwrap_or(now);
+
+            let fds = [Some(self.icmp_fd), self.tcp_fd];
+
+            for fd in self.wait_readable(
+                &fds,
+                wake.saturating_duration_since(Instant::now()),
+            )? {
+                // drain, responses may pile up while `on_hop` is running
+                loop {
+                    let n = recvfrom(
+                        fd,
+                        buf.as_mut_ptr() as *mut c_void,
+                        buf.len(),
+                        MSG_DONTWAIT,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                    );
+
+                    if n < 0 {
+                        break;
+                    }
+
+                    let pkt = &buf[..n as usize];
+                    let matched = if fd == self.icmp_fd {
+                        self.spec.match_icmp(pkt)
+                    }
+                    else {
+                        self.spec.match_tcp(pkt)
+                    };
+
+                    let (id, from, kind) = match matched {
+                        Some(res) => res,
+                        None => continue,
+                    };
+                    let probe = match probes.get_mut(id as usize) {
+                        Some(probe) => probe,
+                        None => continue,
+                    };
+                    let sent = match probe.sent {
+                        Some(sent) if probe.reply.is_none() => sent,
+                        _ => continue,
+                    };
+
+                    probe.reply = Some(ProbeReply {
+                        from,
+                        rtt: Instant::now().saturating_duration_since(sent),
+                        kind,
+                    });
+                    probe.done = true;
+
+                    if kind != ReplyKind::TimeExceeded {
+                        last_ttl = last_ttl.min(probe.ttl);
+                    }
+                }
+            }
+
+            // probes beyond the last hop are never waited
+            for probe in probes.iter_mut().filter(|probe| probe.ttl > last_ttl)
+            {
+                probe.done = true;
+            }
+        }
+
+        Ok(hops)
+    }
+
+    unsafe fn send(&self, pkt: &[u8]) -> Result<()> {
+        let sin: sockaddr_in = SockAddrIn::from(self.spec.dst).into();
+
+        let n = sendto(
+            self.send_fd,
+            pkt.as_ptr() as *const c_void,
+            pkt.len(),
+            0,
+            &sin as *const sockaddr_in as *const sockaddr,
+            size_of::<sockaddr_in>() as socklen_t,
+        );
+
+        if n < 0 {
+            eprintln!("sendto {}: {:?}", self.spec.dst, ErrNo::fetch());
+            return Err(NetErr::SendTo);
+        }
+
+        Ok(())
+    }
+
+    /// Readable ones of `fds`, empty for timeout or EINTR
+    unsafe fn wait_readable(
+        &self,
+        fds: &[Option<i32>],
+        timeout: Duration,
+    ) -> Result<Vec<i32>> {
+        let mut pfds: Vec<pollfd> = fds
+            .iter()
+            .flatten()
+            .map(|fd| pollfd {
+                fd: *fd,
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+        if poll(pfds.as_mut_ptr(), pfds.len() as _, ms) == -1 {
+            let errno = ErrNo::fetch();
+
+            if errno as i32 == EINTR {
+                return Ok(vec![]);
+            }
+
+            eprintln!("poll: {errno:?}");
+            return Err(NetErr::Poll);
+        }
+
+        Ok(pfds
+            .into_iter()
+            .filter(|pfd| pfd.revents & POLLIN != 0)
+            .map(|pfd| pfd.fd)
+            .collect())
+    }
+}
+
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.send_fd);
+            close(self.icmp_fd);
+
+            if let Some(fd) = self.tcp_fd {
+                close(fd);
+            }
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// -> (IP source, payload)
+fn split_ipv4(pkt: &[u8]) -> Option<(Ipv4Addr, &[u8])> {
+    if pkt.len() < IPSZ || pkt[0] >> 4 != 4 {
+        return None;
+    }
+
+    let ihl = (pkt[0] & 0x0f) as usize * 4;
+
+    if ihl < IPSZ || pkt.len() < ihl {
+        return None;
+    }
+
+    let src = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+
+    Some((src, &pkt[ihl..]))
+}
+
+
+unsafe fn open_raw(protocol: i32) -> Result<i32> {
+    Ok(throw_errno!(socket(AF_INET, SOCK_RAW, protocol) throws SocketRaw))
+}
+
+
+/// Source address the kernel would choose for `dst`
+fn source_for(dst: Ipv4Addr) -> Result<Ipv4Addr> {
+    let sock = UdpSocket::bind("0.0.0.0:0").map_err(NetErr::Connect)?;
+    sock.connect((dst, TRACE_UDP_PORT))
+        .map_err(NetErr::Connect)?;
+
+    match sock.local_addr().map_err(NetErr::Connect)? {
+        std::net::SocketAddr::V4(addr) => Ok(*addr.ip()),
+        std::net::SocketAddr::V6(_) => unreachable!(),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::atomic::AtomicBool, time::Duration};
+
+    use super::{
+        ProbeMethod, ProbeSpec, ReplyKind, TraceConf, Tracer, ICMPSZ, IPSZ,
+    };
+    use crate::network::inet_cksum;
+
+    fn spec(method: ProbeMethod, paris: bool) -> ProbeSpec {
+        ProbeSpec {
+            src: Ipv4Addr::new(10, 0, 0, 2),
+            dst: Ipv4Addr::new(10, 0, 9, 9),
+            method,
+            paris,
+            ident: 0x8123,
+            port: 33434,
+            packet_len: 60,
+        }
+    }
+
+    /// ICMP error from `from` quoting the first 28 bytes of `probe`
+    fn icmp_error(from: Ipv4Addr, ty: u8, code: u8, probe: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0u8; IPSZ];
+        pkt[0] = 0x45;
+        pkt[9] = 1;
+        pkt[12..16].copy_from_slice(&from.octets());
+
+        pkt.extend_from_slice(&[ty, code, 0, 0, 0, 0, 0, 0]);
+        pkt.extend_from_slice(&probe[..IPSZ + 8]);
+
+        pkt
+    }
+
+    #[test]
+    fn test_trace_match_quoted() {
+        let router = Ipv4Addr::new(10, 0, 0, 1);
+
+        for method in
+            [ProbeMethod::Udp, ProbeMethod::Icmp, ProbeMethod::TcpSyn]
+        {
+            for paris in [false, true] {
+                let spec = spec(method, paris);
+                let probe = spec.pack(7, 3);
+
+                assert_eq!(probe[8], 3, "ttl");
+                assert_eq!(unsafe { inet_cksum(probe.as_ptr(), IPSZ) }, 0);
+
+                let err = icmp_error(router, 11, 0, &probe);
+                assert_eq!(
+                    spec.match_icmp(&err),
+                    Some((7, router, ReplyKind::TimeExceeded)),
+                    "{method:?} {paris}"
+                );
+
+                let err = icmp_error(spec.dst, 3, 3, &probe);
+                assert_eq!(
+                    spec.match_icmp(&err),
+                    Some((7, spec.dst, ReplyKind::Reached))
+                );
+
+                let err = icmp_error(router, 3, 1, &probe);
+                assert_eq!(
+                    spec.match_icmp(&err),
+                    Some((7, router, ReplyKind::Unreachable(1)))
+                );
+
+                // someone else's probe
+                let mut other = spec.clone();
+                other.ident ^= 0x55;
+                let err = icmp_error(router, 11, 0, &other.pack(7, 3));
+                assert_eq!(spec.match_icmp(&err), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_paris_flow() {
+        // UDP: ports constant
+        let udp = spec(ProbeMethod::Udp, true);
+        let (a, b) = (udp.pack(0, 1), udp.pack(5, 2));
+        assert_eq!(a[IPSZ..IPSZ + 4], b[IPSZ..IPSZ + 4]);
+
+        let udp = spec(ProbeMethod::Udp, false);
+        let (a, b) = (udp.pack(0, 1), udp.pack(5, 2));
+        assert_ne!(a[IPSZ + 2..IPSZ + 4], b[IPSZ + 2..IPSZ + 4]);
+
+        // ICMP: type, code and checksum constant, checksum still valid
+        let icmp = spec(ProbeMethod::Icmp, true);
+        let (a, b) = (icmp.pack(0, 1), icmp.pack(5, 2));
+        assert_eq!(a[IPSZ..IPSZ + 4], b[IPSZ..IPSZ + 4]);
+        assert_eq!(
+            unsafe { inet_cksum(b[IPSZ..].as_ptr(), b.len() - IPSZ) },
+            0
+        );
+        assert_ne!(a[IPSZ + 6..IPSZ + ICMPSZ], b[IPSZ + 6..IPSZ + ICMPSZ]);
+
+        // TCP: ports constant
+        let tcp = spec(ProbeMethod::TcpSyn, true);
+        let (a, b) = (tcp.pack(0, 1), tcp.pack(5, 2));
+        assert_eq!(a[IPSZ..IPSZ + 4], b[IPSZ..IPSZ + 4]);
+    }
+
+    #[test]
+    fn test_trace_match_tcp() {
+        let mut spec = spec(ProbeMethod::TcpSyn, false);
+        spec.port = 80;
+
+        // RST-ACK from destination
+        let mut pkt = vec![0u8; IPSZ + 20];
+        pkt[0] = 0x45;
+        pkt[9] = 6;
+        pkt[12..16].copy_from_slice(&spec.dst.octets());
+        pkt[IPSZ..IPSZ + 2].copy_from_slice(&80u16.to_be_bytes());
+        pkt[IPSZ + 2..IPSZ + 4]
+            .copy_from_slice(&(spec.ident + 4).to_be_bytes());
+        pkt[IPSZ + 8..IPSZ + 12].copy_from_slice(&6u32.to_be_bytes());
+        pkt[IPSZ + 13] = 0x14;
+
+        assert_eq!(
+            spec.match_tcp(&pkt),
+            Some((4, spec.dst, ReplyKind::Reached))
+        );
+
+        // not an answer of ours
+        pkt[IPSZ + 13] = 0x02;
+        assert_eq!(spec.match_tcp(&pkt), None);
+    }
+
+    /// Loopback is one hop away
+    #[test]
+    fn test_trace_loopback() {
+        let conf = TraceConf {
+            method: ProbeMethod::Icmp,
+            max_ttl: 3,
+            wait: Duration::from_secs(1),
+            ..Default::default()
+        };
+
+        unsafe {
+            let mut tracer = match Tracer::new(Ipv4Addr::LOCALHOST, conf) {
+                Ok(tracer) => tracer,
+                Err(err) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+
+            let hops = tracer.run(&AtomicBool::new(false), |_| ()).unwrap();
+
+            assert_eq!(hops.len(), 1);
+            assert!(hops[0].is_last());
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Nonblocking TCP connection with input/output buffers, driven by the
+//! reactor: it reads when readable and flushes when writable, handlers
+//! only deal with the buffers.
+//!
+//! File ranges in the output are sent by `sendfile` without copying.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Error, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::fd::AsRawFd,
+    sync::Arc,
+};
+
+use libc::{off_t, sendfile};
+
+use super::{Interest, TimerId, Token};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+const READ_CHUNK: usize = 4 * 1024;
+/// Bytes read in one readable event so that others don't starve
+const MAX_READ_PER_EVENT: usize = 64 * 1024;
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    Open,
+    /// Closed after output is flushed
+    Draining,
+    Closed,
+}
+
+
+/// Segment of the output
+#[derive(Debug)]
+enum Output {
+    /// Bytes from `pos`
+    Bytes { buf: Vec<u8>, pos: usize },
+    File {
+        file: Arc<File>,
+        offset: u64,
+        remains: u64,
+    },
+}
+
+
+/// `data` is the per-connection state of the handler
+#[derive(Debug)]
+pub struct Conn<S> {
+    token: Token,
+    stream: TcpStream,
+    peer: SocketAddr,
+    state: ConnState,
+    input: Vec<u8>,
+    output: VecDeque<Output>,
+    /// Bytes of output not written yet
+    pending: u64,
+    reading: bool,
+    eof: bool,
+    /// Interest in the epoll set
+    pub(crate) registered: Interest,
+    pub(crate) idle_timer: Option<TimerId>,
+    pub data: S,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl<S> Conn<S> {
+    pub(crate) fn new(
+        token: Token,
+        stream: TcpStream,
+        peer: SocketAddr,
+        data: S,
+    ) -> std::io::Result<Self> {
+        stream.set_nonblocking(true)?;
+
+        Ok(Self {
+            token,
+            stream,
+            peer,
+            state: ConnState::Open,
+            input: vec![],
+            output: VecDeque::new(),
+            pending: 0,
+            reading: true,
+            eof: false,
+            registered: Interest::READABLE,
+            idle_timer: None,
+            data,
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn state(&self) -> ConnState {
+        self.state
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.stream.as_raw_fd()
+    }
+
+    /// Bytes read but not consumed
+    pub fn input(&self) -> &[u8] {
+        &self.input
+    }
+
+    pub fn take_input(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.input)
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.input.drain(..n);
+    }
+
+    /// Queue bytes to be written, ignored once closing
+    pub fn write(&mut self, bytes: &[u8]) {
+        if self.state != ConnState::Open || bytes.is_empty() {
+            return;
+        }
+
+        self.pending += bytes.len() as u64;
+
+        if let Some(Output::Bytes { buf, .. }) = self.output.back_mut() {
+            buf.extend_from_slice(bytes);
+        }
+        else {
+            self.output.push_back(Output::Bytes {
+                buf: bytes.to_vec(),
+                pos: 0,
+            });
+        }
+    }
+
+    /// Queue `len` bytes of `file` from `offset`, the file position isn't
+    /// used so that it can be shared.
+    pub fn write_file(&mut self, file: Arc<File>, offset: u64, len: u64) {
+        if self.state != ConnState::Open || len == 0 {
+            return;
+        }
+
+        self.pending += len;
+        self.output.push_back(Output::File {
+            file,
+            offset,
+            remains: len,
+        });
+    }
+
+    /// Bytes queued but not written yet
+    pub fn pending_output(&self) -> u64 {
+        self.pending
+    }
+
+    /// Peer has shut down its write side
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Stop reading, e.g. while a request is being handled
+    pub fn pause_read(&mut self) {
+        self.reading = false;
+    }
+
+    pub fn resume_read(&mut self) {
+        self.reading = true;
+    }
+
+    /// Close after queued output is written
+    pub fn close(&mut self) {
+        if self.state == ConnState::Open {
+            self.state = ConnState::Draining;
+        }
+    }
+
+    /// Close at once, queued output is discarded
+    pub fn abort(&mut self) {
+        self.state = ConnState::Closed;
+    }
+
+    pub(crate) fn interest(&self) -> Interest {
+        let mut interest = Interest::NONE;
+
+        if self.state == ConnState::Open && self.reading && !self.eof {
+            interest = interest | Interest::READABLE;
+        }
+
+        if self.pending_output() > 0 {
+            interest = interest | Interest::WRITABLE;
+        }
+
+        interest
+    }
+
+    /// Read until it would block, returns bytes read
+    pub(crate) fn fill(&mut self) -> std::io::Result<usize> {
+        let mut total = 0;
+        let mut buf = [0u8; READ_CHUNK];
+
+        while total < MAX_READ_PER_EVENT {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    total += n;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Write until it would block, returns bytes written
+    pub(crate) fn flush(&mut self) -> std::io::Result<u64> {
+        let mut total = 0;
+        let sock = self.stream.as_raw_fd();
+
+        while let Some(out) = self.output.front_mut() {
+            let res = match out {
+                Output::Bytes { buf, pos } => {
+                    self.stream.write(&buf[*pos..]).map(|n| {
+                        *pos += n;
+                        (n as u64, *pos == buf.len())
+                    })
+                }
+                Output::File {
+                    file,
+                    offset,
+                    remains,
+                } => send_file(sock, file, offset, *remains).map(|n| {
+                    *remains -= n;
+                    (n, *remains == 0)
+                }),
+            };
+
+            match res {
+                Ok((0, false)) => return Err(ErrorKind::WriteZero.into()),
+                Ok((n, done)) => {
+                    self.pending -= n;
+                    total += n;
+
+                    if done {
+                        self.output.pop_front();
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Draining one has flushed all or it's aborted
+    pub(crate) fn is_done(&self) -> bool {
+        match self.state {
+            ConnState::Open => false,
+            ConnState::Draining => self.pending_output() == 0,
+            ConnState::Closed => true,
+        }
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        if self.state == ConnState::Draining {
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+
+        self.state = ConnState::Closed;
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+/// One `sendfile` call from `offset`, which moves on by bytes sent.
+/// A file shrunk under us shows as sending zero byte.
+fn send_file(
+    sock: i32,
+    file: &File,
+    offset: &mut u64,
+    count: u64,
+) -> std::io::Result<u64> {
+    let mut off = *offset as off_t;
+    let count = count.min(isize::MAX as u64) as usize;
+
+    let n = unsafe { sendfile(sock, file.as_raw_fd(), &mut off, count) };
+
+    if n == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    *offset = off as u64;
+
+    Ok(n as u64)
+}
@@ -0,0 +1,878 @@
+//! Single-threaded epoll reactor for TCP servers
+//!
+//! [`Poller`] wraps the epoll set (level-triggered), [`Timers`] keeps
+//! deadlines and [`Conn`] buffers a nonblocking stream. [`Reactor`]
+//! accepts on a listener and drives the connections, calling back a
+//! [`Handler`]. Work done on other threads comes back through [`Waker`].
+
+pub mod conn;
+pub mod timer;
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener},
+    ops::BitOr,
+    os::fd::AsRawFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use libc::{
+    c_void, close, epoll_create1, epoll_ctl, epoll_event, epoll_wait, eventfd,
+    read, write, EFD_CLOEXEC, EFD_NONBLOCK, EMFILE, ENFILE, ENOBUFS, ENOMEM,
+    EPOLLERR, EPOLLHUP, EPOLLIN, EPOLLOUT, EPOLLRDHUP, EPOLL_CLOEXEC,
+    EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD,
+};
+use log::warn;
+
+pub use self::{conn::*, timer::*};
+use crate::{c_error::ErrNo, throw_errno, NetErr, Result};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Constant
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+/// First token of connections
+const CONN_TOKEN_START: usize = 2;
+
+const MAX_EVENTS: usize = 1024;
+
+/// The listener isn't watched for a while when fds or memory run out,
+/// it's level-triggered and would be readable all the time.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+/// Key of a registered fd, carried in `epoll_event.u64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Token(pub usize);
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest(u32);
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub token: Token,
+    events: u32,
+}
+
+
+/// Epoll set, closed on drop
+#[derive(Debug)]
+pub struct Poller {
+    epfd: i32,
+    events: Vec<epoll_event>,
+}
+
+
+/// Eventfd that wakes up the poller from other threads
+#[derive(Debug, Clone)]
+pub struct Waker {
+    fd: Arc<EventFd>,
+}
+
+
+#[derive(Debug)]
+struct EventFd(i32);
+
+
+/// Connections by token, those looked up are synced with the epoll set
+/// after the callback.
+#[derive(Debug)]
+pub struct Conns<S> {
+    map: HashMap<Token, Conn<S>>,
+    touched: Vec<Token>,
+}
+
+
+/// Callbacks of [`Reactor`], the connection is dropped after it's
+/// closed and its output flushed.
+pub trait Handler {
+    type State;
+
+    /// State of the new connection
+    fn on_accept(&mut self, peer: SocketAddr) -> Self::State;
+
+    /// New bytes are in [`Conn::input`], or [`Conn::is_eof`] turns true
+    fn on_read(&mut self, conn: &mut Conn<Self::State>);
+
+    /// Some thread has called [`Waker::wake`]
+    fn on_wake(&mut self, _conns: &mut Conns<Self::State>) {}
+
+    /// No progress in the idle timeout, returns whether to close it or
+    /// to wait another round.
+    fn on_idle(&mut self, _conn: &mut Conn<Self::State>) -> bool {
+        true
+    }
+
+    fn on_close(&mut self, _conn: &mut Conn<Self::State>) {}
+}
+
+
+pub struct Reactor<H: Handler> {
+    poller: Poller,
+    listener: TcpListener,
+    waker: Waker,
+    conns: Conns<H::State>,
+    timers: Timers<Token>,
+    idle_timeout: Option<Duration>,
+    next_token: usize,
+    handler: H,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl Interest {
+    pub const NONE: Self = Self(0);
+    pub const READABLE: Self = Self(EPOLLIN as u32 | EPOLLRDHUP as u32);
+    pub const WRITABLE: Self = Self(EPOLLOUT as u32);
+
+    pub fn is_readable(&self) -> bool {
+        self.0 & EPOLLIN as u32 != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.0 & EPOLLOUT as u32 != 0
+    }
+}
+
+
+impl BitOr for Interest {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+
+impl Event {
+    pub fn is_readable(&self) -> bool {
+        self.events & (EPOLLIN | EPOLLRDHUP) as u32 != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.events & EPOLLOUT as u32 != 0
+    }
+
+    /// Hang up or error, reading gets EOF or the error
+    pub fn is_closed(&self) -> bool {
+        self.events & (EPOLLHUP | EPOLLERR) as u32 != 0
+    }
+}
+
+
+impl Poller {
+    pub fn new() -> Result<Self> {
+        let epfd = unsafe {
+            throw_errno!(epoll_create1(EPOLL_CLOEXEC) throws EpollCreate)
+        };
+
+        Ok(Self {
+            epfd,
+            events: vec![epoll_event { events: 0, u64: 0 }; MAX_EVENTS],
+        })
+    }
+
+    pub fn add(
+        &self,
+        fd: i32,
+        token: Token,
+        interest: Interest,
+    ) -> Result<()> {
+        self.ctl(EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub fn modify(
+        &self,
+        fd: i32,
+        token: Token,
+        interest: Interest,
+    ) -> Result<()> {
+        self.ctl(EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    pub fn delete(&self, fd: i32) -> Result<()> {
+        self.ctl(EPOLL_CTL_DEL, fd, Token(0), Interest::NONE)
+    }
+
+    /// Wait for events, `None` blocks until one comes. An interrupted
+    /// wait returns no event.
+    pub fn wait(
+        &mut self,
+        timeout: Option<Duration>,
+        events: &mut Vec<Event>,
+    ) -> Result<()> {
+        events.clear();
+
+        // round up so that a timer isn't polled before its deadline
+        let timeout = timeout
+            .map(|dur| {
+                dur.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128)
+            })
+            .map(|ms| ms as i32)
+            .unwrap_or(-1);
+
+        let n = unsafe {
+            epoll_wait(
+                self.epfd,
+                self.events.as_mut_ptr(),
+                self.events.len() as i32,
+                timeout,
+            )
+        };
+
+        if n == -1 {
+            if ErrNo::fetch() == ErrNo::EINTR {
+                return Ok(());
+            }
+
+            return Err(NetErr::EpollWait);
+        }
+
+        events.extend(self.events[..n as usize].iter().map(|ev| Event {
+            token: Token(ev.u64 as usize),
+            events: ev.events,
+        }));
+
+        Ok(())
+    }
+
+    fn ctl(
+        &self,
+        op: i32,
+        fd: i32,
+        token: Token,
+        interest: Interest,
+    ) -> Result<()> {
+        let mut ev = epoll_event {
+            events: interest.0,
+            u64: token.0 as u64,
+        };
+
+        unsafe {
+            throw_errno!(epoll_ctl(self.epfd, op, fd, &mut ev) throws EpollCtl)
+        };
+
+        Ok(())
+    }
+}
+
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.epfd);
+        }
+    }
+}
+
+
+impl Waker {
+    pub fn new() -> Result<Self> {
+        let flags = EFD_CLOEXEC | EFD_NONBLOCK;
+        let fd = unsafe { throw_errno!(eventfd(0, flags) throws EpollCreate) };
+
+        Ok(Self {
+            fd: Arc::new(EventFd(fd)),
+        })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn wake(&self) {
+        let one = 1u64;
+
+        // only fails when the counter would overflow, it's awake then
+        unsafe {
+            write(self.fd.0, &one as *const u64 as *const c_void, 8);
+        }
+    }
+
+    /// Reset the counter
+    pub fn drain(&self) {
+        let mut cnt = 0u64;
+
+        unsafe {
+            read(self.fd.0, &mut cnt as *mut u64 as *mut c_void, 8);
+        }
+    }
+}
+
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0);
+        }
+    }
+}
+
+
+impl<S> Conns<S> {
+    fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+            touched: vec![],
+        }
+    }
+
+    pub fn get(&self, token: Token) -> Option<&Conn<S>> {
+        self.map.get(&token)
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut Conn<S>> {
+        let conn = self.map.get_mut(&token)?;
+        self.touched.push(token);
+
+        Some(conn)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+
+impl<H: Handler> Reactor<H> {
+    pub fn new(listener: TcpListener, handler: H) -> Result<Self> {
+        Self::with_waker(listener, handler, Waker::new()?)
+    }
+
+    /// The handler may need the waker ahead for its workers
+    pub fn with_waker(
+        listener: TcpListener,
+        handler: H,
+        waker: Waker,
+    ) -> Result<Self> {
+        listener
+            .set_nonblocking(true)
+            .map_err(NetErr::SetStreamOpt)?;
+
+        let poller = Poller::new()?;
+
+        poller.add(listener.as_raw_fd(), LISTENER, Interest::READABLE)?;
+        poller.add(waker.fd(), WAKER, Interest::READABLE)?;
+
+        Ok(Self {
+            poller,
+            listener,
+            waker,
+            conns: Conns::new(),
+            timers: Timers::new(),
+            idle_timeout: None,
+            next_token: CONN_TOKEN_START,
+            handler,
+        })
+    }
+
+    /// Connections without reading or writing progress in `timeout`
+    /// are passed to [`Handler::on_idle`].
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn conns(&self) -> &Conns<H::State> {
+        &self.conns
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let mut events = vec![];
+
+        loop {
+            self.run_once(None, &mut events)?;
+        }
+    }
+
+    /// One round of wait and dispatch, `timeout` caps the wait. A failing
+    /// connection is only logged and dropped.
+    pub fn run_once(
+        &mut self,
+        timeout: Option<Duration>,
+        events: &mut Vec<Event>,
+    ) -> Result<()> {
+        let timeout = match (timeout, self.timers.next_timeout(Instant::now()))
+        {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        self.poller.wait(timeout, events)?;
+
+        for ev in events.iter() {
+            match ev.token {
+                LISTENER => self.accept(),
+                WAKER => {
+                    self.waker.drain();
+                    self.handler.on_wake(&mut self.conns);
+
+                    for token in std::mem::take(&mut self.conns.touched) {
+                        self.sync(token);
+                    }
+                }
+                token => self.dispatch(token, ev),
+            }
+        }
+
+        self.expire();
+
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(pair) => pair,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    return
+                }
+                Err(err)
+                    if matches!(
+                        err.raw_os_error(),
+                        Some(EMFILE | ENFILE | ENOBUFS | ENOMEM)
+                    ) =>
+                {
+                    warn!("accept: {err}, pause {ACCEPT_BACKOFF:?}");
+
+                    self.watch_listener(Interest::NONE);
+                    self.timers.add_after(ACCEPT_BACKOFF, LISTENER);
+
+                    return;
+                }
+                // e.g. the peer has reset it
+                Err(_) => return,
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            let data = self.handler.on_accept(peer);
+            let Ok(mut conn) = Conn::new(token, stream, peer, data)
+            else {
+                continue;
+            };
+
+            let interest = conn.registered;
+
+            if let Err(err) = self.poller.add(conn.fd(), token, interest) {
+                warn!("drop {peer}, epoll add: {err:?}");
+
+                self.handler.on_close(&mut conn);
+                continue;
+            }
+
+            self.conns.map.insert(token, conn);
+            self.rearm(token);
+        }
+    }
+
+    fn dispatch(&mut self, token: Token, ev: &Event) {
+        let Some(conn) = self.conns.map.get_mut(&token)
+        else {
+            return;
+        };
+
+        let mut progress = false;
+
+        if ev.is_writable() || ev.is_closed() {
+            match conn.flush() {
+                Ok(n) => progress |= n > 0,
+                Err(_) => conn.abort(),
+            }
+        }
+
+        let readable = ev.is_readable() || ev.is_closed();
+
+        if readable && conn.interest().is_readable() {
+            match conn.fill() {
+                Ok(n) => {
+                    progress |= n > 0 || conn.is_eof();
+                    self.handler.on_read(conn);
+                }
+                Err(_) => conn.abort(),
+            }
+        }
+        else if ev.is_closed() {
+            // reset while reading is paused, the hang up is reported
+            // level-triggered whatever it's registered for
+            conn.abort();
+        }
+
+        if progress {
+            self.rearm(token);
+        }
+
+        self.sync(token)
+    }
+
+    /// Flush what the handler has queued, then update the epoll set or
+    /// drop the connection.
+    fn sync(&mut self, token: Token) {
+        let Some(conn) = self.conns.map.get_mut(&token)
+        else {
+            return;
+        };
+
+        if conn.pending_output() > 0 && conn.flush().is_err() {
+            conn.abort();
+        }
+
+        let interest = conn.interest();
+
+        if !conn.is_done() && interest != conn.registered {
+            match self.poller.modify(conn.fd(), token, interest) {
+                Ok(()) => conn.registered = interest,
+                Err(err) => {
+                    warn!("drop {}, epoll modify: {err:?}", conn.peer());
+                    conn.abort();
+                }
+            }
+        }
+
+        if !conn.is_done() {
+            return;
+        }
+
+        let mut conn = self.conns.map.remove(&token).unwrap();
+
+        if let Some(timer) = conn.idle_timer.take() {
+            self.timers.cancel(timer);
+        }
+
+        // closing the fd removes it from the epoll set anyway
+        if let Err(err) = self.poller.delete(conn.fd()) {
+            warn!("{}, epoll delete: {err:?}", conn.peer());
+        }
+
+        conn.shutdown();
+        self.handler.on_close(&mut conn);
+    }
+
+    fn watch_listener(&mut self, interest: Interest) {
+        let fd = self.listener.as_raw_fd();
+
+        if let Err(err) = self.poller.modify(fd, LISTENER, interest) {
+            warn!("listener, epoll modify: {err:?}");
+        }
+    }
+
+    /// Restart the idle timer
+    fn rearm(&mut self, token: Token) {
+        let Some(timeout) = self.idle_timeout
+        else {
+            return;
+        };
+
+        let Some(conn) = self.conns.map.get_mut(&token)
+        else {
+            return;
+        };
+
+        if let Some(timer) = conn.idle_timer.take() {
+            self.timers.cancel(timer);
+        }
+
+        conn.idle_timer = Some(self.timers.add_after(timeout, token));
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+
+        while let Some((_, token)) = self.timers.pop_expired(now) {
+            if token == LISTENER {
+                self.watch_listener(Interest::READABLE);
+                continue;
+            }
+
+            let Some(conn) = self.conns.map.get_mut(&token)
+            else {
+                continue;
+            };
+
+            conn.idle_timer = None;
+
+            if self.handler.on_idle(conn) {
+                conn.abort();
+            }
+            else {
+                self.rearm(token);
+            }
+
+            self.sync(token);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::{Read, Write},
+        net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Conn, Conns, Handler, Interest, Reactor, Token};
+
+    /// Echo lines back, `quit` closes the connection
+    #[derive(Default)]
+    struct Echo {
+        accepted: usize,
+        closed: usize,
+        woken: Vec<Token>,
+        /// Range of it is sent between `<` and `>` when woken
+        file: Option<(Arc<File>, u64, u64)>,
+    }
+
+    impl Handler for Echo {
+        type State = usize;
+
+        fn on_accept(&mut self, _peer: SocketAddr) -> usize {
+            self.accepted += 1;
+            0
+        }
+
+        fn on_read(&mut self, conn: &mut Conn<usize>) {
+            while let Some(nl) = conn.input().iter().position(|c| *c == b'\n')
+            {
+                let line = conn.input()[..=nl].to_vec();
+                conn.consume(nl + 1);
+                conn.data += 1;
+
+                if line == b"quit\n" {
+                    conn.close();
+                    return;
+                }
+
+                // as if a worker were handling it
+                if line == b"busy\n" {
+                    conn.write(&line);
+                    conn.pause_read();
+                    return;
+                }
+
+                conn.write(&line);
+            }
+
+            if conn.is_eof() {
+                conn.close();
+            }
+        }
+
+        fn on_wake(&mut self, conns: &mut Conns<usize>) {
+            for token in self.woken.drain(..) {
+                let Some(conn) = conns.get_mut(token)
+                else {
+                    continue;
+                };
+
+                if let Some((file, offset, len)) = self.file.clone() {
+                    conn.write(b"<");
+                    conn.write_file(file, offset, len);
+                    conn.write(b">");
+                }
+                else {
+                    conn.write(b"woken\n");
+                }
+            }
+        }
+
+        fn on_close(&mut self, _conn: &mut Conn<usize>) {
+            self.closed += 1;
+        }
+    }
+
+    fn run_until<F: FnMut(&Reactor<Echo>) -> bool>(
+        reactor: &mut Reactor<Echo>,
+        mut f: F,
+    ) {
+        let start = Instant::now();
+        let mut events = vec![];
+
+        while !f(reactor) {
+            assert!(start.elapsed() < Duration::from_secs(5), "timeout");
+
+            reactor
+                .run_once(Some(Duration::from_millis(10)), &mut events)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reactor_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut reactor = Reactor::new(listener, Echo::default()).unwrap();
+
+        let client = thread::spawn(move || {
+            let mut streams: Vec<TcpStream> =
+                (0..8).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+            for (i, stream) in streams.iter_mut().enumerate() {
+                // split in the middle of the line
+                stream
+                    .write_all(format!("hello {i}\nbye").as_bytes())
+                    .unwrap();
+            }
+
+            for (i, stream) in streams.iter_mut().enumerate() {
+                stream.write_all(b" now\n").unwrap();
+
+                let expected = format!("hello {i}\nbye now\n");
+                let mut buf = vec![0; expected.len()];
+                stream.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, expected.as_bytes());
+            }
+
+            let mut last = streams.pop().unwrap();
+            last.write_all(b"quit\n").unwrap();
+
+            let mut rest = vec![];
+            last.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+
+            for stream in streams {
+                stream.shutdown(Shutdown::Write).unwrap();
+            }
+        });
+
+        run_until(&mut reactor, |r| {
+            r.handler().accepted == 8 && r.conns().is_empty()
+        });
+        client.join().unwrap();
+
+        assert_eq!(reactor.handler().closed, 8);
+    }
+
+    #[test]
+    fn test_reactor_idle_and_wake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut reactor = Reactor::new(listener, Echo::default())
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(200));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        run_until(&mut reactor, |r| r.conns().len() == 1);
+
+        reactor.handler_mut().woken.push(Token(2));
+        reactor.waker().wake();
+
+        let mut buf = [0; 6];
+        run_until(&mut reactor, |_| {
+            stream.set_nonblocking(true).unwrap();
+            stream.read_exact(&mut buf).is_ok()
+        });
+        assert_eq!(&buf, b"woken\n");
+
+        // dropped for idle
+        let start = Instant::now();
+        run_until(&mut reactor, |r| r.conns().is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        stream.set_nonblocking(false).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reactor_reset_while_paused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut reactor = Reactor::new(listener, Echo::default()).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"busy\n").unwrap();
+
+        run_until(&mut reactor, |r| {
+            r.conns()
+                .get(Token(2))
+                .is_some_and(|conn| conn.interest() == Interest::NONE)
+        });
+
+        // closed with the reply unread, it's sent as RST
+        thread::sleep(Duration::from_millis(50));
+        drop(stream);
+
+        let start = Instant::now();
+        run_until(&mut reactor, |r| r.handler().closed == 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(reactor.conns().is_empty());
+    }
+
+    #[test]
+    fn test_reactor_sendfile() {
+        let path = std::env::temp_dir()
+            .join(format!("netlib-sendfile-{}", std::process::id()));
+        let content: Vec<u8> =
+            (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let file = Arc::new(File::open(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut reactor = Reactor::new(listener, Echo::default()).unwrap();
+
+        // larger than the socket buffer, so it has to wait for writable
+        let (offset, len) = (100, content.len() as u64 - 200);
+        reactor.handler_mut().file = Some((file, offset, len));
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut buf = vec![0; len as usize + 2];
+
+            thread::sleep(Duration::from_millis(50));
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(b"quit\n").unwrap();
+
+            buf
+        });
+
+        run_until(&mut reactor, |r| r.conns().len() == 1);
+        reactor.handler_mut().woken.push(Token(2));
+        reactor.waker().wake();
+
+        run_until(&mut reactor, |r| r.handler().closed == 1);
+        let buf = client.join().unwrap();
+
+        assert_eq!(buf[0], b'<');
+        assert_eq!(&buf[1..=len as usize], &content[100..content.len() - 100]);
+        assert_eq!(buf[len as usize + 1], b'>');
+    }
+}
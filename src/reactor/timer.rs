@@ -0,0 +1,146 @@
+//! Deadline timers for the reactor loop, cancelled ones are dropped
+//! lazily when they come to the top of the heap.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::{Duration, Instant},
+};
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Structure
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+
+#[derive(Debug)]
+pub struct Timers<T> {
+    /// Ids of the same deadline fire in insertion order
+    heap: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    entries: HashMap<TimerId, T>,
+    next_id: u64,
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Implementation
+
+impl<T> Default for Timers<T> {
+    fn default() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            entries: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+
+impl<T> Timers<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, deadline: Instant, data: T) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        self.heap.push(Reverse((deadline, id)));
+        self.entries.insert(id, data);
+
+        id
+    }
+
+    pub fn add_after(&mut self, dur: Duration, data: T) -> TimerId {
+        self.add(Instant::now() + dur, data)
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        self.entries.remove(&id)
+    }
+
+    /// Pending timers, cancelled ones excluded
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        self.drop_cancelled();
+
+        self.heap.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    /// Time to wait from `now` for the nearest one, zero if it's overdue
+    pub fn next_timeout(&mut self, now: Instant) -> Option<Duration> {
+        self.next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    /// Pop one timer whose deadline is not after `now`
+    pub fn pop_expired(&mut self, now: Instant) -> Option<(TimerId, T)> {
+        self.drop_cancelled();
+
+        let Reverse((deadline, id)) = *self.heap.peek()?;
+
+        if deadline > now {
+            return None;
+        }
+
+        self.heap.pop();
+
+        self.entries.remove(&id).map(|data| (id, data))
+    }
+
+    fn drop_cancelled(&mut self) {
+        while let Some(Reverse((_, id))) = self.heap.peek() {
+            if self.entries.contains_key(id) {
+                break;
+            }
+
+            self.heap.pop();
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Timers;
+
+    #[test]
+    fn test_timers() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+
+        let mut timers = Timers::new();
+        timers.add(now + ms(30), "c");
+        let b = timers.add(now + ms(20), "b");
+        timers.add(now + ms(10), "a");
+        timers.add(now + ms(10), "a2");
+
+        assert_eq!(timers.len(), 4);
+        assert_eq!(timers.next_timeout(now), Some(ms(10)));
+        assert_eq!(timers.next_timeout(now + ms(15)), Some(ms(0)));
+
+        assert!(timers.pop_expired(now).is_none());
+        assert_eq!(timers.pop_expired(now + ms(10)).unwrap().1, "a");
+        assert_eq!(timers.pop_expired(now + ms(10)).unwrap().1, "a2");
+
+        assert_eq!(timers.cancel(b), Some("b"));
+        assert_eq!(timers.cancel(b), None);
+        assert_eq!(timers.next_deadline(), Some(now + ms(30)));
+
+        assert_eq!(timers.pop_expired(now + ms(40)).unwrap().1, "c");
+        assert!(timers.is_empty());
+        assert_eq!(timers.next_deadline(), None);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::defe;
+use crate::{c_error::ErrNo, defe};
 
 
 defe! {
@@ -12,6 +12,13 @@ defe! {
         YAMLNonExistField(&'static str),
 
         HttpBadReq(HttpKind),
+        /// Error of HTTP client
+        Http(HttpKind),
+        Dhcp(DhcpKind),
+        Dns(DnsKind),
+        FastCgi(FcgiKind),
+        WebSocket(WsKind),
+        Igmp(IgmpKind),
         Log4RS(LoggerKind),
 
         UnresolvedHost(String),
@@ -20,12 +27,16 @@ defe! {
         EpollCreate,
         EpollCtl,
         EpollWait,
+        Poll,
 
         CreateRawSocket,
+        CreateSocket,
+        SetSockOpt,
         GetSockName,
         Accept,
         SendTo,
         RecvFrom,
+        Connect(std::io::Error),
         GetIfAddrs,
         SocketRaw,
         Bind,
@@ -44,6 +55,9 @@ defe! {
         CreateThreadPool(std::io::Error),
         CreateDirAll(std::io::Error),
         GetIf(String),
+        /// Error reported by kernel in NLMSG_ERROR
+        Netlink(ErrNo),
+        NetNs(String),
         GetGateway(String),
         AnyWay(String),
         Bug(String)
@@ -60,11 +74,104 @@ pub enum HttpKind {
     ReqLnNotFound,
     HeaderNotFoundForVersion(String),
     UnRecognizedVerStr(String),
+    InvalidMethod(String),
+    InvalidUri(String),
+    /// Request line is longer than the limit
+    ReqLnTooLong(usize),
+    /// Header section is larger than the limit
+    HeaderTooLarge(usize),
+    TooManyHeaders(usize),
+    /// HTTP/1.1 request without Host header
+    MissingHost,
+    UnsupportedTransferEncoding(String),
+    InvalidChunk(String),
+    /// Body is larger than the limit
+    BodyTooLarge(usize),
+    /// Expect other than 100-continue
+    ExpectationFailed(String),
+    /// Status line of response
+    InvalidStatusLn(String),
+    /// Connection is closed before the end of the body
+    IncompleteBody,
+    UnsupportedContentEncoding(String),
+    UnsupportedScheme(String),
+    TooManyRedirects(usize),
     /// This error should be a bug
     Bug(String)
     // UnSupportedHttpVer(String)
 }
 
+#[derive(Debug)]
+pub enum DhcpKind {
+    TooShort(usize),
+    BadMagic(u32),
+    InvalidOpt(u8),
+    NoMsgType,
+    /// Received message doesn't belong to current transaction
+    UnexpectedMsg(String),
+    NoLease,
+}
+
+#[derive(Debug)]
+pub enum DnsKind {
+    TooShort(usize),
+    /// Invalid label or name longer than 255 bytes
+    BadName(String),
+    /// Compression pointer out of range or looped, at the offset
+    BadPointer(usize),
+    /// RDATA doesn't match the declared length of the type
+    BadRData(u16),
+    /// Non-zero response code
+    Rcode(u8),
+    Timeout,
+    NoAnswer(String),
+    /// Query to send doesn't have exactly one question
+    NoQuestion,
+}
+
+#[derive(Debug)]
+pub enum FcgiKind {
+    TooShort(usize),
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    UnknownRole(u16),
+    /// Name-value pair runs over the content
+    BadParams,
+    /// Record of other request or type that isn't expected here
+    UnexpectedRecord(u8),
+    /// END_REQUEST with a protocol status other than REQUEST_COMPLETE
+    ProtocolStatus(u8),
+    /// Connection is closed in the middle of a request
+    ConnClosed,
+}
+
+#[derive(Debug)]
+pub enum WsKind {
+    /// Upgrade request or 101 response that isn't a valid handshake
+    BadHandshake(String),
+    /// Sec-WebSocket-Version other than 13
+    UnsupportedVersion(String),
+    /// Frame or message that breaks the protocol
+    Protocol(String),
+    /// Frame from the client isn't masked or that from the server is
+    BadMask,
+    /// Payload of a frame or message is larger than the limit
+    TooLarge(usize),
+    /// Text message or close reason isn't UTF-8
+    InvalidUtf8,
+    /// Compressed message can't be inflated
+    BadDeflate(String),
+    /// Close frames have been exchanged or the connection is closed
+    ConnClosed,
+}
+
+#[derive(Debug)]
+pub enum IgmpKind {
+    TooShort(usize),
+    BadChecksum,
+    UnknownType(u8),
+}
+
 #[derive(Debug)]
 pub enum LoggerKind {
     LoadConfigFailed(String),
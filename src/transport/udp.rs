@@ -1,4 +1,22 @@
-use crate::{view::U16N, defraw};
+use std::{
+    mem::{size_of, zeroed},
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use libc::{
+    bind, close, in_addr, ip_mreq, ip_mreq_source, recvfrom, sendto,
+    setsockopt, sockaddr, sockaddr_in, socket, socklen_t, timeval, AF_INET,
+    EAGAIN, IFNAMSIZ, IPPROTO_IP, IP_ADD_MEMBERSHIP, IP_ADD_SOURCE_MEMBERSHIP,
+    IP_DROP_MEMBERSHIP, IP_DROP_SOURCE_MEMBERSHIP, IP_MULTICAST_IF,
+    IP_MULTICAST_LOOP, IP_MULTICAST_TTL, SOCK_DGRAM, SOL_SOCKET,
+    SO_BINDTODEVICE, SO_BROADCAST, SO_RCVTIMEO, SO_REUSEADDR,
+};
+
+use crate::{
+    c_error::ErrNo, data::SockAddrIn, defraw,
+    network::getsockname_sockaddr_in, throw_errno, view::U16N, Result,
+};
 
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -15,8 +33,362 @@ defraw! {
 }
 
 
+/// Thin wrapper of `AF_INET` `SOCK_DGRAM` socket, closed on drop
+#[derive(Debug)]
+pub struct UdpSock {
+    pub fd: i32,
+}
+
 
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Implements
 
+impl UdpSock {
+    /// Create and bind to `addr` (port 0 for an ephemeral port)
+    pub unsafe fn bind(addr: SocketAddrV4) -> Result<Self> {
+        let fd = throw_errno!(
+            socket(AF_INET, SOCK_DGRAM, 0) throws CreateSocket
+        );
+        let it = Self { fd };
+
+        it.set_opt_int(SO_REUSEADDR, 1)?;
+
+        let sin: sockaddr_in = sockaddr_in_of(addr).into();
+        throw_errno!(bind(
+            fd,
+            &sin as *const sockaddr_in as *const sockaddr,
+            size_of::<sockaddr_in>() as socklen_t
+        ) throws Bind);
+
+        Ok(it)
+    }
+
+    /// Bind to `group:port` and join the group on the interface of
+    /// `ifaddr` (unspecified to let kernel choose)
+    ///
+    /// Binding to the group address filters out datagrams of other groups
+    /// on the same port.
+    pub unsafe fn multicast(
+        group: Ipv4Addr,
+        port: u16,
+        ifaddr: Ipv4Addr,
+    ) -> Result<Self> {
+        let it = Self::bind(SocketAddrV4::new(group, port))?;
+
+        it.join_multicast(group, ifaddr)?;
+
+        Ok(it)
+    }
+
+    /// IP_ADD_MEMBERSHIP, kernel sends the IGMP report
+    pub unsafe fn join_multicast(
+        &self,
+        group: Ipv4Addr,
+        ifaddr: Ipv4Addr,
+    ) -> Result<()> {
+        self.set_ip_opt(IP_ADD_MEMBERSHIP, &mreq_of(group, ifaddr))
+    }
+
+    pub unsafe fn leave_multicast(
+        &self,
+        group: Ipv4Addr,
+        ifaddr: Ipv4Addr,
+    ) -> Result<()> {
+        self.set_ip_opt(IP_DROP_MEMBERSHIP, &mreq_of(group, ifaddr))
+    }
+
+    /// Source-specific join (IGMPv3 INCLUDE mode)
+    pub unsafe fn join_source_group(
+        &self,
+        group: Ipv4Addr,
+        source: Ipv4Addr,
+        ifaddr: Ipv4Addr,
+    ) -> Result<()> {
+        self.set_ip_opt(
+            IP_ADD_SOURCE_MEMBERSHIP,
+            &mreq_source_of(group, source, ifaddr),
+        )
+    }
+
+    pub unsafe fn leave_source_group(
+        &self,
+        group: Ipv4Addr,
+        source: Ipv4Addr,
+        ifaddr: Ipv4Addr,
+    ) -> Result<()> {
+        self.set_ip_opt(
+            IP_DROP_SOURCE_MEMBERSHIP,
+            &mreq_source_of(group, source, ifaddr),
+        )
+    }
+
+    /// Outgoing interface of multicast datagrams
+    pub unsafe fn set_multicast_if(&self, ifaddr: Ipv4Addr) -> Result<()> {
+        self.set_ip_opt(IP_MULTICAST_IF, &in_addr_of(ifaddr))
+    }
+
+    pub unsafe fn set_multicast_ttl(&self, ttl: u8) -> Result<()> {
+        self.set_ip_opt(IP_MULTICAST_TTL, &(ttl as i32))
+    }
+
+    /// Whether to receive our own multicast datagrams, on by default
+    pub unsafe fn set_multicast_loop(&self, on: bool) -> Result<()> {
+        self.set_ip_opt(IP_MULTICAST_LOOP, &(on as i32))
+    }
+
+    unsafe fn set_ip_opt<T>(&self, name: i32, val: &T) -> Result<()> {
+        throw_errno!(setsockopt(
+            self.fd,
+            IPPROTO_IP,
+            name,
+            val as *const T as *const _,
+            size_of::<T>() as socklen_t
+        ) throws SetSockOpt);
+
+        Ok(())
+    }
+
+    pub unsafe fn set_broadcast(&self, on: bool) -> Result<()> {
+        self.set_opt_int(SO_BROADCAST, on as i32)
+    }
+
+    /// SO_BINDTODEVICE, requires CAP_NET_RAW
+    pub unsafe fn bind_to_device(&self, ifname: &str) -> Result<()> {
+        let mut name = [0u8; IFNAMSIZ];
+        let n = ifname.len().min(IFNAMSIZ - 1);
+        name[..n].copy_from_slice(&ifname.as_bytes()[..n]);
+
+        throw_errno!(setsockopt(
+            self.fd,
+            SOL_SOCKET,
+            SO_BINDTODEVICE,
+            name.as_ptr() as *const _,
+            (n + 1) as socklen_t
+        ) throws SetSockOpt);
+
+        Ok(())
+    }
+
+    /// None means block forever
+    pub unsafe fn set_recv_timeout(
+        &self,
+        dur: Option<Duration>,
+    ) -> Result<()> {
+        let tv = match dur {
+            Some(dur) => timeval {
+                tv_sec: dur.as_secs() as _,
+                tv_usec: dur.subsec_micros() as _,
+            },
+            None => zeroed(),
+        };
+
+        throw_errno!(setsockopt(
+            self.fd,
+            SOL_SOCKET,
+            SO_RCVTIMEO,
+            &tv as *const timeval as *const _,
+            size_of::<timeval>() as socklen_t
+        ) throws SetSockOpt);
+
+        Ok(())
+    }
+
+    pub unsafe fn set_opt_int(&self, name: i32, val: i32) -> Result<()> {
+        throw_errno!(setsockopt(
+            self.fd,
+            SOL_SOCKET,
+            name,
+            &val as *const i32 as *const _,
+            size_of::<i32>() as socklen_t
+        ) throws SetSockOpt);
+
+        Ok(())
+    }
+
+    pub unsafe fn send_to(
+        &self,
+        buf: &[u8],
+        dst: SocketAddrV4,
+    ) -> Result<usize> {
+        let sin: sockaddr_in = sockaddr_in_of(dst).into();
+
+        let n = throw_errno!(sendto(
+            self.fd,
+            buf.as_ptr() as *const _,
+            buf.len(),
+            0,
+            &sin as *const sockaddr_in as *const sockaddr,
+            size_of::<sockaddr_in>() as socklen_t
+        ) throws SendTo);
+
+        Ok(n as usize)
+    }
+
+    pub unsafe fn local_addr(&self) -> Result<SocketAddrV4> {
+        let sin = getsockname_sockaddr_in(self.fd)?;
+
+        Ok(SocketAddrV4::new(sin.addr.ipv4(), sin.port.native()))
+    }
+
+    /// Return None if receive timeout (see `set_recv_timeout`)
+    pub unsafe fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> Result<Option<(usize, SocketAddrV4)>> {
+        let mut sin: sockaddr_in = zeroed();
+        let mut sin_len = size_of::<sockaddr_in>() as socklen_t;
+
+        let n = recvfrom(
+            self.fd,
+            buf.as_mut_ptr() as *mut _,
+            buf.len(),
+            0,
+            &mut sin as *mut sockaddr_in as *mut sockaddr,
+            &mut sin_len,
+        );
+
+        if n == -1 {
+            let errno = ErrNo::fetch();
+
+            if errno as i32 == EAGAIN {
+                return Ok(None);
+            }
+
+            eprintln!("recvfrom: {errno:?}");
+            return Err(crate::NetErr::RecvFrom);
+        }
+
+        let from = SockAddrIn::from(sin);
+
+        Ok(Some((
+            n as usize,
+            SocketAddrV4::new(from.addr.ipv4(), from.port.native()),
+        )))
+    }
+}
+
+
+impl Drop for UdpSock {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Function
+
+pub fn sockaddr_in_of(addr: SocketAddrV4) -> SockAddrIn {
+    let mut sin = SockAddrIn::from(*addr.ip());
+    sin.port = U16N::from_native(addr.port());
+
+    sin
+}
+
+
+fn in_addr_of(ip: Ipv4Addr) -> in_addr {
+    in_addr {
+        s_addr: u32::from_ne_bytes(ip.octets()),
+    }
+}
+
+
+fn mreq_of(group: Ipv4Addr, ifaddr: Ipv4Addr) -> ip_mreq {
+    ip_mreq {
+        imr_multiaddr: in_addr_of(group),
+        imr_interface: in_addr_of(ifaddr),
+    }
+}
+
+
+fn mreq_source_of(
+    group: Ipv4Addr,
+    source: Ipv4Addr,
+    ifaddr: Ipv4Addr,
+) -> ip_mreq_source {
+    ip_mreq_source {
+        imr_multiaddr: in_addr_of(group),
+        imr_interface: in_addr_of(ifaddr),
+        imr_sourceaddr: in_addr_of(source),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Duration,
+    };
+
+    use super::UdpSock;
+
+    #[test]
+    fn test_udp_loopback() {
+        unsafe {
+            let server =
+                UdpSock::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                    .unwrap();
+            let server_addr = server.local_addr().unwrap();
+
+            let client =
+                UdpSock::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                    .unwrap();
+            client.send_to(b"ping", server_addr).unwrap();
+
+            server
+                .set_recv_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let mut buf = [0u8; 16];
+            let (n, _from) = server.recv_from(&mut buf).unwrap().unwrap();
+
+            assert_eq!(&buf[..n], b"ping");
+
+            server
+                .set_recv_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            assert!(server.recv_from(&mut buf).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_udp_multicast() {
+        let group = Ipv4Addr::new(239, 255, 77, 1);
+
+        unsafe {
+            // lo may not take multicast in some sandbox
+            let server = match UdpSock::multicast(group, 0, Ipv4Addr::LOCALHOST)
+            {
+                Ok(server) => server,
+                Err(err) => {
+                    eprintln!("skip, {err:?}");
+                    return;
+                }
+            };
+            let port = server.local_addr().unwrap().port();
+
+            let client =
+                UdpSock::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                    .unwrap();
+            client.set_multicast_if(Ipv4Addr::LOCALHOST).unwrap();
+            client.set_multicast_ttl(1).unwrap();
+            client.set_multicast_loop(true).unwrap();
+            client
+                .send_to(b"hello group", SocketAddrV4::new(group, port))
+                .unwrap();
+
+            server
+                .set_recv_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let mut buf = [0u8; 16];
+            let (n, _from) = server.recv_from(&mut buf).unwrap().unwrap();
+            assert_eq!(&buf[..n], b"hello group");
+
+            server.leave_multicast(group, Ipv4Addr::LOCALHOST).unwrap();
+        }
+    }
+}